@@ -0,0 +1,50 @@
+// MCTSとExecutorとPredictorを通したセルフプレイの速さを測るCriterionのベンチマークです。
+// バイナリだけのクレートなので、src/main.rsをモジュールとして読み込んで使います。
+// テスト用のものやbenchmark以外の機能は使わないので、使っていない警告は出しません
+
+use criterion::{criterion_group,criterion_main,BenchmarkId,Criterion,Throughput};
+
+#[path = "../src/main.rs"]
+#[allow(dead_code,unused_imports)]
+mod craft_simulator;
+
+use craft_simulator::benchmark::play_selfplay_games;
+use craft_simulator::setting::ModifierParameter;
+
+// 1つの設定で対局する数です。シードは固定なので、同じ設定なら推論数も毎回同じです
+const GAMES : u64 = 8;
+const SIMULATION_NUMS : [u32; 2] = [50, 200];
+const BATCH_SIZES : [usize; 2] = [1, 8];
+
+fn bench_selfplay(c:&mut Criterion) {
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+
+    // 1秒あたりのゲーム数です
+    let mut group = c.benchmark_group("selfplay_games");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(GAMES));
+    for simulation_num in SIMULATION_NUMS {
+        for batch_size in BATCH_SIZES {
+            group.bench_with_input(BenchmarkId::new(format!("simulation_num={}", simulation_num), batch_size), &batch_size, |b,&batch_size| {
+                b.iter(|| play_selfplay_games(&mod_param, simulation_num, batch_size, GAMES))
+            });
+        }
+    }
+    group.finish();
+
+    // 1秒あたりの推論数です。推論数は先に1回対局して数えておきます
+    let mut group = c.benchmark_group("selfplay_predictions");
+    group.sample_size(10);
+    for simulation_num in SIMULATION_NUMS {
+        for batch_size in BATCH_SIZES {
+            group.throughput(Throughput::Elements(play_selfplay_games(&mod_param, simulation_num, batch_size, GAMES)));
+            group.bench_with_input(BenchmarkId::new(format!("simulation_num={}", simulation_num), batch_size), &batch_size, |b,&batch_size| {
+                b.iter(|| play_selfplay_games(&mod_param, simulation_num, batch_size, GAMES))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_selfplay);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use mysql::*;
+use mysql::prelude::*;
+
+use super::selfplay::{EpisodeParameter,selfplay_craftone,predict_batch_or_retry};
+use super::predictor::Predictor;
+use super::executor::Executor;
+use super::cache::WeightsCache;
+use super::selector::get_network_type;
+use super::error::CraftSimError;
+use super::db;
+
+// 2つのネットワークの対戦の設定です
+pub struct ArenaParameter {
+    pub episode_param : EpisodeParameter,
+    pub challenger : String, // 挑戦する側のネットワーク名です。勝敗はこちらから見て数えます
+    pub champion : String,
+    pub games : u64,
+    pub batch_size : usize, // 同時に進めるゲームの数です
+    pub mysql_url : String,
+}
+
+// 報酬の差がこれより小さければ引き分けとします
+const DRAW_EPSILON : f32 = 1e-6;
+
+// 挑戦者から見た対戦成績です
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct ArenaResult {
+    pub wins : u64,
+    pub draws : u64,
+    pub losses : u64,
+}
+
+impl ArenaResult {
+    pub fn add(&mut self, challenger_reward:f32, champion_reward:f32) {
+        if (challenger_reward - champion_reward).abs() < DRAW_EPSILON {
+            self.draws += 1;
+        }
+        else if challenger_reward > champion_reward {
+            self.wins += 1;
+        }
+        else {
+            self.losses += 1;
+        }
+    }
+
+    pub fn games(&self) -> u64 {
+        self.wins + self.draws + self.losses
+    }
+
+    // 引き分けを半分の勝ちとした勝率です
+    pub fn score(&self) -> f64 {
+        if self.games() == 0 {
+            return 0.5;
+        }
+        (self.wins as f64 + 0.5 * self.draws as f64) / self.games() as f64
+    }
+
+    // 勝率から求めたEloレーティングの差です。全勝や全敗では無限大になります
+    pub fn elo_difference(&self) -> f64 {
+        -400.0 * (1.0 / self.score() - 1.0).log10()
+    }
+}
+
+#[test]
+fn test_arena_result()
+{
+    let mut result = ArenaResult::default();
+    assert_eq!( 0.0, result.elo_difference() );
+
+    result.add(1.0, 0.5);
+    result.add(0.5, 1.0);
+    result.add(0.7, 0.7);
+    assert_eq!( ArenaResult { wins:1, draws:1, losses:1 }, result );
+    assert_eq!( 0.5, result.score() );
+
+    // 勝率3/4は約191の差です
+    let result = ArenaResult { wins:3, draws:0, losses:1 };
+    assert!( (result.elo_difference() - 190.85).abs() < 0.01 );
+    assert!( ArenaResult { wins:1, draws:0, losses:0 }.elo_difference().is_infinite() );
+}
+
+// 2つのネットワークに同じ番号のゲームを1回ずつ遊ばせて、報酬を比べます。
+// 同じ番号なら環境の乱数のシードが同じなので、開始状態は同じになり、n回目の手の成功の判定と状態の変化には同じ乱数を使います。
+// 探索は別の乱数を使うので、選んだ手が違っても運による差を打ち消せます。
+// base_seedが無い場合もプロセス内では同じシードになります。使ったシードは各レコードのseedsに残ります。
+// 両方のネットワークは事前にpredictorに読み込んでおく必要があります。推論はpredictで行います
+pub fn play_arena_games<F>( param:&EpisodeParameter, predictor:&mut Predictor, (challenger,champion):(&str,&str), games:u64, batch_size:usize, mut predict:F ) -> ArenaResult
+    where F : FnMut(&mut Predictor)
+{
+    let result = Rc::new(RefCell::new(ArenaResult::default()));
+
+    // batch_size個のコルーチンでゲームを分けて受け持ちます
+    let mut executor = Executor::new();
+    for coroutine_id in 0..batch_size as u64 {
+        let param = param.clone();
+        let (challenger,champion) = (challenger.to_string(),champion.to_string());
+        let predict_queue = predictor.get_queue();
+        let result = result.clone();
+        executor.spawn( async move {
+            for game in (coroutine_id..games).step_by(batch_size) {
+                let challenger_record = selfplay_craftone(&param, &challenger, &predict_queue, (0,0), game).await;
+                let champion_record = selfplay_craftone(&param, &champion, &predict_queue, (0,0), game).await;
+                debug_assert_eq!( challenger_record.seeds, champion_record.seeds );
+                result.borrow_mut().add(challenger_record.reward, champion_record.reward);
+            }
+        });
+    }
+
+    while !executor.is_empty() {
+        executor.poll_all();
+        predict(predictor);
+    }
+
+    let ret = *result.borrow();
+    ret
+}
+
+#[test]
+fn test_play_arena_games()
+{
+    use super::logic::{State,ACTION_NUM};
+    use super::mcts::ActionVector;
+
+    let mut param = super::selfplay::new_test_episode_param();
+    param.base_seed = Some(1);
+
+    // 同じネットワーク同士なら、同じ番号のゲームは全く同じ経過になるので全て引き分けです
+    let mut predictor = Predictor::new();
+    let mock = |_:&str, source:&[State]| -> Vec<(ActionVector,f32)> { source.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], 0.5)).collect() };
+    let result = play_arena_games(&param, &mut predictor, ("a","b"), 5, 2, |p| p.predict_batch_with(mock));
+    assert_eq!( ArenaResult { wins:0, draws:5, losses:0 }, result );
+}
+
+#[test]
+fn test_matched_seeds_share_conditions()
+{
+    use super::logic::{State,Action,ACTION_NUM};
+    use super::mcts::ActionVector;
+
+    let mut param = super::selfplay::new_test_episode_param();
+    param.base_seed = Some(1);
+    let mod_param = param.mod_param.clone();
+
+    // 方策の違う2つのモデルで同じ番号のゲームを遊びます
+    let mut predictor = Predictor::new();
+    let records = Rc::new(RefCell::new(vec![]));
+    let mut executor = Executor::new();
+    for name in ["a","b"] {
+        let (param,predict_queue,records) = (param.clone(),predictor.get_queue(),records.clone());
+        executor.spawn( async move {
+            let record = selfplay_craftone(&param, name, &predict_queue, (0,0), 3).await;
+            records.borrow_mut().push(record);
+        });
+    }
+    let mock = |name:&str, source:&[State]| -> Vec<(ActionVector,f32)> {
+        source.iter().map(|_| {
+            let mut policy = [0.1/ACTION_NUM as f32;ACTION_NUM];
+            policy[if name == "a" { Action::BasicTouch } else { Action::BasicSynthesis } as usize] = 1.0;
+            (policy, 0.5)
+        }).collect()
+    };
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch_with(mock);
+    }
+    let mut records = records.take();
+    records.sort_by(|x,y| x.name.cmp(&y.name));
+    let (a,b) = (&records[0],&records[1]);
+    assert_eq!( a.seeds, b.seeds );
+    assert_eq!( a.samples[0].state, b.samples[0].state );
+    assert!( a.samples.iter().zip(b.samples.iter()).any(|(x,y)| x.action != y.action) );
+
+    // n回目の手がどちらも状態を変える手なら、選んだ手が違っても同じ状態に変わります
+    let transitions = |record:&super::selfplay::Record| -> Vec<(State,Action,State)> {
+        record.samples.iter().enumerate().map(|(t,x)| (x.state.clone(), x.action, record.samples.get(t+1).map(|y| y.state.clone()).unwrap_or_else(|| record.last_state.clone()))).collect()
+    };
+    let changes_condition = |s:&State, a:&Action| s.reachable_states(&mod_param, a).iter().any(|x| x.condition != s.condition);
+    let mut compared = 0;
+    for ((sa,aa,na),(sb,ab,nb)) in transitions(a).into_iter().zip(transitions(b)) {
+        if changes_condition(&sa,&aa) && changes_condition(&sb,&ab) && !na.is_terminated() && !nb.is_terminated() {
+            assert_eq!( na.condition, nb.condition );
+            compared += 1;
+        }
+    }
+    assert!( compared > 3 );
+}
+
+// 対戦成績をarenaテーブルに1行追加します。Eloの更新は集計する側で行います
+fn write_arena_result( conn:&mut PooledConn, param:&ArenaParameter, result:&ArenaResult ) -> std::result::Result<(),mysql::Error> {
+    conn.exec_drop(
+        "INSERT INTO arena (challenger, champion, wins, draws, losses) VALUES (:challenger, :champion, :wins, :draws, :losses)",
+        params!{
+            "challenger" => &param.challenger,
+            "champion" => &param.champion,
+            "wins" => result.wins,
+            "draws" => result.draws,
+            "losses" => result.losses,
+        })
+}
+
+// 2つのネットワークを読み込んで対戦させ、結果をMySQLに保存します
+pub fn run( param:ArenaParameter ) -> std::result::Result<ArenaResult,CraftSimError> {
+    let mysql_pool = db::connect(&param.mysql_url, 1)?;
+    let mut conn = mysql_pool.lock().unwrap().get_conn()?;
+
+    let mut predictor = Predictor::new();
+    let mut weights_cache = WeightsCache::new(2);
+    for name in [&param.challenger, &param.champion] {
+        let network_type = get_network_type(&mut conn, name)?;
+        let weights = weights_cache.load_weights(name, network_type)?;
+        predictor.load_network(name.clone(), &weights)?;
+    }
+
+    let mod_param = param.episode_param.mod_param.clone();
+    let result = play_arena_games(&param.episode_param, &mut predictor, (&param.challenger,&param.champion), param.games, param.batch_size, |p| predict_batch_or_retry(p, &mod_param));
+    tracing::info!(challenger = %param.challenger, champion = %param.champion, wins = result.wins, draws = result.draws, losses = result.losses, "{} vs {}: {} wins {} draws {} losses (elo {:+.1})", param.challenger, param.champion, result.wins, result.draws, result.losses, result.elo_difference());
+
+    write_arena_result(&mut conn, &param, &result)?;
+    Ok(result)
+}
@@ -1,8 +1,14 @@
 use core::cmp::min;
+use std::error::Error;
+use std::time::Instant;
 
 use super::network::*;
-use super::logic::State;
+use super::logic::{State,ACTION_NUM};
 use super::setting::ModifierParameter;
+use super::predictor::{Predictor,Priority};
+use super::executor::Executor;
+use super::mcts::{ActionVector,SimulationBudget,AlphaSchedule,GreedyCriterion,TieBreak,RewardFunction};
+use super::selfplay::{EpisodeParameter,TemperatureSchedule,AbortedReward,selfplay_craftone};
 
 pub struct BenchmarkParameter {
     pub mod_param:ModifierParameter,
@@ -25,3 +31,123 @@ pub fn run_benchmark(param:BenchmarkParameter) {
         remain -= size;
     }
 }
+
+// PredictResultのプールの効果を測ります。
+// ネットワークは使わずに推論を即座に解決して、確保の回数と時間だけ比較します。
+// 同じ状態を繰り返し推論するので、推論結果のキャッシュは無効にしておきます
+pub fn run_pool_benchmark(param:BenchmarkParameter) {
+    let predicts_per_task = param.plays_per_write / param.batch_size;
+
+    for capacity in [0, param.batch_size] {
+        let mut predictor = Predictor::new_with_capacity(capacity, 0);
+        let mut executor = Executor::new();
+
+        for _ in 0..param.batch_size {
+            let queue = predictor.get_queue();
+            let s = State::new(&param.mod_param);
+            executor.spawn( async move {
+                for _ in 0..predicts_per_task {
+                    queue.async_predict("benchmark".to_string(), s.clone(), Priority::Normal).await;
+                }
+            });
+        }
+
+        let start = Instant::now();
+        while !executor.is_empty() {
+            executor.poll_all();
+            predictor.predict_batch_with( |_,source| source.iter().map(|_| ([0.0;ACTION_NUM],0.0)).collect() );
+        }
+
+        println!("pool capacity:{} allocations:{} time:{:?}", capacity, predictor.get_pool().created(), start.elapsed());
+    }
+}
+
+// セルフプレイのベンチマークの設定です。simulation_numsとbatch_sizesの全ての組み合わせを測ります
+pub struct SelfPlayBenchmarkParameter {
+    pub mod_param:ModifierParameter,
+    pub games:u64,
+    pub simulation_nums:Vec<u32>,
+    pub batch_sizes:Vec<usize>,
+}
+
+// 一様な方策と固定の評価値を返すだけのネットワークです。
+// 推論にかかる時間を除いて、MCTSとExecutorとPredictorだけの速さを測るために使います
+struct UniformNetwork;
+
+impl Predict for UniformNetwork {
+    fn predict_batch(&self, states:&[State], _mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn Error>> {
+        Ok(states.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], 0.5)).collect())
+    }
+}
+
+// 結果を比べられるように、乱数のシードを固定して探索の回数以外は同じ設定にします
+fn benchmark_episode_param( mod_param:&ModifierParameter, simulation_num:u32 ) -> EpisodeParameter {
+    EpisodeParameter {
+        mod_param:mod_param.clone(),
+        simulation_budget:SimulationBudget::Fixed(simulation_num),
+        c_puct:1.0,
+        min_simulations:0,
+        parallel_simulations:1,
+        virtual_loss:0,
+        alpha:AlphaSchedule::constant(0.15),
+        eps:0.25,
+        add_root_noise:true,
+        temperature_schedule:TemperatureSchedule::greedy_from(0),
+        no_legal_action_reward:0.0,
+        priority:Priority::Normal,
+        max_collected_turns:None,
+        greedy_criterion:GreedyCriterion::Visits,
+        tie_break:TieBreak::LowestIndex,
+        record_raw_prior:false,
+        collect_timings:false,
+        aux_target_fns:vec![],
+        eval_temperature:0.0,
+        hard_start:None,
+        base_seed:Some(0),
+        resign:None,
+        reward_fn:RewardFunction::Default,
+        max_turns:None,
+        truncated_reward:AbortedReward::RewardFn,
+        gumbel:None,
+        mixed_settings:vec![],
+    }
+}
+
+// selfplay_craftoneをbatch_size個のコルーチンで進めてgames回対局します。戻り値は推論した状態の数です。
+// MySQLもtorchも使わないので、探索やバッチの処理を変えた時の差だけを見られます。benches/からも使います
+pub fn play_selfplay_games( mod_param:&ModifierParameter, simulation_num:u32, batch_size:usize, games:u64 ) -> u64 {
+    let episode_param = benchmark_episode_param(mod_param, simulation_num);
+    let mut predictor = Predictor::new();
+    predictor.insert_network("benchmark".to_string(), Box::new(UniformNetwork));
+    predictor.set_max_batch_size(batch_size);
+
+    let mut executor = Executor::new();
+    for coroutine_id in 0..batch_size as u64 {
+        let episode_param = episode_param.clone();
+        let queue = predictor.get_queue();
+        executor.spawn( async move {
+            for game in (coroutine_id..games).step_by(batch_size) {
+                selfplay_craftone(&episode_param, "benchmark", &queue, (0,0), game).await;
+            }
+        });
+    }
+
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch(mod_param).unwrap();
+    }
+    predictor.prediction_counts().get("benchmark").copied().unwrap_or(0)
+}
+
+// 1秒あたりのゲーム数と推論数を測ります
+pub fn run_selfplay_benchmark(param:SelfPlayBenchmarkParameter) {
+    for &simulation_num in &param.simulation_nums {
+        for &batch_size in &param.batch_sizes {
+            let start = Instant::now();
+            let predictions = play_selfplay_games(&param.mod_param, simulation_num, batch_size, param.games);
+            let secs = start.elapsed().as_secs_f64().max(0.001);
+
+            println!("simulation num:{} batch size:{} games/sec:{:.2} predictions/sec:{:.0} time:{:?}", simulation_num, batch_size, param.games as f64 / secs, predictions as f64 / secs, start.elapsed());
+        }
+    }
+}
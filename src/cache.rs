@@ -1,33 +1,325 @@
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{HashMap,BTreeMap};
 use std::result::Result;
 use std::sync::Arc;
+use std::time::Duration;
 
 use tch::*;
 use tch::nn::*;
+use tracing::error;
 
 use super::gcs::*;
 use super::network::*;
+use super::logic::{State,StateKey};
+use super::mcts::ActionVector;
+use super::error::{CraftSimError,storage_error};
+use super::util::retry_with_backoff;
 
+// ダウンロードしたネットワークの重みのキャッシュです。
+// 容量を超えたら最も長く使われていないものから捨てます。捨てるのはキャッシュの持っている参照だけなので、
+// 使用中の重みはArcで生き残り、次に必要になった時はload_weightsで読み込み直します
 pub struct WeightsCache {
-    weights_map : HashMap<String,Arc<(NetworkType,VarStore)>>,
+    capacity : usize,
+    clock : u64,
+    weights_map : HashMap<String,(Arc<Weights>,u64)>, // 重みと最後に使った時刻です
+    order : BTreeMap<u64,String>, // 最後に使った時刻の古い順です
 }
 
 impl WeightsCache {
-    pub fn new() -> WeightsCache {
-        WeightsCache { weights_map : HashMap::new() }
+    pub fn new(capacity:usize) -> WeightsCache {
+        WeightsCache { capacity:capacity.max(1), clock:0, weights_map:HashMap::new(), order:BTreeMap::new() }
     }
 
-    pub fn load_weights(&mut self, name:&str, network_type:NetworkType) -> Result<Arc<(NetworkType,VarStore)>, Box<dyn Error>> {
-        Ok(self.weights_map.entry(name.to_string()).or_insert( {
+    // 名前が.onnxで終わるものはONNX形式として読み込みます。その場合network_typeは使いません。
+    // 名前がURLの場合はGCSではなくそのURLから取得します(fetch_url_weights)
+    pub fn load_weights(&mut self, name:&str, network_type:NetworkType) -> Result<Arc<Weights>, CraftSimError> {
+        if let Some(weights) = self.get(name) {
+            return Ok(weights);
+        }
+
+        let path = if is_url(name) {
+            fetch_url_weights(name)?
+        }
+        else {
             let path = format!("weights/{}", name);
             std::fs::create_dir_all("weights")?;
-            download(&path,&path)?;
+            download(&path,&path).map_err(storage_error)?;
+            path
+        };
+
+        let weights = match NetworkBackend::from_name(split_checksum(name).0) {
+            NetworkBackend::Torch => {
+                let mut vs = VarStore::new(Device::Cpu);
+                let _ = create_network(&vs.root(), network_type);
+                vs.load(&path)?;
+                Weights::Torch(network_type,vs)
+            },
+            NetworkBackend::Onnx => load_onnx_weights(&path)?,
+        };
+
+        let weights = Arc::new(weights);
+        self.insert(name, weights.clone());
+        Ok(weights)
+    }
+
+    fn get(&mut self, name:&str) -> Option<Arc<Weights>> {
+        let (weights,last_used) = self.weights_map.get_mut(name)?;
+        self.clock += 1;
+        self.order.remove(last_used);
+        *last_used = self.clock;
+        self.order.insert(self.clock, name.to_string());
+        Some(weights.clone())
+    }
+
+    fn insert(&mut self, name:&str, weights:Arc<Weights>) {
+        self.clock += 1;
+        if let Some((_,last_used)) = self.weights_map.insert(name.to_string(), (weights,self.clock)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(self.clock, name.to_string());
+
+        while self.weights_map.len() > self.capacity {
+            let (_,oldest) = self.order.pop_first().unwrap();
+            self.weights_map.remove(&oldest);
+        }
+    }
+}
+
+// URLから取得した重みを置くディレクトリです
+const URL_WEIGHTS_DIR : &str = "weights/url";
+
+// URLからの取得を何回まで試すかです
+const URL_DOWNLOAD_RETRY_NUM : u32 = 3;
+
+// URLごとの保存先です。同じURLなら同じファイルになるので、再起動しても取得し直さずに済みます。
+// 形式の判定に使うので.onnxは残します
+fn url_weights_path( url:&str ) -> String {
+    let extension = if url.ends_with(".onnx") { ".onnx" } else { "" };
+    format!("{}/{}{}", URL_WEIGHTS_DIR, sha256_hex(url.as_bytes()), extension)
+}
+
+#[test]
+fn test_url_weights_path()
+{
+    let a = url_weights_path("https://example.com/weights/a");
+    assert!( a.starts_with("weights/url/") );
+    assert_eq!( a, url_weights_path("https://example.com/weights/a") );
+    assert_ne!( a, url_weights_path("s3://bucket/weights/a") );
+    assert!( url_weights_path("s3://bucket/weights/a.onnx").ends_with(".onnx") );
+}
+
+// URLの重みを取得して、ローカルのパスを返します。
+// 取得済みで検証が通ればそのまま使います。一時的な失敗に備えて待ちながら再試行し、
+// 検証を通ったものだけを保存先に移すので、途中で失敗したファイルを読み込むことはありません
+fn fetch_url_weights( name:&str ) -> Result<String, CraftSimError> {
+    let (url,checksum) = split_checksum(name);
+    let path = url_weights_path(url);
+    if verify_file(&path, checksum).is_ok() {
+        return Ok(path);
+    }
+
+    std::fs::create_dir_all(URL_WEIGHTS_DIR)?;
+    let temporary = format!("{}.download", path);
+    retry_with_backoff( URL_DOWNLOAD_RETRY_NUM, Duration::from_secs(1), || {
+        download_url(url, &temporary)?;
+        verify_file(&temporary, checksum)
+    }).map_err(storage_error)?;
+    std::fs::rename(&temporary, &path)?;
+    Ok(path)
+}
+
+#[cfg(feature="onnx")]
+fn load_onnx_weights(path:&str) -> Result<Weights, CraftSimError> {
+    let network = super::onnx::OnnxNetwork::load(std::path::Path::new(path)).map_err(|e| storage_error(format!("failed to load onnx model {}: {}", path, e)))?;
+    Ok(Weights::Onnx(network))
+}
+
+#[cfg(not(feature="onnx"))]
+fn load_onnx_weights(path:&str) -> Result<Weights, CraftSimError> {
+    Err(CraftSimError::Config(format!("{} is an onnx model but this build has no onnx feature", path)))
+}
+
+#[test]
+fn test_weights_cache_eviction()
+{
+    let new_weights = || Arc::new(Weights::Torch(NetworkType::FullyConnected(1,1), VarStore::new(Device::Cpu)));
+
+    let mut cache = WeightsCache::new(2);
+    let a = new_weights();
+    cache.insert("a", a.clone());
+    cache.insert("b", new_weights());
+
+    // aを使ったので、容量を超えた時には最も古いbが捨てられます
+    assert!( cache.get("a").is_some() );
+    cache.insert("c", new_weights());
+    assert!( cache.get("b").is_none() );
+    assert!( cache.get("a").is_some() && cache.get("c").is_some() );
+
+    // 捨てられても使用中の参照はそのまま使えます
+    cache.insert("d", new_weights());
+    cache.insert("e", new_weights());
+    assert!( cache.get("a").is_none() );
+    assert_eq!( 1, Arc::strong_count(&a) );
+    assert!( matches!( *a, Weights::Torch(NetworkType::FullyConnected(1,1),_) ) );
+}
+
+// 何件書き込むごとにファイルへ保存するかです。
+// セルフプレイのスレッドは終了しないので、定期的に保存しておかないと再起動時に使えません
+const PREDICTION_CACHE_SAVE_INTERVAL : usize = 4096;
+
+// NNの推論結果をモデル名と状態をキーにして保存しておくキャッシュです。
+// 同じモデルの評価をプロセスを跨いで繰り返す時に再計算を省くためのものです。
+// 推論結果はModifierParameterにも依存するので、設定ごとに別のファイルを使ってください。
+pub struct PredictionCache {
+    path : String,
+    max_entries : usize,
+    unsaved : usize,
+    entries : HashMap<(String,StateKey),(ActionVector,f32)>,
+}
+
+impl PredictionCache {
+    // ファイルがあれば読み込みます。無ければ空のキャッシュになります
+    pub fn open(path:&str, max_entries:usize) -> Result<PredictionCache, CraftSimError> {
+        let entries = match std::fs::File::open(path) {
+            Ok(file) => bincode::deserialize_from(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(PredictionCache { path:path.to_string(), max_entries, unsaved:0, entries })
+    }
+
+    pub fn get(&self, name:&str, state:&State) -> Option<(ActionVector,f32)> {
+        self.entries.get(&(name.to_string(),state.canonical_key())).cloned()
+    }
+
+    // 上限に達している場合は追加しません。
+    // 評価では同じ状態が何度も出てくるので、古いものを捨てるより先着を残すほうが当たりやすいです
+    pub fn insert(&mut self, name:&str, state:State, value:(ActionVector,f32)) {
+        if self.entries.len() >= self.max_entries {
+            return;
+        }
+        self.entries.insert((name.to_string(),state.canonical_key()), value);
+        self.unsaved += 1;
+
+        if self.unsaved >= PREDICTION_CACHE_SAVE_INTERVAL {
+            if let Err(e) = self.save() {
+                error!("failed to save prediction cache {:?}", e);
+            }
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), CraftSimError> {
+        let file = std::fs::File::create(&self.path)?;
+        bincode::serialize_into(std::io::BufWriter::new(file), &self.entries)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
 
-            let mut vs = VarStore::new(Device::Cpu);
-            let _ = create_network(&vs.root(), network_type);
-            vs.load(&path).unwrap();
-            Arc::new((network_type,vs))
-        }).clone())
+impl Drop for PredictionCache {
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            if let Err(e) = self.save() {
+                error!("failed to save prediction cache {:?}", e);
+            }
+        }
     }
 }
+
+// 1つのPredictorの中で使う、推論結果のメモリ上のキャッシュです。
+// 別のコルーチンの探索木で同じ状態を推論し直すのを省きます。
+// 容量を超えたら最も長く使われていないものから捨てます。容量が0の場合は何も保存しません
+pub struct LruPredictionCache {
+    capacity : usize,
+    clock : u64,
+    entries : HashMap<(String,StateKey),((ActionVector,f32),u64)>, // 値と最後に使った時刻です
+    order : BTreeMap<u64,(String,StateKey)>, // 最後に使った時刻の古い順です
+}
+
+impl LruPredictionCache {
+    pub fn new(capacity:usize) -> LruPredictionCache {
+        LruPredictionCache { capacity, clock:0, entries:HashMap::new(), order:BTreeMap::new() }
+    }
+
+    pub fn get(&mut self, name:&str, state:&State) -> Option<(ActionVector,f32)> {
+        let key = (name.to_string(),state.canonical_key());
+        let (value,last_used) = self.entries.get_mut(&key)?;
+        self.clock += 1;
+        self.order.remove(last_used);
+        *last_used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(*value)
+    }
+
+    pub fn insert(&mut self, name:&str, state:&State, value:(ActionVector,f32)) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (name.to_string(),state.canonical_key());
+        self.clock += 1;
+        if let Some((_,last_used)) = self.entries.insert(key.clone(), (value,self.clock)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(self.clock, key);
+
+        while self.entries.len() > self.capacity {
+            let (_,oldest) = self.order.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    // 同じ名前で別のネットワークを読み込んだ場合に、古い推論結果を捨てます
+    pub fn remove_network(&mut self, name:&str) {
+        self.entries.retain(|(x,_),_| x != name);
+        self.order.retain(|_,(x,_)| x != name);
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[test]
+fn test_lru_prediction_cache()
+{
+    let mod_param = super::setting::ModifierParameter::new_fountain_of_usouso();
+    let states : Vec<State> = (1..=3).map(|turn| State { turn, ..State::new(&mod_param) }).collect();
+    let value = |x:f32| ([x;32], x);
+
+    let mut cache = LruPredictionCache::new(2);
+    cache.insert("a", &states[0], value(1.0));
+    cache.insert("a", &states[1], value(2.0));
+
+    // 使ったものは残り、最も長く使われていないものから捨てられます
+    assert_eq!( Some(value(1.0)), cache.get("a", &states[0]) );
+    cache.insert("a", &states[2], value(3.0));
+    assert_eq!( 2, cache.len() );
+    assert_eq!( None, cache.get("a", &states[1]) );
+    assert_eq!( Some(value(1.0)), cache.get("a", &states[0]) );
+
+    // ネットワークの名前ごとに別の値を持ちます
+    assert_eq!( None, cache.get("b", &states[0]) );
+    cache.insert("b", &states[0], value(4.0));
+    assert_eq!( Some(value(1.0)), cache.get("a", &states[0]) );
+    cache.remove_network("a");
+    assert_eq!( None, cache.get("a", &states[0]) );
+    assert_eq!( Some(value(4.0)), cache.get("b", &states[0]) );
+
+    // 容量が0なら何も保存しません
+    let mut cache = LruPredictionCache::new(0);
+    cache.insert("a", &states[0], value(1.0));
+    assert_eq!( None, cache.get("a", &states[0]) );
+}
+
+#[test]
+fn test_prediction_cache_open_error()
+{
+    let path = std::env::temp_dir().join(format!("prediction_cache_broken_{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, [0xff; 3]).unwrap();
+
+    let ret = PredictionCache::open(path, 100);
+    std::fs::remove_file(path).unwrap();
+    assert!( matches!( ret, Err(CraftSimError::Serialization(_)) ) );
+}
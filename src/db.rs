@@ -0,0 +1,54 @@
+use std::sync::{Arc,Mutex};
+
+use mysql::{Pool,Opts,DriverError};
+
+use super::error::CraftSimError;
+
+// 接続先のURLです。パスワードは環境変数MYSQL_PASSWORDから読みます
+pub fn mysql_url( mysql_user:&str, mysql_host:&str, mysql_port:u16, mysql_db:&str ) -> String {
+    let mysql_password = match std::env::var("MYSQL_PASSWORD") {
+        Ok(val) => format!(":{}", val ),
+        Err(_) => String::new(),
+    };
+
+    format!("mysql://{}{}@{}:{}/{}", mysql_user, mysql_password, mysql_host, mysql_port, mysql_db )
+}
+
+#[test]
+fn test_mysql_url()
+{
+    let url = mysql_url("worker", "db.internal", 3307, "craft2");
+    assert!( url.starts_with("mysql://worker") );
+    assert!( url.ends_with("@db.internal:3307/craft2") );
+}
+
+pub fn connect( url:&str, pool_size:usize ) -> Result<Arc<Mutex<Pool>>,CraftSimError> {
+    let opts = Opts::from_url(url).map_err(|e| CraftSimError::Config(format!("invalid mysql url: {}", e)))?;
+    let pool = Pool::new_manual(pool_size, pool_size, opts)?;
+    Ok(Arc::new(Mutex::new(pool)))
+}
+
+#[test]
+fn test_connect_error()
+{
+    assert!( matches!( connect("not a url", 1), Err(CraftSimError::Config(_)) ) );
+
+    // 誰も待ち受けていないポートへの接続は失敗します
+    assert!( matches!( connect("mysql://root@127.0.0.1:1/craft", 1), Err(CraftSimError::Database(_)) ) );
+}
+
+// すぐに直る可能性のある接続の断絶やタイムアウトのエラーコードです。
+// 1040:接続数過多 1205:ロック待ちタイムアウト 1213:デッドロック 2006:サーバーが落ちた 2013:問い合わせ中に切断
+const TRANSIENT_MYSQL_ERROR_CODES : [u16; 5] = [1040, 1205, 1213, 2006, 2013];
+
+// データベースの再起動などで一時的に起きるエラーかどうかです。
+// 設定の間違いやデータの不整合は何度やり直しても直らないのでfalseです
+pub fn is_transient( e:&mysql::Error ) -> bool {
+    match e {
+        mysql::Error::IoError(_) => true,
+        mysql::Error::CodecError(_) => true,
+        mysql::Error::DriverError(e) => matches!(e, DriverError::ConnectTimeout | DriverError::CouldNotConnect(_) | DriverError::Timeout),
+        mysql::Error::MySqlError(e) => TRANSIENT_MYSQL_ERROR_CODES.contains(&e.code),
+        _ => false,
+    }
+}
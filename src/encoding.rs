@@ -24,6 +24,13 @@ impl OneHotConvertible for bool {
 }
 
 // ターンが絡むものは全て均等に10で割ることにします(各ノードの影響を均等にする意図)
+// 各インデックスの内容は以下の通りです。
+//   0..=6   ターン/128, 時間/256, 完成フラグ, 工数・品質・耐久・CPをそれぞれ最大値で割ったもの [0,1]
+//   7..=24  インナークワイエットから順に9種類のバフについて、残りターン/10 と 有効かどうか(0か1) の組
+//   25..=29 一心不乱有効, 一心不乱使用済み, 加工・中級加工・経過観察のコンボ(0か1)
+//   30..=35 状態のワンホット。通常, 高品質, 高進捗, 高持続, 頑丈, 安定の順です
+// 高能率は状態の遷移に現れないので含めていません。
+// インデックスを変えると学習済みのモデルが使えなくなるので、変える場合はSTATE_NUMも含めて全て作り直してください
 pub fn encode_state( s:&State, mod_param:&ModifierParameter ) -> StateVector {
     [
         s.turn as f32 / 128.0,
@@ -96,3 +103,29 @@ pub fn decode_pv_batch( (policy_res_t,value_res_t):(Tensor,Tensor) ) -> Vec<(Act
 
     policy_iter.zip(value_iter).collect()
 }
+
+#[test]
+fn test_encode_state()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+
+    let v = encode_state(&s, &mod_param);
+    assert_eq!( [1.0/128.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0], v[0..7] );
+    assert_eq!( [0.3, 1.0], v[9..11] ); // 設計変更は最初から3回残っています
+    assert_eq!( [1.0, 0.0, 0.0, 0.0, 0.0, 0.0], v[30..36] );
+
+    // 状態とバフが違えば対応するインデックスだけ変わります
+    let s = State { condition:Condition::HighQuality, inner_quiet:3, innovation:2, combo_basic_touch:true, ..s };
+    let w = encode_state(&s, &mod_param);
+    assert_eq!( [0.3, 1.0], w[7..9] );
+    assert_eq!( [0.2, 1.0], w[17..19] );
+    assert_eq!( 1.0, w[27] );
+    assert_eq!( [0.0, 1.0, 0.0, 0.0, 0.0, 0.0], w[30..36] );
+    for i in (0..STATE_NUM).filter(|i| ![7,8,17,18,27,30,31].contains(i)) {
+        assert_eq!( v[i], w[i], "index {}", i );
+    }
+
+    let s = State { condition:Condition::Stable, ..s };
+    assert_eq!( [0.0, 0.0, 0.0, 0.0, 0.0, 1.0], encode_state(&s, &mod_param)[30..36] );
+}
@@ -0,0 +1,57 @@
+use thiserror::Error;
+
+use super::selector;
+
+// クレート全体で使うエラーです。
+// 各エントリポイントはpanicせずにこれを返すので、呼び出し側でどこで失敗したかを判断できます
+#[derive(Debug,Error)]
+pub enum CraftSimError {
+    #[error("database error: {0}")]
+    Database(#[from] mysql::Error),
+
+    #[error("inference error: {0}")]
+    Inference(#[from] tch::TchError),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("config error: {0}")]
+    Config(String),
+
+    // 現在のロジックと食い違うデータやスレッドの異常終了など、本来起きないはずのものです
+    #[error("logic violation: {0}")]
+    LogicViolation(String),
+}
+
+impl From<selector::Error> for CraftSimError {
+    fn from(x: selector::Error) -> CraftSimError {
+        match x {
+            selector::Error::MySQLError(e) => CraftSimError::Database(e),
+            selector::Error::InvalidNetworkType(s) => CraftSimError::Config(format!("invalid network type {}", s)),
+            selector::Error::NotFoundNetworkType(s) => CraftSimError::Config(format!("network type of {} is not found", s)),
+            selector::Error::Empty => CraftSimError::Config("no model is registered".to_string()),
+        }
+    }
+}
+
+// GCSとのやり取りはpythonに任せているので、失敗は全てIOとして扱います
+pub fn storage_error( message:String ) -> CraftSimError {
+    CraftSimError::Io(std::io::Error::other(message))
+}
+
+#[test]
+fn test_from_selector_error()
+{
+    assert!( matches!( CraftSimError::from(selector::Error::InvalidNetworkType("x".to_string())), CraftSimError::Config(_) ) );
+    assert!( matches!( CraftSimError::from(selector::Error::Empty), CraftSimError::Config(_) ) );
+}
+
+#[test]
+fn test_from_tch_error()
+{
+    let e : CraftSimError = tch::TchError::Torch("failed".to_string()).into();
+    assert!( matches!( e, CraftSimError::Inference(_) ) );
+}
@@ -1,10 +1,54 @@
 use std::future::Future;
 use std::pin::Pin;
-use std::task::Context;
-use noop_waker::noop_waker;
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::task::{Context,Wake,Waker};
+
+// タスクが起こされたかどうかです。
+// 起こされていないタスクはpoll_allで飛ばすので、推論待ちの間にタスクを空回りさせません
+struct TaskFlag {
+    woken : AtomicBool,
+}
+
+impl Wake for TaskFlag {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+    }
+}
+
+thread_local! {
+    // poll中のタスクが中断を求めたかどうかです
+    static ABORT_REQUESTED : Cell<bool> = const { Cell::new(false) };
+}
+
+// poll中のタスクを中断させます。そのタスクはPendingを返した後にpoll_allで捨てられます。
+// 待っている推論が失敗して二度と結果が来ない時に使います
+pub fn abort_current_task() {
+    ABORT_REQUESTED.with(|x| x.set(true));
+}
+
+struct Task {
+    future : Pin<Box<dyn Future<Output = ()>>>,
+    flag : Arc<TaskFlag>,
+    waker : Waker, // flagから作ったものです。pollのたびに作らないように持っておきます
+}
+
+// poll_allを1回呼んだ結果です
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct PollStatus {
+    pub pending : usize,  // 終わっていないタスクの数です
+    pub advanced : bool,  // 起こされていて実行したタスクがあったかどうかです
+    pub idle : bool,      // 次のpoll_allで実行されるタスクが無いかどうかです。全てのタスクが推論などを待っているか、タスクが残っていません
+    pub aborted : usize,  // abort_current_taskで中断して捨てたタスクの数です
+}
 
 pub struct Executor {
-    tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+    tasks: Vec<Task>,
 }
 
 impl Executor {
@@ -12,24 +56,238 @@ impl Executor {
         Executor { tasks : Vec::new() }
     }
 
+    // 追加したタスクは起こされた状態から始まります
     pub fn spawn<F>(&mut self, future:F )
         where F: Future<Output = ()> + 'static,
     {
-        self.tasks.push(Box::pin(future));
+        let flag = Arc::new(TaskFlag { woken:AtomicBool::new(true) });
+        let waker = Waker::from(flag.clone());
+        self.tasks.push(Task { future:Box::pin(future), flag, waker });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
     }
 
-    pub fn poll_all(&mut self) {
-        let waker = noop_waker();
-        let mut ctx = Context::from_waker(&waker);
+    // 終わっていないタスクの数です
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
 
-        // 内部にあるタスクを全部１回ずつ実行して新しいベクタに入れ替えます
-        // 実装的にはあんまり効率よくない気がする
-        let mut next = vec!{};
-        for mut task in self.tasks.drain(..) {
-            if task.as_mut().poll(&mut ctx).is_pending() {
-                next.push(task)
+    // 終わっていないタスクを全て捨てます
+    pub fn clear(&mut self) {
+        self.tasks.clear();
+    }
+
+    // poll_allで実行されるタスクがあるかどうかです。無ければ全てのタスクが推論などを待っています
+    pub fn has_woken(&self) -> bool {
+        self.tasks.iter().any(|task| task.flag.woken.load(Ordering::SeqCst))
+    }
+
+    pub fn poll_all(&mut self) -> PollStatus {
+        // 起こされたタスクだけ１回ずつ実行して、終わったものを取り除きます
+        let mut advanced = false;
+        let mut aborted = 0;
+        self.tasks.retain_mut(|task| {
+            if !task.flag.woken.swap(false, Ordering::SeqCst) {
+                return true;
+            }
+            advanced = true;
+            let mut ctx = Context::from_waker(&task.waker);
+            ABORT_REQUESTED.with(|x| x.set(false));
+            let pending = task.future.as_mut().poll(&mut ctx).is_pending();
+            if pending && ABORT_REQUESTED.with(|x| x.replace(false)) {
+                aborted += 1;
+                return false;
+            }
+            pending
+        });
+        PollStatus { pending:self.tasks.len(), advanced, idle:!self.has_woken(), aborted }
+    }
+}
+
+#[test]
+fn test_poll_only_woken_tasks()
+{
+    use std::cell::{Cell,RefCell};
+    use std::rc::Rc;
+    use std::task::Poll;
+
+    // 1回目は起こしてもらうためのWakerを保存してPendingを返し、2回目で終わるタスクです
+    struct WaitOnce {
+        polls : Rc<Cell<usize>>,
+        waker : Rc<RefCell<Option<Waker>>>,
+    }
+
+    impl Future for WaitOnce {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            self.polls.set(self.polls.get() + 1);
+            if self.polls.get() == 1 {
+                *self.waker.borrow_mut() = Some(ctx.waker().clone());
+                Poll::Pending
+            }
+            else {
+                Poll::Ready(())
             }
         }
-        self.tasks = next;
     }
+
+    let polls = Rc::new(Cell::new(0));
+    let waker = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    executor.spawn( WaitOnce { polls:polls.clone(), waker:waker.clone() } );
+
+    assert!( executor.has_woken() );
+    assert_eq!( PollStatus { pending:1, advanced:true, idle:true, aborted:0 }, executor.poll_all() );
+    for _ in 0..2 {
+        assert_eq!( PollStatus { pending:1, advanced:false, idle:true, aborted:0 }, executor.poll_all() );
+    }
+    assert_eq!( 1, polls.get() );
+    assert!( !executor.has_woken() );
+    assert!( !executor.is_empty() );
+
+    waker.borrow_mut().take().unwrap().wake();
+    assert_eq!( PollStatus { pending:0, advanced:true, idle:true, aborted:0 }, executor.poll_all() );
+    assert_eq!( 2, polls.get() );
+    assert!( executor.is_empty() );
+}
+
+#[test]
+fn test_poll_status_busy()
+{
+    use std::task::Poll;
+
+    // 自分で自分を起こしながらn回目で終わるタスクです。推論を待たずに進められる間はidleになりません
+    struct YieldTimes(usize);
+
+    impl Future for YieldTimes {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            self.0 -= 1;
+            if self.0 == 0 {
+                Poll::Ready(())
+            }
+            else {
+                ctx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn( YieldTimes(2) );
+    executor.spawn( YieldTimes(3) );
+    assert_eq!( PollStatus { pending:2, advanced:true, idle:false, aborted:0 }, executor.poll_all() );
+    assert_eq!( PollStatus { pending:1, advanced:true, idle:false, aborted:0 }, executor.poll_all() );
+    assert_eq!( PollStatus { pending:0, advanced:true, idle:true, aborted:0 }, executor.poll_all() );
+    assert_eq!( PollStatus { pending:0, advanced:false, idle:true, aborted:0 }, executor.poll_all() );
+}
+
+#[test]
+fn test_abort_current_task()
+{
+    use std::rc::Rc;
+
+    struct AbortOnPoll;
+
+    impl Future for AbortOnPoll {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _ctx: &mut Context) -> std::task::Poll<()> {
+            abort_current_task();
+            std::task::Poll::Pending
+        }
+    }
+
+    // 中断したタスクだけ捨てて、同じpoll_allの他のタスクはそのまま進めます
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    executor.spawn( AbortOnPoll );
+    {
+        let done = done.clone();
+        executor.spawn( async move { done.set(true) } );
+    }
+    assert_eq!( PollStatus { pending:0, advanced:true, idle:true, aborted:1 }, executor.poll_all() );
+    assert!( done.get() );
+}
+
+// 全てのFutureを同時に進めて、全て終わったら渡した順に結果を返します。
+// 1つのタスクの中で複数の推論をまとめて待つためのものです
+pub fn join_all<F:Future>( futures:Vec<F> ) -> JoinAll<F> {
+    let results = futures.iter().map(|_| None).collect();
+    JoinAll { futures:futures.into_iter().map(Box::pin).collect(), results }
+}
+
+pub struct JoinAll<F:Future> {
+    futures : Vec<Pin<Box<F>>>,
+    results : Vec<Option<F::Output>>,
+}
+
+// Futureは個別にBoxに固定しているので、JoinAll自体は動かしても構いません
+impl<F:Future> Unpin for JoinAll<F> {}
+
+impl<F:Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> std::task::Poll<Vec<F::Output>> {
+        let this = self.get_mut();
+        for (future,result) in this.futures.iter_mut().zip(this.results.iter_mut()) {
+            if result.is_none() {
+                if let std::task::Poll::Ready(x) = future.as_mut().poll(ctx) {
+                    *result = Some(x);
+                }
+            }
+        }
+        if this.results.iter().all(|x| x.is_some()) {
+            std::task::Poll::Ready(this.results.iter_mut().map(|x| x.take().unwrap()).collect())
+        }
+        else {
+            std::task::Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn test_join_all()
+{
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    // 1回目はPendingを返して自分を起こし、2回目で値を返すFutureです
+    struct YieldOnce {
+        value : u32,
+        polled : bool,
+    }
+
+    impl Future for YieldOnce {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> std::task::Poll<u32> {
+            if self.polled {
+                return std::task::Poll::Ready(self.value);
+            }
+            self.polled = true;
+            ctx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+
+    // 全て終わるまで待って、渡した順に結果を返します
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let result = result.clone();
+        executor.spawn( async move {
+            let values = join_all(vec![YieldOnce { value:3, polled:true }, YieldOnce { value:1, polled:false }]).await;
+            *result.borrow_mut() = Some(values);
+        });
+    }
+    executor.poll_all();
+    assert!( result.borrow().is_none() );
+    executor.poll_all();
+    assert_eq!( Some(vec![3,1]), *result.borrow() );
+    assert!( executor.is_empty() );
 }
@@ -1,23 +1,98 @@
 ﻿
-use super::selfplay::{Sample,Record};
+use super::selfplay::{Sample,Record,WeightedSetting,setting_weight};
 use super::setting::ModifierParameter;
 use super::encoding::encode_state;
+use serde::{Serialize,Deserialize};
 
 pub trait Formatter {
     fn format(&self, record:&Record) -> Vec<String>;
 }
 
+// Valueの教師データの作り方です
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub enum ValueTarget {
+    MonteCarlo,                                  // 最終報酬をそのまま使います
+    TemporalDifference { n:usize, gamma:f32 },   // nステップ先のバリューネットワークの値を割り引いて使います
+}
+
 #[derive(Clone)]
 pub struct TsvFormatter {
     pub mod_param : ModifierParameter,
+    pub value_target : ValueTarget,
+    pub priority_column : bool, // trueの場合は各行の最後にレコードの優先度(record_priority)を足します
+    pub settings : Vec<WeightedSetting>, // 混ぜている設定です。空でなければ各行の最後に設定の番号(preset_index)と重み(setting_weight)を足します。設定が分からないレコードの番号は-1です
+}
+
+// 設定の列で設定が分からないことを表す値です
+const UNKNOWN_SETTING : f32 = -1.0;
+
+// レコードに設定が残っていれば、mod_paramの代わりにその設定で状態を符号化します。
+// 列の有無はレコードではなくフォーマッタで決めるので、同じフォーマッタで書き出した行は全て同じ列の並びになります。
+// 学習側では設定の列で設定ごとに分けたり条件付けたり、重みの列で設定の偏りを打ち消したりできます
+fn record_setting(record:&Record) -> Option<(ModifierParameter,f32)> {
+    let mod_param = ModifierParameter::from_preset_name(record.setting.as_ref()?)?;
+    let index = mod_param.preset_index()? as f32;
+    Some((mod_param,index))
+}
+
+// 学習側で驚きの大きいレコードを多めに使うための優先度です。
+// 最初のサンプルの探索後の評価値と最終報酬の差の絶対値なので、予想通りに終わったレコードほど小さくなります
+pub fn record_priority(record:&Record) -> f32 {
+    match record.samples.first() {
+        Some(first) => (record.reward - first.root_value).abs(),
+        None => 0.0,
+    }
 }
 
-fn export_by_tsv(s:&Sample, mod_param:&ModifierParameter, reward:f32) -> String {
+// nステップTDのターゲットを計算します。
+// 報酬は最後の行動の後にしか貰えないので、途中の報酬の総和は常に0です。
+// t+nがエピソードの終わりを超える場合は割り引いたモンテカルロ報酬になります
+fn n_step_targets(value_preds:&[f32], reward:f32, n:usize, gamma:f32) -> Vec<f32> {
+    let len = value_preds.len();
+    (0..len).map(|t| {
+        if t + n < len {
+            gamma.powi(n as i32) * value_preds[t + n]
+        }
+        else {
+            gamma.powi((len - t - 1) as i32) * reward
+        }
+    }).collect()
+}
+
+#[test]
+fn test_n_step_targets()
+{
+    let value_preds = [0.1, 0.2, 0.3, 0.4];
+    let targets = n_step_targets(&value_preds, 1.0, 2, 0.5);
+    assert_eq!( vec![0.25*0.3, 0.25*0.4, 0.5, 1.0], targets );
+
+    // n=0は自分自身の予測値です
+    assert_eq!( value_preds.to_vec(), n_step_targets(&value_preds, 1.0, 0, 0.5) );
+}
+
+// レコードのサンプルからバリューの教師を計算します
+pub fn compute_value_targets(record:&Record, value_target:&ValueTarget) -> Vec<f32> {
+    match value_target {
+        ValueTarget::MonteCarlo => vec![record.reward; record.samples.len()],
+        ValueTarget::TemporalDifference { n, gamma } => {
+            let value_preds : Vec<f32> = record.samples.iter().map(|x| x.value_pred).collect();
+            n_step_targets(&value_preds, record.reward, *n, *gamma)
+        },
+    }
+}
+
+// サンプルを間引いたレコードは間引く前に計算した教師を持っているので、そちらを使います
+fn get_value_targets(record:&Record, value_target:&ValueTarget) -> Vec<f32> {
+    let computed = compute_value_targets(record, value_target);
+    record.samples.iter().zip(computed).map(|(x,target)| x.value_target.unwrap_or(target)).collect()
+}
+
+fn export_by_tsv(s:&Sample, mod_param:&ModifierParameter, reward:f32, priority:Option<f32>, setting:Option<[f32;2]>) -> String {
     let state_vec = encode_state(&s.state, mod_param);
     let reward_vec = [reward];
 
-    // State -> Policy -> Value の順に並べます
-    let iter = state_vec.iter().chain(s.mcts_policy.iter()).chain(reward_vec.iter());
+    // State -> Policy -> Value (-> Priority) (-> Setting -> SettingWeight) の順に並べます
+    let iter = state_vec.iter().chain(s.mcts_policy.iter()).chain(reward_vec.iter()).chain(priority.iter()).chain(setting.iter().flatten());
 
     // 文字列化
     let dst : Vec<String> = iter.map(|x| format!("{:.8}",x)).collect();
@@ -28,6 +103,84 @@ fn export_by_tsv(s:&Sample, mod_param:&ModifierParameter, reward:f32) -> String
 
 impl Formatter for TsvFormatter {
     fn format(&self, record:&Record) -> Vec<String> {
-        record.samples.iter().map(|x| export_by_tsv(&x, &self.mod_param, record.reward)).collect()
+        let targets = get_value_targets(record, &self.value_target);
+        let priority = if self.priority_column { Some(record_priority(record)) } else { None };
+        let setting = record_setting(record);
+        let mod_param = setting.as_ref().map(|(x,_)| x).unwrap_or(&self.mod_param);
+        let setting = if self.settings.is_empty() { None } else {
+            let index = setting.as_ref().map(|(_,i)| *i).unwrap_or(UNKNOWN_SETTING);
+            Some([index, setting_weight(&self.settings, record.setting.as_deref())])
+        };
+        record.samples.iter().zip(targets.iter()).map(|(x,target)| export_by_tsv(x, mod_param, *target, priority, setting)).collect()
     }
 }
+
+#[cfg(test)]
+fn new_test_record(root_value:f32, reward:f32) -> Record {
+    use super::logic::{State,Action,ACTION_NUM};
+    use super::selfplay::ResignOutcome;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let samples = (0..3).map(|_| Sample { action:Action::BasicSynthesis, state:State::new(&mod_param), mcts_policy:[0.0;ACTION_NUM], visit_counts:[0.0;ACTION_NUM], root_value, value_pred:root_value, raw_prior:None, aux_targets:vec![], timing:None, value_target:None }).collect();
+    Record { samples, name:"test".to_string(), last_state:State::new(&mod_param), reward, thread_id:0, coroutine_id:0, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[0,0], setting:None, phase:0 }
+}
+
+#[test]
+fn test_record_priority()
+{
+    // 最初の見積もりから外れたレコードほど優先度が高くなります
+    let matched = new_test_record(0.8, 0.8);
+    let diverged = new_test_record(0.8, 0.1);
+    assert_eq!( 0.0, record_priority(&matched) );
+    assert!( record_priority(&diverged) > record_priority(&matched) );
+    assert!( (record_priority(&diverged) - 0.7).abs() < 1e-6 );
+
+    // 優先度の列はpriority_columnの時だけ足します
+    let columns = |priority_column:bool| {
+        let formatter = TsvFormatter { mod_param:ModifierParameter::new_fountain_of_usouso(), value_target:ValueTarget::MonteCarlo, priority_column, settings:vec![] };
+        let lines = formatter.format(&diverged);
+        assert_eq!( 3, lines.len() );
+        lines[0].split('\t').map(|x| x.to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!( columns(false).len() + 1, columns(true).len() );
+    assert_eq!( "0.69999999", columns(true).last().unwrap() );
+}
+
+#[test]
+fn test_setting_column()
+{
+    use super::encoding::encode_state;
+    use argh::FromArgValue;
+
+    let settings = vec![
+        WeightedSetting::from_arg_value("fountain_of_usouso:3").unwrap(),
+        WeightedSetting::from_arg_value("ishgard_reconstruction_4th").unwrap(),
+    ];
+    let formatter = TsvFormatter { mod_param:ModifierParameter::new_fountain_of_usouso(), value_target:ValueTarget::MonteCarlo, priority_column:false, settings };
+    let mut record = new_test_record(0.5, 0.5);
+    let plain = formatter.format(&record);
+    let plain_columns : Vec<&str> = plain[0].split('\t').collect();
+    let n = plain_columns.len();
+    assert_eq!( [format!("{:.8}", UNKNOWN_SETTING), format!("{:.8}", 1.0)], [plain_columns[n-2], plain_columns[n-1]] );
+
+    // 設定が残っているレコードはその設定で符号化して、番号と重みを設定の列に入れます。列の数は変わりません
+    let other = ModifierParameter::new_ishgard_reconstruction_4th();
+    record.setting = other.preset_name().map(|x| x.to_string());
+    let lines = formatter.format(&record);
+    let columns : Vec<&str> = lines[0].split('\t').collect();
+    assert_eq!( n, columns.len() );
+    assert_eq!( format!("{:.8}", other.preset_index().unwrap() as f32), columns[n-2] );
+    assert_eq!( format!("{:.8}", setting_weight(&formatter.settings, record.setting.as_deref())), columns[n-1] );
+    assert_eq!( format!("{:.8}", encode_state(&record.samples[0].state, &other)[6]), columns[6] );
+    assert_ne!( plain_columns[6], columns[6] );
+
+    // 優先度と設定の列を両方付けた場合も、列の位置は設定によらず同じです
+    let both = TsvFormatter { priority_column:true, ..formatter.clone() };
+    let no_setting = TsvFormatter { settings:vec![], ..formatter };
+    let with_setting : Vec<String> = both.format(&record)[0].split('\t').map(|x| x.to_string()).collect();
+    record.setting = None;
+    let without_setting : Vec<String> = both.format(&record)[0].split('\t').map(|x| x.to_string()).collect();
+    assert_eq!( with_setting.len(), without_setting.len() );
+    assert_eq!( with_setting[with_setting.len()-3], without_setting[without_setting.len()-3] );
+    assert_eq!( n - 2, no_setting.format(&record)[0].split('\t').count() );
+}
@@ -1,4 +1,7 @@
 use std::process::{Command, Stdio};
+use std::io::Read;
+
+use sha2::{Digest,Sha256};
 
 pub fn download( source:&str, destination:&str ) -> Result<(),String> {
     let ret = Command::new("python3")
@@ -15,6 +18,88 @@ pub fn download( source:&str, destination:&str ) -> Result<(),String> {
     }
 }
 
+// http(s)://かs3://で始まるモデル名は、GCSではなくそのURLから取得します
+pub fn is_url( name:&str ) -> bool {
+    name.starts_with("http://") || name.starts_with("https://") || name.starts_with("s3://")
+}
+
+// URLの末尾に#sha256=<16進数>を付けると、取得したファイルをそのSHA-256で検証します
+pub fn split_checksum( url:&str ) -> (&str,Option<&str>) {
+    match url.split_once("#sha256=") {
+        Some((url,checksum)) => (url,Some(checksum)),
+        None => (url,None),
+    }
+}
+
+#[test]
+fn test_split_checksum()
+{
+    assert!( is_url("https://example.com/weights/a.onnx") && is_url("s3://bucket/a") );
+    assert!( !is_url("20240101-000000") );
+    assert_eq!( ("s3://bucket/a",Some("abcd")), split_checksum("s3://bucket/a#sha256=abcd") );
+    assert_eq!( ("http://example.com/a",None), split_checksum("http://example.com/a") );
+}
+
+pub fn sha256_hex( bytes:&[u8] ) -> String {
+    Sha256::digest(bytes).iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// 空のファイルは途中で失敗した取得とみなします。checksumがあればSHA-256も比べます
+pub fn verify_file( path:&str, checksum:Option<&str> ) -> Result<(),String> {
+    let mut bytes = Vec::new();
+    std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    if bytes.is_empty() {
+        return Err(format!("{} is empty", path));
+    }
+    match checksum {
+        Some(expected) if !sha256_hex(&bytes).eq_ignore_ascii_case(expected) => Err(format!("sha256 of {} is {} but expected {}", path, sha256_hex(&bytes), expected)),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_verify_file()
+{
+    let path = std::env::temp_dir().join(format!("verify_file_{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    std::fs::write(path, b"").unwrap();
+    assert!( verify_file(path, None).is_err() );
+
+    std::fs::write(path, b"abc").unwrap();
+    assert_eq!( Ok(()), verify_file(path, None) );
+    assert_eq!( Ok(()), verify_file(path, Some("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD")) );
+    assert!( verify_file(path, Some("00")).is_err() );
+    std::fs::remove_file(path).unwrap();
+}
+
+// URLからdestinationに取得します。httpはcurl、s3はaws cliを使います。
+// curlはContent-Lengthより短いまま接続が切れた場合も失敗にします
+pub fn download_url( url:&str, destination:&str ) -> Result<(),String> {
+    let mut command = if url.starts_with("s3://") {
+        let mut command = Command::new("aws");
+        command.args(["s3","cp","--quiet",url,destination]);
+        command
+    }
+    else {
+        let mut command = Command::new("curl");
+        command.args(["--fail","--silent","--show-error","--location","--output",destination,url]);
+        command
+    };
+
+    let ret = command
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .output()
+    .map_err(|e| format!("failed to run download command for {}: {}", url, e))?;
+
+    match ret.status.code() {
+        Some(0) => Ok(()),
+        Some(x) => Err(format!("download of {} exited with status code: {} {}", url, x, String::from_utf8_lossy(&ret.stderr).trim())),
+        None    => Err(format!("download of {} terminated by signal", url)),
+    }
+}
+
 pub fn upload( source:&str, destination:&str, content_type:&str ) -> Result<(),String> {
     let ret = Command::new("python3")
     .args(["pysrc/main.py","upload",source,destination,"--content-type",content_type])
@@ -8,16 +8,22 @@ use mysql::prelude::*;
 use tch::*;
 use tch::nn::*;
 use ulid::*;
+use tracing::{info,warn};
 
 use super::gcs::*;
 use super::network::*;
 use super::logic::*;
+use super::error::{CraftSimError,storage_error};
+use super::db;
 
 pub struct LearnerParameter {
     pub epochs_per_write : usize,
     pub record_buffer_size : usize,
     pub network_type : NetworkType,
     pub mysql_user : String,
+    pub mysql_host : String,
+    pub mysql_port : u16,
+    pub mysql_db : String,
 }
 
 struct RecordBuffer {
@@ -69,33 +75,75 @@ impl RecordBuffer {
 // ファイルからサンプルを読み込みます。
 // 各テンソルの大きさは行数をNとして(N,STATE_NUM),(N,ACTION_NUM),(N,1)となります。
 // 最初に全要素をfloatで読み取り、それをreshapeして、最後に分割します。
+// priority_columnで書き出した優先度の列があれば読み捨てます。優先度を使ったサンプリングはまだしません
 pub fn load_samples<R:BufRead>( reader:R ) -> (Tensor,Tensor,Tensor) {
-    let mut data : Vec<f32> = Vec::new();
-
-    eprintln!("read file...");
+    info!("read file...");
 
-    // まずVecとして読み込みます
-    for line in reader.lines() {
-        line.unwrap().split_whitespace().for_each(|x| data.push(x.parse().ok().unwrap()));
-    }
+    let (data,line_size) = parse_sample_rows(reader);
 
-    eprintln!("create tensors...");
+    info!("create tensors...");
 
     // Tensorに変換
-    let line_size = STATE_NUM+ACTION_NUM+1;
     let line_num = data.len() / line_size;
 
     let mut samples = Tensor::of_slice(&data);
     let _ = samples.resize_(&[line_num as i64,line_size as i64]);
-    eprintln!("load samples: {:?}", samples.size() );
+    info!("load samples: {:?}", samples.size() );
 
-    let tmp = samples.split_with_sizes(&[STATE_NUM as i64,ACTION_NUM as i64, 1], 1);
+    // 優先度や設定の列が後ろに付いていても使いません
+    let extra_size = (line_size - (STATE_NUM+ACTION_NUM+1)) as i64;
+    let tmp = samples.split_with_sizes(&[STATE_NUM as i64,ACTION_NUM as i64, 1, extra_size], 1);
     (tmp[0].shallow_clone(),tmp[1].shallow_clone(),tmp[2].shallow_clone())
 }
 
+// 状態と方策と価値の列の数です。これより少ない行は読めません
+const MIN_SAMPLE_COLUMNS : usize = STATE_NUM+ACTION_NUM+1;
+
+// まずVecとして読み込みます。列の数は最初の行で決めて、列の数が違う行や数値でない値を含む行は捨てます。
+// 1行でも混ざると後ろの行が全てずれてしまうためです
+fn parse_sample_rows<R:BufRead>( reader:R ) -> (Vec<f32>,usize) {
+    let mut data : Vec<f32> = Vec::new();
+    let mut line_size = None;
+    let mut rejected = 0;
+
+    for line in reader.lines() {
+        let row : Option<Vec<f32>> = line.unwrap().split_whitespace().map(|x| x.parse().ok()).collect();
+        let row = match row {
+            Some(row) if row.len() >= MIN_SAMPLE_COLUMNS && line_size.map(|x| x == row.len()).unwrap_or(true) => row,
+            _ => { rejected += 1; continue },
+        };
+        line_size = Some(row.len());
+        data.extend(row);
+    }
+
+    if rejected > 0 {
+        warn!(rejected, "rejected {} sample rows with unexpected columns", rejected);
+    }
+    (data, line_size.unwrap_or(MIN_SAMPLE_COLUMNS))
+}
+
+#[test]
+fn test_parse_sample_rows()
+{
+    let row = |n:usize| vec!["0.5"; n].join("\t");
+    let width = MIN_SAMPLE_COLUMNS;
+
+    // 最初の行と列の数が違う行は捨てます
+    let input = [row(width+1), row(width), row(width+1), row(width+2), "x".to_string(), row(width+1)].join("\n");
+    let (data,line_size) = parse_sample_rows(input.as_bytes());
+    assert_eq!( width+1, line_size );
+    assert_eq!( (width+1)*3, data.len() );
+
+    // 最初の行が足りない場合も捨てて、次の行で列の数を決めます
+    let input = [row(width-1), row(width), row(width)].join("\n");
+    let (data,line_size) = parse_sample_rows(input.as_bytes());
+    assert_eq!( width, line_size );
+    assert_eq!( width*2, data.len() );
+}
+
 fn download_samples( blob_name:&String ) -> (Tensor,Tensor,Tensor) {
     let path = format!("sample/{}.bz2", blob_name);
-    eprintln!("download: {}", path);
+    info!("download: {}", path);
     download( &path, "sample.txt.bz2" ).unwrap();
     let file = std::fs::File::open("sample.txt.bz2").unwrap();
     let reader = BufReader::new(BzDecoder::new(file));
@@ -149,8 +197,8 @@ fn add_samples_from_blobs( record_buffer:&mut RecordBuffer, blobs:&[String], rea
     record_buffer.last_sample_blob = Some(blobs.last().unwrap().clone());
 }
 
-fn train( optimizer:&mut Optimizer, net:&Box<dyn DualNetwork>, record_buffer:&RecordBuffer, epoch_num:usize ) {
-    eprintln!("train for record buffer size: {}", record_buffer.len());
+fn train( optimizer:&mut Optimizer, net:&dyn DualNetwork, record_buffer:&RecordBuffer, epoch_num:usize ) {
+    info!(records = record_buffer.len(), "train for record buffer size: {}", record_buffer.len());
 
     let mut start = Instant::now();
 
@@ -161,64 +209,58 @@ fn train( optimizer:&mut Optimizer, net:&Box<dyn DualNetwork>, record_buffer:&Re
 
         let now = Instant::now();
         let elapsed_time = now - start;
-        eprintln!( "epoch: {:4}, elapsed_time[msec]: {}, loss: {:8.5}, p_loss: {:8.5}, v_loss: {:8.5}", epoch, elapsed_time.as_millis(), f64::from(&loss), f64::from(&p_loss), f64::from(&v_loss) );
+        info!( epoch, "epoch: {:4}, elapsed_time[msec]: {}, loss: {:8.5}, p_loss: {:8.5}, v_loss: {:8.5}", epoch, elapsed_time.as_millis(), f64::from(&loss), f64::from(&p_loss), f64::from(&v_loss) );
         start = now;
     }
 }
 
-fn export_weights( mysql_pool:&Arc<Mutex<Pool>>, vs:&VarStore, network_type:&NetworkType ) -> mysql::Result<()> {
+fn export_weights( mysql_pool:&Arc<Mutex<Pool>>, vs:&VarStore, network_type:&NetworkType ) -> std::result::Result<(),CraftSimError> {
     let ulid = Ulid::new();
-    eprintln!("uploading weights... {}", ulid);
-    vs.save("weights").unwrap();
-    upload("weights", &format!("weights/{}", ulid), "application/x-weights").unwrap();
+    info!(ulid = %ulid, "uploading weights... {}", ulid);
+    vs.save("weights")?;
+    upload("weights", &format!("weights/{}", ulid), "application/x-weights").map_err(storage_error)?;
 
     let mut conn = mysql_pool.lock().unwrap().get_conn()?;
-    let mut tx = conn.start_transaction(TxOpts::default()).unwrap();
+    let mut tx = conn.start_transaction(TxOpts::default())?;
 
     tx.exec_drop("INSERT evaluation (name, total_reward, total_count) VALUES (:name,0,0)", params!{"name" => ulid.to_string()})?;
     tx.exec_drop("INSERT network (name, type) VALUES (:name,:type)", params!{"name" => ulid.to_string(), "type" => network_type.to_string()})?;
 
-    tx.commit()
+    Ok(tx.commit()?)
 }
 
-fn run_epoch_loop( mysql_pool:&Arc<Mutex<Pool>>, record_buffer:&mut RecordBuffer, optimizer:&mut Optimizer, vs:&VarStore, net:&Box<dyn DualNetwork>, network_type:&NetworkType, epoch:usize ) {
-    eprintln!("enumerate sample files from mysql...");
-    let sample_blobs = get_new_samples( mysql_pool, &record_buffer.last_sample_blob ).unwrap();
+fn run_epoch_loop( mysql_pool:&Arc<Mutex<Pool>>, record_buffer:&mut RecordBuffer, optimizer:&mut Optimizer, vs:&VarStore, net:&dyn DualNetwork, network_type:&NetworkType, epoch:usize ) -> std::result::Result<(),CraftSimError> {
+    info!("enumerate sample files from mysql...");
+    let sample_blobs = get_new_samples( mysql_pool, &record_buffer.last_sample_blob )?;
 
-    eprintln!("download samples...");
+    info!("download samples...");
     add_samples_from_blobs( record_buffer, &sample_blobs, 0 );
 
     if record_buffer.is_full() {
         // バッファが埋まり次第処理します
         train( optimizer, net, record_buffer, epoch );
-        export_weights( &mysql_pool, vs, network_type ).unwrap();
+        export_weights( mysql_pool, vs, network_type )?;
     }
-    else if !is_exist_model(mysql_pool).unwrap() {
+    else if !is_exist_model(mysql_pool)? {
         // バッファが埋まっておらず、モデルもないなら、今のモデルを初期状態として出力します。
-        export_weights( &mysql_pool, vs, network_type ).unwrap();
+        export_weights( mysql_pool, vs, network_type )?;
     }
     else {
         // バッファが埋まってないけど、モデルはある状態です。
         // evaluatorとgeneratorは最良モデルを利用して計算しているはずなので、新しいサンプルの到着を待ちます。
-        eprintln!("Wait for new samples... {}/{})", record_buffer.len(), record_buffer.max_length);
+        info!(records = record_buffer.len(), "Wait for new samples... {}/{})", record_buffer.len(), record_buffer.max_length);
         std::thread::sleep( std::time::Duration::from_secs(3) );
     }
+    Ok(())
 }
 
-pub fn run( param:&LearnerParameter ) {
-    let mysql_password = match std::env::var("MYSQL_PASSWORD") {
-        Ok(val) => format!(":{}", val ),
-        Err(_) => String::new(),
-    };
-
-    let url = format!("mysql://{}{}@localhost:3306/craft", param.mysql_user, mysql_password );
-    eprintln!("Connect to mysql...");
-    let mysql_pool_base = Pool::new_manual(2,2,Opts::from_url(&url).unwrap()).unwrap();
-    let mysql_pool = Arc::new(Mutex::new(mysql_pool_base));
+pub fn run( param:&LearnerParameter ) -> std::result::Result<(),CraftSimError> {
+    info!("Connect to mysql...");
+    let mysql_pool = db::connect(&db::mysql_url(&param.mysql_user, &param.mysql_host, param.mysql_port, &param.mysql_db), 2)?;
 
     // GPUが使える場合は使う
     let device = Device::cuda_if_available();
-    eprintln!("device: {:?}", device);
+    info!("device: {:?}", device);
 
     // ここから学習のデータ構造作成
     let mut record_buffer = RecordBuffer::new(device, param.record_buffer_size);
@@ -232,14 +274,14 @@ pub fn run( param:&LearnerParameter ) {
         let net = create_network(&vs.root(), param.network_type);
 
         match std::path::Path::new("weights").exists() {
-            true => { vs.load("weights").unwrap(); eprintln!("load weights"); }
-            false => { eprintln!("cannot find path"); },
+            true => { vs.load("weights")?; info!("load weights"); }
+            false => { warn!("cannot find path"); },
         }
 
         let adam_opt = nn::Adam { wd:0.0001, ..nn::Adam::default() };
-        let mut optimizer = adam_opt.build(&vs, 1e-3).unwrap();
+        let mut optimizer = adam_opt.build(&vs, 1e-3)?;
         // ↑↑↑ここまで
 
-        run_epoch_loop( &mysql_pool, &mut record_buffer, &mut optimizer, &vs, &net, &param.network_type, param.epochs_per_write );
+        run_epoch_loop( &mysql_pool, &mut record_buffer, &mut optimizer, &vs, net.as_ref(), &param.network_type, param.epochs_per_write )?;
     }
 }
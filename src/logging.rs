@@ -0,0 +1,126 @@
+use tracing::{Event,Level,Subscriber};
+use tracing::field::{Field,Visit};
+use tracing_subscriber::fmt::{FmtContext,FormatEvent,FormatFields,MakeWriter};
+use tracing_subscriber::fmt::format;
+use tracing_subscriber::registry::LookupSpan;
+
+use super::error::CraftSimError;
+
+// ログの出力形式を選ぶ環境変数です
+const LOG_FORMAT_ENV : &str = "CRAFT_SIM_LOG_FORMAT";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LogFormat {
+    Plain, // これまでのeprintlnと同じく、メッセージだけを1行ずつ出します
+    Json,  // スパンとフィールドも含めて1行1つのJSONで出します。クラスタでログを集めて解析する時に使います
+}
+
+impl LogFormat {
+    // 未設定ならPlainです
+    fn parse( s:Option<&str> ) -> Result<LogFormat,CraftSimError> {
+        match s {
+            None | Some("") | Some("plain") => Ok(LogFormat::Plain),
+            Some("json") => Ok(LogFormat::Json),
+            Some(x) => Err(CraftSimError::Config(format!("{} must be plain or json but {}", LOG_FORMAT_ENV, x))),
+        }
+    }
+}
+
+#[test]
+fn test_log_format_parse()
+{
+    assert_eq!( LogFormat::Plain, LogFormat::parse(None).unwrap() );
+    assert_eq!( LogFormat::Plain, LogFormat::parse(Some("plain")).unwrap() );
+    assert_eq!( LogFormat::Json, LogFormat::parse(Some("json")).unwrap() );
+    assert!( matches!( LogFormat::parse(Some("xml")), Err(CraftSimError::Config(_)) ) );
+}
+
+// メッセージだけを書き出す形式です。フィールドやスパンは書きません
+struct PlainFormat;
+
+struct MessageVisitor<'a,'w> {
+    writer : &'a mut format::Writer<'w>,
+    result : std::fmt::Result,
+}
+
+impl Visit for MessageVisitor<'_,'_> {
+    fn record_debug(&mut self, field:&Field, value:&dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.result = write!(self.writer, "{:?}", value);
+        }
+    }
+}
+
+impl<S,N> FormatEvent<S,N> for PlainFormat
+    where S : Subscriber + for<'a> LookupSpan<'a>, N : for<'a> FormatFields<'a> + 'static
+{
+    fn format_event(&self, _ctx:&FmtContext<'_,S,N>, mut writer:format::Writer<'_>, event:&Event<'_>) -> std::fmt::Result {
+        let mut visitor = MessageVisitor { writer:&mut writer, result:Ok(()) };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer)
+    }
+}
+
+fn new_subscriber<W>( log_format:LogFormat, make_writer:W ) -> Box<dyn Subscriber + Send + Sync>
+    where W : for<'w> MakeWriter<'w> + Send + Sync + 'static
+{
+    let builder = tracing_subscriber::fmt().with_max_level(Level::INFO).with_writer(make_writer);
+    match log_format {
+        LogFormat::Plain => Box::new(builder.event_format(PlainFormat).finish()),
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).with_span_list(true).finish()),
+    }
+}
+
+// 標準エラー出力にログを出すようにします。起動直後に1回だけ呼びます
+pub fn init() -> Result<(),CraftSimError> {
+    let log_format = LogFormat::parse(std::env::var(LOG_FORMAT_ENV).ok().as_deref())?;
+    tracing::subscriber::set_global_default(new_subscriber(log_format, std::io::stderr)).map_err(|e| CraftSimError::Config(e.to_string()))
+}
+
+#[cfg(test)]
+fn capture_log<F:FnOnce()>( log_format:LogFormat, f:F ) -> String {
+    use std::sync::{Arc,Mutex};
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf:&[u8]) -> std::io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let buffer = Buffer(Arc::new(Mutex::new(vec![])));
+    let writer = buffer.clone();
+    tracing::subscriber::with_default(new_subscriber(log_format, move || writer.clone()), f);
+    let bytes = buffer.0.lock().unwrap().clone();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn test_plain_format()
+{
+    // フィールドやスパンがあっても、これまで通りのメッセージだけになります
+    let output = capture_log(LogFormat::Plain, || {
+        let _span = tracing::info_span!("selfplay", thread_id = 3).entered();
+        tracing::info!(thread_id = 3, discarded = 2, "selfplay{} stopped. discarded {} episodes in progress", 3, 2);
+        tracing::warn!("warning: no legal action");
+        tracing::debug!("hidden");
+    });
+    assert_eq!( "selfplay3 stopped. discarded 2 episodes in progress\nwarning: no legal action\n", output );
+}
+
+#[test]
+fn test_json_format()
+{
+    let output = capture_log(LogFormat::Json, || {
+        let _span = tracing::info_span!("selfplay", thread_id = 3).entered();
+        tracing::info!(model = "m1", records = 5, "write {} records", 5);
+    });
+    let line : serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+    assert_eq!( "INFO", line["level"] );
+    assert_eq!( "write 5 records", line["fields"]["message"] );
+    assert_eq!( "m1", line["fields"]["model"] );
+    assert_eq!( 5, line["fields"]["records"] );
+    assert_eq!( "selfplay", line["span"]["name"] );
+    assert_eq!( 3, line["span"]["thread_id"] );
+}
@@ -2,10 +2,11 @@
 extern crate xorshift;
 
 use super::setting::ModifierParameter;
+use super::util::splitmix64;
 use serde::{Serialize,Deserialize};
 use std::cmp::min;
 use std::hash::Hash;
-use xorshift::{Rng,Xorshift128};
+use xorshift::{Rng,SeedableRng,Xorshift128};
 use num::traits::{FromPrimitive,ToPrimitive};
 
 #[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize,Hash)]
@@ -20,6 +21,9 @@ pub enum Condition
     Stable,         // 安定
 }
 
+// 次のターンに変化し得る状態です。高能率は今のロジックでは出ません
+const NEXT_CONDITIONS : [Condition;6] = [Condition::Standard, Condition::HighQuality, Condition::HighProgress, Condition::Stable, Condition::HighSustain, Condition::Solid];
+
 pub const ACTION_NUM: usize = 32;
 
 #[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq,Hash)]
@@ -58,6 +62,22 @@ pub enum Action {
     TrainedFinesse,     // 匠の神業
 }
 
+// アクションが使えない理由です。
+// ゲームのルールで使えないものの他に、探索で無意味な手として除外したものも含みます
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum IllegalAction {
+    NotEnoughCp,              // CPが足りません
+    RequiresHighQuality,      // 高品質か一心不乱が必要です
+    RequiresInnerQuiet,       // インナークワイエットが必要です
+    RequiresMaxInnerQuiet,    // インナークワイエットが10必要です
+    FirstTurnOnly,            // 1ターン目しか使えません
+    NoCarefulObservationLeft, // 設計変更の残り回数がありません
+    WasteNotActive,           // 倹約中は使えません
+    HeartAndSoulUsed,         // 一心不乱は1回しか使えません
+    PrunedOpening,            // 探索では1ターン目を確信か真価に限定しています
+    PrunedFinalAppraisal,     // 探索では意味の無い最終確認を除外しています
+}
+
 #[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Eq,Hash)]
 pub struct State
 {
@@ -85,6 +105,28 @@ pub struct State
     pub condition : Condition         // 状態
 }
 
+// 置換表と推論キャッシュのキーです。State::canonical_keyで作ります。
+// EqとHashはキーに残したフィールドだけで決まるので、キャッシュが正しいかどうかは含めるフィールドを見れば確かめられます。
+//  - 終了していない状態: 全てのフィールドです。どれもルール・NNの入力(encode_state)・報酬のどれかに使われます
+//  - 終了した状態: 報酬と終了判定に使うcompleted,quality,time,durabilityと、古いノードの削除に使うturnです。
+//    それ以外(working,cp,バフの残りターン,コンボ,一心不乱,condition)は初期値に揃えます
+// フィールドを追加した時は、終了した状態でも意味があるかどうかをcanonical_keyで決めてください
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Eq,Hash)]
+pub struct StateKey(State);
+
+impl StateKey {
+    pub fn turn(&self) -> u32 {
+        self.0.turn
+    }
+
+    // 終了状態のキーは一部の値を捨てているので、元の状態とは一致しません
+    #[allow(dead_code)]
+    pub fn state(&self) -> &State {
+        &self.0
+    }
+}
+
+#[derive(Clone)]
 pub struct Modifier
 {
     pub mod_param : ModifierParameter,
@@ -186,12 +228,31 @@ impl ToPrimitive for Action {
     }
 }
 
-impl Modifier {
-    fn try_random(&mut self, success_rate : f32) -> bool {
-        self.rng.next_f32() < success_rate
+// 学習側で方策の出力やmcts_policyを解釈するための、アクション名とインデックスの対応表です。
+// インデックスはFromPrimitive/ToPrimitiveの値そのものです
+pub fn action_index_map() -> Vec<(String,usize)> {
+    (0..ACTION_NUM).map(|i| (format!("{:?}", Action::from_usize(i).unwrap()), i)).collect()
+}
+
+#[test]
+fn test_action_index_map()
+{
+    use std::collections::HashSet;
+
+    let map = action_index_map();
+    assert_eq!( ACTION_NUM, map.len() );
+
+    let names : HashSet<&String> = map.iter().map(|(name,_)| name).collect();
+    let indices : HashSet<usize> = map.iter().map(|(_,i)| *i).collect();
+    assert_eq!( ACTION_NUM, names.len() );
+    assert_eq!( (0..ACTION_NUM).collect::<HashSet<usize>>(), indices );
+
+    for (_,i) in map {
+        assert_eq!( Some(i), Action::from_usize(i).unwrap().to_usize() );
     }
 }
 
+
 fn decrement_clip( x : u32 ) -> u32 {
     if x > 0 { x - 1 } else { 0 }
 }
@@ -236,6 +297,36 @@ impl State {
         self.is_completed() || self.is_destroyed()
     }
 
+    pub fn canonical_key(&self) -> StateKey {
+        if !self.is_terminated() {
+            return StateKey(self.clone());
+        }
+        StateKey(State {
+            turn:self.turn,
+            time:self.time,
+            completed:self.completed,
+            working:0,
+            quality:self.quality,
+            durability:self.durability,
+            cp:0,
+            inner_quiet:0,
+            careful_observation:0,
+            waste_not:0,
+            veneration:0,
+            great_strides:0,
+            innovation:0,
+            final_appraisal:0,
+            muscle_memory:0,
+            manipulation:0,
+            heart_and_soul:false,
+            heart_and_soul_used:false,
+            combo_basic_touch:false,
+            combo_standard_touch:false,
+            combo_observe:false,
+            condition:Condition::Standard,
+        })
+    }
+
     // 必要CP一覧
     // CPはStateに依存した関数であるためStateの関数とします
     pub fn get_required_cp(&self, a:&Action) -> u32 {
@@ -464,26 +555,37 @@ impl State {
         }
     }
 
+    // ゲームのルールで使える手の一覧です
+    pub fn legal_actions(&self) -> Vec<Action> {
+        (0..ACTION_NUM).map(|a| Action::from_usize(a).unwrap()).filter(|a| self.check_action(a)).collect()
+    }
+
     // 実行確認
     pub fn check_action(&self, a:&Action) -> bool {
-        if self.cp >= self.get_required_cp(&a) {
-            match a {
-                Action::TricksOfTheTrade => self.condition == Condition::HighQuality || self.heart_and_soul,
-                Action::ByregotsBlessing => self.inner_quiet > 0, // ビエルゴはinner_quiet初期値の時は使えません
-                Action::PreciseTouch => self.condition == Condition::HighQuality || self.heart_and_soul,
-                Action::MuscleMemory => self.turn == 1, // 確信バフは最終確認で消えません
-                Action::CarefulObservation => self.careful_observation > 0,
-                Action::PrudentTouch => self.waste_not == 0,
-                Action::Reflect => self.turn == 1, // 真価バフは最終確認で消えません
-                Action::IntensiveSynthesis => self.condition == Condition::HighQuality || self.heart_and_soul,
-                Action::HeartAndSoul => !self.heart_and_soul_used,
-                Action::PrudentSynthesis => self.waste_not == 0,
-                Action::TrainedFinesse => self.inner_quiet == 10, // 匠の神業はIQ10限定
-                _ => true
-            }
+        self.illegal_reason(a).is_none()
+    }
+
+    // アクションが使えない理由を返します。使える場合はNoneです
+    pub fn illegal_reason(&self, a:&Action) -> Option<IllegalAction> {
+        if self.cp < self.get_required_cp(a) {
+            return Some(IllegalAction::NotEnoughCp);
         }
-        else {
-            false
+
+        let high_quality = self.condition == Condition::HighQuality || self.heart_and_soul;
+        let required = |legal:bool, reason:IllegalAction| if legal { None } else { Some(reason) };
+        match a {
+            Action::TricksOfTheTrade => required(high_quality, IllegalAction::RequiresHighQuality),
+            Action::ByregotsBlessing => required(self.inner_quiet > 0, IllegalAction::RequiresInnerQuiet), // ビエルゴはinner_quiet初期値の時は使えません
+            Action::PreciseTouch => required(high_quality, IllegalAction::RequiresHighQuality),
+            Action::MuscleMemory => required(self.turn == 1, IllegalAction::FirstTurnOnly), // 確信バフは最終確認で消えません
+            Action::CarefulObservation => required(self.careful_observation > 0, IllegalAction::NoCarefulObservationLeft),
+            Action::PrudentTouch => required(self.waste_not == 0, IllegalAction::WasteNotActive),
+            Action::Reflect => required(self.turn == 1, IllegalAction::FirstTurnOnly), // 真価バフは最終確認で消えません
+            Action::IntensiveSynthesis => required(high_quality, IllegalAction::RequiresHighQuality),
+            Action::HeartAndSoul => required(!self.heart_and_soul_used, IllegalAction::HeartAndSoulUsed),
+            Action::PrudentSynthesis => required(self.waste_not == 0, IllegalAction::WasteNotActive),
+            Action::TrainedFinesse => required(self.inner_quiet == 10, IllegalAction::RequiresMaxInnerQuiet), // 匠の神業はIQ10限定
+            _ => None,
         }
     }
 
@@ -503,8 +605,8 @@ impl State {
     }
 
     // ヘイスティタッチ
-    fn action_hasty_touch(&self, modifier:&mut Modifier) -> State {
-        if modifier.try_random(self.probability(0.5)) {
+    fn action_hasty_touch(&self, modifier:&mut Modifier, success:bool) -> State {
+        if success {
             // 成功時
             self.add_quality(&modifier.mod_param,100,1).consume_durability(10).next_turn(modifier).change_condition(modifier).add_time(3)
         }
@@ -515,8 +617,8 @@ impl State {
     }
 
     // 突貫作業
-    fn action_rapid_synthesis(&self, modifier:&mut Modifier) -> State {
-        if modifier.try_random(self.probability(0.5)) {
+    fn action_rapid_synthesis(&self, modifier:&mut Modifier, success:bool) -> State {
+        if success {
             // 成功時
             self.add_working(&modifier.mod_param,500).consume_durability(10).next_turn(modifier).change_condition(modifier).add_time(3)
         }
@@ -609,8 +711,8 @@ impl State {
     }
 
     // 注視作業
-    fn action_focused_synthesis(&self, modifier:&mut Modifier) -> State {
-        if self.combo_observe || modifier.try_random(self.probability(0.5)) {
+    fn action_focused_synthesis(&self, modifier:&mut Modifier, success:bool) -> State {
+        if success {
             // 成功の場合
             self.add_working(&modifier.mod_param,150).consume_cp(&Action::FocusedSynthesis).consume_durability(10).next_turn(modifier).change_condition(modifier).add_time(3)
         }
@@ -621,8 +723,8 @@ impl State {
     }
 
     // 注視作業
-    fn action_focused_touch(&self, modifier:&mut Modifier) -> State {
-        if self.combo_observe || modifier.try_random(self.probability(0.5)) {
+    fn action_focused_touch(&self, modifier:&mut Modifier, success:bool) -> State {
+        if success {
             // 成功の場合
             self.add_quality(&modifier.mod_param,150,1).consume_cp(&Action::FocusedTouch).consume_durability(10).next_turn(modifier).change_condition(modifier).add_time(3)
         }
@@ -680,13 +782,65 @@ impl State {
     }
 
     // アクション取得
+    // 手や結果によらず、1回の実行で成功の判定と次の状態の分の乱数を必ず2つ進めます。
+    // 使わなかった乱数も捨てるので、同じシードならどの手を選んでもn回目の実行では同じ乱数を使います
     pub fn run_action(&self, modifier:&mut Modifier, a:&Action) -> State {
+        let success = self.try_action(modifier.rng.next_f32(), a);
+        let mut after = modifier.rng;
+        after.next_f32();
+        let next = self.run_action_with_success(modifier, a, success);
+        modifier.rng = after;
+        next
+    }
+
+    // 成功率のあるアクションが成功するかどうかを乱数の値rollで決めます。成功率の無いアクションは常に成功です
+    fn try_action(&self, roll:f32, a:&Action) -> bool {
+        match a {
+            Action::HastyTouch | Action::RapidSynthesis => roll < self.probability(0.5),
+            Action::FocusedSynthesis | Action::FocusedTouch => self.combo_observe || roll < self.probability(0.5),
+            _ => true,
+        }
+    }
+
+    // 失敗することがあるアクションかどうかです
+    fn can_fail(&self, a:&Action) -> bool {
+        match a {
+            Action::HastyTouch | Action::RapidSynthesis => self.probability(0.5) < 1.0,
+            Action::FocusedSynthesis | Action::FocusedTouch => !self.combo_observe,
+            _ => false,
+        }
+    }
+
+    // aを実行した結果として起こり得る全ての状態です。成功と失敗、次の状態の全てを含みます。
+    // 乱数に関わらず結果が決まるので、レコードが現在のロジックで再現できるかを調べるのに使います
+    pub fn reachable_states(&self, mod_param:&ModifierParameter, a:&Action) -> Vec<State> {
+        // 乱数は成功と状態の変化にしか使わないので、どちらも後から決めれば種は何でも構いません
+        let seeds = [1, 2];
+        let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+        let outcomes : &[bool] = if self.can_fail(a) { &[true,false] } else { &[true] };
+        let mut states = vec![];
+        for success in outcomes {
+            // 状態を変えない手は乱数を進めないので、乱数が進んだかどうかで次の状態が変わり得るかが分かります
+            let mut before = modifier.rng;
+            let next = self.run_action_with_success(&mut modifier, a, *success);
+            let mut after = modifier.rng;
+            if next.is_terminated() || before.next_u64() == after.next_u64() {
+                states.push(next);
+            }
+            else {
+                states.extend(NEXT_CONDITIONS.iter().map(|condition| State { condition:*condition, ..next.clone() }));
+            }
+        }
+        states
+    }
+
+    fn run_action_with_success(&self, modifier:&mut Modifier, a:&Action, success:bool) -> State {
         match a {
             Action::BasicSynthesis => self.action_basic_synthesis(modifier),
             Action::BasicTouch => self.action_basic_touch(modifier),
             Action::MastersMend => self.action_masters_mend(modifier),
-            Action::HastyTouch => self.action_hasty_touch(modifier),
-            Action::RapidSynthesis => self.action_rapid_synthesis(modifier),
+            Action::HastyTouch => self.action_hasty_touch(modifier, success),
+            Action::RapidSynthesis => self.action_rapid_synthesis(modifier, success),
             Action::Observe => self.action_observe(modifier),
             Action::TricksOfTheTrade => self.action_trick_of_the_trade(modifier),
             Action::WasteNot => self.action_waste_not(modifier),
@@ -703,8 +857,8 @@ impl State {
             Action::CarefulSynthesis => self.action_careful_synthesis(modifier),
             Action::Manipulation => self.action_manipulation(modifier),
             Action::PrudentTouch => self.action_prudent_touch(modifier),
-            Action::FocusedSynthesis => self.action_focused_synthesis(modifier),
-            Action::FocusedTouch => self.action_focused_touch(modifier),
+            Action::FocusedSynthesis => self.action_focused_synthesis(modifier, success),
+            Action::FocusedTouch => self.action_focused_touch(modifier, success),
             Action::Reflect => self.action_reflect(modifier),
             Action::PreparatoryTouch => self.action_preparatory_touch(modifier),
             Action::Groundwork => self.action_groundwork(modifier),
@@ -718,6 +872,146 @@ impl State {
     }
 }
 
+#[test]
+fn test_reachable_states()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param).run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&[1u64,2][..]) }, &Action::Reflect);
+
+    // 成功率の無いアクションは状態の数だけ、あるものは成功と失敗の分だけ増えます
+    assert_eq!( NEXT_CONDITIONS.len(), s.reachable_states(&mod_param, &Action::BasicSynthesis).len() );
+    assert_eq!( NEXT_CONDITIONS.len() * 2, s.reachable_states(&mod_param, &Action::HastyTouch).len() );
+
+    // 状態の変わらない手は結果が1つに決まります
+    assert_eq!( vec![s.run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&[3u64,4][..]) }, &Action::FinalAppraisal)], s.reachable_states(&mod_param, &Action::FinalAppraisal) );
+
+    // どの乱数で実行しても、結果は起こり得る状態のどれかになります
+    for seed in 0..64u64 {
+        let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&[seed, seed ^ 0x9e3779b97f4a7c15][..]) };
+        for a in [Action::HastyTouch, Action::RapidSynthesis, Action::FocusedTouch, Action::BasicTouch] {
+            assert!( s.reachable_states(&mod_param, &a).contains(&s.run_action(&mut modifier, &a)), "{:?}", a );
+        }
+    }
+}
+
+#[test]
+fn test_run_action_consumes_fixed_randoms()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let new_modifier = || Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&[1u64,2][..]) };
+
+    // 成功率や状態の変化の有無によらず、実行した後の乱数は同じです
+    let mut rng = new_modifier().rng;
+    rng.next_f32();
+    rng.next_f32();
+    let expected = rng.next_u64();
+    for a in [Action::BasicSynthesis, Action::HastyTouch, Action::FinalAppraisal, Action::CarefulObservation] {
+        let mut modifier = new_modifier();
+        s.run_action(&mut modifier, &a);
+        assert_eq!( expected, modifier.rng.next_u64(), "{:?}", a );
+    }
+}
+
+#[test]
+fn test_canonical_key()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+
+    // 終了した状態ではCPは結果に影響しないので同じキーになります
+    let completed = State { completed:true, working:mod_param.max_working, quality:1000, ..State::new(&mod_param) };
+    assert_eq!( completed.canonical_key(), State { cp:0, ..completed.clone() }.canonical_key() );
+
+    // 耐久は終了していない状態でも終了した状態でも区別します
+    let s = State::new(&mod_param);
+    assert_ne!( s.canonical_key(), State { durability:s.durability-10, ..s.clone() }.canonical_key() );
+    assert_ne!( completed.canonical_key(), State { durability:completed.durability-10, ..completed.clone() }.canonical_key() );
+}
+
+#[test]
+fn test_canonical_key_hash()
+{
+    use std::hash::{Hash,Hasher};
+    use std::collections::hash_map::DefaultHasher;
+
+    let hash = |s:&State| {
+        let mut hasher = DefaultHasher::new();
+        s.canonical_key().hash(&mut hasher);
+        hasher.finish()
+    };
+
+    // 終了した状態で捨てるフィールドだけが違う状態は、同じキーで同じハッシュになります
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let destroyed = State { durability:0, turn:10, quality:500, ..State::new(&mod_param) };
+    let ignored = [
+        State { working:100, ..destroyed.clone() },
+        State { cp:10, ..destroyed.clone() },
+        State { inner_quiet:3, careful_observation:1, waste_not:2, veneration:1, great_strides:2, innovation:3, ..destroyed.clone() },
+        State { final_appraisal:1, muscle_memory:2, manipulation:4, ..destroyed.clone() },
+        State { heart_and_soul:true, heart_and_soul_used:true, ..destroyed.clone() },
+        State { combo_basic_touch:true, combo_standard_touch:true, combo_observe:true, ..destroyed.clone() },
+        State { condition:Condition::HighQuality, ..destroyed.clone() },
+    ];
+    for s in &ignored {
+        assert_eq!( destroyed.canonical_key(), s.canonical_key() );
+        assert_eq!( hash(&destroyed), hash(s) );
+    }
+
+    // 残すフィールドが違えば別のキーです
+    for s in [State { quality:501, ..destroyed.clone() }, State { time:1, ..destroyed.clone() }, State { turn:11, ..destroyed.clone() }] {
+        assert_ne!( destroyed.canonical_key(), s.canonical_key() );
+    }
+}
+
+// ランダムに手を選んで進めながら、訪れた未終了の状態の合法手の数の平均を求めます。
+// 終了したら初期状態からやり直して、samples個の状態を数えるまで続けます。
+// 探索の回数などを決める時の目安にするためのもので、探索で除外している手も数えます
+#[allow(dead_code)]
+pub fn estimate_branching_factor( mod_param:&ModifierParameter, samples:usize, seed:u64 ) -> f64 {
+    let seeds = [splitmix64(seed), splitmix64(splitmix64(seed))];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut state = State::new(mod_param);
+    let mut total = 0;
+
+    for _ in 0..samples {
+        let actions = state.legal_actions();
+        total += actions.len();
+
+        // 合法手が無い場合も終了として扱います
+        if !actions.is_empty() {
+            let a = actions[modifier.rng.gen_range(0, actions.len())];
+            state = state.run_action(&mut modifier, &a);
+        }
+        if actions.is_empty() || state.is_terminated() {
+            state = State::new(mod_param);
+        }
+    }
+
+    total as f64 / samples.max(1) as f64
+}
+
+#[test]
+fn test_estimate_branching_factor()
+{
+    // CPが無ければ初期状態で使えるのはCP0の5つだけです
+    let poor = ModifierParameter { max_cp:0, ..ModifierParameter::new_fountain_of_usouso() };
+    assert_eq!( vec![Action::BasicSynthesis, Action::HastyTouch, Action::RapidSynthesis, Action::CarefulObservation, Action::HeartAndSoul], State::new(&poor).legal_actions() );
+    assert_eq!( 5.0, estimate_branching_factor(&poor, 1, 1) );
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    assert_eq!( 27.0, estimate_branching_factor(&mod_param, 1, 1) );
+
+    // 同じシードなら同じ値になり、CPが多いほど選択肢は増えます
+    let estimate = estimate_branching_factor(&poor, 1000, 1);
+    assert_eq!( estimate, estimate_branching_factor(&poor, 1000, 1) );
+    assert!( 1.0 <= estimate && estimate < estimate_branching_factor(&mod_param, 1000, 1) );
+
+    // シードが0でも乱数の系列は止まらず、他のシードと違う値になります
+    let zero = estimate_branching_factor(&poor, 1000, 0);
+    assert_ne!( estimate, zero );
+    assert!( (1.0..27.0).contains(&zero) );
+}
+
 // Velvet Weissmelさんの調査式を元に計算
 // https://jp.finalfantasyxiv.com/lodestone/character/3514261/blog/4645845/
 pub fn get_technical_point(worth:u32) -> u32 {
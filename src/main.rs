@@ -1,5 +1,6 @@
 ﻿// クラフトシミュレータ by Tsubaki Sakura
 
+// benches/はこのファイルをモジュールとして読み込むので、そこから使うモジュールはpub(crate)にしています
 mod logic;
 mod cui;
 mod selfplay;
@@ -12,20 +13,37 @@ mod selector;
 mod gcs;
 mod cache;
 mod learner;
-mod benchmark;
+pub(crate) mod benchmark;
 mod executor;
 mod predictor;
 mod replay;
-mod setting;
+pub(crate) mod setting;
+mod metrics;
+mod error;
+mod db;
+mod arena;
+mod logging;
+mod util;
+#[cfg(feature="onnx")]
+mod onnx;
 
 use setting::ModifierParameter;
 use argh::FromArgs;
-use selfplay::{WriterParameter,EpisodeParameter,SelfPlayParameter};
+use selfplay::{WriterParameter,EpisodeParameter,SelfPlayParameter,NonFiniteReward,ShutdownMode,RewardReport,SampleRetention,AuxTarget,HardStart,Resignation,AbortedReward,WeightedSetting,TemperatureSchedule,DEFAULT_MAX_TURNS};
 use selector::Selector;
 use learner::{LearnerParameter};
-use benchmark::BenchmarkParameter;
+use benchmark::{BenchmarkParameter,SelfPlayBenchmarkParameter};
 use network::NetworkType;
 use cui::{CuiParameter};
+use replay::RecordSource;
+use formatter::ValueTarget;
+use predictor::Priority;
+use mcts::{GreedyCriterion,TieBreak,SimulationBudget,RewardFunction,AlphaSchedule,GumbelParameter};
+use metrics::DEFAULT_REWARD_BUCKETS;
+use std::time::Duration;
+use std::path::PathBuf;
+use arena::ArenaParameter;
+use error::CraftSimError;
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(description="toplevel command")]
@@ -34,6 +52,8 @@ struct TopLevel {
     sub_command: SubCommand,
 }
 
+// 起動時に1つ作るだけなので、サブコマンドごとの大きさの差は気にしません
+#[allow(clippy::large_enum_variant)]
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
@@ -43,6 +63,10 @@ enum SubCommand {
     Benchmark(SubCommandBenchmark),
     Replay(SubCommandReplay),
     Cui(SubCommandCui),
+    Actions(SubCommandActions),
+    Promotion(SubCommandPromotion),
+    Arena(SubCommandArena),
+    SelfPlay(SubCommandSelfPlay),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -57,18 +81,42 @@ struct SubCommandEvaluator {
     #[argh(option, default="16", description="batch size")]
     batch_size:usize,
 
+    #[argh(option, description="episodes played concurrently per thread (default: batch size)")]
+    coroutine_num:Option<usize>,
+
+    #[argh(option, description="max predictions waiting per thread. must not be less than batch size (default: unlimited)")]
+    max_queued_tasks:Option<usize>,
+
+    #[argh(option, description="device for inference such as cuda:0. repeat to assign threads round-robin")]
+    gpu_device:Vec<String>,
+
     #[argh(option, default="500", description="mcts simulation num")]
     mcts_simulation_num:u32,
 
+    #[argh(option, default="1.0", description="exploration constant of puct")]
+    c_puct:f32,
+
+    #[argh(option, default="0", description="max concurrent network loads(0 for no limit)")]
+    max_concurrent_loads:usize,
+
     #[argh(option, description="use ucb1 selector")]
     ucb1:Option<f64>,
 
-    #[argh(option, description="use optimistic selector")]
-    optimistic:Option<usize>,
+    #[argh(option, description="use optimistic selector with the given prior pseudo count")]
+    optimistic:Option<f64>,
+
+    #[argh(option, default="1.0", description="reward assumed for the prior pseudo count of optimistic selector")]
+    optimistic_initial_reward:f64,
 
     #[argh(option, description="use greedy selector")]
     greedy:Option<usize>,
 
+    #[argh(option, description="use thompson sampling selector with the given prior pseudo count")]
+    thompson:Option<f64>,
+
+    #[argh(option, description="use epsilon greedy selector with the given exploration probability")]
+    epsilon_greedy:Option<f32>,
+
     #[argh(option, default="1", description="torch parallelism thread num")]
     tch_thread_num:u32,
 
@@ -78,8 +126,90 @@ struct SubCommandEvaluator {
     #[argh(option, default="String::from(\"root\")", description="mysql user name")]
     mysql_user:String,
 
+    #[argh(option, default="String::from(\"localhost\")", description="mysql host")]
+    mysql_host:String,
+
+    #[argh(option, default="3306", description="mysql port")]
+    mysql_port:u16,
+
+    #[argh(option, default="String::from(\"craft\")", description="mysql database name")]
+    mysql_db:String,
+
+    #[argh(option, default="5", description="shut down after this many consecutive transient database errors")]
+    max_db_failures:u32,
+
+    #[argh(option, default="5", description="seconds between progress reports of the writer")]
+    progress_interval_secs:u64,
+
+    #[argh(option, default="2", description="seconds between model selections")]
+    model_poll_interval_secs:u64,
+
+    // 1レコードは十数KBなので、既定値でも溜まるのは100MB程度までです
+    #[argh(option, default="4096", description="max records waiting for the writer. selfplay blocks when full")]
+    writer_queue_capacity:usize,
+
+    #[argh(option, description="append records to this json lines file instead of mysql")]
+    record_file:Option<PathBuf>,
+
+    #[argh(option, description="write evaluations to this sqlite file instead of mysql")]
+    sqlite_file:Option<PathBuf>,
+
+    #[argh(option, description="file to cache network predictions across runs")]
+    prediction_cache:Option<String>,
+
+    #[argh(option, default="1000000", description="max entries of prediction cache")]
+    prediction_cache_size:usize,
+
+    #[argh(option, description="write prometheus metrics to this textfile")]
+    metrics_file:Option<String>,
+
+    #[argh(option, description="serve prometheus metrics on this address (requires metrics-server feature)")]
+    metrics_addr:Option<String>,
+
+    #[argh(option, description="verify uploaded records reproduce with current logic before selfplay")]
+    verify_records:Option<String>,
+
+    #[argh(option, description="replace non-finite rewards with this value instead of rejecting them")]
+    replace_non_finite_reward:Option<f32>,
+
+    #[argh(switch, description="discard episodes in progress on shutdown instead of finishing them")]
+    discard_on_shutdown:bool,
+
+    #[argh(option, description="upper bound of a reward histogram bucket in progress. repeat for each (default: 0.0 0.2 0.4 0.6 0.8 1.0)")]
+    reward_bucket:Vec<f32>,
+
+    #[argh(option, description="warn when mean reward in a progress interval is below this value")]
+    warn_mean_reward_below:Option<f32>,
+
+    #[argh(switch, description="choose greedy action by mean action value instead of visit count")]
+    greedy_by_q: bool,
+
+    #[argh(option, default="0.0", description="small temperature to break ties between similar models. 0 is fully greedy")]
+    eval_temperature:f32,
+
+    #[argh(option, default="0", description="minimum mcts simulations per move under any budget(0 to follow the budget exactly)")]
+    min_simulations:u32,
+
+    #[argh(option, default="1", description="number of simulations descended together before waiting for their predictions")]
+    parallel_simulations:u32,
+
+    #[argh(option, default="1", description="virtual visits added to actions on the path of a simulation waiting for prediction")]
+    virtual_loss:u32,
+
+    #[argh(option, description="base seed of random numbers to reproduce episodes")]
+    seed:Option<u64>,
+
+    #[argh(option, description="stop after running this seconds")]
+    max_runtime_secs:Option<u64>,
+
+    #[argh(option, description="stop after writing this number of records")]
+    max_records:Option<u64>,
+
     #[argh(switch, description="profile with flamegraph")]
     flamegraph: bool,
+
+    #[argh(switch, description="print the parameters as a config file for the selfplay command and exit")]
+    print_config: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -94,11 +224,23 @@ struct SubCommandGenerator {
     #[argh(option, default="32", description="batch size")]
     batch_size:usize,
 
+    #[argh(option, description="episodes played concurrently per thread (default: batch size)")]
+    coroutine_num:Option<usize>,
+
+    #[argh(option, description="max predictions waiting per thread. must not be less than batch size (default: unlimited)")]
+    max_queued_tasks:Option<usize>,
+
+    #[argh(option, description="device for inference such as cuda:0. repeat to assign threads round-robin")]
+    gpu_device:Vec<String>,
+
     #[argh(option, default="500", description="mcts simulation num")]
     mcts_simulation_num:u32,
 
-    #[argh(option, default="0.15", description="dirichlet noise alpha")]
-    alpha:f32,
+    #[argh(option, default="1.0", description="exploration constant of puct")]
+    c_puct:f32,
+
+    #[argh(option, default="AlphaSchedule::constant(0.15)", description="dirichlet noise alpha, or turn:alpha pairs like 1:0.3,10:0.15")]
+    alpha:AlphaSchedule,
 
     #[argh(option, default="0.3", description="dirichlet noise epsilon(0 for no noise)")]
     eps:f32,
@@ -106,15 +248,30 @@ struct SubCommandGenerator {
     #[argh(option, default="30", description="start greety algorithm turn")]
     start_greedy_turn:u32,
 
+    #[argh(option, description="temperature schedule as turn:temperature pairs like 1:1.0,10:0.5,20:0. overrides start greedy turn")]
+    temperature_schedule:Option<TemperatureSchedule>,
+
+    #[argh(option, default="0", description="max concurrent network loads(0 for no limit)")]
+    max_concurrent_loads:usize,
+
     #[argh(option, description="use ucb1 selector")]
     ucb1:Option<f64>,
 
-    #[argh(option, description="use optimistic selector")]
-    optimistic:Option<usize>,
+    #[argh(option, description="use optimistic selector with the given prior pseudo count")]
+    optimistic:Option<f64>,
+
+    #[argh(option, default="1.0", description="reward assumed for the prior pseudo count of optimistic selector")]
+    optimistic_initial_reward:f64,
 
     #[argh(option, description="use greedy selector")]
     greedy:Option<usize>,
 
+    #[argh(option, description="use thompson sampling selector with the given prior pseudo count")]
+    thompson:Option<f64>,
+
+    #[argh(option, description="use epsilon greedy selector with the given exploration probability")]
+    epsilon_greedy:Option<f32>,
+
     #[argh(option, default="1", description="torch parallelism thread num")]
     tch_thread_num:u32,
 
@@ -124,8 +281,153 @@ struct SubCommandGenerator {
     #[argh(option, default="String::from(\"root\")", description="mysql user name")]
     mysql_user:String,
 
+    #[argh(option, default="String::from(\"localhost\")", description="mysql host")]
+    mysql_host:String,
+
+    #[argh(option, default="3306", description="mysql port")]
+    mysql_port:u16,
+
+    #[argh(option, default="String::from(\"craft\")", description="mysql database name")]
+    mysql_db:String,
+
+    #[argh(option, default="5", description="shut down after this many consecutive transient database errors")]
+    max_db_failures:u32,
+
+    #[argh(option, default="5", description="seconds between progress reports of the writer")]
+    progress_interval_secs:u64,
+
+    #[argh(option, default="2", description="seconds between model selections")]
+    model_poll_interval_secs:u64,
+
+    // 1レコードは十数KBなので、既定値でも溜まるのは100MB程度までです
+    #[argh(option, default="4096", description="max records waiting for the writer. selfplay blocks when full")]
+    writer_queue_capacity:usize,
+
+    #[argh(option, default="3600", description="generation phase seconds when alternating with evaluation")]
+    generation_secs:u64,
+
+    #[argh(option, description="alternate with evaluation phase of this seconds")]
+    evaluation_secs:Option<u64>,
+
+    #[argh(option, description="append records to this json lines file instead of mysql. evaluation phase is not used")]
+    record_file:Option<PathBuf>,
+
+    #[argh(option, description="write samples and evaluations to this sqlite file instead of mysql and storage")]
+    sqlite_file:Option<PathBuf>,
+
+    #[argh(option, description="use n-step temporal-difference value target")]
+    td_steps:Option<usize>,
+
+    #[argh(option, default="1.0", description="discount rate of temporal-difference value target")]
+    td_gamma:f32,
+
+    #[argh(switch, description="append |reward - first root value| of the record to each sample row as a priority")]
+    priority_column: bool,
+
+    #[argh(option, description="stop collecting samples after this turn")]
+    max_collected_turns:Option<u32>,
+
+    #[argh(option, description="keep only first and last n samples of each episode")]
+    retain_head_tail:Option<usize>,
+
+    #[argh(option, description="preload uploaded records before selfplay")]
+    preload_records:Option<String>,
+
+    #[argh(option, description="preload local record file before selfplay")]
+    preload_records_file:Option<String>,
+
+    #[argh(option, description="write prometheus metrics to this textfile")]
+    metrics_file:Option<String>,
+
+    #[argh(option, description="serve prometheus metrics on this address (requires metrics-server feature)")]
+    metrics_addr:Option<String>,
+
+    #[argh(option, description="verify uploaded records reproduce with current logic before selfplay")]
+    verify_records:Option<String>,
+
+    #[argh(option, description="replace non-finite rewards with this value instead of rejecting them")]
+    replace_non_finite_reward:Option<f32>,
+
+    #[argh(switch, description="discard episodes in progress on shutdown instead of finishing them")]
+    discard_on_shutdown:bool,
+
+    #[argh(option, description="upper bound of a reward histogram bucket in progress. repeat for each (default: 0.0 0.2 0.4 0.6 0.8 1.0)")]
+    reward_bucket:Vec<f32>,
+
+    #[argh(option, description="warn when mean reward in a progress interval is below this value")]
+    warn_mean_reward_below:Option<f32>,
+
+    #[argh(option, description="do not write records with reward below this value")]
+    min_reward:Option<f32>,
+
+    #[argh(switch, description="choose greedy action by mean action value instead of visit count")]
+    greedy_by_q: bool,
+
+    #[argh(option, description="search each move for this milliseconds instead of mcts simulation num")]
+    simulation_time_millis:Option<u64>,
+
+    #[argh(option, default="0", description="minimum mcts simulations per move under any budget(0 to follow the budget exactly)")]
+    min_simulations:u32,
+
+    #[argh(option, default="1", description="number of simulations descended together before waiting for their predictions")]
+    parallel_simulations:u32,
+
+    #[argh(option, default="1", description="virtual visits added to actions on the path of a simulation waiting for prediction")]
+    virtual_loss:u32,
+
+    #[argh(option, description="base seed of random numbers to reproduce episodes")]
+    seed:Option<u64>,
+
+    #[argh(option, description="stop after running this seconds")]
+    max_runtime_secs:Option<u64>,
+
+    #[argh(option, description="stop after writing this number of records")]
+    max_records:Option<u64>,
+
+    #[argh(switch, description="store network prior before dirichlet noise in each sample")]
+    record_raw_prior: bool,
+
+    #[argh(switch, description="store search time and simulation count in each sample")]
+    collect_timings: bool,
+
+    #[argh(option, description="auxiliary target stored in each sample (quality, completed). can be repeated")]
+    aux_target:Vec<AuxTarget>,
+
+    #[argh(option, description="start from hard states whose cp is at least this ratio of max cp")]
+    hard_start_min_cp_ratio:Option<f32>,
+
+    #[argh(option, default="0.9", description="upper ratio of max cp for hard start states")]
+    hard_start_max_cp_ratio:f32,
+
+    #[argh(option, description="resign when the mean value of the root stays below this threshold")]
+    resign_threshold:Option<f32>,
+
+    #[argh(option, default="3", description="number of consecutive turns below the resign threshold before resigning")]
+    resign_turns:u32,
+
+    #[argh(option, default="0.1", description="ratio of episodes played to the end without resigning to check false resignations")]
+    resign_false_positive_rate:f32,
+
+    #[argh(option, default="AbortedReward::Fixed(0.0)", description="reward of resigned episodes (value, reward or a number)")]
+    resign_reward:AbortedReward,
+
+    #[argh(option, default="AbortedReward::RewardFn", description="reward of episodes truncated by the max turns (value, reward or a number)")]
+    truncated_reward:AbortedReward,
+
+    #[argh(option, default="RewardFunction::Default", description="reward of terminal states (default, quality, threshold)")]
+    reward:RewardFunction,
+
+    #[argh(option, description="select actions by gumbel alphazero considering this many actions at the root instead of visit counts")]
+    gumbel_max_considered_actions:Option<usize>,
+
+    #[argh(option, description="mix this setting into selfplay as name:weight (can be repeated)")]
+    mixed_setting:Vec<WeightedSetting>,
+
     #[argh(switch, description="profile with flamegraph")]
     flamegraph: bool,
+
+    #[argh(switch, description="print the parameters as a config file for the selfplay command and exit")]
+    print_config: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -140,6 +442,15 @@ struct SubCommandLearner {
     #[argh(option, default="String::from(\"root\")", description="mysql user name")]
     mysql_user:String,
 
+    #[argh(option, default="String::from(\"localhost\")", description="mysql host")]
+    mysql_host:String,
+
+    #[argh(option, default="3306", description="mysql port")]
+    mysql_port:u16,
+
+    #[argh(option, default="String::from(\"craft\")", description="mysql database name")]
+    mysql_db:String,
+
     #[argh(option, default="NetworkType::FullyConnected(4,128)", description="network type")]
     network_type: NetworkType,
 
@@ -155,6 +466,21 @@ struct SubCommandBenchmark {
 
     #[argh(option, default="16384", description="plays per write")]
     plays_per_write:usize,
+
+    #[argh(switch, description="benchmark predict result pool instead of network")]
+    result_pool:bool,
+
+    #[argh(switch, description="benchmark selfplay with a uniform mock network instead of network")]
+    selfplay:bool,
+
+    #[argh(option, default="32", description="games per setting in selfplay benchmark")]
+    games:u64,
+
+    #[argh(option, description="mcts simulation num in selfplay benchmark. repeat to compare (default: 50 and 200)")]
+    mcts_simulation_num:Vec<u32>,
+
+    #[argh(option, description="batch size in selfplay benchmark. repeat to compare (default: 1 and --batch-size)")]
+    sweep_batch_size:Vec<usize>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -169,131 +495,480 @@ struct SubCommandReplay {
 struct SubCommandCui {
 }
 
-fn get_selector( ucb1:Option<f64>, optimistic:Option<usize>, greedy:Option<usize> ) -> Option<Selector> {
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name="actions", description="print action to index mapping as json")]
+struct SubCommandActions {
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name="promotion", description="check whether model is eligible for promotion")]
+struct SubCommandPromotion {
+    #[argh(positional, description="model name")]
+    name: String,
+
+    #[argh(option, default="1000", description="min evaluation episodes")]
+    min_games:u64,
+
+    #[argh(option, default="0.55", description="win rate against champion that must be exceeded (reaching it exactly is not enough)")]
+    min_winrate:f64,
+
+    #[argh(option, default="String::from(\"root\")", description="mysql user name")]
+    mysql_user:String,
+
+    #[argh(option, default="String::from(\"localhost\")", description="mysql host")]
+    mysql_host:String,
+
+    #[argh(option, default="3306", description="mysql port")]
+    mysql_port:u16,
+
+    #[argh(option, default="String::from(\"craft\")", description="mysql database name")]
+    mysql_db:String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name="selfplay", description="run selfplay with parameters from a config file")]
+struct SubCommandSelfPlay {
+    #[argh(option, description="toml config file of selfplay parameters")]
+    config:PathBuf,
+
+    #[argh(switch, description="profile with flamegraph")]
+    flamegraph: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name="arena", description="play matches between two models with matched seeds")]
+struct SubCommandArena {
+    #[argh(positional, description="challenger model name")]
+    challenger: String,
+
+    #[argh(positional, description="champion model name")]
+    champion: String,
+
+    #[argh(option, default="1000", description="number of games")]
+    games:u64,
+
+    #[argh(option, default="32", description="number of games played concurrently")]
+    batch_size:usize,
+
+    #[argh(option, default="500", description="mcts simulation num")]
+    mcts_simulation_num:u32,
+
+    #[argh(option, default="1.0", description="exploration constant of puct")]
+    c_puct:f32,
+
+    #[argh(option, description="base seed of random numbers to reproduce games")]
+    seed:Option<u64>,
+
+    #[argh(option, default="String::from(\"root\")", description="mysql user name")]
+    mysql_user:String,
+
+    #[argh(option, default="String::from(\"localhost\")", description="mysql host")]
+    mysql_host:String,
+
+    #[argh(option, default="3306", description="mysql port")]
+    mysql_port:u16,
+
+    #[argh(option, default="String::from(\"craft\")", description="mysql database name")]
+    mysql_db:String,
+}
+
+fn parse_devices( names:&[String] ) -> Result<Vec<tch::Device>,CraftSimError> {
+    names.iter().map(|x| network::parse_device(x).map_err(CraftSimError::Config)).collect()
+}
+
+fn get_selector( ucb1:Option<f64>, (optimistic,initial_reward):(Option<f64>,f64), greedy:Option<usize>, thompson:Option<f64>, epsilon_greedy:Option<f32> ) -> Option<Selector> {
     if let Some(x) = ucb1 {
         Some(Selector::UCB1(x))
     }
-    else if let Some(x) = optimistic {
-        Some(Selector::Optimistic(x))
+    else if let Some(prior_count) = optimistic {
+        Some(Selector::Optimistic { initial_reward, prior_count })
     }
     else if let Some(x) = greedy {
         Some(Selector::Greedy(x))
     }
+    else if let Some(x) = thompson {
+        Some(Selector::Thompson(x))
+    }
     else {
-        None
+        epsilon_greedy.map(Selector::EpsilonGreedy)
+    }
+}
+
+fn get_record_source( blob:Option<String>, file:Option<String> ) -> Option<RecordSource> {
+    blob.map(RecordSource::Blob).or_else(|| file.map(RecordSource::File))
+}
+
+// 評価のフェーズを挟む場合、評価でのモデル選択はevaluatorのデフォルトと同じにします
+fn get_generator_schedule( generation_secs:u64, evaluation_secs:Option<u64>, selector:Selector ) -> Vec<(Duration,WriterParameter,Selector)> {
+    match evaluation_secs {
+        Some(x) => vec![
+            (Duration::from_secs(generation_secs), WriterParameter::Generation, selector),
+            (Duration::from_secs(x), WriterParameter::Evaluation, Selector::optimistic(10.0)),
+        ],
+        None => vec![(Duration::MAX, WriterParameter::Generation, selector)],
     }
 }
 
-fn with_flamegraph<F: FnOnce()>( f:F ) {
+// 指定された場合は、各フェーズの書き込み先をSQLiteのファイルに置き換えます
+fn with_sqlite_file( schedule:Vec<(Duration,WriterParameter,Selector)>, sqlite_file:Option<PathBuf> ) -> Vec<(Duration,WriterParameter,Selector)> {
+    match sqlite_file {
+        Some(path) => schedule.into_iter().map(|(duration,writer,selector)| match writer {
+            WriterParameter::Evaluation => (duration, WriterParameter::SqliteEvaluation(path.clone()), selector),
+            WriterParameter::Generation => (duration, WriterParameter::SqliteGeneration(path.clone()), selector),
+            writer => (duration, writer, selector),
+        }).collect(),
+        None => schedule,
+    }
+}
+
+fn with_flamegraph<T, F: FnOnce() -> T>( f:F ) -> T {
     let guard = pprof::ProfilerGuard::new(100).unwrap();
-    f();
+    let ret = f();
     if let Ok(report) = guard.report().build() {
         let file = std::fs::File::create("flamegraph.svg").unwrap();
         report.flamegraph(file).unwrap();
     }
+    ret
 }
 
-fn cmd_evaluator( args:SubCommandEvaluator ) {
+fn cmd_evaluator( args:SubCommandEvaluator ) -> Result<(),CraftSimError> {
     let param = SelfPlayParameter {
         episode_param: EpisodeParameter {
             mod_param:ModifierParameter::new_fountain_of_usouso(),
-            mcts_simulation_num:args.mcts_simulation_num,
-            alpha:0.15,
+            simulation_budget:SimulationBudget::Fixed(args.mcts_simulation_num),
+            min_simulations:args.min_simulations,
+            parallel_simulations:args.parallel_simulations,
+            virtual_loss:args.virtual_loss,
+            c_puct:args.c_puct,
+            alpha:AlphaSchedule::constant(0.15),
             eps:0.0,
-            start_greedy_turn:0,
+            add_root_noise:false,
+            temperature_schedule:TemperatureSchedule::greedy_from(0),
+            no_legal_action_reward:0.0,
+            priority:Priority::High,
+            max_collected_turns:None,
+            greedy_criterion:if args.greedy_by_q { GreedyCriterion::Q } else { GreedyCriterion::Visits },
+            tie_break:TieBreak::LowestIndex,
+            record_raw_prior:false,
+            collect_timings:false,
+            aux_target_fns:vec![],
+            eval_temperature:args.eval_temperature,
+            hard_start:None,
+            base_seed:args.seed,
+            resign:None,
+            reward_fn:RewardFunction::Default,
+            max_turns:Some(DEFAULT_MAX_TURNS),
+            truncated_reward:AbortedReward::RewardFn,
+            gumbel:None,
+            mixed_settings:vec![],
         },
-        selector:get_selector(args.ucb1, args.optimistic, args.greedy).unwrap_or(Selector::Optimistic(10)),
         plays_per_write:args.plays_per_write,
         thread_num:args.thread_num,
+        coroutine_num:args.coroutine_num.unwrap_or(args.batch_size),
+        max_queued_tasks:args.max_queued_tasks,
+        gpu_devices:parse_devices(&args.gpu_device)?,
         batch_size:args.batch_size,
+        max_concurrent_loads:args.max_concurrent_loads,
         tch_thread_num:args.tch_thread_num,
         tch_interop_thread_num:args.tch_interop_thread_num,
         mysql_user:args.mysql_user,
-        writer_param:WriterParameter::Evaluation,
+        mysql_host:args.mysql_host,
+        mysql_port:args.mysql_port,
+        mysql_db:args.mysql_db,
+        writer_schedule:vec![(Duration::MAX, match (args.record_file,args.sqlite_file) {
+            (Some(path),_) => WriterParameter::JsonFile(path),
+            (None,Some(path)) => WriterParameter::SqliteEvaluation(path),
+            (None,None) => WriterParameter::Evaluation,
+        }, get_selector(args.ucb1, (args.optimistic,args.optimistic_initial_reward), args.greedy, args.thompson, args.epsilon_greedy).unwrap_or(Selector::optimistic(10.0)))],
+        preload_records:None,
+        prediction_cache:args.prediction_cache,
+        prediction_cache_size:args.prediction_cache_size,
+        value_target:ValueTarget::MonteCarlo,
+        priority_column:false,
+        non_finite_reward:args.replace_non_finite_reward.map(NonFiniteReward::Replace).unwrap_or(NonFiniteReward::Reject),
+        shutdown_mode:if args.discard_on_shutdown { ShutdownMode::Discard } else { ShutdownMode::Finish },
+        reward_report:RewardReport {
+            buckets:if args.reward_bucket.is_empty() { DEFAULT_REWARD_BUCKETS.to_vec() } else { args.reward_bucket },
+            warn_below:args.warn_mean_reward_below,
+        },
+        min_reward:None,
+        startup_verification:args.verify_records.map(RecordSource::Blob),
+        metrics_file:args.metrics_file,
+        metrics_addr:args.metrics_addr,
+        sample_retention:SampleRetention::All,
+        progress_callback:None,
+        progress_interval:Duration::from_secs(args.progress_interval_secs),
+        model_poll_interval:Duration::from_secs(args.model_poll_interval_secs),
+        writer_queue_capacity:args.writer_queue_capacity,
+        max_runtime:args.max_runtime_secs.map(Duration::from_secs),
+        max_records:args.max_records,
+        max_db_failures:args.max_db_failures,
+        control:None,
     };
 
+    if args.print_config {
+        print!("{}", param.to_toml()?);
+        return Ok(());
+    }
+
     if args.flamegraph {
-        with_flamegraph( ||{ selfplay::run(&param) } );
+        with_flamegraph( ||{ selfplay::run(&param) } )
     }
     else {
-        selfplay::run(&param);
+        selfplay::run(&param)
     }
 }
 
-fn cmd_generator( args:SubCommandGenerator ) {
+fn cmd_generator( args:SubCommandGenerator ) -> Result<(),CraftSimError> {
     let param = SelfPlayParameter {
         episode_param: EpisodeParameter {
             mod_param:ModifierParameter::new_fountain_of_usouso(),
-            mcts_simulation_num:args.mcts_simulation_num,
+            simulation_budget:match args.simulation_time_millis {
+                Some(x) => SimulationBudget::Timed(Duration::from_millis(x)),
+                None => SimulationBudget::Fixed(args.mcts_simulation_num),
+            },
+            min_simulations:args.min_simulations,
+            parallel_simulations:args.parallel_simulations,
+            virtual_loss:args.virtual_loss,
+            c_puct:args.c_puct,
             alpha:args.alpha,
             eps:args.eps,
-            start_greedy_turn:args.start_greedy_turn,
+            add_root_noise:true,
+            temperature_schedule:match args.temperature_schedule {
+                Some(x) => x,
+                None => TemperatureSchedule::greedy_from(args.start_greedy_turn),
+            },
+            no_legal_action_reward:0.0,
+            priority:Priority::Normal,
+            max_collected_turns:args.max_collected_turns,
+            greedy_criterion:if args.greedy_by_q { GreedyCriterion::Q } else { GreedyCriterion::Visits },
+            tie_break:TieBreak::Random,
+            record_raw_prior:args.record_raw_prior,
+            collect_timings:args.collect_timings,
+            aux_target_fns:args.aux_target,
+            eval_temperature:0.0,
+            hard_start:match args.hard_start_min_cp_ratio {
+                Some(min_cp_ratio) => Some(HardStart { min_cp_ratio, max_cp_ratio:args.hard_start_max_cp_ratio }),
+                None => None,
+            },
+            base_seed:args.seed,
+            resign:match args.resign_threshold {
+                Some(threshold) => Some(Resignation { threshold, consecutive_turns:args.resign_turns, reward:args.resign_reward, false_positive_rate:args.resign_false_positive_rate }),
+                None => None,
+            },
+            reward_fn:args.reward,
+            max_turns:Some(DEFAULT_MAX_TURNS),
+            truncated_reward:args.truncated_reward,
+            gumbel:args.gumbel_max_considered_actions.map(|x| GumbelParameter { max_considered_actions:x, ..GumbelParameter::default() }),
+            mixed_settings:args.mixed_setting,
         },
-        selector:get_selector(args.ucb1, args.optimistic, args.greedy).unwrap_or(Selector::Greedy(50)),
         plays_per_write:args.plays_per_write,
         thread_num:args.thread_num,
+        coroutine_num:args.coroutine_num.unwrap_or(args.batch_size),
+        max_queued_tasks:args.max_queued_tasks,
+        gpu_devices:parse_devices(&args.gpu_device)?,
         batch_size:args.batch_size,
+        max_concurrent_loads:args.max_concurrent_loads,
         tch_thread_num:args.tch_thread_num,
         tch_interop_thread_num:args.tch_interop_thread_num,
         mysql_user:args.mysql_user,
-        writer_param:WriterParameter::Generation,
+        mysql_host:args.mysql_host,
+        mysql_port:args.mysql_port,
+        mysql_db:args.mysql_db,
+        writer_schedule:match args.record_file {
+            Some(path) => vec![(Duration::MAX, WriterParameter::JsonFile(path), get_selector(args.ucb1, (args.optimistic,args.optimistic_initial_reward), args.greedy, args.thompson, args.epsilon_greedy).unwrap_or(Selector::Greedy(50)))],
+            None => with_sqlite_file(get_generator_schedule(args.generation_secs, args.evaluation_secs, get_selector(args.ucb1, (args.optimistic,args.optimistic_initial_reward), args.greedy, args.thompson, args.epsilon_greedy).unwrap_or(Selector::Greedy(50))), args.sqlite_file),
+        },
+        preload_records:get_record_source(args.preload_records, args.preload_records_file),
+        prediction_cache:None,
+        prediction_cache_size:0,
+        value_target:match args.td_steps {
+            Some(n) => ValueTarget::TemporalDifference { n, gamma:args.td_gamma },
+            None => ValueTarget::MonteCarlo,
+        },
+        priority_column:args.priority_column,
+        non_finite_reward:args.replace_non_finite_reward.map(NonFiniteReward::Replace).unwrap_or(NonFiniteReward::Reject),
+        shutdown_mode:if args.discard_on_shutdown { ShutdownMode::Discard } else { ShutdownMode::Finish },
+        reward_report:RewardReport {
+            buckets:if args.reward_bucket.is_empty() { DEFAULT_REWARD_BUCKETS.to_vec() } else { args.reward_bucket },
+            warn_below:args.warn_mean_reward_below,
+        },
+        min_reward:args.min_reward,
+        startup_verification:args.verify_records.map(RecordSource::Blob),
+        metrics_file:args.metrics_file,
+        metrics_addr:args.metrics_addr,
+        sample_retention:match args.retain_head_tail {
+            Some(n) => SampleRetention::HeadTail { head:n, tail:n },
+            None => SampleRetention::All,
+        },
+        progress_callback:None,
+        progress_interval:Duration::from_secs(args.progress_interval_secs),
+        model_poll_interval:Duration::from_secs(args.model_poll_interval_secs),
+        writer_queue_capacity:args.writer_queue_capacity,
+        max_runtime:args.max_runtime_secs.map(Duration::from_secs),
+        max_records:args.max_records,
+        max_db_failures:args.max_db_failures,
+        control:None,
     };
 
+    if args.print_config {
+        print!("{}", param.to_toml()?);
+        return Ok(());
+    }
+
     if args.flamegraph {
-        with_flamegraph( ||{ selfplay::run(&param) } );
+        with_flamegraph( ||{ selfplay::run(&param) } )
     }
     else {
-        selfplay::run(&param);
+        selfplay::run(&param)
     }
 }
 
-fn cmd_learner( args:SubCommandLearner ) {
+fn cmd_learner( args:SubCommandLearner ) -> Result<(),CraftSimError> {
     let param = LearnerParameter {
         epochs_per_write:args.epochs_per_write,
         network_type:args.network_type,
         record_buffer_size:args.record_buffer_size,
         mysql_user:args.mysql_user,
+        mysql_host:args.mysql_host,
+        mysql_port:args.mysql_port,
+        mysql_db:args.mysql_db,
     };
 
     if args.flamegraph {
-        with_flamegraph( ||{ learner::run(&param) } );
+        with_flamegraph( ||{ learner::run(&param) } )
     }
     else {
-        learner::run(&param);
+        learner::run(&param)
     }
 }
 
-fn cmd_benchmark( args:SubCommandBenchmark ) {
+fn cmd_benchmark( args:SubCommandBenchmark ) -> Result<(),CraftSimError> {
+    if args.selfplay {
+        benchmark::run_selfplay_benchmark(SelfPlayBenchmarkParameter {
+            mod_param:ModifierParameter::new_fountain_of_usouso(),
+            games:args.games,
+            simulation_nums:if args.mcts_simulation_num.is_empty() { vec![50,200] } else { args.mcts_simulation_num },
+            batch_sizes:if args.sweep_batch_size.is_empty() { vec![1,args.batch_size] } else { args.sweep_batch_size },
+        });
+        return Ok(());
+    }
+
     let param = BenchmarkParameter {
         mod_param:ModifierParameter::new_fountain_of_usouso(),
         batch_size:args.batch_size,
         plays_per_write:args.plays_per_write,
     };
 
-    benchmark::run_benchmark(param);
+    if args.result_pool {
+        benchmark::run_pool_benchmark(param);
+    }
+    else {
+        benchmark::run_benchmark(param);
+    }
+    Ok(())
 }
 
-fn cmd_replay( args: SubCommandReplay ) {
-    replay::run_replay( args.record_names );
+fn cmd_replay( args: SubCommandReplay ) -> Result<(),CraftSimError> {
+    replay::run_replay( args.record_names )
 }
 
-fn cmd_cui( _args:SubCommandCui ) {
+fn cmd_cui( _args:SubCommandCui ) -> Result<(),CraftSimError> {
     let param = CuiParameter {
         mod_param:ModifierParameter::new_fountain_of_usouso(),
     };
 
     cui::run_cui(param);
+    Ok(())
+}
+
+fn cmd_actions( _args:SubCommandActions ) -> Result<(),CraftSimError> {
+    println!("{}", serde_json::to_string_pretty(&logic::action_index_map()).unwrap());
+    Ok(())
+}
+
+fn cmd_promotion( args:SubCommandPromotion ) -> Result<(),CraftSimError> {
+    let eligible = selector::run_promotion_check(&db::mysql_url(&args.mysql_user, &args.mysql_host, args.mysql_port, &args.mysql_db), &args.name, args.min_games, args.min_winrate)?;
+    println!("{}", eligible);
+    std::process::exit( if eligible { 0 } else { 1 } );
+}
+
+fn cmd_selfplay( args:SubCommandSelfPlay ) -> Result<(),CraftSimError> {
+    let param = SelfPlayParameter::from_toml(&args.config)?;
+    if args.flamegraph {
+        with_flamegraph( ||{ selfplay::run(&param) } )
+    }
+    else {
+        selfplay::run(&param)
+    }
+}
+
+fn cmd_arena( args:SubCommandArena ) -> Result<(),CraftSimError> {
+    let param = ArenaParameter {
+        episode_param: EpisodeParameter {
+            mod_param:ModifierParameter::new_fountain_of_usouso(),
+            simulation_budget:SimulationBudget::Fixed(args.mcts_simulation_num),
+            min_simulations:0,
+            parallel_simulations:1,
+            virtual_loss:0,
+            c_puct:args.c_puct,
+            alpha:AlphaSchedule::constant(0.15),
+            eps:0.0,
+            add_root_noise:false,
+            temperature_schedule:TemperatureSchedule::greedy_from(0),
+            no_legal_action_reward:0.0,
+            priority:Priority::Normal,
+            max_collected_turns:None,
+            greedy_criterion:GreedyCriterion::Visits,
+            tie_break:TieBreak::LowestIndex,
+            record_raw_prior:false,
+            collect_timings:false,
+            aux_target_fns:vec![],
+            eval_temperature:0.0,
+            hard_start:None,
+            base_seed:args.seed,
+            resign:None,
+            reward_fn:RewardFunction::Default,
+            max_turns:Some(DEFAULT_MAX_TURNS),
+            truncated_reward:AbortedReward::RewardFn,
+            gumbel:None,
+            mixed_settings:vec![],
+        },
+        challenger:args.challenger,
+        champion:args.champion,
+        games:args.games,
+        batch_size:args.batch_size,
+        mysql_url:db::mysql_url(&args.mysql_user, &args.mysql_host, args.mysql_port, &args.mysql_db),
+    };
+    let result = arena::run(param)?;
+    println!("{}\t{}\t{}", result.wins, result.draws, result.losses);
+    Ok(())
 }
 
 fn main() {
     let cmdline: TopLevel = argh::from_env();
 
-    match cmdline.sub_command {
+    // 環境変数CRAFT_SIM_LOG_FORMAT=jsonでJSONのログにします
+    if let Err(e) = logging::init() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let result = match cmdline.sub_command {
         SubCommand::Evaluator(x) => cmd_evaluator(x),
         SubCommand::Generator(x) => cmd_generator(x),
         SubCommand::Learner(x) => cmd_learner(x),
         SubCommand::Benchmark(x) => cmd_benchmark(x),
         SubCommand::Replay(x) => cmd_replay(x),
         SubCommand::Cui(x) => cmd_cui(x),
+        SubCommand::Actions(x) => cmd_actions(x),
+        SubCommand::Promotion(x) => cmd_promotion(x),
+        SubCommand::Arena(x) => cmd_arena(x),
+        SubCommand::SelfPlay(x) => cmd_selfplay(x),
+    };
+
+    if let Err(e) = result {
+        tracing::error!("{}", e);
+        std::process::exit(1);
     }
 }
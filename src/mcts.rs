@@ -1,16 +1,27 @@
 ﻿use std::collections::HashMap;
-use super::logic::{State,Action,Modifier,ACTION_NUM};
+use super::logic::{State,StateKey,Action,Modifier,IllegalAction,ACTION_NUM};
 use super::setting::ModifierParameter;
 use super::predictor::*;
-use num::FromPrimitive;
+use super::executor::join_all;
+use num::{FromPrimitive,ToPrimitive};
 use xorshift::{Rng,Xorshift128};
 use rand::prelude::*;
 use rand::distributions::Dirichlet;
+use std::time::{Duration,Instant};
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context,Poll,Wake,Waker};
+use serde::{Serialize,Deserialize};
+use tracing::warn;
 
 pub type ActionVector = [f32;ACTION_NUM];
 
+// アクションごとに探索の対象にするかどうかです
+pub type ActionMask = [bool;ACTION_NUM];
+
 #[allow(non_snake_case)]
 #[derive(Debug)]
+#[cfg_attr(feature="debug-snapshot", derive(serde::Serialize,serde::Deserialize))]
 struct Node
 {
     // 探索回数
@@ -21,6 +32,94 @@ struct Node
 
     // 各アクションを取ったときの、子ノードの評価値の総和
     W : ActionVector,
+
+    // バリューネットワークの値
+    V : f32,
+}
+
+// 1手あたりの探索をどこで打ち切るかです
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum SimulationBudget {
+    Fixed(u32),      // 決まった回数だけシミュレーションします
+    Timed(Duration), // 決まった時間が過ぎるまでシミュレーションします。推論の待ち時間も含みます
+}
+
+impl SimulationBudget {
+    fn is_exhausted(&self, count:u32, elapsed:Duration) -> bool {
+        match self {
+            SimulationBudget::Fixed(n) => count >= *n,
+            SimulationBudget::Timed(duration) => elapsed >= *duration,
+        }
+    }
+}
+
+// (ターン,値)の区切りをターンの昇順に並べたものから、turnで使う値を求めます。
+// 区切りのターンから次の区切りの前までその値を使い、最初の区切りより前のターンは最初の値です
+pub fn value_at_turn(breakpoints:&[(u32,f32)], turn:u32) -> f32 {
+    let first = breakpoints.first().map(|x| x.1).unwrap_or(0.0);
+    breakpoints.iter().take_while(|x| x.0 <= turn).last().map(|x| x.1).unwrap_or(first)
+}
+
+// "ターン:値"をカンマで区切って並べたものを読みます。nameはエラーの表示に使います
+pub fn parse_turn_breakpoints(value:&str, name:&str) -> std::result::Result<Vec<(u32,f32)>, String> {
+    let mut breakpoints = vec![];
+    for x in value.split(',') {
+        let (turn,v) = x.split_once(':').ok_or_else(|| format!("invalid breakpoint {}", x))?;
+        let turn = turn.trim().parse::<u32>().map_err(|e| format!("invalid turn {}: {}", turn, e))?;
+        let v = v.trim().parse::<f32>().map_err(|e| format!("invalid {} {}: {}", name, v, e))?;
+        if breakpoints.last().map(|x:&(u32,f32)| x.0 >= turn).unwrap_or(false) {
+            return Err(format!("turns must be increasing {}", value));
+        }
+        breakpoints.push((turn,v));
+    }
+    Ok(breakpoints)
+}
+
+// ターンごとのディリクレノイズのalphaです。区切りの意味はvalue_at_turnと同じです。
+// 序盤は合法手が多く、終盤は少ないので、ターンによって集中度を変えられるようにします
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct AlphaSchedule(pub Vec<(u32,f32)>);
+
+impl AlphaSchedule {
+    pub fn constant(alpha:f32) -> AlphaSchedule {
+        AlphaSchedule(vec![(0,alpha)])
+    }
+
+    pub fn alpha(&self, turn:u32) -> f32 {
+        value_at_turn(&self.0, turn)
+    }
+}
+
+impl From<f32> for AlphaSchedule {
+    fn from(alpha:f32) -> AlphaSchedule {
+        AlphaSchedule::constant(alpha)
+    }
+}
+
+// "0.15"のように値だけなら全ターン同じです。"1:0.3,10:0.15"のように区切りを並べることもできます
+impl argh::FromArgValue for AlphaSchedule {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        if !value.contains(':') {
+            return value.trim().parse::<f32>().map(AlphaSchedule::constant).map_err(|e| format!("invalid alpha {}: {}", value, e));
+        }
+        parse_turn_breakpoints(value, "alpha").map(AlphaSchedule)
+    }
+}
+
+#[test]
+fn test_alpha_schedule()
+{
+    use argh::FromArgValue;
+
+    let schedule = AlphaSchedule::from_arg_value("0.15").unwrap();
+    assert!( (1..50).all(|turn| schedule.alpha(turn) == 0.15) );
+
+    let schedule = AlphaSchedule::from_arg_value("1:0.3,10:0.15").unwrap();
+    assert_eq!( AlphaSchedule(vec![(1,0.3),(10,0.15)]), schedule );
+    assert_eq!( 0.3, schedule.alpha(9) );
+    assert_eq!( 0.15, schedule.alpha(10) );
+    assert!( AlphaSchedule::from_arg_value("10:0.3,5:0.1").is_err() );
+    assert!( AlphaSchedule::from_arg_value("x").is_err() );
 }
 
 pub struct MCTSContext
@@ -28,7 +127,8 @@ pub struct MCTSContext
     // ディリクレノイズの為のパラメータ。
     // ディリクレノイズはこの投稿を参考
     // https://tadaoyamaoka.hatenablog.com/entry/2017/12/10/230549
-    alpha: f32,
+    // ルートの状態のターンで値を選びます
+    alpha: AlphaSchedule,
 
     // ディリクレノイズの割合のパラメータ。
     // 1に近づくほどノイズの割合が大きくなります。0の時はノイズなしで探索されます。
@@ -37,47 +137,189 @@ pub struct MCTSContext
     // UCTの定数
     c_puct: f32,
 
+    // 終了していないのに合法手が無い状態の報酬。ロジックのバグでしか起きないはずです
+    no_legal_action_reward: f32,
+
     // ノード一覧
-    nodes: HashMap<State,Node>,
+    nodes: HashMap<StateKey,Node>,
 
     // 予測システム
     predict_queue: PredictQueue,
 
     // 本コンテキストでキューに渡すグラフ名
     graph_filename: String,
+
+    // 推論の優先度
+    priority: Priority,
+
+    // 直前に探索したルートの、ノイズを加える前のポリシーネットワークの値
+    raw_prior: Option<(State,ActionVector)>,
+
+    // 仮想損失。探索中のパスに評価値0の訪問をこの回数だけ仮に足しておき、
+    // 同時に走っている別のシミュレーションが同じ手に集まらないようにします。0の時は何もしません。
+    // 足した分は後で引くので、訪問回数がずれないように整数にしています
+    virtual_loss: u32,
+
+    // 1回に葉まで降りて推論をまとめて待つシミュレーションの数です。1の時は1つずつ進めます
+    parallel_simulations: u32,
+
+    // 終端に着いた時の報酬の計算方法
+    reward_fn: RewardFunction,
+    root_noise: bool, // falseの場合はepsに関わらずルートにノイズを加えません
+    setting: Option<&'static str>, // 推論で状態を符号化する設定の名前です。Noneの場合は推論する側の設定を使います
 }
 
-enum SearchResult {
+// search_blockingでは毎回pollし直すので、起こされたことを覚えておく必要はありません
+struct NoopWake;
+
+impl Wake for NoopWake {
+    fn wake(self: Arc<Self>) {}
+}
+
+enum LeafResult {
     Expand(State), // 途中の場合
     Reward(f32),   // 報酬がもらえる場合
 }
 
+// アクションごとの探索結果です。
+// 推奨手の理由をUIで説明するために使います(「アクションX: 訪問62%, Q=0.81, 事前確率0.4」など)
+#[allow(dead_code)]
+#[derive(Debug,Clone)]
+pub struct ActionDetail {
+    pub action : Action,
+    pub visit_count : f32,  // 探索回数
+    pub mean_value : f32,   // 子ノードの平均評価値。未探索の場合は0です
+    pub prior : f32,        // ポリシーネットワークの値(ルートのディリクレノイズ込み)
+    pub next_state : State, // アクションを実行した結果の一例。乱数が絡むので必ずこの状態になるとは限りません
+}
+
+#[derive(Debug,Clone)]
+pub struct SearchResult {
+    pub policy : ActionVector,      // searchが返す方策と同じものです
+    pub actions : Vec<ActionDetail>, // 合法手だけ入ります
+    #[allow(dead_code)]
+    pub masked_actions : Vec<(Action,IllegalAction)>, // 探索の対象から外したアクションとその理由です。デバッグ用です
+}
+
 impl State {
     // 現実にあり得ないパターンを除外します。
     // 初手インナークワイエット使うくらいなら真価を使うとか、そういう基本的な手だけ対策します。
     // あと「作業で辿りつける場合は最終確認は無効」とかも削ってよいかもしれません。
     fn check_action_ex(&self, a:&Action) -> bool {
+        self.illegal_reason_ex(a).is_none()
+    }
+
+    // check_action_exで除外される理由を返します
+    fn illegal_reason_ex(&self, a:&Action) -> Option<IllegalAction> {
         if self.turn == 1 {
             // 1ターン目は確信か真価に限定します。
             // 流石にこれ以外のスタートパターンは現実的に存在しないため、これだけは無視します
             match a {
-                Action::MuscleMemory => true,
-                Action::Reflect => true,
-                _ => false,
+                Action::MuscleMemory => self.illegal_reason(a),
+                Action::Reflect => self.illegal_reason(a),
+                _ => Some(IllegalAction::PrunedOpening),
             }
         }
         else if self.final_appraisal > 0 && *a == Action::FinalAppraisal {
             // もし最終確認が有効な場合、最終確認を新たに使うことはあり得ません。これは何の役にも立たずCPだけを消費します。
-            false
+            Some(IllegalAction::PrunedFinalAppraisal)
         }
         else if self.working < 5000 && *a == Action::FinalAppraisal {
             // もし１手で完成に辿りつけない作業工数である場合、最終確認を使うことはあり得ません。次のターンで使えば良いためです。
-            false
+            Some(IllegalAction::PrunedFinalAppraisal)
         }
         else {
-            self.check_action(a)
+            self.illegal_reason(a)
         }
     }
+
+    // 探索で除外されるアクションとその理由を全て返します
+    fn masked_actions(&self) -> Vec<(Action,IllegalAction)> {
+        (0..ACTION_NUM).map(|a| Action::from_usize(a).unwrap())
+            .filter_map(|a| self.illegal_reason_ex(&a).map(|reason| (a,reason)))
+            .collect()
+    }
+
+    pub fn has_valid_action_ex(&self) -> bool {
+        (0..ACTION_NUM).any(|a| self.check_action_ex(&Action::from_usize(a).unwrap()))
+    }
+
+    // 探索で選べるアクションをtrueにしたものです。除外の基準はcheck_action_exと同じです
+    pub fn legal_action_mask(&self) -> ActionMask {
+        let mut mask = [false;ACTION_NUM];
+        for (a,x) in mask.iter_mut().enumerate() {
+            *x = self.check_action_ex(&Action::from_usize(a).unwrap());
+        }
+        mask
+    }
+}
+
+// maskで除外したアクションを0にして、総和が1になるように正規化します。
+// 残ったアクションに確率が全く無い場合は一様にします。選べるアクションが無ければ全て0です
+pub fn mask_policy(v:&ActionVector, mask:&ActionMask) -> ActionVector {
+    let mut r = [0.0;ACTION_NUM];
+    for a in 0..ACTION_NUM {
+        if mask[a] {
+            r[a] = v[a];
+        }
+    }
+
+    let sum : f32 = r.iter().sum();
+    if sum > 0.0 {
+        r.iter_mut().for_each(|x| *x /= sum);
+    }
+    else {
+        let legal_num = mask.iter().filter(|x| **x).count();
+        for a in 0..ACTION_NUM {
+            if mask[a] {
+                r[a] = 1.0 / legal_num as f32;
+            }
+        }
+    }
+    r
+}
+
+#[test]
+fn test_mask_policy()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mask = State::new(&mod_param).legal_action_mask();
+    let (reflect,muscle_memory) = (Action::Reflect.to_usize().unwrap(), Action::MuscleMemory.to_usize().unwrap());
+    assert_eq!( 2, mask.iter().filter(|x| **x).count() );
+    assert!( mask[reflect] && mask[muscle_memory] );
+
+    // 除外したアクションの分を残りに配り直します
+    let mut v = [0.0;ACTION_NUM];
+    v[reflect] = 0.2;
+    v[muscle_memory] = 0.2;
+    v[Action::BasicSynthesis.to_usize().unwrap()] = 0.6;
+    let masked = mask_policy(&v, &mask);
+    assert_eq!( 0.5, masked[reflect] );
+    assert_eq!( 0.5, masked[muscle_memory] );
+    assert_eq!( 1.0, masked.iter().sum::<f32>() );
+
+    // 除外したアクションにしか確率が無ければ一様にします
+    let mut v = [0.0;ACTION_NUM];
+    v[Action::BasicSynthesis.to_usize().unwrap()] = 1.0;
+    assert_eq!( 0.5, mask_policy(&v, &mask)[reflect] );
+    assert_eq!( [0.0;ACTION_NUM], mask_policy(&v, &[false;ACTION_NUM]) );
+}
+
+#[test]
+fn test_masked_actions()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let reason = |s:&State, a:Action| s.masked_actions().iter().find(|(x,_)| *x == a).map(|(_,reason)| *reason);
+
+    let s = State { turn:2, cp:10, ..State::new(&mod_param) };
+    assert_eq!( Some(IllegalAction::NotEnoughCp), reason(&s, Action::BasicTouch) );
+    assert_eq!( Some(IllegalAction::FirstTurnOnly), reason(&s, Action::MuscleMemory) );
+    assert_eq!( Some(IllegalAction::PrunedFinalAppraisal), reason(&s, Action::FinalAppraisal) );
+    assert_eq!( None, reason(&s, Action::BasicSynthesis) );
+
+    let s = State::new(&mod_param);
+    assert_eq!( Some(IllegalAction::PrunedOpening), reason(&s, Action::BasicSynthesis) );
+    assert_eq!( None, reason(&s, Action::Reflect) );
 }
 
 #[allow(non_snake_case)]
@@ -115,6 +357,8 @@ fn lerp_clip( a:f32, b:f32, c:f32 ) -> f32 {
 }
 
 // 報酬関数です。
+// 終了状態の報酬として使うものです。終わっていない状態にも品質と時間からそのまま計算した値を返しますが、
+// そこから先の見込みは含まないので報酬としての意味はありません。投了や打ち切りの報酬はselfplayのAbortedRewardで決めます
 pub fn get_reward(s:&State,mod_param:&ModifierParameter) -> f32 {
     if s.is_destroyed() {
         0.0
@@ -137,6 +381,57 @@ pub fn get_reward(s:&State,mod_param:&ModifierParameter) -> f32 {
     }
 }
 
+// 終了状態の報酬の計算方法です。実験ごとに報酬の付け方を変えられるようにしておきます
+pub trait Reward {
+    fn reward(&self, s:&State, mod_param:&ModifierParameter) -> f32;
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum RewardFunction {
+    Default,   // get_rewardと同じです
+    Quality,   // 品質を品質上限で割った値[0,1]だけを使います
+    Threshold, // 品質がbonus_thresholdに届いていれば1、そうでなければ0です
+}
+
+impl Reward for RewardFunction {
+    fn reward(&self, s:&State, mod_param:&ModifierParameter) -> f32 {
+        if s.is_destroyed() {
+            return 0.0;
+        }
+        match self {
+            RewardFunction::Default => get_reward(s, mod_param),
+            RewardFunction::Quality => s.quality as f32 / mod_param.max_quality as f32,
+            RewardFunction::Threshold => if s.quality >= mod_param.bonus_threshold { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+impl argh::FromArgValue for RewardFunction {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "default" => Ok(RewardFunction::Default),
+            "quality" => Ok(RewardFunction::Quality),
+            "threshold" => Ok(RewardFunction::Threshold),
+            _ => Err(format!("unknown reward function {}", value)),
+        }
+    }
+}
+
+#[test]
+fn test_reward_function()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let finished = |quality| State { working:mod_param.max_working, quality, completed:true, ..State::new(&mod_param) };
+    let low = finished(mod_param.max_quality / 10);
+    let high = finished(mod_param.bonus_threshold);
+    assert!( low.is_terminated() && high.is_terminated() );
+
+    for reward_fn in [RewardFunction::Default, RewardFunction::Quality, RewardFunction::Threshold] {
+        assert!( reward_fn.reward(&high, &mod_param) > reward_fn.reward(&low, &mod_param), "{:?}", reward_fn );
+    }
+    assert_eq!( get_reward(&high, &mod_param), RewardFunction::Default.reward(&high, &mod_param) );
+}
+
 fn select_max_indices(mcts_policy:&ActionVector) -> Vec<usize> {
     // Rustでf32やf64の配列の最大値を得る方法
     // https://qiita.com/lo48576/items/343ca40a03c3b86b67cb
@@ -155,14 +450,80 @@ fn test_select_max_indices()
     assert_eq!( vec![0,15], select_max_indices(&mcts_policy) );
 }
 
-fn choose_max_index(mcts_policy:&ActionVector, rng:&mut Xorshift128) -> usize {
+// 確率の高い順にk個のアクションを返します。
+// 同じ確率の場合はインデックスの小さいほうが先です
+#[allow(dead_code)]
+pub fn top_k_actions(v:&ActionVector, k:usize) -> Vec<(Action,f32)> {
+    let mut indices : Vec<usize> = (0..ACTION_NUM).collect();
+    indices.sort_by(|a,b| v[*b].partial_cmp(&v[*a]).unwrap_or(std::cmp::Ordering::Equal));
+    indices.into_iter().take(k).map(|a| (Action::from_usize(a).unwrap(), v[a])).collect()
+}
+
+#[test]
+fn test_top_k_actions()
+{
+    let mut v = [0.0;ACTION_NUM];
+    v[3] = 0.2;
+    v[7] = 0.5;
+    v[1] = 0.2;
+    v[20] = 0.1;
+
+    let expected = vec![
+        (Action::from_usize(7).unwrap(),0.5),
+        (Action::from_usize(1).unwrap(),0.2),
+        (Action::from_usize(3).unwrap(),0.2),
+        (Action::from_usize(20).unwrap(),0.1),
+    ];
+    assert_eq!( expected, top_k_actions(&v, 4) );
+    assert_eq!( expected[..2], top_k_actions(&v, 2)[..] );
+    assert_eq!( ACTION_NUM, top_k_actions(&v, 100).len() );
+}
+
+// 最大値が複数ある場合にどれを選ぶかです
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum TieBreak {
+    Random,      // 乱数で選びます。学習用のセルフプレイで手順が偏らないようにします
+    LowestIndex, // インデックスが最小のものを選びます。乱数を使わないので評価を再現できます
+}
+
+fn choose_max_index(mcts_policy:&ActionVector, tie_break:TieBreak, rng:&mut Xorshift128) -> usize {
     let indices = select_max_indices(&mcts_policy);
-    *rng.choose(&indices).unwrap()
+    match tie_break {
+        TieBreak::Random => *rng.choose(&indices).unwrap(),
+        TieBreak::LowestIndex => indices[0],
+    }
+}
+
+#[test]
+fn test_choose_max_index_lowest_index()
+{
+    use xorshift::SeedableRng;
+
+    let mut mcts_policy = [0.0;ACTION_NUM];
+    mcts_policy[3] = 0.5;
+    mcts_policy[7] = 0.5;
+
+    // 乱数の状態に関わらず、同点なら常に同じ手を選びます
+    let runs : Vec<Vec<Action>> = (1..=3).map(|seed| {
+        let seeds = [seed, seed];
+        let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+        (0..20).map(|_| select_action_greedy(&mcts_policy, &[true;ACTION_NUM], TieBreak::LowestIndex, &mut rng)).collect()
+    }).collect();
+    assert!( runs.iter().flatten().all(|x| *x == Action::from_usize(3).unwrap()) );
+
+    // 乱数で選ぶ場合は両方出ます
+    let seeds = [1, 2];
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+    let actions : Vec<Action> = (0..20).map(|_| select_action_greedy(&mcts_policy, &[true;ACTION_NUM], TieBreak::Random, &mut rng)).collect();
+    assert!( actions.contains(&Action::from_usize(3).unwrap()) );
+    assert!( actions.contains(&Action::from_usize(7).unwrap()) );
 }
 
 // ノードの選択確率の通りに選択します。
-// mtct_policyは総和が1.0である必要があります。
-pub fn select_action_weighted(mcts_policy:&ActionVector, rng:&mut Xorshift128) -> Action {
+// maskで除外したアクションは選ばないように、除外してから正規化し直して使います
+pub fn select_action_weighted(mcts_policy:&ActionVector, mask:&ActionMask, rng:&mut Xorshift128) -> Action {
+    let mcts_policy = mask_policy(mcts_policy, mask);
+
     // 数値誤差により全アクションの確率を総和してもランダム値がどのアクションにも該当しない場合があります。
     // 失敗した場合はもう一度選択します
     loop {
@@ -178,29 +539,255 @@ pub fn select_action_weighted(mcts_policy:&ActionVector, rng:&mut Xorshift128) -
     }
 }
 
-// greedy(一番よいやつ)を選択します
-pub fn select_action_greedy(mcts_policy:&ActionVector, rng:&mut Xorshift128) -> Action {
-    Action::from_usize( choose_max_index(&mcts_policy, rng) ).unwrap()
+// greedy(一番よいやつ)を選択します。maskで除外したアクションは選びません
+pub fn select_action_greedy(mcts_policy:&ActionVector, mask:&ActionMask, tie_break:TieBreak, rng:&mut Xorshift128) -> Action {
+    Action::from_usize( choose_max_index(&mask_policy(mcts_policy, mask), tie_break, rng) ).unwrap()
+}
+
+// 方策を温度で鋭くしてから重みづけで選択します。temperatureが0以下ならgreedyと同じです。
+// 小さな温度であればほぼgreedyですが、確率の近いアクションの間では時々別の手を選びます
+pub fn select_action_with_temperature(mcts_policy:&ActionVector, mask:&ActionMask, temperature:f32, tie_break:TieBreak, rng:&mut Xorshift128) -> Action {
+    if temperature <= 0.0 {
+        return select_action_greedy(mcts_policy, mask, tie_break, rng);
+    }
+    let mcts_policy = mask_policy(mcts_policy, mask);
+
+    // 最大値で割ってから累乗して、小さな温度でもアンダーフローしないようにします
+    let max_value = mcts_policy.iter().fold(f32::NEG_INFINITY, |m, v| v.max(m));
+    let mut sharpened = [0.0;ACTION_NUM];
+    for i in 0..ACTION_NUM {
+        sharpened[i] = (mcts_policy[i] / max_value).powf(1.0 / temperature);
+    }
+    let sum : f32 = sharpened.iter().sum();
+    for x in sharpened.iter_mut() {
+        *x /= sum;
+    }
+    select_action_weighted(&sharpened, mask, rng)
+}
+
+#[test]
+fn test_select_action_with_temperature()
+{
+    use xorshift::SeedableRng;
+
+    let seeds = [1, 2];
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+    let mut mcts_policy = [0.0;ACTION_NUM];
+    mcts_policy[2] = 0.51;
+    mcts_policy[5] = 0.49;
+    let first = Action::from_usize(2).unwrap();
+
+    // 温度が0なら同じモデル同士は常に同じ手になります
+    assert!( (0..100).all(|_| select_action_with_temperature(&mcts_policy, &[true;ACTION_NUM], 0.0, TieBreak::Random, &mut rng) == first) );
+
+    // 小さな温度なら、確率の近い手は時々入れ替わりますが、確率0の手は選びません
+    let actions : Vec<Action> = (0..100).map(|_| select_action_with_temperature(&mcts_policy, &[true;ACTION_NUM], 0.05, TieBreak::Random, &mut rng)).collect();
+    assert!( actions.contains(&first) );
+    assert!( actions.contains(&Action::from_usize(5).unwrap()) );
+    assert!( actions.iter().all(|x| *x == first || *x == Action::from_usize(5).unwrap()) );
+}
+
+// greedyで何を最大にするかです
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum GreedyCriterion {
+    Visits, // 探索回数
+    Q,      // 平均評価値。探索が短い場合はこちらの方が強いことがあります
+}
+
+// 平均評価値が最大のアクションを選択します。
+// 未探索のアクションは評価値が分からないので対象外にします
+pub fn select_action_max_q(search_result:&SearchResult, tie_break:TieBreak, rng:&mut Xorshift128) -> Action {
+    let mut q = [f32::NEG_INFINITY;ACTION_NUM];
+    for x in search_result.actions.iter().filter(|x| x.visit_count > 0.0) {
+        q[x.action.to_usize().unwrap()] = x.mean_value;
+    }
+
+    // 全て未探索の場合は探索回数で選びます。actionsには合法手だけが入っています
+    if q.iter().all(|x| *x == f32::NEG_INFINITY) {
+        let mut mask = [false;ACTION_NUM];
+        for x in &search_result.actions {
+            mask[x.action.to_usize().unwrap()] = true;
+        }
+        select_action_greedy(&search_result.policy, &mask, tie_break, rng)
+    }
+    else {
+        Action::from_usize( choose_max_index(&q, tie_break, rng) ).unwrap()
+    }
+}
+
+#[test]
+fn test_select_action_max_q()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let detail = |action:Action, visit_count:f32, mean_value:f32| ActionDetail {
+        action, visit_count, mean_value, prior:0.0, next_state:State::new(&mod_param)
+    };
+
+    // 探索回数はReflectが最大、平均評価値はMuscleMemoryが最大です
+    let mut policy = [0.0;ACTION_NUM];
+    policy[Action::Reflect.to_usize().unwrap()] = 0.9;
+    policy[Action::MuscleMemory.to_usize().unwrap()] = 0.1;
+    let search_result = SearchResult {
+        policy,
+        actions : vec![detail(Action::Reflect, 90.0, 0.6), detail(Action::MuscleMemory, 10.0, 0.8), detail(Action::BasicTouch, 0.0, 0.0)],
+        masked_actions : vec![],
+    };
+
+    let seeds = [1, 2];
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+    assert_eq!( Action::Reflect, select_action_greedy(&search_result.policy, &[true;ACTION_NUM], TieBreak::Random, &mut rng) );
+    assert_eq!( Action::MuscleMemory, select_action_max_q(&search_result, TieBreak::Random, &mut rng) );
+}
+
+// Gumbel AlphaZeroで手を選ぶ時の設定です。
+// 事前確率にGumbelノイズを加えて候補を絞り、探索回数を候補に均等に配りながら半分ずつ落としていきます(sequential halving)。
+// 探索回数が少なくても方策が改善されるので、訪問回数を数える方法より少ない予算で使えます
+// https://openreview.net/forum?id=bERaNdoegnO
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub struct GumbelParameter {
+    pub max_considered_actions : usize, // 最初に候補にする手の数です
+    pub c_visit : f32, // 評価値を事前確率のlogitと足せる大きさに変換する係数です。論文の既定値は50です
+    pub c_scale : f32, // 同じく論文の既定値は1です
+}
+
+impl Default for GumbelParameter {
+    fn default() -> GumbelParameter {
+        GumbelParameter { max_considered_actions:16, c_visit:50.0, c_scale:1.0 }
+    }
+}
+
+impl GumbelParameter {
+    // 評価値をlogitに足す大きさに変換します。報酬は[0,1]に収まっているので正規化はしません
+    fn sigma(&self, q:f32, max_visits:f32) -> f32 {
+        (self.c_visit + max_visits) * self.c_scale * q
+    }
+}
+
+// 未探索の手の評価値を補った、手ごとの評価値です。
+// 未探索の手には、バリューネットワークの値と探索済みの手の評価値を事前確率で重み付けして混ぜたものを使います
+#[allow(non_snake_case)]
+fn completed_q(node:&Node, mask:&ActionMask) -> ActionVector {
+    let sum_N : f32 = node.N.iter().sum();
+    let (visited_p,visited_pq) = (0..ACTION_NUM).filter(|a| mask[*a] && node.N[*a] > 0.0)
+        .fold((0.0,0.0), |(p,pq),a| (p + node.P[a], pq + node.P[a] * node.W[a] / node.N[a]));
+    let v_mix = if visited_p > 0.0 { (node.V + sum_N * visited_pq / visited_p) / (1.0 + sum_N) } else { node.V };
+
+    let mut q = [0.0;ACTION_NUM];
+    for a in (0..ACTION_NUM).filter(|a| mask[*a]) {
+        q[a] = if node.N[a] > 0.0 { node.W[a] / node.N[a] } else { v_mix };
+    }
+    q
+}
+
+// 事前確率のlogitに変換後の評価値を足してsoftmaxしたものです。サンプルの方策の学習目標にします。
+// 非合法手は0です
+fn gumbel_improved_policy(node:&Node, mask:&ActionMask, param:&GumbelParameter) -> ActionVector {
+    let q = completed_q(node, mask);
+    let max_visits = node.N.iter().cloned().fold(0.0, f32::max);
+    let mut logits = [f32::NEG_INFINITY;ACTION_NUM];
+    for a in (0..ACTION_NUM).filter(|a| mask[*a]) {
+        logits[a] = node.P[a].ln() + param.sigma(q[a], max_visits);
+    }
+
+    let max_logit = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let mut policy = [0.0;ACTION_NUM];
+    for a in (0..ACTION_NUM).filter(|a| logits[*a] > f32::NEG_INFINITY) {
+        policy[a] = (logits[a] - max_logit).exp();
+    }
+    let sum : f32 = policy.iter().sum();
+    policy.iter_mut().for_each(|x| *x /= sum);
+    policy
+}
+
+#[test]
+fn test_gumbel_improved_policy()
+{
+    let mut mask = [false;ACTION_NUM];
+    mask[0] = true;
+    mask[1] = true;
+    mask[2] = true;
+    let mut node = Node { N:[0.0;ACTION_NUM], P:[0.0;ACTION_NUM], W:[0.0;ACTION_NUM], V:0.5 };
+    node.P[0] = 0.5;
+    node.P[1] = 0.25;
+    node.P[2] = 0.25;
+
+    // 未探索なら事前確率のままです
+    let param = GumbelParameter::default();
+    let policy = gumbel_improved_policy(&node, &mask, &param);
+    assert!( (policy[0] - 0.5).abs() < 1e-6 && (policy[1] - 0.25).abs() < 1e-6 );
+
+    // 評価値の高い手に寄り、未探索の手は探索済みの手とバリューの混ぜた値で補われます
+    node.N[1] = 2.0;
+    node.W[1] = 1.8;
+    node.N[0] = 1.0;
+    node.W[0] = 0.2;
+    let q = completed_q(&node, &mask);
+    assert!( (q[2] - (0.5 + 3.0 * (0.5*0.2 + 0.25*0.9) / 0.75) / 4.0).abs() < 1e-6 );
+    let policy = gumbel_improved_policy(&node, &mask, &param);
+    assert!( policy[1] > 0.99 );
+    assert_eq!( 0.0, policy[3] );
+    assert!( (policy.iter().sum::<f32>() - 1.0).abs() < 1e-5 );
 }
 
 impl MCTSContext {
 
-    pub fn new( c_puct:f32, alpha:f32, eps:f32, predict_queue:PredictQueue, graph_filename:String ) -> MCTSContext {
+    pub fn new<A:Into<AlphaSchedule>>( c_puct:f32, alpha:A, eps:f32, no_legal_action_reward:f32, predict_queue:PredictQueue, graph_filename:String ) -> MCTSContext {
         MCTSContext {
             c_puct: c_puct,
-            alpha: alpha,
+            no_legal_action_reward,
+            alpha: alpha.into(),
             eps: eps,
             nodes: HashMap::new(),
             predict_queue: predict_queue,
             graph_filename: graph_filename,
+            priority: Priority::Normal,
+            raw_prior: None,
+            virtual_loss: 0,
+            parallel_simulations: 1,
+            reward_fn: RewardFunction::Default,
+            root_noise: true,
+            setting: None,
         }
     }
 
+    pub fn set_priority(&mut self, priority:Priority) {
+        self.priority = priority;
+    }
+
+    pub fn set_reward_fn(&mut self, reward_fn:RewardFunction) {
+        self.reward_fn = reward_fn;
+    }
+
+    pub fn set_root_noise(&mut self, root_noise:bool) {
+        self.root_noise = root_noise;
+    }
+
+    pub fn set_setting(&mut self, setting:Option<&'static str>) {
+        self.setting = setting;
+    }
+
+    pub fn set_virtual_loss(&mut self, virtual_loss:u32) {
+        self.virtual_loss = virtual_loss;
+    }
+
+    pub fn set_parallel_simulations(&mut self, parallel_simulations:u32) {
+        self.parallel_simulations = parallel_simulations.max(1);
+    }
+
+    // sをルートとして探索する時のディリクレノイズのalphaです
+    fn root_alpha(&self, s:&State) -> f32 {
+        self.alpha.alpha(s.turn)
+    }
+
     #[allow(non_snake_case)]
-    fn add_dirichlet_noise(&mut self, s:&State, _modifier:&mut Modifier) {
+    fn add_dirichlet_noise(&mut self, s:&State, modifier:&mut Modifier) {
         if self.eps > 0.0 {
+            let alpha = self.root_alpha(s);
+
             // ノードを探し出します。expandしてますので絶対に成功します。
-            let mut node = self.nodes.get_mut(s).unwrap();
+            let node = self.nodes.get_mut(&s.canonical_key()).unwrap();
 
             // ディリクレノイズを計算します。
             // まず合法手のインデックスだけ求めます
@@ -212,9 +799,10 @@ impl MCTSContext {
                 }
             }
 
-            // ディリクレ分布を求めます
-            let dirichlet = Dirichlet::new_with_param(self.alpha as f64, valid_actions.len());
-            let samples = dirichlet.sample(&mut rand::thread_rng()); // TODO: Xorshiftが使えなかった
+            // ディリクレ分布を求めます。
+            // randの分布はXorshiftを直接使えないので、modifierの乱数から種を作ったrandの乱数で引きます
+            let dirichlet = Dirichlet::new_with_param(alpha as f64, valid_actions.len());
+            let samples = dirichlet.sample(&mut StdRng::seed_from_u64(modifier.rng.next_u64()));
 
             // ノイズを対象インデックスに足す
             for i in 0..valid_actions.len() {
@@ -224,86 +812,339 @@ impl MCTSContext {
     }
 
     // 現在の地点から葉までノードを探索します。
-    fn search_leaf(&self, start:&State, modifier:&mut Modifier) -> (Vec<(State,usize)>,SearchResult) {
+    // 降りていったパスには仮想損失を足します。add_valueで本当の評価値を足す時に取り除きます
+    fn search_leaf(&mut self, start:&State, modifier:&mut Modifier) -> (Vec<(State,usize)>,LeafResult) {
         let mut s = start.clone();
         let mut path = vec!{};
         loop {
             if s.is_terminated() {
-                return (path,SearchResult::Reward(get_reward(&s,&modifier.mod_param)));
+                return (path,LeafResult::Reward(self.reward_fn.reward(&s,&modifier.mod_param)));
             }
-            else if let Some(node) = self.nodes.get(&s) {
+            else if let Some(node) = self.nodes.get_mut(&s.canonical_key()) {
                 let scores = get_scores(self.c_puct, &s, node);
-                let a = choose_max_index(&scores, &mut modifier.rng);
+
+                // 合法手が無い場合は終端として扱います
+                if scores.iter().all(|x| *x == f32::NEG_INFINITY) {
+                    return (path,LeafResult::Reward(self.no_legal_action_reward));
+                }
+
+                let a = choose_max_index(&scores, TieBreak::Random, &mut modifier.rng);
+                node.N[a] += self.virtual_loss as f32;
                 let ns = s.run_action(modifier, &Action::from_usize(a).unwrap());
                 path.push((s,a));
                 s = ns
             }
             else {
-                return (path,SearchResult::Expand(s));
+                return (path,LeafResult::Expand(s));
             }
         }
     }
 
     // ノードを展開します。
-    fn expand(&mut self, s:State, nn_policy:ActionVector) {
+    // ネットワークが非合法手に付けた確率は探索で使われず無駄になるので、除外して合法手だけで正規化し直します
+    fn expand(&mut self, s:State, nn_policy:ActionVector, nn_value:f32) {
+        let nn_policy = mask_policy(&nn_policy, &s.legal_action_mask());
         // insert関数はOption<V>で元の値を返しますが、expandの時点では元のノードが存在しないため、常にNoneが帰ります
-        self.nodes.insert(s, Node {
+        self.nodes.insert(s.canonical_key(), Node {
             N: [0.0;ACTION_NUM],
             W: [0.0;ACTION_NUM],
             P: nn_policy,
+            V: nn_value,
         });
     }
 
-    // 評価値を足します。
+    // 評価値を足します。search_leafで足した仮想損失はここで取り除きます
     fn add_value(&mut self, path:&Vec<(State,usize)>, v:f32) {
         for (s,a) in path {
-            let node = self.nodes.get_mut(s).unwrap();
+            let node = self.nodes.get_mut(&s.canonical_key()).unwrap();
             node.W[*a] += v;
-            node.N[*a] += 1.0;
+            node.N[*a] += 1.0 - self.virtual_loss as f32;
         }
     }
 
     async fn run_simulation(&mut self, start:&State, modifier:&mut Modifier) {
-        let ret = self.search_leaf(start,modifier);
-        match ret {
-            (path,SearchResult::Expand(leaf)) => {
-                let (nn_policy,nn_value) = self.predict_queue.async_predict(self.graph_filename.clone(), leaf.clone()).await;
-                self.expand(leaf,nn_policy);
+        let (path,leaf) = self.search_leaf(start,modifier);
+        self.evaluate_leaf(path,leaf).await;
+    }
+
+    // n個のシミュレーションを先に全て葉まで降ろして、葉の推論をまとめて待ちます。
+    // 先に降りたパスには仮想損失が残っているので、後から降りるシミュレーションは別の手を選びやすくなります
+    async fn run_simulations(&mut self, start:&State, modifier:&mut Modifier, n:u32) {
+        if n <= 1 {
+            return self.run_simulation(start, modifier).await;
+        }
+
+        let leaves : Vec<(Vec<(State,usize)>,LeafResult)> = (0..n).map(|_| self.search_leaf(start, modifier)).collect();
+        let queue = self.predict_queue.clone();
+        let futures : Vec<_> = leaves.iter().map(|(_,leaf)| {
+            let (queue,name,setting,priority) = (&queue,self.graph_filename.clone(),self.setting,self.priority);
+            let leaf = match leaf {
+                LeafResult::Expand(leaf) => Some(leaf.clone()),
+                LeafResult::Reward(_) => None,
+            };
+            async move {
+                match leaf {
+                    Some(leaf) => Some(queue.async_predict_with_setting(name, setting, leaf, priority).await),
+                    None => None,
+                }
+            }
+        }).collect();
+        let predictions = join_all(futures).await;
+
+        for ((path,leaf),prediction) in leaves.into_iter().zip(predictions) {
+            match (leaf,prediction) {
+                (LeafResult::Expand(leaf),Some((nn_policy,nn_value))) => {
+                    // 同じ葉に降りたシミュレーションがあれば、先に展開したものをそのまま使います
+                    if !self.nodes.contains_key(&leaf.canonical_key()) {
+                        self.expand(leaf,nn_policy,nn_value);
+                    }
+                    self.add_value(&path,nn_value);
+                },
+                (LeafResult::Reward(reward),_) => self.add_value(&path,reward),
+                (LeafResult::Expand(_),None) => unreachable!(),
+            }
+        }
+    }
+
+    // ルートの手をaに決めてシミュレーションします。2手目からはrun_simulationと同じです
+    async fn run_simulation_from(&mut self, start:&State, a:usize, modifier:&mut Modifier) {
+        self.nodes.get_mut(&start.canonical_key()).unwrap().N[a] += self.virtual_loss as f32;
+        let next = start.run_action(modifier, &Action::from_usize(a).unwrap());
+        let (mut path,leaf) = self.search_leaf(&next,modifier);
+        path.insert(0, (start.clone(),a));
+        self.evaluate_leaf(path,leaf).await;
+    }
+
+    async fn evaluate_leaf(&mut self, path:Vec<(State,usize)>, leaf:LeafResult) {
+        match leaf {
+            LeafResult::Expand(leaf) => {
+                let (nn_policy,nn_value) = self.predict_queue.async_predict_with_setting(self.graph_filename.clone(), self.setting, leaf.clone(), self.priority).await;
+                self.expand(leaf,nn_policy,nn_value);
                 self.add_value(&path,nn_value);
             },
-            (path,SearchResult::Reward(reward)) => {
+            LeafResult::Reward(reward) => {
                 self.add_value(&path,reward);
             },
         }
     }
 
+    // ルートが未展開なら推論して展開します
+    // 前の手番の探索で展開済みの子ノードがルートになった場合は、残っている方策と評価値をそのまま使い、推論し直しません。
+    // 木を引き継いでいるので1手ごとに1回の推論が省けます
+    async fn expand_root(&mut self, s:&State) {
+        if !self.nodes.contains_key( &s.canonical_key() ) {
+            let (nn_policy,nn_value) = self.predict_queue.async_predict_with_setting(self.graph_filename.clone(), self.setting, s.clone(), self.priority).await;
+            self.expand( s.clone(), nn_policy, nn_value );
+        }
+    }
+
     // 現在の状態に絶対に辿りつけないノードを除去します。
     //
     // 設計変更や最終確認が同一ターンで別状態となるため同一ターンは維持しています。
     // 上記ルールも判定したうえで消せばメモリ効率が上がりますが、そこまで切り詰める必要もないので、このルールで保留しています
     fn remove_unused_nodes(&mut self, root_state:&State ) {
-        self.nodes.retain(|s,_| s.turn >= root_state.turn)
+        self.nodes.retain(|k,_| k.turn() >= root_state.turn)
     }
 
-    pub async fn search(&mut self, s:&State, modifier:&mut Modifier, num_simulations:u32) -> ActionVector {
+    // budgetに関わらず、少なくともmin_simulations回はシミュレーションしてから方策を決めます。
+    // シミュレーション中の手の実行や同点の判定でmodifierの乱数を進めるので、環境とは別の乱数を渡してください
+    pub async fn search(&mut self, s:&State, modifier:&mut Modifier, budget:&SimulationBudget, min_simulations:u32) -> ActionVector {
 
         self.remove_unused_nodes(s);
+        self.expand_root(s).await;
 
-        if !self.nodes.contains_key( s ) {
-            let (nn_policy,_) = self.predict_queue.async_predict(self.graph_filename.clone(), s.clone()).await;
-            self.expand( s.clone(), nn_policy );
+        // 合法手が無い場合は探索できないので空の方策を返します。
+        // 呼び出し側で事前に確認しておくべきなので警告を出しておきます
+        if !s.has_valid_action_ex() {
+            warn!(turn = s.turn, "warning: no legal action in search {:?}", s);
+            return [0.0;ACTION_NUM];
         }
 
         // 初手の場合だけディリクレノイズを加えます。
-        self.add_dirichlet_noise(s, modifier);
+        // ノイズでどれだけ手が変わったかを調べられるように、加える前の値を残しておきます
+        self.raw_prior = Some((s.clone(), self.nodes.get(&s.canonical_key()).unwrap().P));
+        if self.root_noise {
+            self.add_dirichlet_noise(s, modifier);
+        }
 
-        // シミュレーションを規定回数実行します
-        for _ in 0..num_simulations {
-            self.run_simulation(s,modifier).await;
+        // シミュレーションを予算の分だけ実行します
+        let start = Instant::now();
+        let mut count = 0;
+        while count < min_simulations || !budget.is_exhausted(count, start.elapsed()) {
+            // 固定の予算は超えないように、最後は残りの回数だけまとめます
+            let remaining = match budget {
+                SimulationBudget::Fixed(n) => (*n).max(min_simulations) - count,
+                SimulationBudget::Timed(_) => u32::MAX,
+            };
+            let n = self.parallel_simulations.min(remaining.max(1));
+            self.run_simulations(s,modifier,n).await;
+            count += n;
         }
 
         // 方策決定します。単に全体をNで割って返す
-        get_mcts_policy( &self.nodes.get(s).unwrap().N )
+        get_mcts_policy( &self.nodes.get(&s.canonical_key()).unwrap().N )
+    }
+
+    // Gumbel AlphaZeroでルートの手を選び、選んだ手と学習目標にする改善された方策を返します。
+    // ディリクレノイズの代わりにGumbelノイズで探索をばらつかせます。root_noiseがfalseの場合はノイズ無しで選びます。
+    // 時間の予算の場合は各フェーズに時間を均等に配り、フェーズの時間が来るまで候補を順番に探索します。
+    // 合法手が無い場合は選べないのでNoneを返します
+    #[allow(non_snake_case)]
+    pub async fn select_action_gumbel(&mut self, s:&State, modifier:&mut Modifier, budget:&SimulationBudget, min_simulations:u32, param:&GumbelParameter) -> Option<(Action,ActionVector)> {
+        self.remove_unused_nodes(s);
+        self.expand_root(s).await;
+
+        let mask = s.legal_action_mask();
+        let P = self.nodes.get(&s.canonical_key()).unwrap().P;
+        self.raw_prior = Some((s.clone(), P));
+
+        // 候補はGumbelノイズを加えたlogitの上位から選びます。ノイズは手の選択と順位付けの両方で同じものを使います
+        let mut gumbel = [0.0;ACTION_NUM];
+        if self.root_noise {
+            for g in gumbel.iter_mut() {
+                *g = -(-modifier.rng.next_f32().max(f32::MIN_POSITIVE).ln()).ln();
+            }
+        }
+        let score = |a:usize, q:&ActionVector, max_visits:f32| gumbel[a] + P[a].ln() + param.sigma(q[a], max_visits);
+        let rank = |candidates:&mut Vec<usize>, scores:&dyn Fn(usize) -> f32| {
+            candidates.sort_by(|a,b| scores(*b).total_cmp(&scores(*a)).then(a.cmp(b)));
+        };
+
+        let mut candidates : Vec<usize> = (0..ACTION_NUM).filter(|a| mask[*a]).collect();
+        if candidates.is_empty() {
+            warn!(turn = s.turn, "warning: no legal action in gumbel search {:?}", s);
+            return None;
+        }
+        rank(&mut candidates, &|a| gumbel[a] + P[a].ln());
+        candidates.truncate(param.max_considered_actions.max(1));
+
+        // 時間の予算でもmin_simulationsの回数は同じように配ります
+        let simulation_num = match budget {
+            SimulationBudget::Fixed(n) => (*n).max(min_simulations),
+            SimulationBudget::Timed(_) => min_simulations,
+        };
+        let phase_num = (candidates.len() as f32).log2().ceil().max(1.0) as u32;
+
+        // 候補が1つになるまで、探索回数を均等に配ってから評価の低い半分を落とします
+        let start = Instant::now();
+        let mut phase = 0;
+        while candidates.len() > 1 {
+            phase += 1;
+            let visits = (simulation_num / (phase_num * candidates.len() as u32)).max(1);
+            // 時間の予算では、このフェーズまでに配った時間が過ぎるまで続けます
+            let phase_deadline = match budget {
+                SimulationBudget::Fixed(_) => Duration::ZERO,
+                SimulationBudget::Timed(duration) => *duration * phase / phase_num,
+            };
+            let mut round = 0;
+            while round < visits || start.elapsed() < phase_deadline {
+                for &a in &candidates {
+                    self.run_simulation_from(s, a, modifier).await;
+                }
+                round += 1;
+            }
+
+            let node = self.nodes.get(&s.canonical_key()).unwrap();
+            let q = completed_q(node, &mask);
+            let max_visits = node.N.iter().cloned().fold(0.0, f32::max);
+            rank(&mut candidates, &|a| score(a, &q, max_visits));
+            candidates.truncate(candidates.len().div_ceil(2));
+        }
+
+        let node = self.nodes.get(&s.canonical_key()).unwrap();
+        Some((Action::from_usize(candidates[0]).unwrap(), gumbel_improved_policy(node, &mask, param)))
+    }
+
+    // 推論を同期的なevaluatorで行うsearchです。コルーチンや推論キューを用意せずに探索だけを試すためのものです。
+    // 探索の間だけ専用のキューに差し替えるので、他のコルーチンのタスクやキャッシュには触れません
+    #[allow(dead_code)]
+    pub fn search_blocking(&mut self, s:&State, modifier:&mut Modifier, budget:&SimulationBudget, min_simulations:u32, evaluator:&mut dyn FnMut(&State) -> (ActionVector,f32)) -> ActionVector {
+        let queue = Predictor::new_with_capacity(0, 0).get_queue();
+        let original_queue = std::mem::replace(&mut self.predict_queue, queue.clone());
+
+        let policy = {
+            let mut future = Box::pin(self.search(s, modifier, budget, min_simulations));
+            let waker = Waker::from(Arc::new(NoopWake));
+            let mut ctx = Context::from_waker(&waker);
+            loop {
+                if let Poll::Ready(policy) = future.as_mut().poll(&mut ctx) {
+                    break policy;
+                }
+                queue.resolve_with(|_,source| source.iter().map(&mut *evaluator).collect());
+            }
+        };
+
+        self.predict_queue = original_queue;
+        policy
+    }
+
+    // searchの結果に加えて、アクションごとの探索回数などの詳細を返します。
+    // 結果状態の例を作るのに乱数を使いますが、modifierの乱数は進めないように複製して使います
+    #[allow(non_snake_case)]
+    pub async fn search_detailed(&mut self, s:&State, modifier:&mut Modifier, budget:&SimulationBudget, min_simulations:u32) -> SearchResult {
+        let policy = self.search(s, modifier, budget, min_simulations).await;
+        let node = self.nodes.get(&s.canonical_key()).unwrap();
+        let mut sample_modifier = modifier.clone();
+
+        let actions = (0..ACTION_NUM).map(|a| (a,Action::from_usize(a).unwrap()))
+            .filter(|(_,action)| s.check_action_ex(action))
+            .map(|(a,action)| ActionDetail {
+                action,
+                visit_count : node.N[a],
+                mean_value : if node.N[a] != 0.0 { node.W[a] / node.N[a] } else { 0.0 },
+                prior : node.P[a],
+                next_state : s.run_action(&mut sample_modifier, &action),
+            })
+            .collect();
+
+        SearchResult { policy, actions, masked_actions:s.masked_actions() }
+    }
+
+    // sをルートにして探索した時の、ノイズを加える前の事前確率を返します。
+    // 直前に探索したルートのものしか残していません
+    pub fn get_raw_prior(&self, s:&State) -> Option<ActionVector> {
+        match &self.raw_prior {
+            Some((root,prior)) if root == s => Some(*prior),
+            _ => None,
+        }
+    }
+
+    // 展開済みの状態に対するバリューネットワークの値を返します。
+    // searchした後の状態なら必ず展開済みです
+    pub fn get_value_prediction(&self, s:&State) -> Option<f32> {
+        self.nodes.get(&s.canonical_key()).map(|node| node.V)
+    }
+
+    // 展開済みの状態の、手ごとの探索回数です。searchが返す方策はこれを合計で割ったものです
+    pub fn get_visit_counts(&self, s:&State) -> Option<ActionVector> {
+        self.nodes.get(&s.canonical_key()).map(|node| node.N)
+    }
+
+    // 展開済みの状態で、探索した全ての手の平均評価値を返します。未探索ならNoneです
+    #[allow(non_snake_case)]
+    pub fn get_mean_value(&self, s:&State) -> Option<f32> {
+        let node = self.nodes.get(&s.canonical_key())?;
+        let sum_N : f32 = node.N.iter().sum();
+        if sum_N > 0.0 { Some(node.W.iter().sum::<f32>() / sum_N) } else { None }
+    }
+
+    // 探索木を丸ごとJSONにします。
+    // 予想外の方策が出た時に、木を保存して後から調べたり、restoreして探索を続けたりするためのものです
+    #[cfg(feature="debug-snapshot")]
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> String {
+        // JSONのキーは文字列しか使えないので、状態とノードの組の列にします
+        let nodes : Vec<(&StateKey,&Node)> = self.nodes.iter().collect();
+        serde_json::to_string(&nodes).unwrap()
+    }
+
+    // snapshotで保存した探索木に置き換えます
+    #[cfg(feature="debug-snapshot")]
+    #[allow(dead_code)]
+    pub fn restore(&mut self, snapshot:&str) -> serde_json::Result<()> {
+        let nodes : Vec<(StateKey,Node)> = serde_json::from_str(snapshot)?;
+        self.nodes = nodes.into_iter().collect();
+        Ok(())
     }
 
     // デバッグする時に呼び出すコードなので無効にしておきます
@@ -313,6 +1154,548 @@ impl MCTSContext {
     }
 }
 
+// searchを1回実行します。推論は状態から決まる適当な値を返します
+#[cfg(test)]
+fn search_for_test(predictor:&mut Predictor, mcts_context:MCTSContext, s:&State, modifier:Modifier, budget:(SimulationBudget,u32)) -> (MCTSContext,Modifier,ActionVector) {
+    let (mcts_context,modifier,policy,_) = search_counting_for_test(predictor, mcts_context, s, modifier, budget);
+    (mcts_context,modifier,policy)
+}
+
+// search_for_testと同じですが、ネットワークで推論した状態の数も返します
+#[cfg(test)]
+fn search_counting_for_test(predictor:&mut Predictor, mcts_context:MCTSContext, s:&State, modifier:Modifier, (budget,min_simulations):(SimulationBudget,u32)) -> (MCTSContext,Modifier,ActionVector,usize) {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::executor::Executor;
+
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (result,s) = (result.clone(),s.clone());
+        executor.spawn( async move {
+            let (mut mcts_context,mut modifier) = (mcts_context,modifier);
+            let policy = mcts_context.search(&s, &mut modifier, &budget, min_simulations).await;
+            *result.borrow_mut() = Some((mcts_context,modifier,policy));
+        });
+    }
+
+    let mut predictions = 0;
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch_with( |_,source| {
+            predictions += source.len();
+            source.iter().map(|x| ([1.0/ACTION_NUM as f32;ACTION_NUM], ((x.quality*7 + x.working*3) % 100) as f32 / 100.0)).collect()
+        });
+    }
+
+    let (mcts_context,modifier,policy) = result.borrow_mut().take().unwrap();
+    (mcts_context,modifier,policy,predictions)
+}
+
+#[cfg(feature="debug-snapshot")]
+#[test]
+fn test_snapshot_restore()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let new_modifier = || Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+    let new_context = |predictor:&Predictor| MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+
+    // 途中で保存して、別のコンテキストに復元してから続きを探索します
+    let mcts_context = new_context(&predictor);
+    let (interrupted,modifier,_) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(50),0));
+    let mut resumed = new_context(&predictor);
+    resumed.restore(&interrupted.snapshot()).unwrap();
+    let (_,_,resumed_policy) = search_for_test(&mut predictor, resumed, &s, modifier, (SimulationBudget::Fixed(50),0));
+
+    // 中断せずに同じ回数探索した場合と一致します
+    let mcts_context = new_context(&predictor);
+    let (_,_,policy) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(100),0));
+    assert_eq!( policy, resumed_policy );
+}
+
+#[test]
+fn test_raw_prior()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+    let prior = mask_policy(&[1.0/ACTION_NUM as f32;ACTION_NUM], &s.legal_action_mask());
+
+    // ノイズが無ければ、探索に使った事前確率はネットワークの値から非合法手を除いたものです
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+    let (mcts_context,modifier,_) = search_for_test(&mut predictor, mcts_context, &s, modifier, (SimulationBudget::Fixed(10),0));
+    assert_eq!( Some(prior), mcts_context.get_raw_prior(&s) );
+    assert_eq!( prior, mcts_context.nodes.get(&s.canonical_key()).unwrap().P );
+
+    // ノイズがあれば、事前確率だけが変わります
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    let (mcts_context,_,_) = search_for_test(&mut predictor, mcts_context, &s, modifier, (SimulationBudget::Fixed(10),0));
+    assert_eq!( Some(prior), mcts_context.get_raw_prior(&s) );
+    assert_ne!( prior, mcts_context.nodes.get(&s.canonical_key()).unwrap().P );
+}
+
+#[test]
+fn test_search_detailed()
+{
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use xorshift::{Rng,SeedableRng};
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let seeds = [1, 2];
+    let s = State::new(&mod_param).run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) }, &Action::Reflect);
+    let new_modifier = || Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+
+    // ノイズも乱数から引くので、同じ乱数で探索すればsearchと同じ木になります
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    let (expected_context,mut expected_modifier,expected_policy) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(30),0));
+
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (result,s) = (result.clone(),s.clone());
+        let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+        let mut modifier = new_modifier();
+        executor.spawn( async move {
+            let ret = mcts_context.search_detailed(&s, &mut modifier, &SimulationBudget::Fixed(30), 0).await;
+            *result.borrow_mut() = Some((modifier,ret));
+        });
+    }
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch_with( |_,source| {
+            source.iter().map(|x| ([1.0/ACTION_NUM as f32;ACTION_NUM], ((x.quality*7 + x.working*3) % 100) as f32 / 100.0)).collect()
+        });
+    }
+    let (mut modifier,result) = result.borrow_mut().take().unwrap();
+
+    // 方策はsearchと同じで、結果状態の例を作っても乱数は進めません
+    assert_eq!( expected_policy, result.policy );
+    assert_eq!( expected_modifier.rng.next_u64(), modifier.rng.next_u64() );
+
+    // 詳細はsearchした木のルートと一致して、合法手を全て含みます
+    let visits = expected_context.get_visit_counts(&s).unwrap();
+    let total : f32 = visits.iter().sum();
+    let mask = s.legal_action_mask();
+    assert_eq!( mask.iter().filter(|x| **x).count(), result.actions.len() );
+    for detail in &result.actions {
+        let a = detail.action.to_usize().unwrap();
+        assert!( mask[a] );
+        assert_eq!( visits[a], detail.visit_count );
+        assert_eq!( expected_policy[a], detail.visit_count / total );
+    }
+    assert_eq!( total, result.actions.iter().map(|x| x.visit_count).sum::<f32>() );
+    assert!( result.masked_actions.iter().all(|(action,_)| !mask[action.to_usize().unwrap()]) );
+}
+
+// 一様な方策と状態ごとに違う評価値を返す推論で、select_action_gumbelを実行します
+#[cfg(test)]
+fn select_action_gumbel_for_test( s:&State, (budget,min_simulations):(SimulationBudget,u32), param:GumbelParameter ) -> (MCTSContext,Option<(Action,ActionVector)>) {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use xorshift::SeedableRng;
+    use super::executor::Executor;
+
+    let seeds = [1, 2];
+    let mut predictor = Predictor::new();
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (result,s) = (result.clone(),s.clone());
+        let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+        let mut modifier = Modifier { mod_param:ModifierParameter::new_fountain_of_usouso(), rng:SeedableRng::from_seed(&seeds[..]) };
+        executor.spawn( async move {
+            let ret = mcts_context.select_action_gumbel(&s, &mut modifier, &budget, min_simulations, &param).await;
+            *result.borrow_mut() = Some((mcts_context,ret));
+        });
+    }
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch_with( |_,source| {
+            source.iter().map(|x| ([1.0/ACTION_NUM as f32;ACTION_NUM], ((x.quality*7 + x.working*3) % 100) as f32 / 100.0)).collect()
+        });
+    }
+    let ret = result.borrow_mut().take().unwrap();
+    ret
+}
+
+#[test]
+fn test_select_action_gumbel()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let seeds = [1, 2];
+    // 1ターン目は候補が2つしかないので、1手進めた状態から選びます
+    let s = State::new(&mod_param).run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) }, &Action::Reflect);
+    let param = GumbelParameter { max_considered_actions:4, ..GumbelParameter::default() };
+    let (mcts_context,ret) = select_action_gumbel_for_test(&s, (SimulationBudget::Fixed(16),0), param);
+    let (action,policy) = ret.unwrap();
+
+    // 4つの候補に2回ずつ、残った2つに4回ずつ探索して、最後に残った手を選びます
+    let visits = mcts_context.get_visit_counts(&s).unwrap();
+    assert_eq!( 4, visits.iter().filter(|x| **x > 0.0).count() );
+    assert_eq!( 16.0, visits.iter().sum::<f32>() );
+    assert_eq!( 6.0, visits[action.to_usize().unwrap()] );
+    assert!( s.check_action_ex(&action) );
+
+    // 方策は合法手だけに確率があり、選んだ手が最大です
+    let mask = s.legal_action_mask();
+    assert!( (policy.iter().sum::<f32>() - 1.0).abs() < 1e-5 );
+    assert!( (0..ACTION_NUM).all(|a| mask[a] || policy[a] == 0.0) );
+    assert_eq!( vec![action.to_usize().unwrap()], select_max_indices(&policy) );
+}
+
+#[test]
+fn test_select_action_gumbel_timed()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let seeds = [1, 2];
+    let s = State::new(&mod_param).run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) }, &Action::Reflect);
+    let param = GumbelParameter { max_considered_actions:4, ..GumbelParameter::default() };
+
+    // 時間の予算では、最低回数が0でも1回ずつで終わらずに時間まで探索します
+    let start = Instant::now();
+    let (mcts_context,ret) = select_action_gumbel_for_test(&s, (SimulationBudget::Timed(Duration::from_millis(50)),0), param);
+    assert!( start.elapsed() >= Duration::from_millis(50) );
+    let (action,_) = ret.unwrap();
+    let visits = mcts_context.get_visit_counts(&s).unwrap();
+    assert!( visits.iter().sum::<f32>() > 6.0 );
+    assert!( s.check_action_ex(&action) );
+}
+
+#[test]
+fn test_select_action_gumbel_no_legal_action()
+{
+    // 合法手が無ければ選ばずにNoneを返します
+    let mod_param = ModifierParameter { max_cp:0, ..ModifierParameter::new_fountain_of_usouso() };
+    let s = State::new(&mod_param);
+    let (mcts_context,ret) = select_action_gumbel_for_test(&s, (SimulationBudget::Fixed(16),0), GumbelParameter::default());
+    assert!( ret.is_none() );
+    assert_eq!( Some(0.0), mcts_context.get_visit_counts(&s).map(|x| x.iter().sum()) );
+}
+
+#[test]
+fn test_root_alpha()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let predictor = Predictor::new();
+    let mcts_context = MCTSContext::new(1.0, AlphaSchedule(vec![(1,0.5),(5,0.2),(20,0.05)]), 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    for (turn,alpha) in [(1,0.5), (4,0.5), (5,0.2), (19,0.2), (20,0.05), (40,0.05)] {
+        assert_eq!( alpha, mcts_context.root_alpha(&State { turn, ..State::new(&mod_param) }), "turn {}", turn );
+    }
+
+    // 値だけを渡した場合は全ターン同じです
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    assert_eq!( 0.15, mcts_context.root_alpha(&State { turn:30, ..State::new(&mod_param) }) );
+}
+
+#[test]
+fn test_illegal_action_not_visited()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mask = s.legal_action_mask();
+    let illegal = Action::BasicSynthesis.to_usize().unwrap();
+    assert!( !mask[illegal] );
+
+    // ネットワークが非合法手に全ての確率を付けても、その手は探索されず方策にも入りません
+    let predictor = Predictor::new();
+    let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    let mut evaluator = |_:&State| {
+        let mut p = [0.0;ACTION_NUM];
+        p[illegal] = 1.0;
+        (p, 0.5)
+    };
+    let policy = mcts_context.search_blocking(&s, &mut modifier, &SimulationBudget::Fixed(50), 0, &mut evaluator);
+
+    let node = mcts_context.nodes.get(&s.canonical_key()).unwrap();
+    for a in (0..ACTION_NUM).filter(|a| !mask[*a]) {
+        assert_eq!( 0.0, node.N[a] );
+        assert_eq!( 0.0, node.P[a] );
+        assert_eq!( 0.0, policy[a] );
+    }
+    assert!( (node.P.iter().sum::<f32>() - 1.0).abs() < 1e-6 );
+    assert!( mask[select_action_greedy(&policy, &mask, TieBreak::Random, &mut modifier.rng).to_usize().unwrap()] );
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_c_puct()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State { turn:2, ..State::new(&mod_param) }; // 1ターン目は探索で手を絞っているので2ターン目で比べます
+    let predictor = Predictor::new();
+
+    let mut P = [0.0;ACTION_NUM];
+    P[0] = 0.6;
+    P[1] = 0.4;
+    let scores = |c_puct:f32| {
+        let mut mcts_context = MCTSContext::new(c_puct, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+        mcts_context.expand(s.clone(), P, 0.0);
+        let node = mcts_context.nodes.get_mut(&s.canonical_key()).unwrap();
+        node.N[0] = 3.0;
+        node.W[0] = 1.5;
+        node.N[1] = 1.0;
+        node.W[1] = 0.2;
+        get_scores(mcts_context.c_puct, &s, node)
+    };
+
+    // 同じ訪問回数なら、Qの項は変わらず事前確率の項だけがc_puctに比例します
+    let (low,high) = (scores(1.0), scores(2.0));
+    let q = [0.5, 0.2, 0.0];
+    for a in 0..3 {
+        assert!( ((high[a] - q[a]) - 2.0 * (low[a] - q[a])).abs() < 1e-6 );
+    }
+    assert!( (low[0] - (0.5 + 0.6 * 2.0 / 4.0)).abs() < 1e-6 );
+
+    // c_puctが大きいほど訪問回数の少ない手の評価が上がります
+    assert!( high[1] - high[0] > low[1] - low[0] );
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_virtual_loss()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State { turn:2, ..State::new(&mod_param) };
+    let seeds = [1, 2];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let predictor = Predictor::new();
+
+    // 評価値は0番の手の方が少しだけ高い状態にしておきます
+    let mut P = [0.0;ACTION_NUM];
+    P[0] = 0.5;
+    P[1] = 0.5;
+    let new_context = |virtual_loss:u32| {
+        let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+        mcts_context.set_virtual_loss(virtual_loss);
+        mcts_context.expand(s.clone(), P, 0.0);
+        let node = mcts_context.nodes.get_mut(&s.canonical_key()).unwrap();
+        node.N[0] = 1.0;
+        node.W[0] = 0.5;
+        node.N[1] = 1.0;
+        node.W[1] = 0.4;
+        mcts_context
+    };
+    let first_action = |(path,_):(Vec<(State,usize)>,LeafResult)| path[0].1;
+
+    // 仮想損失が無ければ、評価値を足す前に選ぶと同じ手に集まります
+    let mut mcts_context = new_context(0);
+    let a = first_action(mcts_context.search_leaf(&s, &mut modifier));
+    let b = first_action(mcts_context.search_leaf(&s, &mut modifier));
+    assert_eq!( a, b );
+
+    // 仮想損失があれば、探索中の手を避けて別の手を選びます
+    let mut mcts_context = new_context(1);
+    let (path,_) = mcts_context.search_leaf(&s, &mut modifier);
+    let b = first_action(mcts_context.search_leaf(&s, &mut modifier));
+    assert_eq!( 0, path[0].1 );
+    assert_eq!( 1, b );
+
+    // 評価値を足すと仮想損失は取り除かれて、本当の訪問回数だけが残ります
+    mcts_context.add_value(&path, 0.5);
+    let node = mcts_context.nodes.get(&s.canonical_key()).unwrap();
+    assert_eq!( 2.0, node.N[0] );
+    assert_eq!( 1.0, node.W[0] );
+}
+
+#[test]
+fn test_parallel_simulations()
+{
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use xorshift::SeedableRng;
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let seeds = [1, 2];
+    let s = State::new(&mod_param).run_action(&mut Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) }, &Action::Reflect);
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (result,s) = (result.clone(),s.clone());
+        let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+        mcts_context.set_virtual_loss(1);
+        mcts_context.set_parallel_simulations(4);
+        let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+        executor.spawn( async move {
+            mcts_context.search(&s, &mut modifier, &SimulationBudget::Fixed(10), 0).await;
+            *result.borrow_mut() = Some(mcts_context);
+        });
+    }
+
+    // ルートを展開した後は4つずつ同時に降りて、仮想損失で別々の葉をまとめて推論します。最後は予算の残りの2つだけです
+    let mut batches = vec![];
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch_with( |_,source| {
+            batches.push( (source.len(), source.iter().map(|x| x.canonical_key()).collect::<HashSet<_>>().len()) );
+            source.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], 0.5)).collect()
+        });
+    }
+    assert_eq!( vec![(1,1),(4,4),(4,4),(2,2)], batches );
+
+    // 仮想損失は全て取り除かれて、訪問回数は予算の回数と一致します
+    let mcts_context = result.borrow_mut().take().unwrap();
+    assert_eq!( 10.0, mcts_context.get_visit_counts(&s).unwrap().iter().sum::<f32>() );
+}
+
+#[test]
+fn test_search_no_legal_action()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter { max_cp:0, ..ModifierParameter::new_fountain_of_usouso() };
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+
+    // 合法手が無いルートは展開だけして、シミュレーションせずに空の方策を返します
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.25, -0.5, predictor.get_queue(), "mock".to_string());
+    let (mcts_context,_,policy) = search_for_test(&mut predictor, mcts_context, &s, modifier, (SimulationBudget::Fixed(10),0));
+    assert_eq!( [0.0;ACTION_NUM], policy );
+    assert_eq!( Some(0.0), mcts_context.get_visit_counts(&s).map(|x| x.iter().sum()) );
+}
+
+#[test]
+fn test_min_simulations()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let new_modifier = || Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+    let new_context = |predictor:&Predictor| MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+    let visits = |mcts_context:&MCTSContext| mcts_context.nodes.get(&s.canonical_key()).unwrap().N.iter().sum::<f32>() as u32;
+
+    // 時間が無くても最低回数は探索します
+    let mcts_context = new_context(&predictor);
+    let (mcts_context,_,_) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Timed(Duration::from_nanos(1)),20));
+    assert_eq!( 20, visits(&mcts_context) );
+
+    // 回数の予算が最低回数より少ない場合も同じです
+    let mcts_context = new_context(&predictor);
+    let (mcts_context,_,_) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(3),20));
+    assert_eq!( 20, visits(&mcts_context) );
+
+    let mcts_context = new_context(&predictor);
+    let (mcts_context,_,_) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(30),20));
+    assert_eq!( 30, visits(&mcts_context) );
+}
+
+#[test]
+fn test_search_blocking()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State { turn:2, ..State::new(&mod_param) }; // 1ターン目は探索で手を絞っているので2ターン目で試します
+    let seeds = [1, 2];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let predictor = Predictor::new();
+    let mut mcts_context = MCTSContext::new(1.0, 0.15, 0.0, 0.0, predictor.get_queue(), "mock".to_string());
+
+    // 事前確率をほぼ1つの手に集めると、その手が一番探索されます
+    let favorite = Action::BasicTouch.to_usize().unwrap();
+    let mut calls = 0;
+    let mut evaluator = |_:&State| {
+        calls += 1;
+        let mut policy = [0.01;ACTION_NUM];
+        policy[favorite] = 0.9;
+        (policy, 0.5)
+    };
+    let policy = mcts_context.search_blocking(&s, &mut modifier, &SimulationBudget::Fixed(50), 1, &mut evaluator);
+    assert_eq!( favorite, choose_max_index(&policy, TieBreak::Random, &mut modifier.rng) );
+    assert!( (policy.iter().sum::<f32>() - 1.0).abs() < 1e-6 );
+    assert_eq!( 50.0, mcts_context.get_visit_counts(&s).unwrap().iter().sum::<f32>() );
+
+    // 推論は展開したノードの数だけ呼ばれます
+    assert_eq!( calls, mcts_context.nodes.len() );
+}
+
+#[test]
+fn test_tree_reuse()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let seeds = [1, 2];
+    let modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let mut predictor = Predictor::new();
+    let visits = |mcts_context:&MCTSContext, s:&State| mcts_context.nodes.get(&s.canonical_key()).map(|x| x.N.iter().sum::<f32>()).unwrap_or(0.0);
+
+    let mcts_context = MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+    let (mcts_context,modifier,_) = search_for_test(&mut predictor, mcts_context, &s, modifier, (SimulationBudget::Fixed(100),0));
+
+    // 一番探索された次の状態を、実際に進んだ先とします
+    let next = mcts_context.nodes.iter()
+        .filter(|(k,_)| k.turn() == s.turn + 1)
+        .max_by(|(_,x),(_,y)| x.N.iter().sum::<f32>().partial_cmp(&y.N.iter().sum::<f32>()).unwrap())
+        .map(|(k,_)| k.state().clone())
+        .unwrap();
+    let reused = visits(&mcts_context, &next);
+    assert!( reused > 0.0 );
+
+    // 次の探索はその状態までに貯めた探索回数から続き、前の手番のノードは捨てられます
+    let (mcts_context,_,_) = search_for_test(&mut predictor, mcts_context, &next, modifier, (SimulationBudget::Fixed(10),0));
+    assert_eq!( reused + 10.0, visits(&mcts_context, &next) );
+    assert!( mcts_context.nodes.keys().all(|k| k.turn() > s.turn) );
+    assert_ne!( mcts_context.get_raw_prior(&next).unwrap(), mcts_context.nodes.get(&next.canonical_key()).unwrap().P );
+}
+
+#[test]
+fn test_tree_reuse_saves_root_prediction()
+{
+    use xorshift::SeedableRng;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let s = State::new(&mod_param);
+    let new_modifier = || { let seeds = [1, 2]; Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) } };
+    let new_context = |predictor:&Predictor| MCTSContext::new(1.0, 0.15, 0.25, 0.0, predictor.get_queue(), "mock".to_string());
+
+    let mut predictor = Predictor::new();
+    let mcts_context = new_context(&predictor);
+    let (mcts_context,modifier,_) = search_for_test(&mut predictor, mcts_context, &s, new_modifier(), (SimulationBudget::Fixed(100),0));
+    let next = mcts_context.nodes.keys().find(|k| k.turn() == s.turn + 1).map(|k| k.state().clone()).unwrap();
+
+    // 前の探索で展開済みのルートは推論し直さず、シミュレーションの分だけ推論します
+    let (_,_,_,reused) = search_counting_for_test(&mut predictor, mcts_context, &next, modifier, (SimulationBudget::Fixed(0),0));
+    assert_eq!( 0, reused );
+
+    // 木を引き継がない場合はルートの評価に1回推論します。推論のキャッシュを共有しないよう別のPredictorで比べます
+    let mut predictor = Predictor::new();
+    let mcts_context = new_context(&predictor);
+    let (_,_,_,fresh) = search_counting_for_test(&mut predictor, mcts_context, &next, new_modifier(), (SimulationBudget::Fixed(0),0));
+    assert_eq!( 1, fresh );
+}
+
 // デバッグする時に呼び出すコードなので無効にしておきます
 #[allow(dead_code)]
 pub fn print_mcts_stats() {
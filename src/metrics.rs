@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::Instant;
+use num::{FromPrimitive,ToPrimitive};
+
+use super::logic::{Action,ACTION_NUM};
+
+// モデルごとのラベルを保持する最大数です。
+// モデルは学習が進むたびに増えるので、最近使われたものだけ残してラベルの数を抑えます
+const ACTION_COUNTER_MAX_MODELS : usize = 8;
+
+// 新しいモデルを足す前に、上限を超えないように最も長く使われていないモデルを捨てます
+fn evict_oldest_model<T,F:Fn(&T)->u64>(models:&mut HashMap<String,T>, model:&str, last_used:F) {
+    if !models.contains_key(model) && models.len() >= ACTION_COUNTER_MAX_MODELS {
+        let oldest = models.iter().min_by_key(|(_,x)| last_used(x)).map(|(name,_)| name.clone()).unwrap();
+        models.remove(&oldest);
+    }
+}
+
+struct ModelCounter {
+    last_used : u64,
+    counts : [u64;ACTION_NUM],
+}
+
+// モデルとアクションごとの選択回数です。
+// モデルの方策が急に偏った(崩壊や配布ミス)ことを監視で検知するために使います
+pub struct ActionCounters {
+    inner : Mutex<(u64,HashMap<String,ModelCounter>)>,
+}
+
+impl ActionCounters {
+    pub fn new() -> ActionCounters {
+        ActionCounters { inner : Mutex::new((0,HashMap::new())) }
+    }
+
+    pub fn add(&self, model:&str, actions:&[Action]) {
+        let mut inner = self.inner.lock().unwrap();
+        let (clock,models) = &mut *inner;
+        *clock += 1;
+
+        evict_oldest_model(models, model, |x| x.last_used);
+
+        let counter = models.entry(model.to_string()).or_insert(ModelCounter { last_used:0, counts:[0;ACTION_NUM] });
+        counter.last_used = *clock;
+        for action in actions {
+            counter.counts[action.to_usize().unwrap()] += 1;
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, model:&str, action:Action) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.1.get(model).map(|x| x.counts[action.to_usize().unwrap()]).unwrap_or(0)
+    }
+
+    // Prometheusのテキスト形式で出力します
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut models : Vec<(&String,&ModelCounter)> = inner.1.iter().collect();
+        models.sort_by_key(|(name,_)| name.to_string());
+
+        let mut dst = String::new();
+        dst += "# HELP craft_action_selected_total Number of actions selected in selfplay.\n";
+        dst += "# TYPE craft_action_selected_total counter\n";
+        for (name,counter) in models {
+            for (a,count) in counter.counts.iter().enumerate() {
+                dst += &format!("craft_action_selected_total{{model=\"{}\",action=\"{:?}\"}} {}\n", name, Action::from_usize(a).unwrap(), count);
+            }
+        }
+        dst
+    }
+
+}
+
+// node_exporterのtextfile collectorで読めるように書き込みます。
+// 読み込み途中のファイルを見られないように一時ファイルからrenameします
+pub fn write_textfile(path:&str, text:&str) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, text)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[test]
+fn test_action_counters()
+{
+    let counters = ActionCounters::new();
+    counters.add("model", &[Action::BasicSynthesis, Action::Reflect, Action::BasicSynthesis]);
+
+    assert_eq!( 2, counters.get("model", Action::BasicSynthesis) );
+    assert_eq!( 1, counters.get("model", Action::Reflect) );
+    assert_eq!( 0, counters.get("model", Action::BasicTouch) );
+    assert!( counters.render().contains("craft_action_selected_total{model=\"model\",action=\"BasicSynthesis\"} 2\n") );
+
+    // 古いモデルから捨てられます
+    for i in 0..ACTION_COUNTER_MAX_MODELS {
+        counters.add(&format!("new{}", i), &[Action::Observe]);
+    }
+    assert_eq!( 0, counters.get("model", Action::BasicSynthesis) );
+    assert_eq!( 1, counters.get("new0", Action::Observe) );
+}
+
+// エピソードのターン数のヒストグラムの区切りです。最後の区切りより長いものは+Infに入ります
+pub const EPISODE_LENGTH_BUCKETS : [u32;8] = [5, 10, 15, 20, 25, 30, 40, 60];
+
+// エピソードのターン数の分布です。
+// すぐに壊れるエピソードと最後まで作るエピソードに分かれているような偏りは、スループットだけでは分からないので記録します
+pub struct EpisodeLengthHistogram {
+    inner : Mutex<([u64;EPISODE_LENGTH_BUCKETS.len()+1],u64)>, // 区切りごとの件数(累積ではありません)とターン数の合計です
+}
+
+impl EpisodeLengthHistogram {
+    pub fn new() -> EpisodeLengthHistogram {
+        EpisodeLengthHistogram { inner : Mutex::new(([0;EPISODE_LENGTH_BUCKETS.len()+1],0)) }
+    }
+
+    pub fn add(&self, turns:u32) {
+        let mut inner = self.inner.lock().unwrap();
+        let index = EPISODE_LENGTH_BUCKETS.iter().position(|x| turns <= *x).unwrap_or(EPISODE_LENGTH_BUCKETS.len());
+        inner.0[index] += 1;
+        inner.1 += turns as u64;
+    }
+
+    // 足したエピソードの数です
+    #[cfg(test)]
+    pub fn count(&self) -> u64 {
+        self.inner.lock().unwrap().0.iter().sum()
+    }
+
+    // 区切りの上限と件数の組です。上限がNoneのものは+Infです
+    pub fn counts(&self) -> Vec<(Option<u32>,u64)> {
+        let inner = self.inner.lock().unwrap();
+        EPISODE_LENGTH_BUCKETS.iter().map(|x| Some(*x)).chain(std::iter::once(None)).zip(inner.0.iter().cloned()).collect()
+    }
+
+    // Prometheusのテキスト形式で出力します
+    pub fn render(&self) -> String {
+        let sum = self.inner.lock().unwrap().1;
+        let mut dst = String::new();
+        dst += "# HELP craft_episode_turns Number of turns of selfplay episodes.\n";
+        dst += "# TYPE craft_episode_turns histogram\n";
+        let mut total = 0;
+        for (le,count) in self.counts() {
+            total += count;
+            let le = le.map(|x| x.to_string()).unwrap_or_else(|| "+Inf".to_string());
+            dst += &format!("craft_episode_turns_bucket{{le=\"{}\"}} {}\n", le, total);
+        }
+        dst += &format!("craft_episode_turns_sum {}\n", sum);
+        dst += &format!("craft_episode_turns_count {}\n", total);
+        dst
+    }
+}
+
+#[test]
+fn test_episode_length_histogram()
+{
+    let histogram = EpisodeLengthHistogram::new();
+    for turns in &[3, 5, 6, 12, 100] {
+        histogram.add(*turns);
+    }
+
+    let counts = histogram.counts();
+    assert_eq!( (Some(5),2), counts[0] );
+    assert_eq!( (Some(10),1), counts[1] );
+    assert_eq!( (Some(15),1), counts[2] );
+    assert_eq!( (None,1), counts[EPISODE_LENGTH_BUCKETS.len()] );
+    assert_eq!( 5, histogram.count() );
+
+    // Prometheusのバケットは累積です
+    let text = histogram.render();
+    assert!( text.contains("craft_episode_turns_bucket{le=\"10\"} 3\n") );
+    assert!( text.contains("craft_episode_turns_bucket{le=\"+Inf\"} 5\n") );
+    assert!( text.contains("craft_episode_turns_sum 126\n") );
+}
+
+// 進捗に出す報酬のヒストグラムの区切りの既定値です。報酬はおおむね[0,1]に収まります
+pub const DEFAULT_REWARD_BUCKETS : [f32;6] = [0.0, 0.2, 0.4, 0.6, 0.8, 1.0];
+
+// 書き込んだレコードの報酬の分布です。
+// 学習が崩れて品質が落ちていくのはスループットだけでは分からないので、進捗に出して早めに気付けるようにします。
+// 書き込みスレッドだけで使うのでロックは持ちません
+#[derive(Debug,Clone)]
+pub struct RewardHistogram {
+    buckets : Vec<f32>, // 昇順の区切りです。最後の区切りより大きいものは+Infに入ります
+    counts : Vec<u64>,  // 区切りごとの件数です(累積ではありません)
+    sum : f64,
+}
+
+impl RewardHistogram {
+    pub fn new(buckets:&[f32]) -> RewardHistogram {
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by(f32::total_cmp);
+        let counts = vec![0; buckets.len()+1];
+        RewardHistogram { buckets, counts, sum:0.0 }
+    }
+
+    pub fn add(&mut self, reward:f32) {
+        let index = self.buckets.iter().position(|x| reward <= *x).unwrap_or(self.buckets.len());
+        self.counts[index] += 1;
+        self.sum += reward as f64;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // 1件も無ければNoneです
+    pub fn mean(&self) -> Option<f64> {
+        match self.count() {
+            0 => None,
+            n => Some(self.sum / n as f64),
+        }
+    }
+
+    // 区切りの上限と件数の組です。上限がNoneのものは+Infです
+    pub fn counts(&self) -> Vec<(Option<f32>,u64)> {
+        self.buckets.iter().map(|x| Some(*x)).chain(std::iter::once(None)).zip(self.counts.iter().cloned()).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|x| *x = 0);
+        self.sum = 0.0;
+    }
+}
+
+#[test]
+fn test_reward_histogram()
+{
+    let mut histogram = RewardHistogram::new(&[0.5, 0.0]);
+    assert_eq!( None, histogram.mean() );
+
+    for reward in &[0.0, 0.25, 0.5, 1.0] {
+        histogram.add(*reward);
+    }
+    assert_eq!( vec![(Some(0.0),1),(Some(0.5),2),(None,1)], histogram.counts() );
+    assert_eq!( Some(0.4375), histogram.mean() );
+
+    histogram.clear();
+    assert_eq!( 0, histogram.count() );
+    assert_eq!( None, histogram.mean() );
+}
+
+// セルフプレイ全体の進み具合です。各スレッドから更新して、監視用にまとめて出力します
+pub struct SelfPlayGauges {
+    start : Instant,
+    records_sent : AtomicU64,     // セルフプレイのスレッドが書き込みスレッドへ送ったレコードの数です
+    records_received : AtomicU64, // 書き込みスレッドが受け取ったレコードの数です。送った数との差がキューに溜まっている数です
+    records_written : AtomicU64,
+    samples_written : AtomicU64,
+    selected_model : Mutex<Option<String>>,
+    predictions : Mutex<(u64,HashMap<String,PredictionCounter>)>,
+}
+
+// モデルごとの推論した状態の数です
+struct PredictionCounter {
+    last_used : u64,
+    count : u64,
+}
+
+impl SelfPlayGauges {
+    pub fn new() -> SelfPlayGauges {
+        SelfPlayGauges {
+            start : Instant::now(),
+            records_sent : AtomicU64::new(0),
+            records_received : AtomicU64::new(0),
+            records_written : AtomicU64::new(0),
+            samples_written : AtomicU64::new(0),
+            selected_model : Mutex::new(None),
+            predictions : Mutex::new((0,HashMap::new())),
+        }
+    }
+
+    pub fn add_sent(&self) {
+        self.records_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self) {
+        self.records_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 書き込み先へ渡したレコードです。捨てたレコードや起動時に読み込んだレコードは含めません
+    pub fn add_written(&self, samples:usize) {
+        self.records_written.fetch_add(1, Ordering::Relaxed);
+        self.samples_written.fetch_add(samples as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_selected_model(&self, model:&str) {
+        *self.selected_model.lock().unwrap() = Some(model.to_string());
+    }
+
+    // ネットワークで推論した状態の数を足します。キャッシュから返したものは含めません
+    pub fn add_predictions(&self, model:&str, count:usize) {
+        let mut inner = self.predictions.lock().unwrap();
+        let (clock,models) = &mut *inner;
+        *clock += 1;
+        evict_oldest_model(models, model, |x| x.last_used);
+
+        let counter = models.entry(model.to_string()).or_insert(PredictionCounter { last_used:0, count:0 });
+        counter.last_used = *clock;
+        counter.count += count as u64;
+    }
+
+    // 書き込んだレコードの数です
+    pub fn written_records(&self) -> u64 {
+        self.records_written.load(Ordering::Relaxed)
+    }
+
+    // 送ったけれどまだ書き込みスレッドが受け取っていないレコードの数です
+    pub fn queue_depth(&self) -> u64 {
+        // 別々に読むので一瞬だけ受け取った数が上回ることがあります
+        self.records_sent.load(Ordering::Relaxed).saturating_sub(self.records_received.load(Ordering::Relaxed))
+    }
+
+    // モデルごとの推論した状態の数です。使われなくなって忘れたモデルは含みません
+    pub fn prediction_counts(&self) -> HashMap<String,u64> {
+        self.predictions.lock().unwrap().1.iter().map(|(name,counter)| (name.clone(),counter.count)).collect()
+    }
+
+    // Prometheusのテキスト形式で出力します
+    pub fn render(&self) -> String {
+        let records = self.records_written.load(Ordering::Relaxed);
+        let secs = self.start.elapsed().as_secs_f64().max(0.001);
+
+        let mut dst = String::new();
+        dst += "# HELP craft_records_total Number of records written.\n";
+        dst += "# TYPE craft_records_total counter\n";
+        dst += &format!("craft_records_total {}\n", records);
+        dst += "# HELP craft_samples_total Number of samples written.\n";
+        dst += "# TYPE craft_samples_total counter\n";
+        dst += &format!("craft_samples_total {}\n", self.samples_written.load(Ordering::Relaxed));
+        dst += "# HELP craft_records_per_second Average records written per second since start.\n";
+        dst += "# TYPE craft_records_per_second gauge\n";
+        dst += &format!("craft_records_per_second {}\n", records as f64 / secs);
+        dst += "# HELP craft_writer_queue_depth Number of records waiting for the writer.\n";
+        dst += "# TYPE craft_writer_queue_depth gauge\n";
+        dst += &format!("craft_writer_queue_depth {}\n", self.queue_depth());
+
+        let inner = self.predictions.lock().unwrap();
+        let mut models : Vec<(&String,&PredictionCounter)> = inner.1.iter().collect();
+        models.sort_by_key(|(name,_)| name.to_string());
+        dst += "# HELP craft_predictions_total Number of states predicted by network.\n";
+        dst += "# TYPE craft_predictions_total counter\n";
+        for (name,counter) in models {
+            dst += &format!("craft_predictions_total{{model=\"{}\"}} {}\n", name, counter.count);
+        }
+
+        // 選ばれているモデルは名前をラベルにして値を1とします
+        if let Some(model) = self.selected_model.lock().unwrap().as_ref() {
+            dst += "# HELP craft_selected_model Model currently selected for selfplay.\n";
+            dst += "# TYPE craft_selected_model gauge\n";
+            dst += &format!("craft_selected_model{{model=\"{}\"}} 1\n", model);
+        }
+        dst
+    }
+}
+
+#[test]
+fn test_selfplay_gauges()
+{
+    let gauges = SelfPlayGauges::new();
+    for _ in 0..3 {
+        gauges.add_sent();
+    }
+    gauges.add_received();
+    gauges.add_written(10);
+    gauges.add_predictions("a", 5);
+    gauges.add_predictions("a", 2);
+    gauges.set_selected_model("a");
+    assert_eq!( 2, gauges.queue_depth() );
+    assert_eq!( 1, gauges.written_records() );
+    assert_eq!( HashMap::from([("a".to_string(),7)]), gauges.prediction_counts() );
+
+    let text = gauges.render();
+    assert!( text.contains("craft_records_total 1\n") );
+    assert!( text.contains("craft_samples_total 10\n") );
+    assert!( text.contains("craft_writer_queue_depth 2\n") );
+    assert!( text.contains("craft_predictions_total{model=\"a\"} 7\n") );
+    assert!( text.contains("craft_selected_model{model=\"a\"} 1\n") );
+}
+
+// 監視の読み込みを待つ時間です。何も送らない接続で他の読み込みが止まらないように、超えたら切断します
+#[cfg(feature="metrics-server")]
+pub const METRICS_CONNECTION_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(5);
+
+// GET /metricsにrenderの結果を返すだけのHTTPサーバーを別スレッドで動かします。
+// 監視から数秒おきに読まれるだけなので、1つずつ順番に応答します。スレッドはプロセスの終了まで残ります。
+// 1つの接続の読み書きはtimeoutまでしか待ちません
+#[cfg(feature="metrics-server")]
+pub fn spawn_metrics_server<F>(listener:std::net::TcpListener, timeout:std::time::Duration, render:F) -> std::io::Result<std::thread::JoinHandle<()>>
+    where F : Fn() -> String + Send + 'static
+{
+    use std::io::{BufRead,BufReader,Write};
+
+    std::thread::Builder::new().name("metrics".to_string()).spawn( move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => { tracing::error!("failed to accept metrics request {:?}", e); continue },
+            };
+            if let Err(e) = stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))) {
+                tracing::error!("failed to set timeout of metrics request {:?}", e);
+                continue;
+            }
+
+            // リクエスト行のパスだけを見ます。ヘッダーは読み捨てます
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            if let Err(e) = reader.read_line(&mut request_line) {
+                tracing::warn!("failed to read metrics request {:?}", e);
+                continue;
+            }
+            let mut header = String::new();
+            while reader.read_line(&mut header).map(|n| n > 0 && header != "\r\n").unwrap_or(false) {
+                header.clear();
+            }
+
+            let response = match request_line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["GET", "/metrics", ..] => {
+                    let body = render();
+                    format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+                },
+                _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                tracing::error!("failed to write metrics response {:?}", e);
+            }
+        }
+    })
+}
+
+#[cfg(feature="metrics-server")]
+#[test]
+fn test_metrics_server()
+{
+    use std::io::{Read,Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn_metrics_server(listener, std::time::Duration::from_millis(100), || "craft_records_total 3\n".to_string()).unwrap();
+
+    let request = |path:&str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = request("/metrics");
+    assert!( response.starts_with("HTTP/1.0 200 OK\r\n") );
+    assert!( response.ends_with("\r\n\r\ncraft_records_total 3\n") );
+    assert!( request("/").starts_with("HTTP/1.0 404") );
+
+    // 何も送らない接続があっても、待つ時間を過ぎれば次の接続に応答します
+    let _idle = std::net::TcpStream::connect(addr).unwrap();
+    assert!( request("/metrics").starts_with("HTTP/1.0 200 OK\r\n") );
+}
@@ -88,6 +88,105 @@ pub fn create_network(vs: &nn::Path, network_type: NetworkType) -> Box<dyn DualN
     }
 }
 
+// ネットワークの重みの形式です。モデル名の拡張子で決めます
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum NetworkBackend {
+    Torch, // tchで保存したVarStoreです。NetworkTypeで構造を決めます
+    Onnx,  // 他のフレームワークからONNX形式で書き出したものです。onnxのfeatureが必要です
+}
+
+impl NetworkBackend {
+    pub fn from_name(name:&str) -> NetworkBackend {
+        if name.ends_with(".onnx") { NetworkBackend::Onnx } else { NetworkBackend::Torch }
+    }
+}
+
+#[test]
+fn test_network_backend()
+{
+    assert_eq!( NetworkBackend::Torch, NetworkBackend::from_name("20240101-000000") );
+    assert_eq!( NetworkBackend::Onnx, NetworkBackend::from_name("20240101-000000.onnx") );
+}
+
+// 読み込んだネットワークの重みです。スレッド間で共有して、各スレッドのPredictorに読み込みます
+pub enum Weights {
+    Torch(NetworkType,VarStore),
+    #[cfg(feature="onnx")]
+    Onnx(super::onnx::OnnxNetwork),
+    #[cfg(test)]
+    Mock(f32), // 一様な方策とこの評価値を返す偽物です。torchを使わずにセルフプレイのスレッドを動かすテスト用です
+}
+
+// 状態のバッチから方策と評価値を推論するものです。
+// Predictorはこれだけを使うので、torchを使わない偽物のネットワークとも差し替えられます
+pub trait Predict {
+    fn predict_batch(&self, states:&[State], mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn Error>>;
+}
+
+impl Predict for Box<dyn DualNetwork> {
+    fn predict_batch(&self, states:&[State], mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn Error>> {
+        self.as_ref().predict_batch(states, mod_param)
+    }
+}
+
+// 入力を指定したデバイスに移してから推論するネットワークです。結果はCPUに戻してから取り出します
+pub struct DeviceNetwork {
+    pub net : Box<dyn DualNetwork>,
+    pub device : Device,
+}
+
+impl Predict for DeviceNetwork {
+    fn predict_batch(&self, states:&[State], mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn Error>> {
+        let state_vec_t = encode_state_batch( states, mod_param ).to_device(self.device);
+        let (policy_t,value_t) = self.net.forward_t(&state_vec_t, false);
+        Ok(decode_pv_batch((policy_t.to_device(Device::Cpu), value_t.to_device(Device::Cpu))))
+    }
+}
+
+// コマンドラインで指定するデバイスの名前です。cpu、cuda:0、/GPU:0の形式を受け付けます
+pub fn parse_device(name:&str) -> Result<Device, String> {
+    let lower = name.to_lowercase();
+    let index = lower.strip_prefix("cuda:").or_else(|| lower.strip_prefix("/gpu:"));
+    match (lower.as_str(), index) {
+        ("cpu",_) => Ok(Device::Cpu),
+        (_,Some(index)) => index.parse::<usize>().map(Device::Cuda).map_err(|_| format!("invalid device index {}", name)),
+        _ => Err(format!("unknown device {}", name)),
+    }
+}
+
+// parse_deviceで読める名前です
+pub fn device_name(device:&Device) -> String {
+    match device {
+        Device::Cpu => "cpu".to_string(),
+        Device::Cuda(index) => format!("cuda:{}", index),
+    }
+}
+
+#[test]
+fn test_parse_device()
+{
+    assert_eq!( Ok(Device::Cpu), parse_device("cpu") );
+    assert_eq!( Ok(Device::Cuda(0)), parse_device("cuda:0") );
+    assert_eq!( Ok(Device::Cuda(1)), parse_device("/GPU:1") );
+    assert!( parse_device("cuda:x").is_err() );
+    assert!( parse_device("tpu:0").is_err() );
+    assert_eq!( Ok(Device::Cuda(2)), parse_device(&device_name(&Device::Cuda(2))) );
+}
+
+// 設定ファイルではデバイスをparse_deviceと同じ名前で書きます。#[serde(with="device_names")]で使います
+pub mod device_names {
+    use serde::{Serialize,Serializer,Deserialize,Deserializer};
+    use super::*;
+
+    pub fn serialize<S:Serializer>(devices:&[Device], serializer:S) -> Result<S::Ok,S::Error> {
+        devices.iter().map(device_name).collect::<Vec<_>>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de,D:Deserializer<'de>>(deserializer:D) -> Result<Vec<Device>,D::Error> {
+        Vec::<String>::deserialize(deserializer)?.iter().map(|x| parse_device(x).map_err(serde::de::Error::custom)).collect()
+    }
+}
+
 pub trait DualNetwork {
 
     fn forward_t(&self, input: &Tensor, train:bool) -> (Tensor,Tensor);
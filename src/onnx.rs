@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use tract_onnx::prelude::*;
+
+use super::logic::{State,ACTION_NUM};
+use super::setting::ModifierParameter;
+use super::encoding::encode_state;
+use super::network::{Predict,STATE_NUM};
+use super::mcts::ActionVector;
+
+// ONNX形式で書き出したネットワークです。torchのネットワークと同じ形の入出力である必要があります。
+// 入力は[バッチ,STATE_NUM]のencode_stateの結果で、出力は1つ目が方策の確率[バッチ,ACTION_NUM]、2つ目が評価値[バッチ,1]です。
+// 推論するだけで状態を持たないので、複製したものは全て同じモデルを共有します
+#[derive(Clone)]
+pub struct OnnxNetwork {
+    model : Arc<TypedRunnableModel<TypedModel>>,
+}
+
+impl OnnxNetwork {
+    pub fn load(path:&Path) -> TractResult<OnnxNetwork> {
+        OnnxNetwork::from_model(tract_onnx::onnx().model_for_path(path)?)
+    }
+
+    fn from_model(model:InferenceModel) -> TractResult<OnnxNetwork> {
+        let model = model.into_optimized()?.into_runnable()?;
+        Ok(OnnxNetwork { model:Arc::new(model) })
+    }
+}
+
+impl Predict for OnnxNetwork {
+    fn predict_batch(&self, states:&[State], mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn Error>> {
+        let input : Vec<f32> = states.iter().flat_map(|s| encode_state(s, mod_param)).collect();
+        let input = tract_ndarray::Array2::from_shape_vec((states.len(), STATE_NUM), input)?;
+        let outputs = self.model.run(tvec!(Tensor::from(input).into()))?;
+        if outputs.len() < 2 {
+            return Err(format!("onnx model must have policy and value outputs but has {}", outputs.len()).into());
+        }
+
+        let policy = outputs[0].to_array_view::<f32>()?;
+        let value = outputs[1].to_array_view::<f32>()?;
+        if policy.shape() != [states.len(), ACTION_NUM] || value.shape() != [states.len(), 1] {
+            return Err(format!("unexpected output shape policy:{:?} value:{:?}", policy.shape(), value.shape()).into());
+        }
+
+        Ok((0..states.len()).map(|i| {
+            let mut p = [0.0;ACTION_NUM];
+            for (a,x) in p.iter_mut().enumerate() {
+                *x = policy[[i,a]];
+            }
+            (p, value[[i,0]])
+        }).collect())
+    }
+}
+
+#[test]
+fn test_onnx_network()
+{
+    use tract_onnx::pb::*;
+
+    // 重みが0で、バイアスだけを返す線形のネットワークを組み立てます
+    let tensor = |name:&str, dims:Vec<i64>, data:Vec<f32>| TensorProto { name:name.to_string(), dims, data_type:tensor_proto::DataType::Float as i32, float_data:data, ..Default::default() };
+    let node = |op:&str, input:[&str;2], output:&str| NodeProto { op_type:op.to_string(), input:input.iter().map(|x| x.to_string()).collect(), output:vec![output.to_string()], ..Default::default() };
+    let value_info = |name:&str, width:i64| {
+        let dims = vec![
+            tensor_shape_proto::Dimension { value:Some(tensor_shape_proto::dimension::Value::DimParam("N".to_string())), ..Default::default() },
+            tensor_shape_proto::Dimension { value:Some(tensor_shape_proto::dimension::Value::DimValue(width)), ..Default::default() },
+        ];
+        let tensor_type = type_proto::Tensor { elem_type:tensor_proto::DataType::Float as i32, shape:Some(TensorShapeProto { dim:dims }) };
+        ValueInfoProto { name:name.to_string(), r#type:Some(TypeProto { value:Some(type_proto::Value::TensorType(tensor_type)), ..Default::default() }), ..Default::default() }
+    };
+
+    let policy_bias : Vec<f32> = (0..ACTION_NUM).map(|a| a as f32 / 1000.0).collect();
+    let graph = GraphProto {
+        node:vec![
+            node("MatMul", ["x","policy_w"], "policy_mm"),
+            node("Add", ["policy_mm","policy_b"], "policy"),
+            node("MatMul", ["x","value_w"], "value_mm"),
+            node("Add", ["value_mm","value_b"], "value"),
+        ],
+        initializer:vec![
+            tensor("policy_w", vec![STATE_NUM as i64, ACTION_NUM as i64], vec![0.0; STATE_NUM*ACTION_NUM]),
+            tensor("policy_b", vec![ACTION_NUM as i64], policy_bias.clone()),
+            tensor("value_w", vec![STATE_NUM as i64, 1], vec![0.0; STATE_NUM]),
+            tensor("value_b", vec![1], vec![0.25]),
+        ],
+        input:vec![value_info("x", STATE_NUM as i64)],
+        output:vec![value_info("policy", ACTION_NUM as i64), value_info("value", 1)],
+        ..Default::default()
+    };
+    let proto = ModelProto { ir_version:7, opset_import:vec![OperatorSetIdProto { domain:String::new(), version:13 }], graph:Some(graph), ..Default::default() };
+
+    let network = OnnxNetwork::from_model(tract_onnx::onnx().model_for_proto_model(&proto).unwrap()).unwrap();
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let states = vec![State::new(&mod_param); 3];
+    let results = network.predict_batch(&states, &mod_param).unwrap();
+    assert_eq!( 3, results.len() );
+    for (policy,value) in results {
+        assert_eq!( policy_bias[..], policy[..] );
+        assert_eq!( 0.25, value );
+    }
+}
@@ -1,26 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{HashMap,BTreeMap};
 use std::future::Future;
 use std::pin::Pin;
-use std::task::{Context,Poll};
+use std::task::{Context,Poll,Waker};
 use std::cell::{Cell,RefCell};
 use std::rc::Rc;
+use std::sync::{Arc,Mutex};
 
 use super::mcts::ActionVector;
 use super::logic::State;
+#[cfg(test)]
+use super::logic::ACTION_NUM;
 use super::setting::ModifierParameter;
 use super::network::*;
+use super::cache::{PredictionCache,LruPredictionCache};
+use super::metrics::SelfPlayGauges;
+use super::executor::abort_current_task;
+use serde::{Serialize,Deserialize};
 
 // 個々のNNが予測した結果を保存するための場所
 // PendingおよびReadyがそのまま入っています。実質Optionと一緒。
 // そのままFutureの戻り値として使えます。
+// Pendingの間はWakerを預かっておき、結果が入った時にだけ起こします
 #[derive(Clone)]
 pub struct PredictResult {
-    res : Rc<Cell<Poll<(ActionVector,f32)>>>
+    res : Rc<Cell<Poll<(ActionVector,f32)>>>,
+    waker : Rc<Cell<Option<Waker>>>,
+    failed : Rc<Cell<bool>>, // 推論を諦めて、結果が二度と入らないものです
 }
 
 impl PredictResult {
     pub fn new() -> PredictResult {
-        PredictResult { res : Rc::new(Cell::new(Poll::Pending)) }
+        PredictResult { res : Rc::new(Cell::new(Poll::Pending)), waker : Rc::new(Cell::new(None)), failed : Rc::new(Cell::new(false)) }
+    }
+
+    fn set_ready(&self, x:(ActionVector,f32)) {
+        self.res.set(Poll::Ready(x));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // 待っているタスクを起こして、次のpollで中断させます
+    fn set_failed(&self) {
+        self.failed.set(true);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
     }
 }
 
@@ -28,63 +53,896 @@ impl Future for PredictResult {
     type Output = (ActionVector,f32);
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<(ActionVector,f32)> {
-        ctx.waker().wake_by_ref();
-        self.res.get()
+        if self.failed.get() {
+            abort_current_task();
+            return Poll::Pending;
+        }
+        let res = self.res.get();
+        if res.is_pending() {
+            self.waker.set(Some(ctx.waker().clone()));
+        }
+        res
+    }
+}
+
+// プールに保持するPredictResultの最大数です
+const RESULT_POOL_CAPACITY : usize = 1024;
+
+// Predictorごとに覚えておく推論結果の最大数です
+const LRU_CACHE_CAPACITY : usize = 65536;
+
+// 解決済みのPredictResultを再利用するためのプールです。Predictorごとに1つ持ちます。
+// 推論のたびに小さな確保と解放が大量に起きるのを抑えます
+#[derive(Clone)]
+pub struct ResultPool {
+    capacity : usize,
+    free : Rc<RefCell<Vec<PredictResult>>>,
+    created : Rc<Cell<usize>>, // 新しく確保した数です(ベンチマーク用)
+}
+
+impl ResultPool {
+    pub fn new(capacity:usize) -> ResultPool {
+        ResultPool { capacity, free:Rc::new(RefCell::new(vec![])), created:Rc::new(Cell::new(0)) }
+    }
+
+    // 取り出したものは必ずPendingです。前回の値が残らないようにここで戻します
+    fn acquire(&self) -> PredictResult {
+        match self.free.borrow_mut().pop() {
+            Some(x) => {
+                x.res.set(Poll::Pending);
+                x.waker.set(None);
+                x.failed.set(false);
+                x
+            },
+            None => {
+                self.created.set(self.created.get() + 1);
+                PredictResult::new()
+            },
+        }
+    }
+
+    // 他から参照されていない場合だけ戻します。
+    // predict_batchの時点では結果を受け取る前なので、戻すのは結果を読んだ側です
+    fn release(&self, x:PredictResult) {
+        let mut free = self.free.borrow_mut();
+        if Rc::strong_count(&x.res) == 1 && free.len() < self.capacity {
+            free.push(x);
+        }
+    }
+
+    pub fn created(&self) -> usize {
+        self.created.get()
+    }
+}
+
+#[test]
+fn test_result_pool()
+{
+    let pool = ResultPool::new(4);
+
+    // 解決済みの値はプールから取り出した時に残っていません
+    let x = pool.acquire();
+    x.res.set(Poll::Ready(([1.0;32], 1.0)));
+    pool.release(x);
+    let y = pool.acquire();
+    assert!( y.res.get().is_pending() );
+    assert_eq!( 1, pool.created() );
+
+    // まだ参照されているものは戻しません
+    let shared = y.clone();
+    pool.release(y);
+    assert!( pool.acquire().res.get().is_pending() );
+    assert_eq!( 2, pool.created() );
+    drop(shared);
+}
+
+// 予測の優先度です。
+// 評価のように少数で待ち時間が問題になるものはHighにすると、生成の大量のタスクより先に推論されます。
+// Normalのタスクも溜まっている場合、predict_batchはHighのタスクだけの小さいバッチで先に返します
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+// 優先度とネットワーク名ごとに、溜まっている推論タスクです。
+// 推論する順番が実行ごとに変わらないように、優先度、ネットワーク名、積まれた順で並べます
+// キーの3つ目は状態を符号化する設定の名前です(ModifierParameter::preset_name)。Noneの場合はpredict_batchに渡した設定を使います。
+// 設定が違えば同じ状態でもネットワークの入力が変わるので、設定ごとに別のバッチで推論します
+type TaskMap = BTreeMap<(Priority,String,Option<&'static str>),Vec<(State,PredictResult)>>;
+
+// キャッシュはネットワーク名と状態で引くので、設定を指定した場合はネットワーク名に設定の名前を付けて区別します
+fn cache_name<'a>( name:&'a str, setting:Option<&str> ) -> std::borrow::Cow<'a,str> {
+    match setting {
+        Some(setting) => format!("{}@{}", name, setting).into(),
+        None => name.into(),
+    }
+}
+
+// 溜めておける推論タスクの数の上限です。PredictorとPredictQueueで共有します。
+// 上限に達したasync_predictはWakerを預けて待ち、タスクが解決された時にまとめて起こされます
+struct QueueLimit {
+    max : Cell<usize>,
+    wakers : RefCell<Vec<Waker>>,
+}
+
+impl QueueLimit {
+    fn new() -> QueueLimit {
+        QueueLimit { max:Cell::new(usize::MAX), wakers:RefCell::new(vec![]) }
+    }
+
+    fn wake_all(&self) {
+        for waker in self.wakers.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+// キューに空きができるまで待つFutureです
+struct WaitForSpace<'a> {
+    queue : &'a PredictQueue,
+}
+
+impl Future for WaitForSpace<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.queue.len() < self.queue.limit.max.get() {
+            return Poll::Ready(());
+        }
+        self.queue.limit.wakers.borrow_mut().push(ctx.waker().clone());
+        Poll::Pending
     }
 }
 
 // 予測システム
 pub struct Predictor {
-    networks : HashMap<String,(tch::nn::VarStore,Box<dyn DualNetwork>)>,
-    tasks : Rc<RefCell<HashMap<String,Vec<(State,PredictResult)>>>>,
+    networks : HashMap<String,(Option<tch::nn::VarStore>,Box<dyn Predict>)>, // VarStoreはtorchのネットワークの重みを持っておくためのものです
+    tasks : Rc<RefCell<TaskMap>>,
+    cache : Option<Arc<Mutex<PredictionCache>>>,
+    pool : ResultPool,
+    lru_cache : Rc<RefCell<LruPredictionCache>>,
+    max_batch_size : usize, // ネットワークに1回で渡す状態の最大数です。溜まったタスクが多い場合は分けて推論します
+    device : tch::Device,   // torchのネットワークを読み込むデバイスです
+    gauges : Option<Arc<SelfPlayGauges>>, // ネットワークごとの推論数を数える先です
+    prediction_counts : HashMap<String,u64>, // このPredictorでネットワークごとに推論した状態の数です
+    limit : Rc<QueueLimit>,
+    high_priority_only : bool, // 直前の推論でHighのタスクだけを推論した場合はtrueです
 }
 
 #[derive(Clone)]
 pub struct PredictQueue {
-    tasks : Rc<RefCell<HashMap<String,Vec<(State,PredictResult)>>>>,
+    tasks : Rc<RefCell<TaskMap>>,
+    pool : ResultPool,
+    lru_cache : Rc<RefCell<LruPredictionCache>>, // ここにあるものはタスクを積まずにすぐ返します
+    limit : Rc<QueueLimit>,
 }
 
 impl Predictor {
     pub fn new() -> Predictor {
-        Predictor { networks : HashMap::new(), tasks:Rc::new(RefCell::new(HashMap::new())) }
+        Predictor::new_with_capacity(RESULT_POOL_CAPACITY, LRU_CACHE_CAPACITY)
     }
 
-    pub fn load_network(&mut self, name:String, (network_type,source_vs):&(NetworkType,tch::nn::VarStore) ) {
-        if !self.networks.contains_key(&name) {
-            let mut vs = tch::nn::VarStore::new(tch::Device::Cpu);
-            let net = create_network(&vs.root(), *network_type);
-            vs.copy(source_vs).unwrap(); // ファイルから直接読んでも良いです。どうせ全体から見るとどちらも大差ない
-            self.networks.insert(name, (vs,net) );
+    // pool_capacityが0の場合はPredictResultを再利用しません。
+    // lru_cache_capacityが0の場合は推論結果を覚えておかず、毎回推論します
+    pub fn new_with_capacity(pool_capacity:usize, lru_cache_capacity:usize) -> Predictor {
+        Predictor {
+            networks : HashMap::new(),
+            tasks:Rc::new(RefCell::new(BTreeMap::new())),
+            cache:None,
+            pool:ResultPool::new(pool_capacity),
+            lru_cache:Rc::new(RefCell::new(LruPredictionCache::new(lru_cache_capacity))),
+            max_batch_size:usize::MAX,
+            device:tch::Device::Cpu,
+            gauges:None,
+            prediction_counts:HashMap::new(),
+            limit:Rc::new(QueueLimit::new()),
+            high_priority_only:false,
         }
     }
 
-    pub fn predict_batch(&mut self, mod_param:&ModifierParameter) {
-        let mut tasks = self.tasks.borrow_mut();
+    // 推論結果のキャッシュを設定します。スレッド間で共有して構いません
+    pub fn set_cache(&mut self, cache:Arc<Mutex<PredictionCache>>) {
+        self.cache = Some(cache);
+    }
+
+    // これより後に読み込むtorchのネットワークはdeviceで推論します。ONNXのネットワークは常にCPUです
+    pub fn set_device(&mut self, device:tch::Device) {
+        self.device = device;
+    }
 
-        for (name,task_vec) in tasks.iter() {
+    // ネットワークで推論した状態の数をgaugesに足していきます。スレッド間で共有して構いません
+    pub fn set_gauges(&mut self, gauges:Arc<SelfPlayGauges>) {
+        self.gauges = Some(gauges);
+    }
+
+    pub fn load_network(&mut self, name:String, weights:&Weights ) -> Result<(),tch::TchError> {
+        if self.networks.contains_key(&name) {
+            return Ok(());
+        }
+        match weights {
+            Weights::Torch(network_type,source_vs) => {
+                let mut vs = tch::nn::VarStore::new(self.device);
+                let net = create_network(&vs.root(), *network_type);
+                vs.copy(source_vs)?; // ファイルから直接読んでも良いです。どうせ全体から見るとどちらも大差ない
+                self.lru_cache.borrow_mut().remove_network(&name);
+                self.networks.insert(name, (Some(vs),Box::new(DeviceNetwork { net, device:self.device })) );
+            },
+            #[cfg(feature="onnx")]
+            Weights::Onnx(network) => {
+                self.lru_cache.borrow_mut().remove_network(&name);
+                self.networks.insert(name, (None,Box::new(network.clone())) );
+            },
+            #[cfg(test)]
+            Weights::Mock(value) => {
+                self.lru_cache.borrow_mut().remove_network(&name);
+                self.networks.insert(name, (None,Box::new(MockNetwork { value:*value })) );
+            },
+        }
+        Ok(())
+    }
+
+    // torchを使わないネットワークをnameとして登録します。同じ名前のものがあれば置き換えます
+    #[allow(dead_code)]
+    pub fn insert_network(&mut self, name:String, network:Box<dyn Predict>) {
+        self.lru_cache.borrow_mut().remove_network(&name);
+        self.networks.insert(name, (None,network));
+    }
+
+    pub fn contains_network(&self, name:&str) -> bool {
+        self.networks.contains_key(name)
+    }
+
+    pub fn set_max_batch_size(&mut self, max_batch_size:usize) {
+        self.max_batch_size = max_batch_size.max(1);
+    }
+
+    // 推論を待つタスクの数の上限です。推論が遅くてタスクが際限なく溜まるのを防ぎます。
+    // 上限に達するとasync_predictはタスクが解決されるまで待つので、推論する側は溜まった分を必ず推論する必要があります。
+    // flush_when_batchedはbatch_size個溜まるまで推論しないので、batch_sizeより小さくするとバッチが埋まらなくなります。
+    // 上限を超えて溜まっているタスクはそのまま残します
+    pub fn set_max_queued_tasks(&mut self, max_queued_tasks:usize) {
+        self.limit.max.set(max_queued_tasks.max(1));
+        self.limit.wake_all();
+    }
+
+    // 推論に失敗したネットワークのタスクは解決せずに残しておくので、次に呼んだ時に推論し直します。
+    // 他のネットワークのタスクはそのまま解決します
+    pub fn predict_batch(&mut self, mod_param:&ModifierParameter) -> Result<(),Box<dyn std::error::Error>> {
+        let mut batch = self.take_batch();
+        let networks = &self.networks;
+        let max_batch_size = self.max_batch_size;
+        let gauges = &self.gauges;
+        let prediction_counts = &mut self.prediction_counts;
+        let ret = try_resolve_tasks( &mut batch, self.cache.as_deref(), Some(&mut self.lru_cache.borrow_mut()), |name,setting,source| {
             // ここでnameに対応するnetworkは絶対に見つかる想定です。
             // ここで見つからない場合はロジックがおかしいので処理を見直します
-            let network = self.networks.get(name).expect("not found network");
-            let (source,results) : (Vec<State>, Vec<PredictResult>) = task_vec.iter().cloned().unzip();
-            let dest = network.1.predict_batch( &source, mod_param ).unwrap();
-
-            for (result,d) in results.iter().zip( dest.iter() ) {
-                result.res.set(Poll::Ready(*d))
+            let network = networks.get(name).expect("not found network");
+            let preset = setting.map(|x| ModifierParameter::from_preset_name(x).expect("not found setting"));
+            let mod_param = preset.as_ref().unwrap_or(mod_param);
+            let dest = source.chunks(max_batch_size).map(|x| network.1.predict_batch( x, mod_param )).collect::<Result<Vec<_>,_>>()?;
+            if let Some(gauges) = gauges {
+                gauges.add_predictions(name, source.len());
             }
+            *prediction_counts.entry(name.to_string()).or_insert(0) += source.len() as u64;
+            Ok(dest.concat())
+        });
+        self.tasks.borrow_mut().append(&mut batch);
+        self.limit.wake_all();
+        ret
+    }
+
+    // 次に推論するタスクを取り出します。
+    // HighとNormalのタスクが両方ある場合はHighだけを取り出して、待っている評価のタスクを大量の生成のタスクより早く返します。
+    // Normalのタスクが溜まったままにならないように、2回続けてHighだけにはしません
+    fn take_batch(&mut self) -> TaskMap {
+        let mut tasks = self.tasks.borrow_mut();
+        let mut batch = std::mem::take(&mut *tasks);
+        let normal = batch.split_off(&(Priority::Normal,String::new(),None));
+        self.high_priority_only = !self.high_priority_only && !batch.is_empty() && !normal.is_empty();
+        if self.high_priority_only {
+            *tasks = normal;
         }
+        else {
+            batch.extend(normal);
+        }
+        batch
+    }
 
+    // 残っているタスクを全て失敗させます。待っていたタスクはExecutor::poll_allで中断されます。
+    // predict_batchが失敗し続けるネットワークのタスクで、終了処理などが終わらなくなるのを防ぎます。戻り値は失敗させたタスクの数です
+    pub fn fail_pending(&mut self) -> usize {
+        let mut tasks = self.tasks.borrow_mut();
+        let failed = tasks.values().map(|x| x.len()).sum();
+        for (_,result) in tasks.values().flatten() {
+            result.set_failed();
+        }
         tasks.clear();
+        self.limit.wake_all();
+        failed
+    }
+
+    // ネットワークの代わりにfで推論します。torchを使わずにセルフプレイを動かすテストやベンチマーク用です
+    pub fn predict_batch_with<F>(&mut self, f:F)
+        where F : FnMut(&str,&[State]) -> Vec<(ActionVector,f32)>
+    {
+        let mut batch = self.take_batch();
+        resolve_tasks( &mut batch, self.cache.as_deref(), Some(&mut self.lru_cache.borrow_mut()), f );
+        self.limit.wake_all();
     }
 
     pub fn get_queue(&self) -> PredictQueue {
-        PredictQueue { tasks : self.tasks.clone() }
+        PredictQueue { tasks : self.tasks.clone(), pool : self.pool.clone(), lru_cache : self.lru_cache.clone(), limit : self.limit.clone() }
+    }
+
+    // predict_batchでネットワークごとに推論した状態の数です。キャッシュで解決したものは含みません
+    pub fn prediction_counts(&self) -> HashMap<String,u64> {
+        self.prediction_counts.clone()
+    }
+
+    pub fn get_pool(&self) -> &ResultPool {
+        &self.pool
+    }
+}
+
+// 溜まっているタスクを全て解決します。
+// キャッシュにあるものはそのまま返して、無いものだけpredictでまとめて推論します。
+// 優先度の高いものから順に、優先度ごとに別のバッチで推論します。
+// 解決した結果は全てlru_cacheにも入れます
+fn resolve_tasks<F>( tasks:&mut TaskMap, cache:Option<&Mutex<PredictionCache>>, lru_cache:Option<&mut LruPredictionCache>, mut predict:F )
+    where F : FnMut(&str,&[State]) -> Vec<(ActionVector,f32)>
+{
+    let ret : Result<(),std::convert::Infallible> = try_resolve_tasks( tasks, cache, lru_cache, |name,_,source| Ok(predict(name,source)) );
+    let Ok(()) = ret;
+}
+
+// resolve_tasksと同じですが、predictが失敗したものはキャッシュに無かったタスクだけ残しておきます。
+// エラーは最初のものを返します
+fn try_resolve_tasks<F,E>( tasks:&mut TaskMap, cache:Option<&Mutex<PredictionCache>>, mut lru_cache:Option<&mut LruPredictionCache>, mut predict:F ) -> Result<(),E>
+    where F : FnMut(&str,Option<&'static str>,&[State]) -> Result<Vec<(ActionVector,f32)>,E>
+{
+    let mut ret = Ok(());
+    for (key,task_vec) in tasks.iter_mut() {
+        let (_,name,setting) = key;
+        let cache_name = cache_name(name, *setting);
+        let cache_name = cache_name.as_ref();
+        let misses : Vec<(State,PredictResult)> = match cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                task_vec.iter().filter(|(state,result)| {
+                    match cache.get(cache_name, state) {
+                        Some(d) => {
+                            if let Some(lru_cache) = lru_cache.as_mut() {
+                                lru_cache.insert(cache_name, state, d);
+                            }
+                            result.set_ready(d);
+                            false
+                        },
+                        None => true,
+                    }
+                }).cloned().collect()
+            },
+            None => task_vec.clone(),
+        };
+
+        if misses.is_empty() {
+            task_vec.clear();
+            continue;
+        }
+
+        let source : Vec<State> = misses.iter().map(|(state,_)| state.clone()).collect();
+        let dest = match predict( name, *setting, &source ) {
+            Ok(dest) => dest,
+            Err(e) => {
+                *task_vec = misses;
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+                continue;
+            },
+        };
+        let results : Vec<PredictResult> = misses.into_iter().map(|(_,result)| result).collect();
+        task_vec.clear();
+
+        for (result,d) in results.iter().zip( dest.iter() ) {
+            result.set_ready(*d)
+        }
+
+        if let Some(lru_cache) = lru_cache.as_mut() {
+            for (state,d) in source.iter().zip( dest.iter() ) {
+                lru_cache.insert( cache_name, state, *d );
+            }
+        }
+
+        if let Some(cache) = cache {
+            let mut cache = cache.lock().unwrap();
+            for (state,d) in source.into_iter().zip( dest ) {
+                cache.insert( cache_name, state, d );
+            }
+        }
+    }
+
+    tasks.retain(|_,task_vec| !task_vec.is_empty());
+    ret
+}
+
+#[test]
+fn test_resolve_tasks_warm_cache()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let initial = State::new(&mod_param);
+    let mut second = initial.clone();
+    second.turn += 1;
+    let states = [initial, second];
+
+    let path = std::env::temp_dir().join(format!("prediction_cache_test_{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    let run = |cache:&Mutex<PredictionCache>| {
+        let mut calls = 0;
+        let mut tasks = BTreeMap::new();
+        let results : Vec<PredictResult> = states.iter().map(|_| PredictResult::new()).collect();
+        tasks.insert( (Priority::Normal,"model".to_string(),None), states.iter().cloned().zip(results.iter().cloned()).collect() );
+        resolve_tasks( &mut tasks, Some(cache), None, |_,source| {
+            calls += source.len();
+            source.iter().map(|x| ([0.5;32], x.turn as f32)).collect()
+        });
+        assert!( results.iter().all(|x| x.res.get().is_ready()) );
+        calls
+    };
+
+    // 1回目は全部推論して、ファイルに保存します
+    {
+        let cache = Mutex::new(PredictionCache::open(path, 100).unwrap());
+        assert_eq!( 2, run(&cache) );
+    }
+
+    // 2回目は読み込んだキャッシュだけで解決します
+    {
+        let cache = Mutex::new(PredictionCache::open(path, 100).unwrap());
+        assert_eq!( 0, run(&cache) );
     }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_resolve_tasks_priority()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let normal = PredictResult::new();
+    let high = PredictResult::new();
+
+    // 先に積まれたNormalより後から積まれたHighが先に解決されます
+    let mut tasks = BTreeMap::new();
+    tasks.insert( (Priority::Normal,"model".to_string(),None), vec![(State::new(&mod_param),normal.clone())] );
+    tasks.insert( (Priority::High,"model".to_string(),None), vec![(State::new(&mod_param),high.clone())] );
+
+    let mut order = vec![];
+    resolve_tasks( &mut tasks, None, None, |_,source| {
+        order.push( (high.res.get().is_ready(), normal.res.get().is_ready()) );
+        source.iter().map(|_| ([0.0;32], 0.0)).collect()
+    });
+
+    assert_eq!( vec![(false,false),(true,false)], order );
+    assert!( tasks.is_empty() );
+}
+
+#[test]
+fn test_predict_batch_high_priority_only()
+{
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    let mut executor = Executor::new();
+    let queue = predictor.get_queue();
+    let spawn = |executor:&mut Executor, priority:Priority, turns:std::ops::RangeInclusive<u32>| {
+        for turn in turns {
+            let queue = queue.clone();
+            let state = State { turn, ..State::new(&mod_param) };
+            executor.spawn( async move {
+                queue.async_predict("mock".to_string(), state, priority).await;
+            });
+        }
+    };
+    let predict = |predictor:&mut Predictor| {
+        let mut sizes = vec![];
+        predictor.predict_batch_with( |_,source| { sizes.push(source.len()); source.iter().map(|_| ([0.0;ACTION_NUM], 0.0)).collect() } );
+        sizes
+    };
+
+    // 先に積まれたNormalを残して、後から積まれたHighだけを小さいバッチで推論します
+    spawn(&mut executor, Priority::Normal, 1..=8);
+    spawn(&mut executor, Priority::High, 11..=12);
+    executor.poll_all();
+    assert_eq!( vec![2], predict(&mut predictor) );
+    assert_eq!( 8, queue.len() );
+
+    // 次はHighがあってもNormalをまとめて推論します
+    spawn(&mut executor, Priority::High, 13..=13);
+    executor.poll_all();
+    assert_eq!( vec![1,8], predict(&mut predictor) );
+    assert!( queue.is_empty() );
+
+    // Highだけの場合はそのまま推論します
+    spawn(&mut executor, Priority::High, 14..=14);
+    executor.poll_all();
+    assert_eq!( vec![1], predict(&mut predictor) );
+    executor.poll_all();
+    assert!( executor.is_empty() );
+}
+
+#[test]
+fn test_resolve_tasks_stable_order()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+
+    // 積む順番を変えても、推論する順番はネットワーク名と積まれた順で決まります
+    let run = |names:&[&str]| {
+        let mut tasks = TaskMap::new();
+        for name in names {
+            for turn in 1..=3 {
+                let mut state = State::new(&mod_param);
+                state.turn = turn;
+                tasks.entry((Priority::Normal,name.to_string(),None)).or_default().push( (state,PredictResult::new()) );
+            }
+        }
+
+        let mut order = vec![];
+        resolve_tasks( &mut tasks, None, None, |name,source| {
+            order.extend( source.iter().map(|x| (name.to_string(), x.turn)) );
+            source.iter().map(|_| ([0.0;32], 0.0)).collect()
+        });
+        order
+    };
+
+    let expected = run(&["c","a","d","b"]);
+    assert_eq!( ("a".to_string(),1), expected[0] );
+    assert_eq!( ("a".to_string(),3), expected[2] );
+    assert_eq!( ("d".to_string(),3), expected[11] );
+    for _ in 0..4 {
+        assert_eq!( expected, run(&["c","a","d","b"]) );
+    }
+    assert_eq!( expected, run(&["b","d","a","c"]) );
+}
+
+#[test]
+fn test_predict_result_wake()
+{
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut predictor = Predictor::new();
+    let queue = predictor.get_queue();
+    let done = Rc::new(Cell::new(false));
+
+    let mut executor = Executor::new();
+    {
+        let done = done.clone();
+        let state = State::new(&mod_param);
+        executor.spawn( async move {
+            queue.async_predict("model".to_string(), state, Priority::Normal).await;
+            done.set(true);
+        });
+    }
+
+    // 推論されるまでは起こされないので、何回pollしても進みません
+    executor.poll_all();
+    executor.poll_all();
+    assert!( !done.get() );
+
+    // 結果が入ると起こされて、次のpollで終わります
+    predictor.predict_batch_with( |_,source| source.iter().map(|_| ([0.0;32], 0.0)).collect() );
+    assert!( !done.get() );
+    executor.poll_all();
+    assert!( done.get() );
+    assert!( executor.is_empty() );
+}
+
+#[test]
+fn test_async_predict_lru_cache()
+{
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let run = |predictor:&mut Predictor| {
+        let mut executor = Executor::new();
+        for _ in 0..2 {
+            let queue = predictor.get_queue();
+            let state = State::new(&mod_param);
+            executor.spawn( async move {
+                for _ in 0..3 {
+                    queue.async_predict("model".to_string(), state.clone(), Priority::Normal).await;
+                }
+            });
+        }
+
+        let mut calls = 0;
+        while !executor.is_empty() {
+            executor.poll_all();
+            predictor.predict_batch_with( |_,source| { calls += source.len(); source.iter().map(|_| ([0.0;32], 0.0)).collect() } );
+        }
+        calls
+    };
+
+    // 最初の1回で同時に積まれた2つだけ推論して、残りは覚えておいた結果を返します
+    assert_eq!( 2, run(&mut Predictor::new()) );
+    assert_eq!( 6, run(&mut Predictor::new_with_capacity(RESULT_POOL_CAPACITY, 0)) );
+}
+
+// 一様な方策と固定の評価値を返すネットワークです
+#[cfg(test)]
+pub struct MockNetwork {
+    pub value : f32,
+}
+
+#[cfg(test)]
+impl Predict for MockNetwork {
+    fn predict_batch(&self, states:&[State], _mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn std::error::Error>> {
+        Ok(states.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], self.value)).collect())
+    }
+}
+
+#[test]
+fn test_predict_batch_mock_network()
+{
+    use super::executor::Executor;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut predictor = Predictor::new();
+    predictor.insert_network("mock".to_string(), Box::new(MockNetwork { value:0.25 }));
+    assert!( predictor.contains_network("mock") );
+
+    let results = Rc::new(RefCell::new(vec![]));
+    let mut executor = Executor::new();
+    for turn in 1..=3 {
+        let (queue,results) = (predictor.get_queue(),results.clone());
+        let state = State { turn, ..State::new(&mod_param) };
+        executor.spawn( async move {
+            let ret = queue.async_predict("mock".to_string(), state, Priority::Normal).await;
+            results.borrow_mut().push(ret);
+        });
+    }
+
+    // 本物のネットワークと同じ経路でまとめて推論します
+    while !executor.is_empty() {
+        executor.poll_all();
+        predictor.predict_batch(&mod_param).unwrap();
+    }
+    assert_eq!( vec![([1.0/ACTION_NUM as f32;ACTION_NUM], 0.25); 3], *results.borrow() );
+}
+
+#[test]
+fn test_predict_batch_with_setting()
+{
+    use super::executor::Executor;
+
+    // 符号化に使った設定の初期CPを評価値として返すネットワークです
+    struct MaxCpNetwork;
+    impl Predict for MaxCpNetwork {
+        fn predict_batch(&self, states:&[State], mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn std::error::Error>> {
+            Ok(states.iter().map(|_| ([0.0;ACTION_NUM], mod_param.max_cp as f32)).collect())
+        }
+    }
+
+    let default = ModifierParameter::new_fountain_of_usouso();
+    let other = ModifierParameter::new_ishgard_reconstruction_4th();
+    let mut predictor = Predictor::new();
+    predictor.insert_network("mock".to_string(), Box::new(MaxCpNetwork));
+
+    // 同じ状態でも設定ごとに別々に推論して、キャッシュも混ざりません
+    let results = Rc::new(RefCell::new(vec![]));
+    let mut executor = Executor::new();
+    for setting in [None, other.preset_name(), None, other.preset_name()] {
+        let (queue,results,state) = (predictor.get_queue(),results.clone(),State::new(&default));
+        executor.spawn( async move {
+            let (_,value) = queue.async_predict_with_setting("mock".to_string(), setting, state, Priority::Normal).await;
+            results.borrow_mut().push((setting,value));
+        });
+        while !executor.is_empty() {
+            executor.poll_all();
+            predictor.predict_batch(&default).unwrap();
+        }
+    }
+
+    let expected = |setting:Option<&'static str>| (setting, if setting.is_some() { other.max_cp } else { default.max_cp } as f32);
+    assert_eq!( vec![expected(None), expected(other.preset_name()), expected(None), expected(other.preset_name())], *results.borrow() );
+    assert_eq!( Some(&2), predictor.prediction_counts().get("mock") );
+}
+
+#[test]
+fn test_max_batch_size()
+{
+    use super::executor::Executor;
+
+    // 渡された状態の数を覚えておくネットワークです
+    struct CountingNetwork {
+        sizes : Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Predict for CountingNetwork {
+        fn predict_batch(&self, states:&[State], _mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn std::error::Error>> {
+            self.sizes.borrow_mut().push(states.len());
+            Ok(states.iter().map(|_| ([0.0;ACTION_NUM], 0.0)).collect())
+        }
+    }
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let sizes = Rc::new(RefCell::new(vec![]));
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    predictor.insert_network("mock".to_string(), Box::new(CountingNetwork { sizes:sizes.clone() }));
+    predictor.set_max_batch_size(2);
+    let gauges = Arc::new(SelfPlayGauges::new());
+    predictor.set_gauges(gauges.clone());
+
+    let mut executor = Executor::new();
+    for turn in 1..=5 {
+        let queue = predictor.get_queue();
+        let state = State { turn, ..State::new(&mod_param) };
+        executor.spawn( async move {
+            queue.async_predict("mock".to_string(), state, Priority::Normal).await;
+        });
+    }
+
+    // 1回のpredict_batchで全て解決しますが、ネットワークには2個ずつ渡します
+    executor.poll_all();
+    predictor.predict_batch(&mod_param).unwrap();
+    executor.poll_all();
+    assert!( executor.is_empty() );
+    assert_eq!( vec![2,2,1], *sizes.borrow() );
+    assert!( gauges.render().contains("craft_predictions_total{model=\"mock\"} 5\n") );
+    assert_eq!( HashMap::from([("mock".to_string(),5)]), predictor.prediction_counts() );
+}
+
+#[test]
+fn test_predict_batch_error()
+{
+    use super::executor::Executor;
+
+    // 最初のfail_num回だけ失敗するネットワークです
+    struct FlakyNetwork {
+        fail_num : Cell<usize>,
+    }
+
+    impl Predict for FlakyNetwork {
+        fn predict_batch(&self, states:&[State], _mod_param:&ModifierParameter) -> Result<Vec<(ActionVector,f32)>, Box<dyn std::error::Error>> {
+            if self.fail_num.get() > 0 {
+                self.fail_num.set(self.fail_num.get() - 1);
+                return Err("out of memory".into());
+            }
+            Ok(states.iter().map(|_| ([0.0;ACTION_NUM], 0.5)).collect())
+        }
+    }
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    predictor.insert_network("flaky".to_string(), Box::new(FlakyNetwork { fail_num:Cell::new(1) }));
+    predictor.insert_network("mock".to_string(), Box::new(MockNetwork { value:0.25 }));
+
+    let results = Rc::new(RefCell::new(vec![]));
+    let mut executor = Executor::new();
+    for name in ["flaky","mock"] {
+        let (queue,results) = (predictor.get_queue(),results.clone());
+        let state = State::new(&mod_param);
+        executor.spawn( async move {
+            let ret = queue.async_predict(name.to_string(), state, Priority::Normal).await;
+            results.borrow_mut().push(ret.1);
+        });
+    }
+
+    // 失敗したネットワークのタスクだけ残り、他のネットワークのタスクは解決します
+    executor.poll_all();
+    assert!( predictor.predict_batch(&mod_param).is_err() );
+    executor.poll_all();
+    assert_eq!( vec![0.25], *results.borrow() );
+    assert_eq!( 1, predictor.get_queue().len() );
+
+    // 次の呼び出しで推論し直します
+    predictor.predict_batch(&mod_param).unwrap();
+    executor.poll_all();
+    assert!( executor.is_empty() );
+    assert_eq!( vec![0.25,0.5], *results.borrow() );
 }
 
 impl PredictQueue {
-    pub async fn async_predict( &self, name:String, x:State ) -> (ActionVector,f32) {
-        let pr = PredictResult::new();
-        self.tasks.borrow_mut().entry(name).or_insert(Vec::new()).push( (x,pr.clone()) );
-        pr.await
+    // 溜まっているタスクをキャッシュを使わずにfで推論します。MCTSContext::search_blockingで使います
+    pub fn resolve_with<F>(&self, f:F)
+        where F : FnMut(&str,&[State]) -> Vec<(ActionVector,f32)>
+    {
+        resolve_tasks( &mut self.tasks.borrow_mut(), None, None, f );
+        self.limit.wake_all();
+    }
+
+    // 溜まっているタスクを推論せずに捨てます。待っていたasync_predictは二度と起こされないので、終了する時だけ使います
+    pub fn clear(&self) {
+        self.tasks.borrow_mut().clear();
+        self.limit.wake_all();
+    }
+
+    // 推論を待っているタスクの数です
+    pub fn len(&self) -> usize {
+        self.tasks.borrow().values().map(|x| x.len()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub async fn async_predict( &self, name:String, x:State, priority:Priority ) -> (ActionVector,f32) {
+        self.async_predict_with_setting(name, None, x, priority).await
+    }
+
+    // settingで指定した設定で状態を符号化して推論します。Noneの場合はasync_predictと同じです
+    pub async fn async_predict_with_setting( &self, name:String, setting:Option<&'static str>, x:State, priority:Priority ) -> (ActionVector,f32) {
+        if let Some(ret) = self.lru_cache.borrow_mut().get(&cache_name(&name, setting), &x) {
+            return ret;
+        }
+
+        // 上限に達している場合は、他のタスクが推論されて空きができるまで待ちます
+        WaitForSpace { queue:self }.await;
+
+        let pr = self.pool.acquire();
+        self.tasks.borrow_mut().entry((priority,name,setting)).or_default().push( (x,pr.clone()) );
+        let ret = pr.clone().await;
+        self.pool.release(pr);
+        ret
+    }
+}
+
+#[test]
+fn test_queue_len()
+{
+    use super::executor::Executor;
+
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    let queue = predictor.get_queue();
+    let mut executor = Executor::new();
+    for name in ["a","a","b"] {
+        let queue = queue.clone();
+        executor.spawn( async move {
+            queue.async_predict(name.to_string(), State::new(&ModifierParameter::new_fountain_of_usouso()), Priority::Normal).await;
+        });
+    }
+    assert!( queue.is_empty() );
+
+    executor.poll_all();
+    assert_eq!( 3, queue.len() );
+
+    predictor.predict_batch_with( |_,source| source.iter().map(|_| ([0.0;ACTION_NUM],0.0)).collect() );
+    assert!( queue.is_empty() );
+}
+
+#[test]
+fn test_max_queued_tasks()
+{
+    use super::executor::Executor;
+
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    predictor.set_max_queued_tasks(2);
+    let queue = predictor.get_queue();
+    let done = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for turn in 0..5 {
+        let (queue,done) = (queue.clone(),done.clone());
+        executor.spawn( async move {
+            let mut state = State::new(&ModifierParameter::new_fountain_of_usouso());
+            state.turn = turn;
+            queue.async_predict("a".to_string(), state, Priority::Normal).await;
+            done.set(done.get() + 1);
+        });
+    }
+
+    // 上限を超えた分は空きができるまで待ちます
+    let mock = |_:&str, source:&[State]| -> Vec<(ActionVector,f32)> { source.iter().map(|_| ([0.0;ACTION_NUM],0.0)).collect() };
+    let mut lens = vec![];
+    while !executor.is_empty() {
+        executor.poll_all();
+        lens.push(queue.len());
+        predictor.predict_batch_with(mock);
     }
+    assert_eq!( vec![2,2,1,0], lens );
+    assert_eq!( 5, done.get() );
 }
@@ -3,29 +3,348 @@ use std::collections::HashMap;
 use num::FromPrimitive;
 
 use bzip2::read::BzDecoder;
+#[cfg(test)]
+use xorshift::SeedableRng;
+use tracing::info;
 
 use super::logic::*;
+use super::mcts::ActionVector;
 use super::selfplay::*;
 use super::gcs::*;
+use super::setting::ModifierParameter;
+use super::error::{CraftSimError,storage_error};
+use serde::{Serialize,Deserialize};
 
-fn get_records( record_name: String ) -> Vec<Record> {
-    eprintln!("{} Downloading...", record_name);
+// 過去のレコードの読み込み元です
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub enum RecordSource {
+    File(String), // ローカルにあるレコードファイル(record.bincode.bz2形式)
+    Blob(String), // アップロード済みのレコード名(record/{name}.bz2)
+}
 
-    // レコード取得
-    let path = format!("record/{}.bz2", record_name);
-    std::fs::create_dir_all("record").unwrap();
-    download(&path,&path).unwrap();
+// レコードファイルの先頭に付ける目印です。
+// 目印の無い古いファイルは先頭がレコード数(u64)なので、この値と重なることはありません
+const RECORD_FILE_MAGIC : [u8;8] = *b"CSRECORD";
 
-    eprintln!("{} Done.", record_name);
+// レコードファイルの形式の版です。目印の後ろにu32で書きます。
+// RecordやSampleのフィールドを変えた時は版を上げて、deserialize_recordsに前の版を読む処理を足してください
+// 1: 目印の無い最初の形式です(LegacyRecord)
+// 2: 生成したスレッドや探索の結果、シード、設定などを足した形式です(RecordV2)
+// 3: 間引く前に計算したバリューの教師(Sample::value_target)を足した形式です
+const RECORD_FORMAT_VERSION : u32 = 3;
 
+// 版1のサンプルです
+#[derive(Deserialize)]
+struct LegacySample {
+    action : Action,
+    state : State,
+    mcts_policy : ActionVector,
+}
+
+// 版1のレコードです
+#[derive(Deserialize)]
+struct LegacyRecord {
+    samples : Vec<LegacySample>,
+    name : String,
+    last_state : State,
+    reward : f32,
+}
+
+impl From<LegacyRecord> for Record {
+    // 版1に無い値は分からないので、何もしなかった場合の値で埋めます
+    fn from(x: LegacyRecord) -> Record {
+        let samples = x.samples.into_iter().map(|s| Sample { action:s.action, state:s.state, mcts_policy:s.mcts_policy, visit_counts:[0.0;ACTION_NUM], root_value:0.0, value_pred:0.0, raw_prior:None, aux_targets:vec![], timing:None, value_target:None }).collect();
+        Record { samples, name:x.name, last_state:x.last_state, reward:x.reward, thread_id:0, coroutine_id:0, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[0,0], setting:None, phase:0 }
+    }
+}
+
+// 版2のサンプルです
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct SampleV2 {
+    action : Action,
+    state : State,
+    mcts_policy : ActionVector,
+    visit_counts : ActionVector,
+    root_value : f32,
+    value_pred : f32,
+    raw_prior : Option<ActionVector>,
+    aux_targets : Vec<f32>,
+    timing : Option<SearchTiming>,
+}
+
+// 版2のレコードです
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct RecordV2 {
+    samples : Vec<SampleV2>,
+    name : String,
+    last_state : State,
+    reward : f32,
+    thread_id : u32,
+    coroutine_id : u32,
+    adversarial : bool,
+    resign : ResignOutcome,
+    truncated : bool,
+    seeds : [u64;2],
+    setting : Option<String>,
+}
+
+impl From<RecordV2> for Record {
+    // 版2では間引いたサンプルのバリューの教師を残していないので、formatterで計算し直します
+    fn from(x: RecordV2) -> Record {
+        let samples = x.samples.into_iter().map(|s| Sample { action:s.action, state:s.state, mcts_policy:s.mcts_policy, visit_counts:s.visit_counts, root_value:s.root_value, value_pred:s.value_pred, raw_prior:s.raw_prior, aux_targets:s.aux_targets, timing:s.timing, value_target:None }).collect();
+        Record { samples, name:x.name, last_state:x.last_state, reward:x.reward, thread_id:x.thread_id, coroutine_id:x.coroutine_id, adversarial:x.adversarial, resign:x.resign, truncated:x.truncated, seeds:x.seeds, setting:x.setting, phase:0 }
+    }
+}
+
+pub fn serialize_records( records:&[Record] ) -> Result<Vec<u8>,CraftSimError> {
+    let mut serialized = RECORD_FILE_MAGIC.to_vec();
+    serialized.extend_from_slice(&RECORD_FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut serialized, records)?;
+    Ok(serialized)
+}
+
+pub fn deserialize_records( serialized:&[u8] ) -> Result<Vec<Record>,CraftSimError> {
+    let body = match serialized.strip_prefix(&RECORD_FILE_MAGIC[..]) {
+        Some(body) => body,
+        None => {
+            let legacy : Vec<LegacyRecord> = bincode::deserialize(serialized)?;
+            return Ok(legacy.into_iter().map(Record::from).collect());
+        },
+    };
+
+    if body.len() < 4 {
+        return Err(CraftSimError::Serialization(Box::new(bincode::ErrorKind::Custom("record file has no format version".to_string()))));
+    }
+    let (version,body) = body.split_at(4);
+    match u32::from_le_bytes([version[0],version[1],version[2],version[3]]) {
+        2 => {
+            let records : Vec<RecordV2> = bincode::deserialize(body)?;
+            Ok(records.into_iter().map(Record::from).collect())
+        },
+        RECORD_FORMAT_VERSION => Ok(bincode::deserialize(body)?),
+        version => Err(CraftSimError::Serialization(Box::new(bincode::ErrorKind::Custom(format!("unsupported record format version {}", version))))),
+    }
+}
+
+#[test]
+fn test_deserialize_records()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let record = new_test_episode(&mod_param);
+    let samples_len = record.samples.len();
+
+    // 今の形式は版の付いたまま読み書きできます
+    let serialized = serialize_records(&[record]).unwrap();
+    assert_eq!( &RECORD_FILE_MAGIC[..], &serialized[..8] );
+    let records = deserialize_records(&serialized).unwrap();
+    assert_eq!( 1, records.len() );
+    assert_eq!( samples_len, records[0].samples.len() );
+    assert_eq!( [0,0], records[0].seeds );
+
+    // 版の無い古い形式も読めます
+    #[derive(Serialize)]
+    struct OldSample { action:Action, state:State, mcts_policy:ActionVector }
+    #[derive(Serialize)]
+    struct OldRecord { samples:Vec<OldSample>, name:String, last_state:State, reward:f32 }
+    let state = State::new(&mod_param);
+    let old = vec![OldRecord { samples:vec![OldSample { action:Action::BasicSynthesis, state:state.clone(), mcts_policy:[0.5;ACTION_NUM] }], name:"old".to_string(), last_state:state.clone(), reward:0.25 }];
+    let records = deserialize_records(&bincode::serialize(&old).unwrap()).unwrap();
+    assert_eq!( 1, records.len() );
+    assert_eq!( "old", records[0].name );
+    assert_eq!( 0.25, records[0].reward );
+    assert_eq!( state, records[0].last_state );
+    assert_eq!( [0.5;ACTION_NUM], records[0].samples[0].mcts_policy );
+    assert!( records[0].setting.is_none() );
+
+    // 版2の形式も読めます
+    let v2 = vec![RecordV2 { samples:vec![SampleV2 { action:Action::BasicSynthesis, state:state.clone(), mcts_policy:[0.5;ACTION_NUM], visit_counts:[2.0;ACTION_NUM], root_value:0.5, value_pred:0.25, raw_prior:None, aux_targets:vec![1.0], timing:None }], name:"v2".to_string(), last_state:state.clone(), reward:0.75, thread_id:3, coroutine_id:4, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[5,6], setting:None }];
+    let mut serialized_v2 = RECORD_FILE_MAGIC.to_vec();
+    serialized_v2.extend_from_slice(&2u32.to_le_bytes());
+    bincode::serialize_into(&mut serialized_v2, &v2).unwrap();
+    let records = deserialize_records(&serialized_v2).unwrap();
+    assert_eq!( 1, records.len() );
+    assert_eq!( (3,4,[5,6]), (records[0].thread_id,records[0].coroutine_id,records[0].seeds) );
+    assert_eq!( 0.25, records[0].samples[0].value_pred );
+    assert!( records[0].samples[0].value_target.is_none() );
+
+    // 知らない版は読めません
+    let mut unknown = serialized.clone();
+    unknown[8] = 0xff;
+    assert!( matches!( deserialize_records(&unknown), Err(CraftSimError::Serialization(_)) ) );
+}
+
+pub fn read_records_file( path: &str ) -> Result<Vec<Record>,CraftSimError> {
     // デコード
-    let file = std::fs::File::open(&path).unwrap();
+    let file = std::fs::File::open(path)?;
     let mut reader = BufReader::new(BzDecoder::new(file));
     let mut serialized = Vec::new();
-    reader.read_to_end(&mut serialized).unwrap();
+    reader.read_to_end(&mut serialized)?;
 
     // デシリアライズ
-    bincode::deserialize(&serialized).unwrap()
+    deserialize_records(&serialized)
+}
+
+#[test]
+fn test_read_records_file_error()
+{
+    let path = std::env::temp_dir().join(format!("records_missing_{}.bz2", std::process::id()));
+    assert!( matches!( read_records_file(path.to_str().unwrap()), Err(CraftSimError::Io(_)) ) );
+}
+
+pub fn get_records( record_name: String ) -> Result<Vec<Record>,CraftSimError> {
+    info!(record = %record_name, "{} Downloading...", record_name);
+
+    // レコード取得
+    let path = format!("record/{}.bz2", record_name);
+    std::fs::create_dir_all("record")?;
+    download(&path,&path).map_err(storage_error)?;
+
+    info!(record = %record_name, "{} Done.", record_name);
+
+    read_records_file(&path)
+}
+
+pub fn load_records( source: &RecordSource ) -> Result<Vec<Record>,CraftSimError> {
+    match source {
+        RecordSource::File(path) => read_records_file(path),
+        RecordSource::Blob(name) => get_records(name.clone()),
+    }
+}
+
+// 起動時の検証で使うレコードの最大数です
+const STARTUP_VERIFY_RECORD_NUM : usize = 16;
+
+// 開始状態の状態(Condition)は乱数で決まるので比較の対象から外します
+fn without_condition( s:&State ) -> State {
+    State { condition:Condition::Standard, ..s.clone() }
+}
+
+// sでaを実行した結果がnextになり得るかどうかを調べます。
+// 成功と失敗、次の状態の全ての組み合わせと比べるので、どの乱数で作ったレコードでも判定できます
+fn can_reproduce( s:&State, a:&Action, next:&State, mod_param:&ModifierParameter ) -> bool {
+    s.reachable_states(mod_param, a).contains(next)
+}
+
+// レコードが現在のロジックで再現できるかを調べます。
+// ロジックを変更した後に、古いデータと食い違うデータを追加してしまうのを防ぐためのものです
+pub fn verify_record( record:&Record, mod_param:&ModifierParameter ) -> Result<(),String> {
+    if let Some(first) = record.samples.first() {
+        // 難しい開始状態から始めたレコードは初期CPだけ違います
+        let initial = if record.adversarial { State { cp:first.state.cp, ..State::new(mod_param) } } else { State::new(mod_param) };
+        if without_condition(&first.state) != without_condition(&initial) {
+            return Err(format!("initial state does not match {:?}", first.state));
+        }
+    }
+
+    for (t,sample) in record.samples.iter().enumerate() {
+        let next = record.samples.get(t+1).map(|x| &x.state).unwrap_or(&record.last_state);
+        if !can_reproduce(&sample.state, &sample.action, next, mod_param) {
+            return Err(format!("turn {} {:?} does not reproduce {:?} -> {:?}", sample.state.turn, sample.action, sample.state, next));
+        }
+    }
+
+    Ok(())
+}
+
+// レコードを作った時の設定です。mixed_settingsで選んだ設定が残っていればそれを、無ければdefaultを使います
+fn record_mod_param( record:&Record, default:&ModifierParameter ) -> Result<ModifierParameter,String> {
+    match &record.setting {
+        Some(name) => ModifierParameter::from_preset_name(name).ok_or_else(|| format!("unknown setting {}", name)),
+        None => Ok(default.clone()),
+    }
+}
+
+// 起動時の検証です。再現できないレコードがあればエラーを返します。
+// 設定を混ぜて作ったレコードは、それぞれのレコードの設定で検証します
+pub fn verify_startup_records( source:&RecordSource, mod_param:&ModifierParameter ) -> Result<(),CraftSimError> {
+    let records = load_records(source)?;
+    for record in records.iter().take(STARTUP_VERIFY_RECORD_NUM) {
+        record_mod_param(record, mod_param)
+            .and_then(|x| verify_record(record, &x))
+            .map_err(|e| CraftSimError::LogicViolation(format!("record of {} can not be reproduced by current logic: {}", record.name, e)))?;
+    }
+    info!("verified {} records.", records.len().min(STARTUP_VERIFY_RECORD_NUM));
+    Ok(())
+}
+
+#[cfg(test)]
+fn new_test_episode( mod_param:&ModifierParameter ) -> Record {
+    let seeds = [1, 2];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let actions = [Action::Reflect, Action::HastyTouch, Action::BasicTouch, Action::RapidSynthesis, Action::BasicSynthesis];
+    let mut state = State::new(mod_param);
+    let mut samples = vec![];
+
+    for action in actions.iter().cycle().take(20) {
+        if state.is_terminated() {
+            break;
+        }
+        if state.check_action(action) {
+            samples.push( Sample { action:*action, state:state.clone(), mcts_policy:[0.0;ACTION_NUM], visit_counts:[0.0;ACTION_NUM], root_value:0.0, value_pred:0.0, raw_prior:None, aux_targets:vec![], timing:None, value_target:None } );
+            state = state.run_action(&mut modifier, action);
+        }
+    }
+
+    Record { samples, name:"test".to_string(), last_state:state, reward:0.0, thread_id:0, coroutine_id:0, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[0,0], setting:None, phase:0 }
+}
+
+#[test]
+fn test_verify_record()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut record = new_test_episode(&mod_param);
+    assert!( record.samples.len() > 5 );
+    assert_eq!( Ok(()), verify_record(&record, &mod_param) );
+
+    // ロジックが変わって品質の上がり方が変わった場合を再現します
+    record.samples[3].state.quality += 1;
+    assert!( verify_record(&record, &mod_param).is_err() );
+}
+
+#[cfg(test)]
+fn verify_test_records_file( name:&str, records:Vec<Record>, mod_param:&ModifierParameter ) -> Result<(),CraftSimError> {
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("records_{}_{}.bz2", name, std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    {
+        let file = std::fs::File::create(&path).unwrap();
+        let mut writer = bzip2::write::BzEncoder::new(file, bzip2::Compression::fast());
+        writer.write_all(&serialize_records(&records).unwrap()).unwrap();
+    }
+
+    let ret = verify_startup_records(&RecordSource::File(path.clone()), mod_param);
+    std::fs::remove_file(&path).unwrap();
+    ret
+}
+
+#[test]
+fn test_verify_startup_records_error()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let mut record = new_test_episode(&mod_param);
+    record.samples[3].state.quality += 1;
+
+    let ret = verify_test_records_file("tampered", vec![record], &mod_param);
+    assert!( matches!( ret, Err(CraftSimError::LogicViolation(_)) ) );
+}
+
+#[test]
+fn test_verify_startup_records_mixed_settings()
+{
+    // 別の設定で作ったレコードも、レコードに残った設定で検証します
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let other = ModifierParameter::new_ishgard_reconstruction_4th();
+    let new_other_record = |setting:Option<&str>| Record { setting:setting.map(|x| x.to_string()), ..new_test_episode(&other) };
+    assert!( verify_record(&new_other_record(None), &mod_param).is_err() );
+    let records = vec![new_other_record(other.preset_name()), new_test_episode(&mod_param)];
+    assert!( verify_test_records_file("mixed", records, &mod_param).is_ok() );
+
+    // 知らない設定の名前が残っている場合は検証できません
+    let records = vec![new_other_record(Some("unknown"))];
+    assert!( matches!( verify_test_records_file("unknown_setting", records, &mod_param), Err(CraftSimError::LogicViolation(_)) ) );
 }
 
 const HEADER: [&str; 16] = [
@@ -110,12 +429,12 @@ fn write_skill_histogram( counter: &HashMap<(Action,Condition),u32> ) {
     }
 }
 
-pub fn run_replay( record_names:Vec<String> ) {
+pub fn run_replay( record_names:Vec<String> ) -> Result<(),CraftSimError> {
 
     let mut counter : HashMap<(Action,Condition),u32> = HashMap::new();
 
     for record_name in record_names {
-        let records = get_records(record_name);
+        let records = get_records(record_name)?;
 
         for record in records {
             write_record( &record );
@@ -124,4 +443,5 @@ pub fn run_replay( record_names:Vec<String> ) {
     }
 
     write_skill_histogram( &counter );
+    Ok(())
 }
@@ -2,19 +2,39 @@ use std::sync::{Arc,Mutex};
 
 use mysql::*;
 use mysql::prelude::*;
+use tracing::info;
+
+use rand::{Rng,FromEntropy};
+use rand::rngs::StdRng;
+use rand::distributions::{Beta,Distribution};
 
 use super::network::*;
+use super::error::CraftSimError;
+use super::db;
+use serde::{Serialize,Deserialize};
 
-#[derive(Debug,Clone)]
+#[derive(Serialize,Deserialize,Debug,Clone)]
 pub enum Selector {
     UCB1(f64),
-    Optimistic(usize),
+    // 評価ごとの平均報酬に、initial_rewardをprior_count回取ったという擬似的な結果を足して比べます。
+    // 評価の少ないモデルはinitial_rewardに近い値になるので、大きくするほど新しいモデルを長く試します
+    Optimistic { initial_reward:f64, prior_count:f64 },
     Greedy(usize),
+    Thompson(f64),
+    EpsilonGreedy(f32),
+}
+
+impl Selector {
+    // 最良の報酬1.0をprior_count回取ったと見なす楽観的初期化です
+    pub fn optimistic(prior_count:f64) -> Selector {
+        Selector::Optimistic { initial_reward:1.0, prior_count }
+    }
 }
 
 #[derive(Clone)]
 pub struct UCB1Context {
     mysql_pool : Arc<Mutex<Pool>>,
+    rng : StdRng, // モデル選択の乱数です。セルフプレイの乱数の系列とは別にしておきます
 }
 
 #[derive(Debug)]
@@ -31,6 +51,32 @@ impl std::convert::From<mysql::Error> for Error {
     }
 }
 
+impl Error {
+    // データベースの再起動などで一時的に起きるエラーかどうかです
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::MySQLError(e) => db::is_transient(e),
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_is_transient()
+{
+    let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+    assert!( Error::MySQLError(mysql::Error::IoError(io)).is_transient() );
+    assert!( Error::MySQLError(mysql::Error::DriverError(DriverError::Timeout)).is_transient() );
+    assert!( !Error::MySQLError(mysql::Error::DriverError(DriverError::MixedParams)).is_transient() );
+
+    let server_error = |code:u16| Error::MySQLError(mysql::Error::MySqlError(MySqlError { state:"HY000".to_string(), message:"error".to_string(), code }));
+    assert!( server_error(2006).is_transient() );
+    assert!( !server_error(1146).is_transient() ); // 表が無い
+
+    assert!( !Error::InvalidNetworkType("x".to_string()).is_transient() );
+    assert!( !Error::Empty.is_transient() );
+}
+
 // UCB1法
 // cは探索に使うパラメータで、大きくなればなるほど活用よりも探索を大きく見積もります
 fn get_ucb1_model(conn:&mut PooledConn, c:f64) -> std::result::Result<String,Error> {
@@ -58,18 +104,52 @@ fn get_ucb1_model(conn:&mut PooledConn, c:f64) -> std::result::Result<String,Err
     }
 }
 
-// 楽観的初期化法
-// nは最良値(==1.0)を取ったとする期待値の回数を指定しておきます
-fn get_optimistic_model(conn:&mut PooledConn , n:usize) -> std::result::Result<String,Error> {
-    // 1個だけ取得してその結果を返します。ここでvalueは取る必要ない
-    let res : Option<(String,f64)> = conn.query_first(format!("SELECT name, (total_reward+{})/(total_count+{}) as value FROM evaluation ORDER BY value DESC LIMIT 1",n,n))?;
+// 楽観的初期化法の評価値です。評価回数が増えるほど実際の平均報酬に近づきます
+fn optimistic_value(total_reward:f64, total_count:f64, initial_reward:f64, prior_count:f64) -> f64 {
+    (total_reward + initial_reward * prior_count) / (total_count + prior_count)
+}
 
-    if let Some((name,_)) = res {
-        Ok(name)
-    }
-    else {
-        Err(Error::Empty)
+// 評価値が最大のモデルを選びます。同じ値なら先のものです
+fn select_optimistic(rows:&[(String,f64,f64)], initial_reward:f64, prior_count:f64) -> Option<String> {
+    rows.iter()
+        .map(|(name,reward,count)| (name, optimistic_value(*reward, *count, initial_reward, prior_count)))
+        .fold(None, |best:Option<(&String,f64)>, (name,v)| match best {
+            Some((_,best_v)) if best_v >= v => best,
+            _ => Some((name,v)),
+        })
+        .map(|(name,_)| name.clone())
+}
+
+#[test]
+fn test_select_optimistic()
+{
+    assert_eq!( None, select_optimistic(&[], 1.0, 10.0) );
+
+    // 平均0.8の実績があるモデルに、平均0.5の新しいモデルを足して評価を重ねていきます。
+    // (0.5k+10)/(k+10)が0.8を下回る7回目までは新しいモデルを選びます
+    let mut rows = vec![("incumbent".to_string(),800.0,1000.0), ("new".to_string(),0.0,0.0)];
+    for k in 0..20 {
+        let selected = select_optimistic(&rows, 1.0, 10.0).unwrap();
+        assert_eq!( if k < 7 { "new" } else { "incumbent" }, selected, "{}", k );
+        rows[1].1 += 0.5;
+        rows[1].2 += 1.0;
     }
+
+    // 初期値を実績より低くすれば、新しいモデルは最初から選ばれません
+    let rows = vec![("incumbent".to_string(),800.0,1000.0), ("new".to_string(),0.0,0.0)];
+    assert_eq!( Some("incumbent".to_string()), select_optimistic(&rows, 0.7, 10.0) );
+
+    // 擬似的な回数が多いほど長く試します
+    let rows = vec![("incumbent".to_string(),800.0,1000.0), ("new".to_string(),10.0,20.0)];
+    assert_eq!( Some("incumbent".to_string()), select_optimistic(&rows, 1.0, 10.0) );
+    assert_eq!( Some("new".to_string()), select_optimistic(&rows, 1.0, 100.0) );
+}
+
+// 楽観的初期化法
+// UCB1と同じ評価の行を使って、楽観的な評価値が最大のモデルを選びます
+fn get_optimistic_model(conn:&mut PooledConn, initial_reward:f64, prior_count:f64) -> std::result::Result<String,Error> {
+    let res : Vec<(String,f64,f64)> = conn.query("SELECT name, total_reward, total_count FROM evaluation")?;
+    select_optimistic(&res, initial_reward, prior_count).ok_or(Error::Empty)
 }
 
 fn get_greedy_model(conn:&mut PooledConn, threshold:usize) -> std::result::Result<String,Error> {
@@ -84,6 +164,109 @@ fn get_greedy_model(conn:&mut PooledConn, threshold:usize) -> std::result::Resul
     }
 }
 
+// トンプソンサンプリングで使う、報酬の事後分布のベータ分布のパラメータです。
+// 報酬は[0,1]なので、合計報酬を成功の回数、残りを失敗の回数と見なします。
+// priorは事前分布Beta(prior,prior)の擬似的な回数です
+fn beta_posterior(total_reward:f64, total_count:f64, prior:f64) -> (f64,f64) {
+    (prior + total_reward, prior + (total_count - total_reward).max(0.0))
+}
+
+#[test]
+fn test_beta_posterior()
+{
+    assert_eq!( (1.0,1.0), beta_posterior(0.0, 0.0, 1.0) );
+    assert_eq!( (8.0,4.0), beta_posterior(7.0, 10.0, 1.0) );
+    assert_eq!( (2.5,0.5), beta_posterior(2.0, 2.0, 0.5) );
+
+    // 事後分布の平均は(prior+報酬)/(2prior+回数)です
+    let (a,b) = beta_posterior(60.0, 100.0, 1.0);
+    assert!( (a/(a+b) - 61.0/102.0).abs() < 1e-12 );
+}
+
+// 各モデルの事後分布から1つずつ値を引いて、最大のものを選びます
+fn select_thompson<R:Rng>(rows:&[(String,f64,f64)], prior:f64, rng:&mut R) -> Option<String> {
+    rows.iter()
+        .map(|(name,reward,count)| {
+            let (a,b) = beta_posterior(*reward, *count, prior);
+            (name, Beta::new(a,b).sample(rng))
+        })
+        .fold(None, |best:Option<(&String,f64)>, (name,v)| match best {
+            Some((_,best_v)) if best_v >= v => best,
+            _ => Some((name,v)),
+        })
+        .map(|(name,_)| name.clone())
+}
+
+#[test]
+fn test_select_thompson()
+{
+    use rand::SeedableRng;
+
+    let mut rng = StdRng::seed_from_u64(1);
+    assert_eq!( None, select_thompson(&[], 1.0, &mut rng) );
+
+    // 証拠が十分にあれば良い方をほぼ必ず選びます
+    let rows = vec![("bad".to_string(),300.0,1000.0), ("good".to_string(),700.0,1000.0)];
+    assert!( (0..100).all(|_| select_thompson(&rows, 1.0, &mut rng) == Some("good".to_string())) );
+
+    // 評価の少ないモデルは平均が低くても時々選ばれます
+    let rows = vec![("known".to_string(),600.0,1000.0), ("new".to_string(),1.0,2.0)];
+    let new_count = (0..1000).filter(|_| select_thompson(&rows, 1.0, &mut rng) == Some("new".to_string())).count();
+    assert!( new_count > 100 && new_count < 600, "{}", new_count );
+}
+
+// トンプソンサンプリング
+// UCB1と同じ評価の行を使って、報酬の事後分布から引いた値の最も大きいモデルを選びます
+fn get_thompson_model<R:Rng>(conn:&mut PooledConn, prior:f64, rng:&mut R) -> std::result::Result<String,Error> {
+    let res : Vec<(String,f64,f64)> = conn.query("SELECT name, total_reward, total_count FROM evaluation")?;
+    select_thompson(&res, prior, rng).ok_or(Error::Empty)
+}
+
+// epsilonの確率で全てのモデルから一様に選び、それ以外は平均報酬が最良のモデルを選びます。
+// 評価回数0のモデルは平均が無いので、ランダムに選ばれた時だけ遊ばれます
+fn select_epsilon_greedy<R:Rng>(rows:&[(String,f64,f64)], epsilon:f32, rng:&mut R) -> Option<String> {
+    if rows.is_empty() {
+        return None;
+    }
+    let evaluated : Vec<&(String,f64,f64)> = rows.iter().filter(|(_,_,count)| *count > 0.0).collect();
+    if evaluated.is_empty() || rng.gen::<f32>() < epsilon {
+        return Some(rows[rng.gen_range(0, rows.len())].0.clone());
+    }
+    evaluated.iter()
+        .fold(None, |best:Option<(&String,f64)>, (name,reward,count)| match best {
+            Some((_,best_v)) if best_v >= reward/count => best,
+            _ => Some((name,reward/count)),
+        })
+        .map(|(name,_)| name.clone())
+}
+
+#[test]
+fn test_select_epsilon_greedy()
+{
+    use rand::SeedableRng;
+
+    let mut rng = StdRng::seed_from_u64(1);
+    assert_eq!( None, select_epsilon_greedy(&[], 0.5, &mut rng) );
+
+    let rows = vec![("a".to_string(),30.0,100.0), ("b".to_string(),70.0,100.0), ("c".to_string(),0.0,0.0)];
+    assert!( (0..100).all(|_| select_epsilon_greedy(&rows, 0.0, &mut rng) == Some("b".to_string())) );
+
+    // epsilonが1なら全てのモデルが一様に選ばれます
+    let counts : Vec<usize> = ["a","b","c"].iter().map(|name| {
+        (0..3000).filter(|_| select_epsilon_greedy(&rows, 1.0, &mut rng) == Some(name.to_string())).count()
+    }).collect();
+    assert!( counts.iter().all(|x| *x > 800 && *x < 1200), "{:?}", counts );
+
+    // まだ誰も評価されていなければランダムに選びます
+    let rows = vec![("x".to_string(),0.0,0.0)];
+    assert_eq!( Some("x".to_string()), select_epsilon_greedy(&rows, 0.0, &mut rng) );
+}
+
+fn get_epsilon_greedy_model<R:Rng>(conn:&mut PooledConn, epsilon:f32, rng:&mut R) -> std::result::Result<String,Error> {
+    let res : Vec<(String,f64,f64)> = conn.query("SELECT name, total_reward, total_count FROM evaluation")?;
+    select_epsilon_greedy(&res, epsilon, rng).ok_or(Error::Empty)
+}
+
 pub fn get_network_type(conn:&mut PooledConn, name:&str) -> std::result::Result<NetworkType,Error> {
     let res : Option<String> = conn.exec_first("SELECT type FROM network WHERE name=:name", params!{"name"=>name} )?;
 
@@ -98,9 +281,65 @@ pub fn get_network_type(conn:&mut PooledConn, name:&str) -> std::result::Result<
     }
 }
 
+// 昇格の条件を満たしているかどうかです。
+// 勝率は評価エピソードのうち、チャンピオンの平均報酬を上回った割合です。
+// 勝率はmin_winrateを上回る必要があり、ちょうど同じ場合は昇格しません
+fn is_eligible(games:u64, wins:u64, min_games:u64, min_winrate:f64) -> bool {
+    games >= min_games && games > 0 && wins as f64 / games as f64 > min_winrate
+}
+
+#[test]
+fn test_is_eligible()
+{
+    // 評価回数が足りない場合
+    assert!( !is_eligible(99, 99, 100, 0.55) );
+    assert!( is_eligible(100, 56, 100, 0.55) );
+
+    // 勝率が閾値ちょうど以下の場合
+    assert!( !is_eligible(100, 55, 100, 0.55) );
+    assert!( !is_eligible(200, 109, 100, 0.55) );
+    assert!( is_eligible(200, 111, 100, 0.55) );
+
+    assert!( !is_eligible(0, 0, 0, 0.0) );
+}
+
+// 95%信頼区間のz値です
+const WILSON_Z : f64 = 1.96;
+
+// 勝率のWilsonスコア信頼区間です。
+// 評価回数が少ない時に、見かけの勝率の差が意味のあるものかを判断するために使います
+pub fn wilson_interval(wins:u64, games:u64, z:f64) -> (f64,f64) {
+    if games == 0 {
+        return (0.0,1.0);
+    }
+
+    let n = games as f64;
+    let p = wins as f64 / n;
+    let z2 = z * z;
+    let denominator = 1.0 + z2 / n;
+    let center = (p + z2 / (2.0 * n)) / denominator;
+    let half_width = z / denominator * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((center - half_width).max(0.0), (center + half_width).min(1.0))
+}
+
+#[test]
+fn test_wilson_interval()
+{
+    let assert_near = |expected:(f64,f64), actual:(f64,f64)| {
+        assert!( (expected.0 - actual.0).abs() < 1e-4, "{:?} {:?}", expected, actual );
+        assert!( (expected.1 - actual.1).abs() < 1e-4, "{:?} {:?}", expected, actual );
+    };
+
+    assert_near( (0.2366,0.7634), wilson_interval(5, 10, WILSON_Z) );
+    assert_near( (0.5693,0.6299), wilson_interval(600, 1000, WILSON_Z) );
+    assert_near( (0.0,0.2775), wilson_interval(0, 10, WILSON_Z) );
+    assert_near( (0.7225,1.0), wilson_interval(10, 10, WILSON_Z) );
+    assert_eq!( (0.0,1.0), wilson_interval(0, 0, WILSON_Z) );
+}
+
 impl UCB1Context {
     pub fn new( mysql_pool : Arc<Mutex<Pool>> ) -> UCB1Context {
-        UCB1Context { mysql_pool : mysql_pool }
+        UCB1Context { mysql_pool, rng : StdRng::from_entropy() }
     }
 
     pub fn get_model(&mut self, selector:&Selector) -> std::result::Result<(String,NetworkType),Error> {
@@ -108,11 +347,102 @@ impl UCB1Context {
 
         let model_name = match *selector {
             Selector::UCB1(x) => get_ucb1_model(&mut conn, x)?,
-            Selector::Optimistic(x) => get_optimistic_model(&mut conn, x)?,
+            Selector::Optimistic { initial_reward, prior_count } => get_optimistic_model(&mut conn, initial_reward, prior_count)?,
             Selector::Greedy(x) => get_greedy_model(&mut conn, x)?,
+            Selector::Thompson(x) => get_thompson_model(&mut conn, x, &mut self.rng)?,
+            Selector::EpsilonGreedy(x) => get_epsilon_greedy_model(&mut conn, x, &mut self.rng)?,
         };
 
         let network_type = get_network_type(&mut conn, &model_name)?;
         Ok((model_name,network_type))
     }
+
+    // nameのモデルを本番に昇格してよいかを返します。勝率はmin_winrateを上回る必要があります
+    pub fn eligible_for_promotion(&self, name:&str, min_games:u64, min_winrate:f64) -> std::result::Result<bool,Error> {
+        let mut conn = self.mysql_pool.lock().unwrap().get_conn()?;
+        check_promotion(&mut conn, name, min_games, min_winrate)
+    }
+}
+
+// 昇格の判定に使う集計です。テストではMySQLの代わりに手で作った表から集計します
+trait PromotionStore {
+    // name以外でmin_games回以上評価されたモデルのうち、最良の平均報酬です
+    fn champion_value(&mut self, name:&str, min_games:u64) -> std::result::Result<Option<f64>,Error>;
+    // nameの評価エピソードの数と、そのうち報酬がthresholdを上回った数です
+    fn games_and_wins(&mut self, name:&str, threshold:f64) -> std::result::Result<(u64,u64),Error>;
+}
+
+impl PromotionStore for PooledConn {
+    fn champion_value(&mut self, name:&str, min_games:u64) -> std::result::Result<Option<f64>,Error> {
+        let champion : Option<(String,f64)> = self.exec_first(
+            "SELECT name, total_reward/total_count as value FROM evaluation WHERE name<>:name AND total_count>=:min_games ORDER BY value DESC LIMIT 1",
+            params!{"name"=>name, "min_games"=>min_games} )?;
+        Ok(champion.map(|(_,value)| value))
+    }
+
+    fn games_and_wins(&mut self, name:&str, threshold:f64) -> std::result::Result<(u64,u64),Error> {
+        let res : Option<(u64,Option<u64>)> = self.exec_first(
+            "SELECT COUNT(*), SUM(reward>:threshold) FROM episode WHERE name=:name",
+            params!{"name"=>name, "threshold"=>threshold} )?;
+        Ok(res.map(|(games,wins)| (games,wins.unwrap_or(0))).unwrap_or((0,0)))
+    }
+}
+
+// チャンピオンの平均報酬を上回ったエピソードの割合で判定します。
+// チャンピオンがいない場合は評価回数だけで判定します
+fn check_promotion<S:PromotionStore>(store:&mut S, name:&str, min_games:u64, min_winrate:f64) -> std::result::Result<bool,Error> {
+    let threshold = store.champion_value(name, min_games)?.unwrap_or(f64::MIN);
+    let (games,wins) = store.games_and_wins(name, threshold)?;
+    let (lower,upper) = wilson_interval(wins, games, WILSON_Z);
+    info!(model = name, wins, games, "{} wins {}/{} (95% interval {:.3}-{:.3})", name, wins, games, lower, upper);
+    Ok(is_eligible(games, wins, min_games, min_winrate))
+}
+
+#[test]
+fn test_check_promotion()
+{
+    // evaluation表とepisode表の代わりです
+    struct MockStore {
+        evaluations : Vec<(&'static str,f64,u64)>,
+        episodes : Vec<(&'static str,f64)>,
+    }
+
+    impl PromotionStore for MockStore {
+        fn champion_value(&mut self, name:&str, min_games:u64) -> std::result::Result<Option<f64>,Error> {
+            Ok(self.evaluations.iter()
+                .filter(|(x,_,count)| *x != name && *count >= min_games)
+                .map(|(_,reward,count)| reward / *count as f64)
+                .fold(None, |best:Option<f64>,x| Some(best.map_or(x, |best| best.max(x)))))
+        }
+
+        fn games_and_wins(&mut self, name:&str, threshold:f64) -> std::result::Result<(u64,u64),Error> {
+            let rewards : Vec<f64> = self.episodes.iter().filter(|(x,_)| *x == name).map(|(_,reward)| *reward).collect();
+            Ok((rewards.len() as u64, rewards.iter().filter(|x| **x > threshold).count() as u64))
+        }
+    }
+
+    // チャンピオンの平均報酬は0.5です。評価回数が足りないモデルはチャンピオンになりません
+    let new_store = |wins:usize, games:usize| {
+        let mut episodes = vec![("new",0.75); wins];
+        episodes.resize(games, ("new",0.25));
+        MockStore { evaluations:vec![("champion",50.0,100), ("lucky",9.0,9), ("new",0.0,games as u64)], episodes }
+    };
+
+    // 勝率が閾値ちょうどの場合は昇格せず、上回れば昇格します
+    assert!( !check_promotion(&mut new_store(55, 100), "new", 100, 0.55).unwrap() );
+    assert!( check_promotion(&mut new_store(56, 100), "new", 100, 0.55).unwrap() );
+
+    // 評価回数が1回足りない場合は勝率が高くても昇格しません
+    assert!( !check_promotion(&mut new_store(99, 99), "new", 100, 0.55).unwrap() );
+
+    // チャンピオンと同じ報酬は勝ちに数えません
+    let mut store = new_store(0, 0);
+    store.episodes = vec![("new",0.5); 100];
+    assert!( !check_promotion(&mut store, "new", 100, 0.0).unwrap() );
+}
+
+// 外部のスクリプトから昇格の判定をするための入口です
+pub fn run_promotion_check(mysql_url:&str, name:&str, min_games:u64, min_winrate:f64) -> std::result::Result<bool,CraftSimError> {
+    let mysql_pool = db::connect(mysql_url, 1)?;
+    Ok(UCB1Context::new(mysql_pool).eligible_for_promotion(name, min_games, min_winrate)?)
 }
@@ -1,288 +1,2886 @@
 ﻿
-use std::sync::{Arc,Mutex};
-use std::sync::mpsc::{channel,Sender,Receiver,TryRecvError};
+use std::sync::{Arc,Mutex,Condvar,OnceLock};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::sync::mpsc::{channel,sync_channel,Sender,SyncSender,Receiver,TryRecvError,RecvTimeoutError};
 use std::thread::JoinHandle;
 use std::time::{Instant,SystemTime,Duration};
-use std::cell::RefCell;
+use std::cell::{Cell,RefCell};
+use std::path::{Path,PathBuf};
 use std::rc::Rc;
+use std::collections::HashMap;
 
 use mysql::*;
 use serde::{Serialize,Deserialize};
-use xorshift::{SeedableRng};
+use tracing::{info,warn,error,info_span};
+use xorshift::{SeedableRng,Rng,Xorshift128};
 
 use super::selector::{Selector,UCB1Context};
 use super::logic::{State,Action,Modifier};
+#[cfg(test)]
+use super::logic::ACTION_NUM;
 use super::setting::ModifierParameter;
-use super::mcts::{MCTSContext,ActionVector,select_action_weighted,select_action_greedy,get_reward};
+use super::mcts::{MCTSContext,SimulationBudget,ActionVector,GreedyCriterion,TieBreak,AlphaSchedule,GumbelParameter,value_at_turn,parse_turn_breakpoints,select_action_with_temperature,select_action_max_q,Reward,RewardFunction};
 use super::writer::*;
 use super::cache::*;
 use super::executor::*;
 use super::predictor::*;
 use super::network::*;
+use super::replay::{RecordSource,load_records,verify_startup_records};
+use super::formatter::{TsvFormatter,ValueTarget,compute_value_targets};
+use super::metrics::{ActionCounters,EpisodeLengthHistogram,RewardHistogram,SelfPlayGauges,DEFAULT_REWARD_BUCKETS,write_textfile};
+use super::error::CraftSimError;
+use super::db;
+use super::util::{splitmix64,retry_with_backoff};
 
-#[derive(Debug,Clone)]
+#[derive(Serialize,Deserialize,Debug,Clone)]
 pub enum WriterParameter {
     Evaluation,
     Generation,
+    JsonFile(PathBuf), // MySQLを使わずにレコードをJSON Linesでファイルに追記します
+    SqliteEvaluation(PathBuf), // Evaluationと同じ内容をMySQLの代わりにSQLiteのファイルに書き込みます
+    SqliteGeneration(PathBuf), // Generationと同じ内容をMySQLとアップロードの代わりにSQLiteのファイルに書き込みます
 }
 
-#[derive(Clone)]
+impl WriterParameter {
+    // モデルの強さを測るための書き込み先かどうかです
+    pub fn is_evaluation(&self) -> bool {
+        matches!( self, WriterParameter::Evaluation | WriterParameter::SqliteEvaluation(_) )
+    }
+}
+
+// 報酬がNaNやInfになってしまった時の扱いです
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub enum NonFiniteReward {
+    Reject,       // 書き込まずに捨てます
+    Replace(f32), // 指定の値に置き換えて書き込みます
+}
+
+// 終了する時に実行中のエピソードをどうするかです
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum ShutdownMode {
+    Finish,  // 最後まで遊んで送ります。残りの推論の分だけ終了が遅れます
+    Discard, // 途中のエピソードは捨ててすぐに終わります
+}
+
+// 報酬とは別に、終了状態から計算してサンプルに保存する補助的な学習目標です。
+// バリューヘッドを増やして試す時のためのもので、報酬の計算には影響しません
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum AuxTarget {
+    Quality,   // 品質を品質上限で割った値[0,1]
+    Completed, // 完成していれば1、そうでなければ0
+}
+
+impl AuxTarget {
+    pub fn compute(&self, s:&State, mod_param:&ModifierParameter) -> f32 {
+        match self {
+            AuxTarget::Quality => s.quality as f32 / mod_param.max_quality as f32,
+            AuxTarget::Completed => if s.is_completed() { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+impl argh::FromArgValue for AuxTarget {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "quality" => Ok(AuxTarget::Quality),
+            "completed" => Ok(AuxTarget::Completed),
+            _ => Err(format!("unknown aux target {}", value)),
+        }
+    }
+}
+
+// max_turnsの標準の値です。普通のゲームは数十手で終わるので、十分に大きくしてあります
+pub const DEFAULT_MAX_TURNS : u32 = 200;
+
+// 難しい開始状態からセルフプレイするための設定です。
+// 初期CPをmax_cpのmin_cp_ratio倍からmax_cp_ratio倍の間で一様に選んで、CPが足りない場面のデータを集めます
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub struct HardStart {
+    pub min_cp_ratio : f32,
+    pub max_cp_ratio : f32,
+}
+
+impl HardStart {
+    pub fn sample(&self, mod_param:&ModifierParameter, rng:&mut Xorshift128) -> State {
+        let ratio = self.min_cp_ratio + (self.max_cp_ratio - self.min_cp_ratio) * rng.next_f32();
+        State { cp:(mod_param.max_cp as f32 * ratio) as u32, ..State::new(mod_param) }
+    }
+}
+
+// 1つのプロセスで複数の設定を混ぜてセルフプレイする時の、設定とその選ばれやすさです。
+// 設定ファイルには定義済みのものの名前で書くので、推論でも名前で設定を引きます
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct WeightedSetting {
+    pub mod_param : ModifierParameter,
+    pub weight : f32,
+}
+
+// "名前:重み"の形式です。重みを省略した場合は1です
+impl argh::FromArgValue for WeightedSetting {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        let (name,weight) = match value.split_once(':') {
+            Some((name,weight)) => (name, weight.parse::<f32>().map_err(|_| format!("invalid weight of setting {}", value))?),
+            None => (value, 1.0),
+        };
+        if !(weight > 0.0 && weight.is_finite()) {
+            return Err(format!("weight of setting must be positive but {}", value));
+        }
+        let mod_param = ModifierParameter::from_preset_name(name).ok_or_else(|| format!("unknown setting {}", name))?;
+        Ok(WeightedSetting { mod_param, weight })
+    }
+}
+
+// 重みに比例した確率で設定を1つ選びます。空の場合は選ばずに乱数も進めません
+pub fn sample_setting<'a>( settings:&'a [WeightedSetting], rng:&mut Xorshift128 ) -> Option<&'a ModifierParameter> {
+    if settings.is_empty() {
+        return None;
+    }
+    let total : f32 = settings.iter().map(|x| x.weight).sum();
+    let mut r = rng.next_f32() * total;
+    for setting in settings {
+        if r < setting.weight {
+            return Some(&setting.mod_param);
+        }
+        r -= setting.weight;
+    }
+    settings.last().map(|x| &x.mod_param) // 丸め誤差で最後まで届いた場合です
+}
+
+// 設定ごとの学習の重みです。選ばれる確率に反比例させて、選ばれる確率で平均すると1になるようにします。
+// 選ばれにくい設定のサンプルほど重くなるので、学習側で簡単な設定に報酬が偏るのを打ち消せます。
+// 設定を混ぜていない場合や知らない設定の場合は1です
+pub fn setting_weight( settings:&[WeightedSetting], setting:Option<&str> ) -> f32 {
+    let total : f32 = settings.iter().map(|x| x.weight).sum();
+    match setting.and_then(|name| settings.iter().find(|x| x.mod_param.preset_name() == Some(name))) {
+        Some(x) => total / (settings.len() as f32 * x.weight),
+        None => 1.0,
+    }
+}
+
+#[test]
+fn test_setting_weight()
+{
+    use argh::FromArgValue;
+
+    // 3倍選ばれにくい設定は3倍の重みになり、選ばれる確率で平均すると1です
+    let settings = vec![
+        WeightedSetting::from_arg_value("fountain_of_usouso:3").unwrap(),
+        WeightedSetting::from_arg_value("ishgard_reconstruction_4th:1").unwrap(),
+    ];
+    let common = setting_weight(&settings, Some("fountain_of_usouso"));
+    let rare = setting_weight(&settings, Some("ishgard_reconstruction_4th"));
+    assert!( (rare / common - 3.0).abs() < 1e-6 );
+    assert!( (0.75 * common + 0.25 * rare - 1.0).abs() < 1e-6 );
+
+    // 同じ重みなら全て1です
+    let uniform = vec![
+        WeightedSetting::from_arg_value("fountain_of_usouso").unwrap(),
+        WeightedSetting::from_arg_value("ishgard_reconstruction_4th").unwrap(),
+    ];
+    assert_eq!( 1.0, setting_weight(&uniform, Some("fountain_of_usouso")) );
+
+    assert_eq!( 1.0, setting_weight(&settings, None) );
+    assert_eq!( 1.0, setting_weight(&settings, Some("unknown")) );
+    assert_eq!( 1.0, setting_weight(&[], Some("fountain_of_usouso")) );
+}
+
+#[test]
+fn test_sample_setting()
+{
+    use argh::FromArgValue;
+
+    let settings = vec![
+        WeightedSetting::from_arg_value("fountain_of_usouso").unwrap(),
+        WeightedSetting::from_arg_value("ishgard_reconstruction_4th:3").unwrap(),
+    ];
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&[1u64, 2][..]);
+    let n = 10000;
+    let ishgard = (0..n).filter(|_| sample_setting(&settings, &mut rng).unwrap().preset_name() == Some("ishgard_reconstruction_4th")).count();
+    let ratio = ishgard as f64 / n as f64;
+    assert!( (ratio - 0.75).abs() < 0.02, "{}", ratio );
+
+    // 空の場合は乱数を進めません
+    let mut copied = rng;
+    assert!( sample_setting(&[], &mut rng).is_none() );
+    assert_eq!( copied.next_u64(), rng.next_u64() );
+
+    assert!( WeightedSetting::from_arg_value("unknown:1").is_err() );
+    assert!( WeightedSetting::from_arg_value("fountain_of_usouso:0").is_err() );
+    assert!( WeightedSetting::from_arg_value("fountain_of_usouso:x").is_err() );
+}
+
+// 投了や打ち切りで最後まで遊ばなかったエピソードの報酬の付け方です
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum AbortedReward {
+    Fixed(f32),    // 常にこの値です。0にすると最悪の報酬になります
+    ValueEstimate, // 最後に探索したルートの平均評価値です。1回も探索していなければ0です
+    RewardFn,      // 終わっていない最後の状態にreward_fnをそのまま使います
+}
+
+impl AbortedReward {
+    pub fn reward(&self, s:&State, mod_param:&ModifierParameter, reward_fn:&RewardFunction, last_value:Option<f32>) -> f32 {
+        match self {
+            AbortedReward::Fixed(x) => *x,
+            AbortedReward::ValueEstimate => last_value.unwrap_or(0.0),
+            AbortedReward::RewardFn => reward_fn.reward(s, mod_param),
+        }
+    }
+}
+
+impl argh::FromArgValue for AbortedReward {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "value" => Ok(AbortedReward::ValueEstimate),
+            "reward" => Ok(AbortedReward::RewardFn),
+            _ => value.parse().map(AbortedReward::Fixed).map_err(|_| format!("aborted reward must be value, reward or a number but {}", value)),
+        }
+    }
+}
+
+#[test]
+fn test_aborted_reward_from_arg_value()
+{
+    use argh::FromArgValue;
+
+    assert_eq!( Ok(AbortedReward::ValueEstimate), AbortedReward::from_arg_value("value") );
+    assert_eq!( Ok(AbortedReward::RewardFn), AbortedReward::from_arg_value("reward") );
+    assert_eq!( Ok(AbortedReward::Fixed(-0.5)), AbortedReward::from_arg_value("-0.5") );
+    assert!( AbortedReward::from_arg_value("worst").is_err() );
+}
+
+// 見込みの無いエピソードを途中で投了するための設定です。
+// 探索後のルートの平均評価値がthresholdを下回るターンがconsecutive_turns回続いたら打ち切って、報酬をrewardで決めます。
+// 投了が正しかったかを調べられるように、false_positive_rateの割合のエピソードでは投了せずに最後まで遊びます
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub struct Resignation {
+    pub threshold : f32,
+    pub consecutive_turns : u32,
+    pub reward : AbortedReward,
+    pub false_positive_rate : f32,
+}
+
+// エピソードが投了したかどうかです
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum ResignOutcome {
+    Played,      // 投了の条件を満たさずに最後まで遊びました
+    Resigned,    // 投了して打ち切りました
+    WouldResign, // 投了の条件を満たしましたが、false_positive_rateで投了せずに最後まで遊びました。報酬で投了の誤りが分かります
+}
+
+#[test]
+fn test_hard_start()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let hard_start = HardStart { min_cp_ratio:0.5, max_cp_ratio:0.8 };
+    let seeds = [1, 2];
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+    let (min_cp,max_cp) = ((mod_param.max_cp as f32 * 0.5) as u32, (mod_param.max_cp as f32 * 0.8) as u32);
+
+    let states : Vec<State> = (0..100).map(|_| hard_start.sample(&mod_param, &mut rng)).collect();
+    assert!( states.iter().all(|s| min_cp <= s.cp && s.cp <= max_cp) );
+    assert!( states.iter().any(|s| s.cp < (min_cp + max_cp) / 2) );
+    assert!( states.iter().all(|s| State { cp:mod_param.max_cp, ..s.clone() } == State::new(&mod_param)) );
+
+    let param = EpisodeParameter { hard_start:Some(hard_start), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( record.adversarial );
+        assert!( record.samples[0].state.cp <= max_cp );
+    }
+}
+
+// ターンごとの行動選択の温度です。
+// (ターン,温度)の区切りをターンの昇順に並べたもので、区切りのターンから次の区切りの前までその温度を使います。
+// 最初の区切りより前のターンは最初の温度です。温度1は探索回数に比例して選び、0はgreedyです
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct TemperatureSchedule(pub Vec<(u32,f32)>);
+
+impl TemperatureSchedule {
+    // 以前のstart_greedy_turnと同じく、turnより前は探索回数に比例して選び、turnからgreedyにします
+    pub fn greedy_from(turn:u32) -> TemperatureSchedule {
+        TemperatureSchedule(vec![(0,1.0),(turn,0.0)])
+    }
+
+    pub fn temperature(&self, turn:u32) -> f32 {
+        value_at_turn(&self.0, turn)
+    }
+}
+
+// "ターン:温度"をカンマで区切って並べます。例えば"1:1.0,10:0.5,20:0"です
+impl argh::FromArgValue for TemperatureSchedule {
+    fn from_arg_value(value: &str) -> std::result::Result<Self, String> {
+        parse_turn_breakpoints(value, "temperature").map(TemperatureSchedule)
+    }
+}
+
+#[test]
+fn test_temperature_schedule()
+{
+    use argh::FromArgValue;
+
+    let schedule = TemperatureSchedule::from_arg_value("5:1.0,10:0.5,20:0").unwrap();
+    assert_eq!( TemperatureSchedule(vec![(5,1.0),(10,0.5),(20,0.0)]), schedule );
+    assert_eq!( 1.0, schedule.temperature(1) );
+    assert_eq!( 1.0, schedule.temperature(9) );
+    assert_eq!( 0.5, schedule.temperature(10) );
+    assert_eq!( 0.0, schedule.temperature(25) );
+    assert!( TemperatureSchedule::from_arg_value("10:1.0,5:0").is_err() );
+    assert!( TemperatureSchedule::from_arg_value("10").is_err() );
+
+    // start_greedy_turnと同じ切り替えになります
+    let schedule = TemperatureSchedule::greedy_from(30);
+    assert!( (1..30).all(|turn| schedule.temperature(turn) == 1.0) );
+    assert!( (30..40).all(|turn| schedule.temperature(turn) == 0.0) );
+    assert!( (1..10).all(|turn| TemperatureSchedule::greedy_from(0).temperature(turn) == 0.0) );
+}
+
+// 進捗に出す報酬の分布の設定です
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RewardReport {
+    pub buckets : Vec<f32>,       // ヒストグラムの区切りです。最後の区切りより大きいものは+Infに入ります
+    pub warn_below : Option<f32>, // 進捗の間隔ごとの平均報酬がこれを下回ったら警告します
+}
+
+impl Default for RewardReport {
+    fn default() -> RewardReport {
+        RewardReport { buckets:DEFAULT_REWARD_BUCKETS.to_vec(), warn_below:None }
+    }
+}
+
+// レコードに残すサンプルの範囲です
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub enum SampleRetention {
+    All,                                 // 全て残します
+    HeadTail { head:usize, tail:usize }, // 最初のhead個と最後のtail個だけ残して途中は捨てます
+}
+
+#[derive(Serialize,Deserialize,Clone)]
 pub struct EpisodeParameter {
     pub mod_param : ModifierParameter,
-    pub mcts_simulation_num : u32,
-    pub alpha : f32,
+    pub simulation_budget : SimulationBudget,
+    pub c_puct : f32, // PUCTの事前確率の項の重みです。大きいほど探索回数の少ない手を試します
+    pub min_simulations : u32, // 予算に関わらず、1手ごとに少なくともこの回数はシミュレーションします。0の場合は予算の通りです
+    // 1回に葉まで降ろして推論をまとめて待つシミュレーションの数です。1の場合は1つずつ進めます
+    pub parallel_simulations : u32,
+    // 推論を待っているシミュレーションが通った手に仮想的に足す訪問回数です。0の場合は足しません。
+    // parallel_simulationsが2以上の場合に、同時に降りるシミュレーションが同じ手に集まるのを防ぎます
+    pub virtual_loss : u32,
+    pub alpha : AlphaSchedule, // ルートのディリクレノイズのalphaです。ターンごとに変えられます
     pub eps : f32,
-    pub start_greedy_turn : u32,
+    pub add_root_noise : bool, // falseの場合はalphaとepsに関わらずノイズを加えません。評価では強さだけを測るためにfalseにします
+    pub temperature_schedule : TemperatureSchedule,
+    pub no_legal_action_reward : f32,
+    pub priority : Priority,
+    pub max_collected_turns : Option<u32>, // このターンより後はサンプルを保存しません。報酬のために最後までは遊びます
+    pub greedy_criterion : GreedyCriterion,
+    pub tie_break : TieBreak, // greedyで同点の手をどう選ぶかです。評価ではLowestIndexにすると同じシードで同じ手順になります
+    pub record_raw_prior : bool, // ノイズを加える前の事前確率もサンプルに保存します
+    pub collect_timings : bool, // 1手ごとの探索時間と探索回数をサンプルに保存します。分析用なので普段はfalseです
+    pub aux_target_fns : Vec<AuxTarget>, // 各サンプルのaux_targetsにこの順で保存します
+
+    // greedyの代わりにこの温度で選択します。0の場合は完全にgreedyです。
+    // 評価で似たモデル同士が全く同じ手順を繰り返して差が出なくなるのを防ぐためのものです。
+    // ただし同じシードで比べても手順が揃わなくなるので、ごく小さな値にしてください
+    pub eval_temperature : f32,
+
+    pub hard_start : Option<HardStart>, // 指定された場合は難しい開始状態から始めて、レコードにadversarialを付けます
+
+    // 乱数のシードの元です。指定すれば同じ設定で同じエピソードを再現できます。
+    // ディリクレノイズも探索の乱数から引くので、ノイズを加える場合も再現します
+    pub base_seed : Option<u64>,
+
+    pub resign : Option<Resignation>, // 指定された場合は見込みの無いエピソードを投了します
+    pub reward_fn : RewardFunction, // 終了状態の報酬の計算方法です。探索中の終端の評価にも使います
+    pub max_turns : Option<u32>, // この回数だけ手を選んでも終わらなければ打ち切ります。ロジックのバグで終わらないゲームでスレッドが止まるのを防ぎます
+    pub truncated_reward : AbortedReward, // max_turnsで打ち切ったエピソードの報酬の付け方です
+
+    // 指定された場合は訪問回数の代わりにGumbel AlphaZeroで手を選び、改善された方策をmcts_policyに保存します。
+    // 温度とgreedy_criterionは使いません
+    pub gumbel : Option<GumbelParameter>,
+
+    // 空でない場合はエピソードごとにここから重みに従って設定を選び、mod_paramの代わりに使います。
+    // 選んだ設定はレコードに残します
+    pub mixed_settings : Vec<WeightedSetting>,
 }
 
-#[derive(Clone)]
+#[derive(Serialize,Deserialize,Clone)]
 pub struct SelfPlayParameter {
     pub episode_param : EpisodeParameter,
-    pub selector : Selector,
     pub plays_per_write : usize,
     pub mysql_user : String,
+    pub mysql_host : String,
+    pub mysql_port : u16,
+    pub mysql_db : String,
     pub thread_num : u32,
     pub tch_thread_num : u32,
     pub tch_interop_thread_num : u32,
-    pub batch_size : usize,
-    pub writer_param : WriterParameter,
+    pub coroutine_num : usize, // スレッドごとに同時に進めるエピソードの数です
+    pub batch_size : usize,    // 1回の推論でネットワークに渡す状態の最大数です
+    pub max_queued_tasks : Option<usize>, // スレッドごとに推論を待つタスクの上限です。batch_size以上である必要があります
+    #[serde(with="device_names")]
+    pub gpu_devices : Vec<tch::Device>, // スレッドに順番に割り当てるデバイスです。空の場合は全てCPUで推論します
+    pub max_concurrent_loads : usize,
+    // 書き込み先とモデルの選び方を時間で切り替えるフェーズです。評価のフェーズではエピソードの設定も評価用に切り替えます
+    #[serde(with="writer_phases")]
+    pub writer_schedule : Vec<(Duration,WriterParameter,Selector)>,
+    pub preload_records : Option<RecordSource>,
+    pub prediction_cache : Option<String>,
+    pub prediction_cache_size : usize,
+    pub value_target : ValueTarget,
+    pub priority_column : bool, // 生成するサンプルの各行にレコードの優先度を足します。学習側で優先度付きサンプリングをするためのものです
+    pub non_finite_reward : NonFiniteReward,
+    pub shutdown_mode : ShutdownMode,
+    pub min_reward : Option<f32>, // 報酬がこれより低いレコードは書き込まずに捨てます。捨てた数は進捗に出します
+    pub startup_verification : Option<RecordSource>,
+    pub metrics_file : Option<String>,
+    pub metrics_addr : Option<String>, // metrics-serverを有効にした場合、このアドレスでGET /metricsに応答します
+    pub sample_retention : SampleRetention,
+    pub reward_report : RewardReport,
+    #[serde(skip)]
+    pub progress_callback : Option<ProgressCallback>, // Noneの場合は標準エラーに出力します
+    pub progress_interval : Duration, // 書き込みの進捗を報告する間隔です
+    pub model_poll_interval : Duration, // 新しいモデルを選び直す間隔です
+    pub writer_queue_capacity : usize, // 書き込み待ちのレコードの上限です。一杯になるとセルフプレイのスレッドは送信で止まります
+    pub max_runtime : Option<Duration>, // この時間が過ぎたら書き込みを終えて終了します
+    pub max_records : Option<u64>, // この数のレコードを書き込んだら終了します。実行中のエピソードも書き込むので少し超えます
+    #[serde(skip)]
+    pub control : Option<SelfPlayControl>, // 実行中に設定を変えたい場合に渡します
+    pub max_db_failures : u32, // モデルの選択や書き込みで一時的なデータベースのエラーがこの回数続いたら終了します
+}
+
+// 設定ファイルでは書き込みのフェーズを秒数の表で書きます。
+// TOMLの整数はi64なので、終わらないフェーズ(Duration::MAX)はsecsを省略して表します
+mod writer_phases {
+    use std::time::Duration;
+    use serde::{Serialize,Serializer,Deserialize,Deserializer};
+    use super::{WriterParameter,Selector};
+
+    #[derive(Serialize,Deserialize)]
+    struct WriterPhase {
+        secs : Option<u64>,
+        writer : WriterParameter,
+        selector : Selector,
+    }
+
+    pub fn serialize<S:Serializer>(schedule:&[(Duration,WriterParameter,Selector)], serializer:S) -> Result<S::Ok,S::Error> {
+        schedule.iter().map(|(duration,writer,selector)| WriterPhase {
+            secs : if *duration == Duration::MAX { None } else { Some(duration.as_secs()) },
+            writer : writer.clone(),
+            selector : selector.clone(),
+        }).collect::<Vec<_>>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de,D:Deserializer<'de>>(deserializer:D) -> Result<Vec<(Duration,WriterParameter,Selector)>,D::Error> {
+        let phases = Vec::<WriterPhase>::deserialize(deserializer)?;
+        Ok(phases.into_iter().map(|x| (x.secs.map(Duration::from_secs).unwrap_or(Duration::MAX), x.writer, x.selector)).collect())
+    }
+}
+
+// 設定ファイルはTOMLで書きます。progress_callbackとcontrolは組み込んだ側で渡すものなので含めません
+impl SelfPlayParameter {
+    pub fn from_toml(path:&Path) -> std::result::Result<SelfPlayParameter,CraftSimError> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| CraftSimError::Config(format!("invalid config {}: {}", path.display(), e)))
+    }
+
+    // コマンドラインで組み立てた設定を設定ファイルとして書き出す時に使います
+    pub fn to_toml(&self) -> std::result::Result<String,CraftSimError> {
+        toml::to_string(self).map_err(|e| CraftSimError::Config(format!("failed to serialize config: {}", e)))
+    }
+}
+
+// 実行中のセルフプレイへエピソードの設定を送るためのハンドルです。
+// SelfPlayParameterに入れてrunに渡しておき、組み込んだ側の別スレッドからupdate_episode_paramを呼びます。
+// 各コルーチンは実行中のエピソードを古い設定のまま終えて、次のエピソードから新しい設定を使います。
+// mod_paramは書き込みスレッドの整形にも使っていて、そちらには反映されないので変えないでください
+#[derive(Clone,Default)]
+pub struct SelfPlayControl {
+    pending : Arc<Mutex<Option<EpisodeParameter>>>,
+}
+
+impl SelfPlayControl {
+    #[allow(dead_code)]
+    pub fn new() -> SelfPlayControl {
+        Default::default()
+    }
+
+    // 次のメインループで全スレッドに配信します。配信前に複数回呼んだ場合は最後のものだけ使います
+    #[allow(dead_code)]
+    pub fn update_episode_param(&self, param:EpisodeParameter) {
+        *self.pending.lock().unwrap() = Some(param);
+    }
+
+    fn take_episode_param(&self) -> Option<EpisodeParameter> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+// 書き込みスレッドが一定間隔で報告する進捗です
+#[derive(Debug,Clone,PartialEq)]
+pub struct SelfPlayProgress {
+    pub elapsed : Duration,
+    pub record_count : usize,
+    pub sample_count : usize,
+    pub records_per_sec : f64,
+    pub samples_per_sec : f64,
+    pub episode_lengths : Vec<(Option<u32>,u64)>, // EpisodeLengthHistogram::countsと同じ形式です
+    pub filtered_count : usize, // min_rewardを下回ったので書き込まなかったレコードの数です。record_countには含みません
+    pub rewards : Vec<(Option<f32>,u64)>, // 前回の進捗から書き込んだレコードの報酬の分布です。finishedの時は全体の分布です
+    pub mean_reward : Option<f64>,        // rewardsと同じ範囲の平均です。1件も無ければNoneです
+    pub finished : bool, // 書き込み先を閉じる時の集計です。間隔に関わらず最後に1回だけ送ります
+}
+
+// 組み込んだ側で進捗を受け取るためのコールバックです。書き込みスレッドから呼ばれます
+pub type ProgressCallback = Arc<dyn Fn(SelfPlayProgress) + Send + Sync>;
+
+fn print_progress( progress:SelfPlayProgress ) {
+    info!(records = progress.record_count, samples = progress.sample_count, filtered = progress.filtered_count, finished = progress.finished,
+        "{}{:.3}[secs] {}[records] {}[samples] {:.3}[records/secs] {:.3}[samples/sec] {}[filtered]",
+        if progress.finished { "total " } else { "" }, progress.elapsed.as_millis() as f64 / 1000.0, progress.record_count, progress.sample_count, progress.records_per_sec, progress.samples_per_sec, progress.filtered_count );
+
+    let buckets : Vec<String> = progress.episode_lengths.iter().map(|(le,count)| match le {
+        Some(le) => format!("<={}:{}", le, count),
+        None => format!("inf:{}", count),
+    }).collect();
+    info!("turns {}", buckets.join(" "));
+
+    let buckets : Vec<String> = progress.rewards.iter().map(|(le,count)| match le {
+        Some(le) => format!("<={}:{}", le, count),
+        None => format!("inf:{}", count),
+    }).collect();
+    match progress.mean_reward {
+        Some(mean) => info!(mean_reward = mean, "rewards {} mean:{:.3}", buckets.join(" "), mean),
+        None => info!("rewards {}", buckets.join(" ")),
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct Sample {
+    pub action : Action, // 無くても問題ないけどログ見るのに便利なので出しておく
+    pub state : State,
+    // 探索で作った学習用の方策です。訪問回数で選んだ場合はvisit_countsを合計で割ったものと同じですが、
+    // gumbelを使った場合は改善された方策なので一致しません。visit_countsから作り直さずにこちらを使ってください
+    pub mcts_policy : ActionVector,
+    pub visit_counts : ActionVector, // 探索後のルートの手ごとの探索回数です。木を使い回すので前の手番の探索の分も含みます
+    pub root_value : f32, // 探索後のルートの平均評価値です
+    pub value_pred : f32, // 探索前のバリューネットワークの値です。TDターゲットの計算に使います
+    pub raw_prior : Option<ActionVector>, // ノイズを加える前のポリシーネットワークの値です。record_raw_priorの時だけ保存します
+    pub aux_targets : Vec<f32>, // 終了状態から計算した補助的な学習目標です。aux_target_fnsが空なら空です
+    pub timing : Option<SearchTiming>, // collect_timingsの時だけ保存します
+    pub value_target : Option<f32>, // サンプルを間引く前に計算したバリューの教師です。Noneの場合は書き出す時にレコードから計算します
+}
+
+// 1手の探索にかかった時間と、実際に行ったシミュレーションの回数です。
+// 時間で打ち切った場合やGumbelの逐次半減で、設定した回数と違うことがあります
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq)]
+pub struct SearchTiming {
+    pub elapsed : Duration,
+    pub simulations : u32,
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct Record {
+    pub samples : Vec<Sample>,
+    pub name : String,
+    pub last_state : State,
+    pub reward : f32,
+    pub thread_id : u32,    // 生成したスレッドです。特定のスレッドだけデータが悪い場合の調査用です
+    pub coroutine_id : u32, // 生成したスレッド内のコルーチンの番号です
+    pub adversarial : bool, // HardStartで難しい開始状態から始めたレコードです
+    pub resign : ResignOutcome,
+    pub truncated : bool, // max_turnsで打ち切ったレコードです
+
+    // エピソードの環境の乱数のシードです。同じシードなら開始状態が同じで、n回目の手の成功の判定と状態の変化に同じ乱数を使います。
+    // 探索は別の乱数を使うので、別のモデルで同じシードのレコードを組にすれば、報酬の差から運の影響を除いて比べられます
+    pub seeds : [u64;2],
+
+    pub setting : Option<String>, // mixed_settingsから選んだ設定の名前です。混ぜていない場合はNoneです
+
+    // 作った時の書き込みのフェーズの番号です。フェーズの境目で作りかけだったレコードが、別のフェーズの書き込み先に混ざらないようにするためのものです。
+    // 振り分けにしか使わないので保存はしません
+    #[serde(skip)]
+    pub phase : usize,
+}
+
+impl Record {
+    // 実際に行ったターン数です。サンプルはmax_collected_turnsや間引きで減っていることがあるので最終状態から求めます。
+    // 終了した状態ではターンが進まないので、投了や合法手が無い、max_turnsで打ち切った場合だけ1つ少なくなります
+    pub fn turn_count(&self) -> u32 {
+        if self.last_state.is_terminated() { self.last_state.turn } else { self.last_state.turn - 1 }
+    }
+}
+
+// ネットワークの読み込みを何回まで試すかです
+const LOAD_RETRY_NUM : u32 = 3;
+
+// 覚えておくネットワークの重みの数です。選ばれるモデルが入れ替わっても、メモリを使い続けないようにします
+const WEIGHTS_CACHE_CAPACITY : usize = 8;
+
+// 推論に失敗した時に、推論し直すまで待つ時間です。GPUのメモリ不足などが収まるのを待ちます
+const PREDICT_RETRY_DELAY : Duration = Duration::from_millis(100);
+
+// 推論に続けて失敗した時に諦めるまでの回数です
+const PREDICT_RETRY_LIMIT : u32 = 10;
+
+// 推論します。失敗したタスクはpredictorに残っているので、少し待ってから推論し直します。
+// エラーでスレッドごと止めてしまうと、そのスレッドの分だけ生成が止まってしまうのでここで握りつぶします。
+// PREDICT_RETRY_LIMIT回続けて失敗した場合は直らないエラーとみなして残ったタスクを失敗させ、待っていたコルーチンを中断させます
+pub fn predict_batch_or_retry( predictor:&mut Predictor, mod_param:&ModifierParameter ) {
+    predict_batch_with_retry( predictor, mod_param, (PREDICT_RETRY_LIMIT,PREDICT_RETRY_DELAY) )
+}
+
+fn predict_batch_with_retry( predictor:&mut Predictor, mod_param:&ModifierParameter, (retry_limit,retry_delay):(u32,Duration) ) {
+    for retry in 1..=retry_limit {
+        match predictor.predict_batch(mod_param) {
+            Ok(()) => return,
+            Err(e) if retry < retry_limit => {
+                error!(retry, "failed to predict {}. retry after {:?}", e, retry_delay);
+                std::thread::sleep(retry_delay);
+            },
+            Err(e) => {
+                let failed = predictor.fail_pending();
+                error!(failed, "failed to predict {} {} times. abort {} waiting tasks", e, retry_limit, failed);
+            },
+        }
+    }
+}
+
+// 全スレッドで共有するものです
+#[derive(Clone)]
+struct SharedContext {
+    load_limiter : Arc<LoadLimiter>,
+    prediction_cache : Option<Arc<Mutex<PredictionCache>>>,
+    action_counters : Arc<ActionCounters>,
+    gauges : Arc<SelfPlayGauges>,
+    capacities : Arc<Vec<AtomicUsize>>, // スレッドごとの実際のバッチサイズです。読み込みに失敗して縮小したり、諦めて0になったりします
+}
+
+type GraphInfo = (String,Arc<Weights>);
+
+// メインループからセルフプレイのスレッドへ送るメッセージです
+enum ThreadMessage {
+    Network(GraphInfo),                 // このモデルに切り替えます
+    EpisodeParameter(usize,Box<EpisodeParameter>), // 次のエピソードからこのフェーズの番号と設定を使います
+}
+
+struct ThreadContext {
+    thread_id : usize,
+    episode_param : EpisodeParameter,
+    coroutine_num : usize,
+    batch_size : usize,
+    max_queued_tasks : Option<usize>,
+    shutdown_mode : ShutdownMode,
+    device : tch::Device,
+    shared : SharedContext,
+    selfplay_receiver : Receiver<ThreadMessage>,
+    writer_sender : SyncSender<Record>,
+}
+
+struct CoroutineContext {
+    thread_id : u32,
+    episode_param : RefCell<EpisodeParameter>, // エピソードの開始時に複製して使うので、途中で差し替えても実行中のエピソードには影響しません
+    writer_sender : SyncSender<Record>,
+    action_counters : Arc<ActionCounters>,
+    gauges : Arc<SelfPlayGauges>,
+    predict_queue : PredictQueue,
+    graph_info : RefCell<GraphInfo>, // CellはCopy traitを要求します。StringもArcもCloneが無いのでRefCellが必要であるようです
+    stopping : Cell<bool>, // trueになったら新しいエピソードを始めません
+    phase : Cell<usize>, // episode_paramを使う書き込みのフェーズの番号です。作ったレコードに付けます
+}
+
+// ネットワーク読み込みの同時実行数を制限するためのセマフォです。
+// 新しいモデルが配信されると全スレッドが一斉に読み込みを始めてしまい、CPUやメモリの負荷が跳ね上がるので、それを抑えます。
+// max_numが0の場合は無制限です。
+pub struct LoadLimiter {
+    max_num : usize,
+    count : Mutex<usize>,
+    condvar : Condvar,
+}
+
+pub struct LoadPermit<'a> {
+    limiter : &'a LoadLimiter,
+}
+
+impl LoadLimiter {
+    pub fn new( max_num:usize ) -> LoadLimiter {
+        LoadLimiter { max_num, count:Mutex::new(0), condvar:Condvar::new() }
+    }
+
+    // 許可が取れるまで待ちます
+    pub fn acquire(&self) -> LoadPermit<'_> {
+        let mut count = self.count.lock().unwrap();
+        while self.max_num > 0 && *count >= self.max_num {
+            count = self.condvar.wait(count).unwrap();
+        }
+        *count += 1;
+        LoadPermit { limiter:self }
+    }
+
+    // 許可が取れない場合は待たずにNoneを返します
+    pub fn try_acquire(&self) -> Option<LoadPermit<'_>> {
+        let mut count = self.count.lock().unwrap();
+        if self.max_num > 0 && *count >= self.max_num {
+            None
+        }
+        else {
+            *count += 1;
+            Some(LoadPermit { limiter:self })
+        }
+    }
+}
+
+impl<'a> Drop for LoadPermit<'a> {
+    fn drop(&mut self) {
+        *self.limiter.count.lock().unwrap() -= 1;
+        self.limiter.condvar.notify_one();
+    }
+}
+
+#[test]
+fn test_load_limiter_sequential()
+{
+    use std::sync::atomic::{AtomicUsize,Ordering};
+
+    let limiter = Arc::new(LoadLimiter::new(1));
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+
+    let handles : Vec<JoinHandle<()>> = (0..4).map(|_| {
+        let (limiter,active,max_active) = (limiter.clone(),active.clone(),max_active.clone());
+        std::thread::spawn( move || {
+            let _permit = limiter.acquire();
+            let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active.fetch_max(n, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            active.fetch_sub(1, Ordering::SeqCst);
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!( 1, max_active.load(Ordering::SeqCst) );
+    assert!( limiter.try_acquire().is_some() );
+}
+
+// base_seedが無い場合に使うシードです。プロセスで1回だけ時刻とプロセスIDから作ります。
+// エピソードごとに時刻を読むと、同時に始めたエピソードが同じシードになってしまいます
+fn process_seed() -> u64 {
+    static SEED : OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get UNIXTIME").as_nanos() as u64;
+        splitmix64(nanos ^ ((std::process::id() as u64) << 32))
+    })
 }
 
-#[derive(Serialize,Deserialize,Debug)]
-pub struct Sample {
-    pub action : Action, // 無くても問題ないけどログ見るのに便利なので出しておく
-    pub state : State,
-    pub mcts_policy : ActionVector,
+// エピソードごとのxorshiftのシードです。
+// 前半は(スレッド番号,コルーチン番号)から、後半はコルーチン内のエピソードの番号から作るので、
+// 同じbase_seedならどれかの番号が違えば必ず違う系列になり、全て同じなら同じ系列になります
+fn episode_seeds( base_seed:u64, (thread_id,coroutine_id):(u32,u32), episode:u64 ) -> [u64;2] {
+    let origin = ((thread_id as u64) << 32) | coroutine_id as u64;
+    [splitmix64(base_seed ^ origin), splitmix64(splitmix64(base_seed) ^ episode)]
+}
+
+// 探索に使う乱数のシードです。エピソードのシードから作るので、同じエピソードなら探索も再現できます
+fn search_seeds( seeds:[u64;2] ) -> [u64;2] {
+    const SEARCH_SEED_SALT : u64 = 0x5ea7c4;
+    [splitmix64(seeds[0] ^ SEARCH_SEED_SALT), splitmix64(seeds[1] ^ SEARCH_SEED_SALT)]
+}
+
+#[test]
+fn test_episode_seeds()
+{
+    let mut seeds = std::collections::HashSet::new();
+    for thread_id in 0..4 {
+        for coroutine_id in 0..16 {
+            for episode in 0..16 {
+                assert!( seeds.insert(episode_seeds(1, (thread_id,coroutine_id), episode)) );
+            }
+        }
+    }
+    assert_eq!( episode_seeds(1, (2,3), 4), episode_seeds(1, (2,3), 4) );
+    assert_ne!( episode_seeds(1, (2,3), 4), episode_seeds(2, (2,3), 4) );
+
+    // シードを指定すれば同じエピソードを再現できます
+    let param = EpisodeParameter { base_seed:Some(1), ..new_test_episode_param() };
+    let actions = |mut records:Vec<Record>| -> Vec<Vec<Action>> {
+        records.sort_by_key(|x| x.coroutine_id);
+        records.iter().map(|x| x.samples.iter().map(|x| x.action).collect()).collect()
+    };
+    let first = actions(generate_test_episodes(&param, 3));
+    assert_eq!( first, actions(generate_test_episodes(&param, 3)) );
+    assert!( first[0] != first[1] || first[1] != first[2] );
+
+    // 使ったシードはレコードに残ります
+    for record in generate_test_episodes(&param, 3) {
+        assert_eq!( episode_seeds(1, (0,record.coroutine_id), 0), record.seeds );
+    }
+}
+
+// originは生成元の(スレッド番号,コルーチン番号)で、そのままレコードに記録します。
+// episodeはコルーチンの中で何番目のエピソードかで、乱数のシードに使います
+pub async fn selfplay_craftone( param:&EpisodeParameter, graph_filename:&str, predict_queue:&PredictQueue, (thread_id,coroutine_id):(u32,u32), episode:u64 ) -> Record {
+
+    let seeds = episode_seeds(param.base_seed.unwrap_or_else(process_seed), (thread_id,coroutine_id), episode);
+    let mut modifier = Modifier { mod_param:param.mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+
+    // 設定はエピソードの乱数で選ぶので、同じシードなら同じ設定になります
+    let setting = sample_setting(&param.mixed_settings, &mut modifier.rng).and_then(|x| {
+        modifier.mod_param = x.clone();
+        x.preset_name()
+    });
+
+    let mut samples = vec![];
+    let mut state = match &param.hard_start {
+        Some(hard_start) => hard_start.sample(&modifier.mod_param, &mut modifier.rng),
+        None => State::new(&modifier.mod_param),
+    };
+
+    // コンテキストはゲーム中ずっと使い回します。ノードは状態で引くので、実際に進んだ先の探索回数はそのまま次の探索に引き継がれます。
+    // 進んだ先が未展開なら新しく展開し、前の手番のノードはsearchの最初に捨てます
+    let mut mcts_context = MCTSContext::new(param.c_puct, param.alpha.clone(), param.eps, param.no_legal_action_reward, predict_queue.clone(), graph_filename.to_string());
+    mcts_context.set_priority(param.priority);
+    mcts_context.set_reward_fn(param.reward_fn);
+    mcts_context.set_root_noise(param.add_root_noise);
+    mcts_context.set_virtual_loss(param.virtual_loss);
+    mcts_context.set_parallel_simulations(param.parallel_simulations);
+    mcts_context.set_setting(setting);
+
+    // 投了しないエピソードを先に決めておきます。投了しない設定の場合は乱数を進めません
+    let can_resign = match &param.resign {
+        Some(resign) => modifier.rng.next_f32() >= resign.false_positive_rate,
+        None => false,
+    };
+
+    // 探索と手の選択には環境とは別の乱数を使います。環境の乱数は実際に手を実行する時だけ進むので、
+    // 同じシードならモデルがどれだけ探索してどの手を選んでも、n回目の手では同じ乱数で状態が変わります
+    let mut search_modifier = Modifier { mod_param:modifier.mod_param.clone(), rng:SeedableRng::from_seed(&search_seeds(seeds)[..]) };
+    let mut resign_outcome = ResignOutcome::Played;
+    let mut hopeless_turns = 0;
+    let mut truncated = false;
+    let mut action_count = 0;
+    let mut last_value = None; // 最後に探索したルートの平均評価値です。投了や打ち切りの報酬に使います
+
+    while !state.is_terminated() {
+        // ターンの進まない手もあるので、ターンではなく選んだ手の数で数えます
+        if param.max_turns.map(|x| action_count >= x).unwrap_or(false) {
+            warn!(turn = state.turn, "warning: episode truncated at turn {} {:?}", state.turn, state);
+            truncated = true;
+            break;
+        }
+        action_count += 1;
+
+        // 合法手が無い場合は終端として扱います。ロジックのバグの可能性が高いので警告を出します
+        if !state.has_valid_action_ex() {
+            warn!(turn = state.turn, "warning: no legal action at turn {} {:?}", state.turn, state);
+            break;
+        }
+
+        let temperature = param.temperature_schedule.temperature(state.turn);
+        let greedy = temperature <= 0.0;
+
+        // ルートの訪問回数は木を使い回した分も含むので、探索前との差をシミュレーションの回数とします
+        let search_start = if param.collect_timings { Some((Instant::now(), root_visit_count(&mcts_context, &state))) } else { None };
+
+        let (mcts_policy,action) = if let Some(gumbel) = &param.gumbel {
+            // 候補にできる合法手が無い場合も、上と同じく終端として扱います
+            match mcts_context.select_action_gumbel(&state, &mut search_modifier, &param.simulation_budget, param.min_simulations, gumbel).await {
+                Some((action,improved_policy)) => (improved_policy, action),
+                None => break,
+            }
+        }
+        else if greedy && param.greedy_criterion == GreedyCriterion::Q {
+            let search_result = mcts_context.search_detailed(&state, &mut search_modifier, &param.simulation_budget, param.min_simulations).await;
+            let action = select_action_max_q(&search_result, param.tie_break, &mut search_modifier.rng);
+            (search_result.policy, action)
+        }
+        else {
+            let mcts_policy = mcts_context.search(&state, &mut search_modifier, &param.simulation_budget, param.min_simulations).await;
+            let action = select_action_with_temperature(&mcts_policy, &state.legal_action_mask(), if greedy { param.eval_temperature } else { temperature }, param.tie_break, &mut search_modifier.rng);
+            (mcts_policy, action)
+        };
+
+        let timing = search_start.map(|(start,visits)| SearchTiming { elapsed:start.elapsed(), simulations:(root_visit_count(&mcts_context, &state) - visits) as u32 });
+
+        let root_value = mcts_context.get_mean_value(&state).or_else(|| mcts_context.get_value_prediction(&state)); // 1回も探索しなかった場合はネットワークの値です
+        last_value = root_value;
+
+        let collect = match param.max_collected_turns {
+            Some(x) => state.turn <= x,
+            None => true,
+        };
+        if collect {
+            let value_pred = mcts_context.get_value_prediction(&state).unwrap();
+            let raw_prior = if param.record_raw_prior { mcts_context.get_raw_prior(&state) } else { None };
+            let visit_counts = mcts_context.get_visit_counts(&state).unwrap();
+            let root_value = root_value.unwrap_or(value_pred);
+            samples.push( Sample { action, state:state.clone(), mcts_policy, visit_counts, root_value, value_pred, raw_prior, aux_targets:vec![], timing, value_target:None } );
+        }
+
+        let hopeless = match &param.resign {
+            Some(resign) => mcts_context.get_mean_value(&state).map(|x| x < resign.threshold).unwrap_or(false),
+            None => false,
+        };
+
+        state = state.run_action(&mut modifier,&action);
+
+        // 選んだ手は実行してから打ち切るので、レコードの最終状態は最後のサンプルの次の状態になります
+        if let Some(resign) = &param.resign {
+            hopeless_turns = if hopeless { hopeless_turns + 1 } else { 0 };
+            if hopeless_turns >= resign.consecutive_turns && resign_outcome == ResignOutcome::Played && !state.is_terminated() {
+                resign_outcome = if can_resign { ResignOutcome::Resigned } else { ResignOutcome::WouldResign };
+                if can_resign {
+                    break;
+                }
+            }
+        }
+    }
+
+    // 最終的な報酬を計算します。
+    // 最後まで遊ばなかったエピソードは終わっていない状態で止まっているので、設定した方法で報酬を決めます
+    let reward = match (resign_outcome,&param.resign) {
+        (ResignOutcome::Resigned,Some(resign)) => resign.reward.reward(&state, &modifier.mod_param, &param.reward_fn, last_value),
+        _ if truncated => param.truncated_reward.reward(&state, &modifier.mod_param, &param.reward_fn, last_value),
+        _ if state.is_terminated() => param.reward_fn.reward(&state,&modifier.mod_param),
+        _ => param.no_legal_action_reward,
+    };
+
+    // 補助的な学習目標は全サンプルで同じ値です
+    if !param.aux_target_fns.is_empty() {
+        let aux_targets : Vec<f32> = param.aux_target_fns.iter().map(|x| x.compute(&state,&modifier.mod_param)).collect();
+        for sample in &mut samples {
+            sample.aux_targets = aux_targets.clone();
+        }
+    }
+
+    // 結果を返す
+    Record { samples, name:graph_filename.to_string(), last_state:state, reward, thread_id, coroutine_id, adversarial:param.hard_start.is_some(), resign:resign_outcome, truncated, seeds, setting:setting.map(|x| x.to_string()), phase:0 }
+}
+
+fn root_visit_count( mcts_context:&MCTSContext, s:&State ) -> f32 {
+    mcts_context.get_visit_counts(s).map(|x| x.iter().sum()).unwrap_or(0.0)
+}
+
+async fn selfplay_coroutine( co_ctx:Rc<CoroutineContext>, coroutine_id:u32 ) {
+    // 停止の合図が出ても、実行中のエピソードは最後まで遊んで送ってから終わります
+    let mut episode = 0;
+    while !co_ctx.stopping.get() {
+        let (graph_filename,_) = co_ctx.graph_info.borrow().clone();
+        let episode_param = co_ctx.episode_param.borrow().clone();
+        let phase = co_ctx.phase.get();
+        let record = Record { phase, ..selfplay_craftone(&episode_param, &graph_filename, &co_ctx.predict_queue, (co_ctx.thread_id,coroutine_id), episode).await };
+        episode += 1;
+        let actions : Vec<Action> = record.samples.iter().map(|x| x.action).collect();
+        co_ctx.action_counters.add(&record.name, &actions);
+        co_ctx.gauges.add_sent();
+
+        // 書き込みスレッドが異常終了していた場合は、これ以上作っても捨てるだけなので止めます
+        if co_ctx.writer_sender.send(record).is_err() {
+            error!(coroutine_id, "writer is closed. stop selfplay{} coroutine {}", co_ctx.thread_id, coroutine_id);
+            co_ctx.stopping.set(true);
+        }
+    }
+}
+
+// 新しいエピソードを始めないように合図してから、実行中のエピソードを終わらせます。
+// Finishでは全て最後まで進めて送り、Discardでは推論を待っているタスクごと捨てます。
+// どちらも推論待ちのタスクは残りません。戻り値は捨てたエピソードの数です。
+// Finishでも推論を諦めて中断したエピソードは捨てた数に含めます
+fn finish_coroutines<F:FnMut()>( co_ctx:&CoroutineContext, executor:&mut Executor, mode:ShutdownMode, mut predict_batch:F ) -> usize {
+    co_ctx.stopping.set(true);
+    match mode {
+        ShutdownMode::Finish => {
+            let mut discarded = 0;
+            while !executor.is_empty() {
+                discarded += executor.poll_all().aborted;
+                predict_batch();
+            }
+            discarded
+        },
+        // コルーチンはエピソードの途中でしか止まらないので、残っているタスクの数が途中のエピソードの数です
+        ShutdownMode::Discard => {
+            let discarded = executor.len();
+            executor.clear();
+            co_ctx.predict_queue.clear();
+            discarded
+        },
+    }
+}
+
+#[test]
+fn test_finish_coroutines()
+{
+    let (writer_sender,writer_receiver) = sync_channel(16);
+    let mut predictor = Predictor::new();
+    let co_ctx = Rc::new(CoroutineContext {
+        thread_id:0,
+        episode_param:RefCell::new(new_test_episode_param()),
+        writer_sender,
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        predict_queue:predictor.get_queue(),
+        graph_info:RefCell::new(("mock".to_string(), Arc::new(Weights::Torch(NetworkType::FullyConnected(1,1), tch::nn::VarStore::new(tch::Device::Cpu))))),
+        stopping:Cell::new(false),
+        phase:Cell::new(0),
+    });
+    let mock = |_:&str, source:&[State]| -> Vec<(ActionVector,f32)> { source.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], 0.5)).collect() };
+
+    let mut executor = Executor::new();
+    for coroutine_id in 0..3 {
+        executor.spawn( selfplay_coroutine( co_ctx.clone(), coroutine_id ) );
+    }
+
+    // 全てのエピソードが途中の状態で止めます
+    for _ in 0..3 {
+        executor.poll_all();
+        predictor.predict_batch_with(mock);
+    }
+    assert!( writer_receiver.try_recv().is_err() );
+
+    // 途中でフェーズが変わっても、実行中のエピソードは始めた時のフェーズのレコードになります
+    co_ctx.phase.set(1);
+    assert_eq!( 0, finish_coroutines(&co_ctx, &mut executor, ShutdownMode::Finish, || predictor.predict_batch_with(mock)) );
+    assert!( co_ctx.predict_queue.is_empty() );
+    drop(co_ctx);
+
+    // 実行中だった3つのエピソードは最後まで遊んで送られ、新しいエピソードは始まっていません
+    let records : Vec<Record> = writer_receiver.iter().collect();
+    let mut origins : Vec<u32> = records.iter().map(|x| x.coroutine_id).collect();
+    origins.sort();
+    assert_eq!( vec![0,1,2], origins );
+    assert!( records.iter().all(|x| x.phase == 0) );
+}
+
+#[test]
+fn test_finish_coroutines_failing_network()
+{
+    // 常に推論に失敗するネットワークです
+    struct FailingNetwork;
+    impl Predict for FailingNetwork {
+        fn predict_batch(&self, _states:&[State], _mod_param:&ModifierParameter) -> std::result::Result<Vec<(ActionVector,f32)>, Box<dyn std::error::Error>> {
+            Err("broken network".into())
+        }
+    }
+
+    let (writer_sender,writer_receiver) = sync_channel(16);
+    let mut predictor = Predictor::new();
+    predictor.insert_network("broken".to_string(), Box::new(FailingNetwork));
+    let co_ctx = Rc::new(CoroutineContext {
+        thread_id:0,
+        episode_param:RefCell::new(new_test_episode_param()),
+        writer_sender,
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        predict_queue:predictor.get_queue(),
+        graph_info:RefCell::new(("broken".to_string(), Arc::new(Weights::Mock(0.5)))),
+        stopping:Cell::new(false),
+        phase:Cell::new(0),
+    });
+
+    let mut executor = Executor::new();
+    for coroutine_id in 0..3 {
+        executor.spawn( selfplay_coroutine( co_ctx.clone(), coroutine_id ) );
+    }
+    executor.poll_all();
+    assert_eq!( 3, co_ctx.predict_queue.len() );
+
+    // 終わるまで待つ設定でも、諦めた推論を待つエピソードは中断されて止まります
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    assert_eq!( 3, finish_coroutines(&co_ctx, &mut executor, ShutdownMode::Finish, || predict_batch_with_retry(&mut predictor, &mod_param, (3,Duration::ZERO))) );
+    assert!( executor.is_empty() );
+    assert!( co_ctx.predict_queue.is_empty() );
+    drop(co_ctx);
+    assert_eq!( 0, writer_receiver.iter().count() );
+}
+
+#[test]
+fn test_finish_coroutines_discard()
+{
+    let (writer_sender,writer_receiver) = sync_channel(16);
+    let mut predictor = Predictor::new();
+    let co_ctx = Rc::new(CoroutineContext {
+        thread_id:0,
+        episode_param:RefCell::new(new_test_episode_param()),
+        writer_sender,
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        predict_queue:predictor.get_queue(),
+        graph_info:RefCell::new(("mock".to_string(), Arc::new(Weights::Mock(0.5)))),
+        stopping:Cell::new(false),
+        phase:Cell::new(0),
+    });
+    let mock = |_:&str, source:&[State]| -> Vec<(ActionVector,f32)> { source.iter().map(|_| ([1.0/ACTION_NUM as f32;ACTION_NUM], 0.5)).collect() };
+
+    let mut executor = Executor::new();
+    for coroutine_id in 0..3 {
+        executor.spawn( selfplay_coroutine( co_ctx.clone(), coroutine_id ) );
+    }
+    for _ in 0..3 {
+        executor.poll_all();
+        predictor.predict_batch_with(mock);
+    }
+    executor.poll_all();
+    assert_eq!( 3, co_ctx.predict_queue.len() );
+
+    // 途中の3つのエピソードは推論待ちのタスクごと捨てられ、何も送られません
+    assert_eq!( 3, finish_coroutines(&co_ctx, &mut executor, ShutdownMode::Discard, || predictor.predict_batch_with(mock)) );
+    assert!( executor.is_empty() );
+    assert!( co_ctx.predict_queue.is_empty() );
+    drop(co_ctx);
+    assert_eq!( 0, writer_receiver.iter().count() );
+}
+
+// キューにあるメッセージを全て処理します。モデルは最新のものだけpending_graph_infoに残します。
+// 送信側が閉じた場合はfalseを返します
+fn receive_thread_messages( receiver:&Receiver<ThreadMessage>, pending_graph_info:&mut Option<GraphInfo>, (episode_param,phase):(&RefCell<EpisodeParameter>,&Cell<usize>) ) -> bool {
+    loop {
+        match receiver.try_recv() {
+            Ok(ThreadMessage::Network(graph_info)) => *pending_graph_info = Some(graph_info),
+            Ok(ThreadMessage::EpisodeParameter(index,param)) => {
+                phase.set(index);
+                *episode_param.borrow_mut() = *param;
+            },
+            Err(TryRecvError::Disconnected) => return false,
+            Err(TryRecvError::Empty) => return true,
+        }
+    }
+}
+
+#[test]
+fn test_receive_thread_messages()
+{
+    let episode_param = RefCell::new(new_test_episode_param());
+    let phase = Cell::new(0);
+    let mut pending_graph_info = None;
+    let (sender,receiver) = channel();
+
+    // 実行中のエピソードは受信前に複製した設定のまま進みます
+    let running = episode_param.borrow().clone();
+    sender.send(ThreadMessage::EpisodeParameter(1, Box::new(EpisodeParameter { max_collected_turns:Some(2), ..new_test_episode_param() }))).unwrap();
+    assert!( receive_thread_messages(&receiver, &mut pending_graph_info, (&episode_param,&phase)) );
+    assert!( pending_graph_info.is_none() );
+    assert_eq!( None, running.max_collected_turns );
+    assert_eq!( 1, phase.get() );
+
+    // 次のエピソードから新しい設定が使われます
+    let next = episode_param.borrow().clone();
+    for record in generate_test_episodes(&next, 2) {
+        assert!( record.samples.iter().all(|x| x.state.turn <= 2) );
+    }
+
+    drop(sender);
+    assert!( !receive_thread_messages(&receiver, &mut pending_graph_info, (&episode_param,&phase)) );
+}
+
+fn selfplay_thread( ctx:ThreadContext ) {
+
+    // 最初のモデルだけ初期化のために同期待ちします。それまでに届いた設定はそのまま使います
+    let mut episode_param = ctx.episode_param;
+    let mut phase = 0;
+    let graph_info = loop {
+        match ctx.selfplay_receiver.recv() {
+            Ok(ThreadMessage::Network(x)) => break x,
+            Ok(ThreadMessage::EpisodeParameter(index,x)) => {
+                phase = index;
+                episode_param = *x;
+            },
+            Err(_) => return,
+        }
+    };
+
+    let mut predictor = Predictor::new();
+    predictor.set_device(ctx.device);
+    predictor.set_gauges(ctx.shared.gauges.clone());
+    if let Some(cache) = &ctx.shared.prediction_cache {
+        predictor.set_cache(cache.clone());
+    }
+
+    // GPUのメモリ不足などで一時的に失敗することがあるので、待ちながら再試行します。
+    // それでも失敗する場合はバッチサイズを半分にして使うメモリを減らしてから再挑戦します
+    let capacity = &ctx.shared.capacities[ctx.thread_id];
+    let mut batch_size = ctx.batch_size;
+    loop {
+        let load_limiter = &ctx.shared.load_limiter;
+        let ret = retry_with_backoff( LOAD_RETRY_NUM, Duration::from_secs(1), || {
+            let _permit = load_limiter.acquire();
+            predictor.load_network( graph_info.0.clone(), &graph_info.1 )
+        });
+
+        match ret {
+            Ok(()) => break,
+            Err(e) if batch_size > 1 => {
+                batch_size /= 2;
+                warn!(model = %graph_info.0, batch_size, "failed to load network {:?}. reduce batch size to {}", e, batch_size);
+            },
+            Err(e) => {
+                error!(model = %graph_info.0, "failed to load network {:?}. give up", e);
+                capacity.store(0, Ordering::SeqCst);
+                return;
+            },
+        }
+    }
+    capacity.store(batch_size, Ordering::SeqCst);
+    predictor.set_max_batch_size(batch_size);
+    if let Some(max_queued_tasks) = ctx.max_queued_tasks {
+        predictor.set_max_queued_tasks(max_queued_tasks);
+    }
+
+    // コルーチン間の共有コンテキスト
+    let co_ctx = Rc::new(CoroutineContext {
+        thread_id:ctx.thread_id as u32,
+        episode_param:RefCell::new(episode_param),
+        writer_sender:ctx.writer_sender,
+        action_counters:ctx.shared.action_counters.clone(),
+        gauges:ctx.shared.gauges.clone(),
+        predict_queue:predictor.get_queue(),
+        graph_info:RefCell::new(graph_info),
+        stopping:Cell::new(false),
+        phase:Cell::new(phase),
+    });
+
+    // 非同期Executor
+    let mut executor = Executor::new();
+    for coroutine_id in 0..ctx.coroutine_num {
+        executor.spawn( selfplay_coroutine( co_ctx.clone(), coroutine_id as u32 ) );
+    }
+
+    // 以下制作ループ
+    let mut pending_graph_info = None;
+    loop {
+        // 送信側が閉じたら終了の合図なので、実行中のエピソードを送り終えてから終わります
+        if !receive_thread_messages(&ctx.selfplay_receiver, &mut pending_graph_info, (&co_ctx.episode_param,&co_ctx.phase)) {
+            let discarded = finish_coroutines( &co_ctx, &mut executor, ctx.shutdown_mode, || predict_batch_or_retry( &mut predictor, &co_ctx.episode_param.borrow().mod_param ) );
+            info!(discarded, "selfplay{} stopped. discarded {} episodes in progress", ctx.thread_id, discarded);
+            return;
+        }
+
+        // 未読み込みのネットワークは許可が取れた時だけ読み込みます。
+        // 許可が取れなければ古いモデルのまま続行して、次のループで再挑戦します。
+        // 読み込みに失敗した場合も古いモデルのまま続行します。同じモデルもMODEL_REBROADCAST_INTERVALごとに配信されてくるのでその時に再挑戦します
+        if let Some(graph_info) = pending_graph_info.take() {
+            if predictor.contains_network(&graph_info.0) {
+                *co_ctx.graph_info.borrow_mut() = graph_info;
+            }
+            else if let Some(_permit) = ctx.shared.load_limiter.try_acquire() {
+                match predictor.load_network( graph_info.0.clone(), &graph_info.1 ) {
+                    Ok(()) => *co_ctx.graph_info.borrow_mut() = graph_info,
+                    Err(e) => error!(model = %graph_info.0, "failed to load network {} {:?}", graph_info.0, e),
+                }
+            }
+            else {
+                pending_graph_info = Some(graph_info);
+            }
+        }
+
+        flush_when_batched( &mut executor, &co_ctx.predict_queue, batch_size );
+        predict_batch_or_retry( &mut predictor, &co_ctx.episode_param.borrow().mod_param );
+
+        // 推論を諦めて全てのコルーチンが中断した場合は、これ以上何も作れないのでスレッドを止めます
+        if executor.is_empty() {
+            error!("all coroutines of selfplay{} are aborted. stop thread", ctx.thread_id);
+            return;
+        }
+    }
+}
+
+// 推論待ちのタスクがbatch_size個溜まるまでタスクを進めます。
+// 全てのタスクが推論を待っていてそれ以上進められない場合は、batch_sizeに届かなくても諦めて返ります
+fn flush_when_batched( executor:&mut Executor, predict_queue:&PredictQueue, batch_size:usize ) {
+    loop {
+        let status = executor.poll_all();
+        if predict_queue.len() >= batch_size || status.idle {
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_flush_when_batched()
+{
+    let mut predictor = Predictor::new_with_capacity(0, 0);
+    let mut executor = Executor::new();
+    let queue = predictor.get_queue();
+    for _ in 0..3 {
+        let queue = queue.clone();
+        executor.spawn( async move {
+            queue.async_predict("mock".to_string(), State::new(&ModifierParameter::new_fountain_of_usouso()), Priority::Normal).await;
+        });
+    }
+
+    // batch_sizeより少ないタスクしか無くても止まらずに返ります
+    flush_when_batched( &mut executor, &queue, 8 );
+    assert_eq!( 3, queue.len() );
+
+    predictor.predict_batch_with( |_,source| source.iter().map(|_| ([0.0;ACTION_NUM],0.0)).collect() );
+    flush_when_batched( &mut executor, &queue, 8 );
+    assert!( executor.is_empty() );
+}
+
+// スレッドやチャンネル、MySQLを使わずにn回エピソードを実行して結果を返します。
+// 別のアプリケーションに組み込んで使う時の入口です。バイナリからは使っていません。
+// modelの名前のネットワークは事前にpredictorに読み込んでおく必要があります。
+#[allow(dead_code)]
+pub fn generate_episodes( param:&EpisodeParameter, predictor:&mut Predictor, model:&str, n:usize ) -> Vec<Record> {
+    let records = Rc::new(RefCell::new(vec![]));
+
+    // n個のエピソードを全部同時に走らせるので、バッチサイズはnになります
+    let mut executor = Executor::new();
+    for coroutine_id in 0..n {
+        let param = param.clone();
+        let graph_filename = model.to_string();
+        let predict_queue = predictor.get_queue();
+        let records = records.clone();
+        executor.spawn( async move {
+            let record = selfplay_craftone(&param, &graph_filename, &predict_queue, (0,coroutine_id as u32), 0).await;
+            records.borrow_mut().push(record);
+        });
+    }
+
+    while !executor.is_empty() {
+        executor.poll_all();
+        predict_batch_or_retry( predictor, &param.mod_param );
+    }
+
+    records.replace(vec![])
+}
+
+#[cfg(test)]
+pub fn new_test_episode_param() -> EpisodeParameter {
+    EpisodeParameter {
+        mod_param:ModifierParameter::new_fountain_of_usouso(),
+        simulation_budget:SimulationBudget::Fixed(8),
+        c_puct:1.0,
+        min_simulations:0,
+        parallel_simulations:1,
+        virtual_loss:0,
+        alpha:AlphaSchedule::constant(0.15),
+        eps:0.0,
+        add_root_noise:true,
+        temperature_schedule:TemperatureSchedule::greedy_from(0),
+        no_legal_action_reward:0.0,
+        priority:Priority::Normal,
+        max_collected_turns:None,
+        greedy_criterion:GreedyCriterion::Visits,
+        tie_break:TieBreak::Random,
+        record_raw_prior:false,
+        collect_timings:false,
+        aux_target_fns:vec![],
+        eval_temperature:0.0,
+        hard_start:None,
+        base_seed:None,
+        resign:None,
+        reward_fn:RewardFunction::Default,
+        max_turns:None,
+        truncated_reward:AbortedReward::RewardFn,
+        gumbel:None,
+        mixed_settings:vec![],
+    }
+}
+
+#[test]
+fn test_selfplay_parameter_toml()
+{
+    let param = SelfPlayParameter {
+        episode_param : EpisodeParameter { hard_start:Some(HardStart { min_cp_ratio:0.5, max_cp_ratio:0.8 }), aux_target_fns:vec![AuxTarget::Quality], ..new_test_episode_param() },
+        plays_per_write : 10,
+        mysql_user : "root".to_string(),
+        mysql_host : "localhost".to_string(),
+        mysql_port : 3306,
+        mysql_db : "craft".to_string(),
+        thread_num : 2,
+        tch_thread_num : 1,
+        tch_interop_thread_num : 1,
+        coroutine_num : 4,
+        batch_size : 4,
+        max_queued_tasks : Some(16),
+        gpu_devices : vec![tch::Device::Cpu, tch::Device::Cuda(1)],
+        max_concurrent_loads : 0,
+        writer_schedule : vec![(Duration::from_secs(600), WriterParameter::Generation, Selector::UCB1(1.0)), (Duration::MAX, WriterParameter::JsonFile(PathBuf::from("records.jsonl")), Selector::Greedy(50))],
+        preload_records : Some(RecordSource::File("record.bincode.bz2".to_string())),
+        prediction_cache : None,
+        prediction_cache_size : 0,
+        value_target : ValueTarget::TemporalDifference { n:3, gamma:0.9 },
+        priority_column : true,
+        non_finite_reward : NonFiniteReward::Replace(0.0),
+        shutdown_mode : ShutdownMode::Discard,
+        min_reward : Some(0.5),
+        startup_verification : None,
+        metrics_file : None,
+        metrics_addr : Some("127.0.0.1:9100".to_string()),
+        sample_retention : SampleRetention::HeadTail { head:2, tail:3 },
+        reward_report : RewardReport { buckets:vec![0.5, 1.0], warn_below:Some(0.3) },
+        progress_callback : None,
+        progress_interval : Duration::from_secs(10),
+        model_poll_interval : Duration::from_secs(2),
+        writer_queue_capacity : 128,
+        max_runtime : Some(Duration::from_secs(3600)),
+        max_records : None,
+        control : None,
+        max_db_failures : 5,
+    };
+
+    // 書き出したものを読み直して、もう一度書き出しても同じになります
+    let text = param.to_toml().unwrap();
+    let path = std::env::temp_dir().join(format!("selfplay_config_{}.toml", std::process::id()));
+    std::fs::write(&path, &text).unwrap();
+    let loaded = SelfPlayParameter::from_toml(&path);
+    std::fs::remove_file(&path).unwrap();
+    let loaded = loaded.unwrap();
+    assert_eq!( text, loaded.to_toml().unwrap() );
+    assert_eq!( Duration::MAX, loaded.writer_schedule[1].0 );
+    assert_eq!( vec![tch::Device::Cpu, tch::Device::Cuda(1)], loaded.gpu_devices );
+    assert_eq!( Some("fountain_of_usouso"), loaded.episode_param.mod_param.preset_name() );
+
+    assert!( matches!( SelfPlayParameter::from_toml(Path::new("/nonexistent/selfplay.toml")), Err(CraftSimError::Io(_)) ) );
+}
+
+// 一様な方策と固定の評価値を返すネットワークで、generate_episodesからエピソードをn個実行します
+#[cfg(test)]
+fn generate_test_episodes( param:&EpisodeParameter, n:usize ) -> Vec<Record> {
+    let mut predictor = Predictor::new();
+    predictor.insert_network("mock".to_string(), Box::new(MockNetwork { value:0.5 }));
+    generate_episodes(param, &mut predictor, "mock", n)
+}
+
+#[test]
+fn test_generate_episodes()
+{
+    use super::replay::verify_record;
+
+    // 全て同時に走らせて、終わった順に返します
+    let param = EpisodeParameter { base_seed:Some(1), ..new_test_episode_param() };
+    let records = generate_test_episodes(&param, 3);
+    let mut coroutine_ids : Vec<u32> = records.iter().map(|x| x.coroutine_id).collect();
+    coroutine_ids.sort();
+    assert_eq!( vec![0,1,2], coroutine_ids );
+    for record in &records {
+        assert_eq!( "mock", record.name );
+        assert!( record.last_state.is_terminated() );
+        assert!( !record.samples.is_empty() );
+        assert_eq!( Ok(()), verify_record(record, &param.mod_param) );
+    }
+}
+
+#[test]
+fn test_selfplay_records_verify()
+{
+    use super::replay::verify_record;
+
+    // エピソードごとに違うシードで作った実際のレコードも、失敗したアクションを含めて再現できます
+    for base_seed in 1..=4 {
+        let param = EpisodeParameter { base_seed:Some(base_seed), ..new_test_episode_param() };
+        for record in generate_test_episodes(&param, 4) {
+            assert_eq!( Ok(()), verify_record(&record, &param.mod_param) );
+        }
+    }
+}
+
+#[test]
+fn test_selfplay_mixed_settings()
+{
+    use argh::FromArgValue;
+
+    let settings = ["fountain_of_usouso", "ishgard_reconstruction_4th"];
+    let param = EpisodeParameter {
+        mixed_settings:settings.iter().map(|x| WeightedSetting::from_arg_value(x).unwrap()).collect(),
+        base_seed:Some(1),
+        ..new_test_episode_param()
+    };
+
+    // 選んだ設定で始めて、その名前をレコードに残します
+    let records = generate_test_episodes(&param, 16);
+    for record in &records {
+        let mod_param = ModifierParameter::from_preset_name(record.setting.as_ref().unwrap()).unwrap();
+        assert_eq!( State::new(&mod_param), record.samples[0].state );
+        assert!( record.last_state.is_terminated() );
+    }
+    for setting in settings.iter() {
+        assert!( records.iter().any(|x| x.setting.as_deref() == Some(*setting)) );
+    }
+
+    assert!( generate_test_episodes(&new_test_episode_param(), 2).iter().all(|x| x.setting.is_none()) );
+}
+
+#[test]
+fn test_selfplay_gumbel()
+{
+    let param = EpisodeParameter { gumbel:Some(GumbelParameter { max_considered_actions:4, ..GumbelParameter::default() }), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( record.last_state.is_terminated() );
+        for sample in &record.samples {
+            let mask = sample.state.legal_action_mask();
+            assert!( (sample.mcts_policy.iter().sum::<f32>() - 1.0).abs() < 1e-4 );
+            assert!( (0..ACTION_NUM).all(|a| mask[a] || sample.mcts_policy[a] == 0.0) );
+        }
+    }
+}
+
+#[test]
+fn test_collect_timings()
+{
+    // 1手ごとにFixed(8)の回数だけシミュレーションします
+    let param = EpisodeParameter { collect_timings:true, ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( record.samples.iter().all(|x| x.timing.map(|t| t.simulations) == Some(8)) );
+    }
+
+    let records = generate_test_episodes(&new_test_episode_param(), 1);
+    assert!( records[0].samples.iter().all(|x| x.timing.is_none()) );
+}
+
+#[test]
+fn test_record_origin()
+{
+    let mut origins : Vec<(u32,u32)> = generate_test_episodes(&new_test_episode_param(), 3).iter().map(|x| (x.thread_id,x.coroutine_id)).collect();
+    origins.sort();
+    assert_eq!( vec![(0,0),(0,1),(0,2)], origins );
+}
+
+#[test]
+fn test_evaluation_without_root_noise()
+{
+    // epsが大きくても加えなければ、同じシードで同じ手順になります
+    let param = EpisodeParameter { eps:0.25, add_root_noise:false, base_seed:Some(1), tie_break:TieBreak::LowestIndex, ..new_test_episode_param() };
+    let first = seeded_trajectories(&param);
+    assert!( first.iter().all(|x| !x.is_empty()) );
+    assert_eq!( first, seeded_trajectories(&param) );
+}
+
+#[cfg(test)]
+fn seeded_trajectories( param:&EpisodeParameter ) -> Vec<Vec<(State,Action,ActionVector)>> {
+    let mut records = generate_test_episodes(param, 3);
+    records.sort_by_key(|x| x.coroutine_id);
+    records.iter().map(|x| x.samples.iter().map(|s| (s.state.clone(),s.action,s.mcts_policy)).collect()).collect()
+}
+
+#[test]
+fn test_root_noise_reproducible()
+{
+    // ディリクレノイズも探索の乱数から引くので、同じシードなら同じ方策と手順になります
+    let param = EpisodeParameter { eps:0.25, add_root_noise:true, base_seed:Some(1), ..new_test_episode_param() };
+    let first = seeded_trajectories(&param);
+    assert!( first.iter().all(|x| !x.is_empty()) );
+    assert_eq!( first, seeded_trajectories(&param) );
+
+    // シードが違えばノイズも変わります
+    assert_ne!( first, seeded_trajectories(&EpisodeParameter { base_seed:Some(2), ..param }) );
+}
+
+#[test]
+fn test_selfplay_parallel_simulations()
+{
+    use super::replay::verify_record;
+
+    // 同時に降ろしても仮想損失は全て取り除かれるので、訪問回数は整数のままです
+    let param = EpisodeParameter { base_seed:Some(1), parallel_simulations:4, virtual_loss:1, ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( !record.samples.is_empty() );
+        assert!( record.samples.iter().all(|x| x.visit_counts.iter().all(|n| n.fract() == 0.0 && *n >= 0.0)) );
+        assert_eq!( Ok(()), verify_record(&record, &param.mod_param) );
+    }
+}
+
+#[test]
+fn test_max_collected_turns()
+{
+    let param = EpisodeParameter { max_collected_turns:Some(3), ..new_test_episode_param() };
+
+    for record in generate_test_episodes(&param, 4) {
+        assert!( !record.samples.is_empty() );
+        assert!( record.samples.iter().all(|x| x.state.turn <= 3) );
+        assert!( record.last_state.is_terminated() );
+        assert!( record.last_state.turn > 3 );
+    }
+}
+
+#[test]
+fn test_visit_counts()
+{
+    // gumbelを使わない場合はmcts_policyが訪問回数の割合と一致します
+    let param = new_test_episode_param();
+    assert!( param.gumbel.is_none() );
+
+    for record in generate_test_episodes(&param, 2) {
+        for sample in &record.samples {
+            let sum : f32 = sample.visit_counts.iter().sum();
+            assert!( sum >= 8.0 );
+            assert!( sample.visit_counts.iter().zip(sample.mcts_policy.iter()).all(|(n,p)| (n / sum - p).abs() < 1e-6) );
+            assert!( 0.0 <= sample.root_value && sample.root_value <= 1.0 );
+        }
+    }
+}
+
+#[test]
+fn test_resignation()
+{
+    use super::replay::verify_record;
+
+    // 評価値は1を超えないので、必ず投了する閾値にしておきます
+    let resign = Resignation { threshold:2.0, consecutive_turns:2, reward:AbortedReward::Fixed(-1.0), false_positive_rate:0.0 };
+    let param = EpisodeParameter { resign:Some(resign), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 4) {
+        assert_eq!( ResignOutcome::Resigned, record.resign );
+        assert_eq!( 2, record.samples.len() );
+        assert_eq!( -1.0, record.reward );
+        assert!( !record.last_state.is_terminated() );
+        assert!( record.last_state.time > record.samples[1].state.time );
+        assert_eq!( Ok(()), verify_record(&record, &param.mod_param) );
+    }
+
+    // 投了しないエピソードは最後まで遊んで、投了していたかどうかだけ記録します
+    let param = EpisodeParameter { resign:Some(Resignation { false_positive_rate:1.0, ..resign }), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 4) {
+        assert_eq!( ResignOutcome::WouldResign, record.resign );
+        assert!( record.last_state.is_terminated() );
+        assert!( record.reward >= 0.0 );
+    }
+
+    assert!( generate_test_episodes(&new_test_episode_param(), 2).iter().all(|x| x.resign == ResignOutcome::Played) );
+
+    // 評価値で報酬を付ける場合は、投了を決めた最後の探索の評価値になります
+    let param = EpisodeParameter { resign:Some(Resignation { reward:AbortedReward::ValueEstimate, ..resign }), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 4) {
+        assert_eq!( ResignOutcome::Resigned, record.resign );
+        assert_eq!( record.samples.last().unwrap().root_value, record.reward );
+    }
+}
+
+#[test]
+fn test_no_legal_action()
+{
+    // 1ターン目に使える確信も真価もCPが足りない状態から始めると、終了していないのに合法手がありません
+    let mod_param = ModifierParameter { max_cp:0, ..ModifierParameter::new_fountain_of_usouso() };
+    assert!( !State::new(&mod_param).has_valid_action_ex() );
+
+    // 探索せずに終端として扱い、設定した報酬で終わります
+    let param = EpisodeParameter { mod_param, no_legal_action_reward:-0.5, ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( record.samples.is_empty() );
+        assert!( !record.truncated );
+        assert!( !record.last_state.is_terminated() );
+        assert_eq!( 1, record.last_state.turn );
+        assert_eq!( -0.5, record.reward );
+    }
+}
+
+#[test]
+fn test_max_turns()
+{
+    let param = EpisodeParameter { max_turns:Some(3), ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 4) {
+        assert!( record.truncated );
+        assert_eq!( 3, record.samples.len() );
+        assert!( !record.last_state.is_terminated() );
+        assert_eq!( param.reward_fn.reward(&record.last_state, &param.mod_param), record.reward );
+    }
+
+    let param = EpisodeParameter { max_turns:Some(3), truncated_reward:AbortedReward::Fixed(0.25), ..new_test_episode_param() };
+    assert!( generate_test_episodes(&param, 2).iter().all(|x| x.truncated && x.reward == 0.25) );
+
+    let param = EpisodeParameter { max_turns:Some(3), truncated_reward:AbortedReward::ValueEstimate, ..new_test_episode_param() };
+    for record in generate_test_episodes(&param, 2) {
+        assert!( record.truncated );
+        assert_eq!( record.samples.last().unwrap().root_value, record.reward );
+    }
+
+    assert!( generate_test_episodes(&new_test_episode_param(), 2).iter().all(|x| !x.truncated && x.last_state.is_terminated()) );
+}
+
+#[test]
+fn test_aux_targets()
+{
+    let param = EpisodeParameter { aux_target_fns:vec![AuxTarget::Quality,AuxTarget::Completed], ..new_test_episode_param() };
+
+    for record in generate_test_episodes(&param, 4) {
+        let s = &record.last_state;
+        assert!( s.is_terminated() );
+        let expected = vec![s.quality as f32 / param.mod_param.max_quality as f32, if s.is_completed() { 1.0 } else { 0.0 }];
+        assert!( record.samples.iter().all(|x| x.aux_targets == expected) );
+    }
+
+    assert!( generate_test_episodes(&new_test_episode_param(), 1)[0].samples.iter().all(|x| x.aux_targets.is_empty()) );
+}
+
+// スレッドに割り当てるデバイスです。デバイスが足りなければ同じものを順番に使い回します
+fn thread_device( gpu_devices:&[tch::Device], thread_id:usize ) -> tch::Device {
+    if gpu_devices.is_empty() { tch::Device::Cpu } else { gpu_devices[thread_id % gpu_devices.len()] }
+}
+
+#[test]
+fn test_thread_device()
+{
+    use tch::Device;
+    assert_eq!( Device::Cpu, thread_device(&[], 3) );
+    let devices = [Device::Cuda(0), Device::Cuda(1)];
+    let assigned : Vec<Device> = (0..5).map(|x| thread_device(&devices, x)).collect();
+    assert_eq!( vec![Device::Cuda(0), Device::Cuda(1), Device::Cuda(0), Device::Cuda(1), Device::Cuda(0)], assigned );
+}
+
+// 戻り値の型は利用者側の都合でVecのタプルで返したほうが良いと思います
+fn spawn_selfplay_threads( episode_param:&EpisodeParameter, writer_sender:&SyncSender<Record>, thread_num:u32, (coroutine_num,batch_size,max_queued_tasks,shutdown_mode):(usize,usize,Option<usize>,ShutdownMode), gpu_devices:&[tch::Device], shared:&SharedContext ) -> (Vec<JoinHandle<()>>,Vec<Sender<ThreadMessage>>) {
+    let mut handles = vec![];
+    let mut senders = vec![];
+    for thread_id in 0..thread_num {
+        let (sender,receiver) = channel();
+        let ctx = ThreadContext {
+            thread_id:thread_id as usize,
+            episode_param:episode_param.clone(),
+            coroutine_num,
+            batch_size,
+            max_queued_tasks,
+            shutdown_mode,
+            device:thread_device(gpu_devices, thread_id as usize),
+            shared:shared.clone(),
+            selfplay_receiver:receiver,
+            writer_sender:writer_sender.clone(),
+        };
+        // スパンはこのスレッドで作って、run_simulationのスパンの子にします
+        let span = info_span!("selfplay", thread_id);
+        let handle = std::thread::Builder::new().name(format!("selfplay{}",thread_id)).spawn( move ||{ let _span = span.entered(); selfplay_thread(ctx); } ).unwrap();
+        handles.push(handle);
+        senders.push(sender);
+    }
+    (handles,senders)
+}
+
+// ここは借用ではなくmoveである必要があるようです。詳しくはこちら
+// https://users.rust-lang.org/t/how-to-join-handles-of-threads/52494
+// 途中でpanicしたスレッドがあっても、残りのスレッドは全て待ってから返します
+fn wait_threads(handles:Vec<JoinHandle<()>>) -> std::result::Result<(),CraftSimError> {
+    let panicked = handles.into_iter().map(|x| x.join()).filter(|x| x.is_err()).count();
+    if panicked > 0 {
+        return Err(CraftSimError::LogicViolation(format!("{} selfplay threads panicked", panicked)));
+    }
+    Ok(())
+}
+
+// 各スレッドのコルーチンが作ったレコードには、作ったスレッドとコルーチンの番号が付きます
+#[test]
+fn test_selfplay_threads_record_origin()
+{
+    use std::collections::HashSet;
+
+    let mut episode_param = new_test_episode_param();
+    episode_param.base_seed = Some(1);
+    let shared = SharedContext {
+        load_limiter:Arc::new(LoadLimiter::new(0)),
+        prediction_cache:None,
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        capacities:Arc::new((0..3).map(|_| AtomicUsize::new(3)).collect()),
+    };
+
+    let (writer_sender,writer_receiver) = sync_channel(4);
+    let (handles,senders) = spawn_selfplay_threads( &episode_param, &writer_sender, 3, (3,3,None,ShutdownMode::Discard), &[], &shared );
+    for sender in &senders {
+        sender.send(ThreadMessage::Network(("mock".to_string(), Arc::new(Weights::Mock(0.5))))).unwrap();
+    }
+
+    // 全てのスレッドとコルーチンの組から届くまで待ちます
+    let expected : HashSet<(u32,u32)> = (0..3).flat_map(|thread_id| (0..3).map(move |coroutine_id| (thread_id,coroutine_id))).collect();
+    let mut records = vec![];
+    let start = Instant::now();
+    while !expected.iter().all(|x| records.iter().any(|r:&Record| (r.thread_id,r.coroutine_id) == *x)) {
+        assert!( start.elapsed() < Duration::from_secs(60), "selfplay threads did not write records" );
+        if let Ok(record) = writer_receiver.recv_timeout(Duration::from_millis(100)) {
+            records.push(record);
+        }
+    }
+    drop(senders);
+    drop(writer_sender);
+    records.extend(writer_receiver.iter());
+    wait_threads(handles).unwrap();
+
+    // 番号はシードにも使われているので、そのスレッドとコルーチンのエピソードのシードと一致します
+    for record in &records {
+        assert!( expected.contains(&(record.thread_id,record.coroutine_id)) );
+        assert!( (0..64).any(|episode| episode_seeds(1, (record.thread_id,record.coroutine_id), episode) == record.seeds) );
+    }
+}
+
+// MySQLもtorchも使わずに、セルフプレイのスレッドからJSON Linesへの書き込みまでを通して動かします。
+// 終了の手順で実行中のエピソードが失われないことと、書き込んだレコードが読み戻せることを確かめます
+#[test]
+fn test_selfplay_threads_to_jsonl()
+{
+    use std::io::{BufRead,BufReader};
+
+    let path = std::env::temp_dir().join(format!("selfplay_threads_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut episode_param = new_test_episode_param();
+    episode_param.base_seed = Some(1);
+    let shared = SharedContext {
+        load_limiter:Arc::new(LoadLimiter::new(0)),
+        prediction_cache:None,
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        capacities:Arc::new((0..2).map(|_| AtomicUsize::new(2)).collect()),
+    };
+
+    let (writer_sender,writer_receiver) = sync_channel(4);
+    let (handles,senders) = spawn_selfplay_threads( &episode_param, &writer_sender, 2, (2,2,None,ShutdownMode::Finish), &[], &shared );
+    for sender in &senders {
+        sender.send(ThreadMessage::Network(("mock".to_string(), Arc::new(Weights::Mock(0.5))))).unwrap();
+    }
+
+    let episode_lengths = Arc::new(EpisodeLengthHistogram::new());
+    let writer = {
+        let (path,episode_lengths,gauges) = (path.clone(),episode_lengths.clone(),shared.gauges.clone());
+        std::thread::spawn( move || {
+            write_records( JsonlWriter::open(&path).unwrap(), vec![], &writer_receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&|_| {},Duration::from_secs(3600),&episode_lengths,&gauges) )
+        })
+    };
+
+    // いくつか書き込まれたら、run_simulationと同じ順番で終了させます
+    let start = Instant::now();
+    while episode_lengths.count() < 4 {
+        assert!( start.elapsed() < Duration::from_secs(60), "selfplay threads did not write records" );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(senders);
+    wait_threads(handles).unwrap();
+    drop(writer_sender);
+    assert!( !writer.join().unwrap().unwrap() );
+
+    let file = std::fs::File::open(&path).unwrap();
+    let records : Vec<Record> = BufReader::new(file).lines().map(|line| serde_json::from_str(&line.unwrap()).unwrap()).collect();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!( episode_lengths.count(), records.len() as u64 );
+    for record in &records {
+        // 最初のターンから終わるまで1手ずつ記録しています。ターンを進めないアクションもあるので、ターンは1ずつか同じまま進みます
+        assert!( record.last_state.is_terminated() );
+        assert_eq!( Some(1), record.samples.first().map(|x| x.state.turn) );
+        for (sample,next) in record.samples.iter().zip(record.samples.iter().skip(1)) {
+            assert!( next.state.turn - sample.state.turn <= 1 );
+        }
+        for sample in &record.samples {
+            assert!( (sample.mcts_policy.iter().sum::<f32>() - 1.0).abs() < 1e-4 );
+        }
+        assert_eq!( "mock", record.name );
+    }
+}
+
+// フェーズの書き込み先に合わせたエピソードの設定です。
+// 評価のフェーズではモデルの強さを測るので、evaluatorと同じくノイズや難しい開始状態、投了を使わずに温度0で手を選びます
+fn phase_episode_param( episode_param:&EpisodeParameter, writer:&WriterParameter ) -> EpisodeParameter {
+    if writer.is_evaluation() {
+        EpisodeParameter {
+            eps:0.0,
+            add_root_noise:false,
+            temperature_schedule:TemperatureSchedule::greedy_from(0),
+            priority:Priority::High,
+            tie_break:TieBreak::LowestIndex,
+            hard_start:None,
+            resign:None,
+            ..episode_param.clone()
+        }
+    }
+    else {
+        episode_param.clone()
+    }
+}
+
+#[test]
+fn test_phase_episode_param()
+{
+    let episode_param = EpisodeParameter { add_root_noise:true, hard_start:Some(HardStart { min_cp_ratio:0.5, max_cp_ratio:0.8 }), ..new_test_episode_param() };
+
+    // 生成のフェーズは設定をそのまま使います
+    let generation = phase_episode_param(&episode_param, &WriterParameter::Generation);
+    assert!( generation.add_root_noise );
+    assert!( generation.hard_start.is_some() );
+
+    // 評価のフェーズはノイズを加えずに最初から温度0で選びます
+    for writer in [WriterParameter::Evaluation, WriterParameter::SqliteEvaluation(PathBuf::from("eval.sqlite"))] {
+        let evaluation = phase_episode_param(&episode_param, &writer);
+        assert!( !evaluation.add_root_noise );
+        assert_eq!( 0.0, evaluation.eps );
+        assert_eq!( 0.0, evaluation.temperature_schedule.temperature(1) );
+        assert!( evaluation.hard_start.is_none() );
+        assert_eq!( episode_param.simulation_budget, evaluation.simulation_budget );
+    }
+}
+
+// 開始からの経過時間に対して有効なフェーズの番号と、そのフェーズの残り時間を返します。
+// スケジュールは最後まで行くと最初に戻ります
+fn active_phase( schedule:&[(Duration,WriterParameter,Selector)], elapsed:Duration ) -> (usize,Duration) {
+    let total : Duration = schedule.iter().map(|x| x.0).sum();
+    let mut t = Duration::from_nanos( (elapsed.as_nanos() % total.as_nanos().max(1)) as u64 );
+    for (i,(duration,_,_)) in schedule.iter().enumerate() {
+        if t < *duration {
+            return (i, *duration - t);
+        }
+        t -= *duration;
+    }
+    (0, schedule[0].0)
+}
+
+#[test]
+fn test_active_phase()
+{
+    let schedule = vec![
+        (Duration::from_secs(10), WriterParameter::Generation, Selector::Greedy(50)),
+        (Duration::from_secs(5), WriterParameter::Evaluation, Selector::optimistic(10.0)),
+    ];
+    let phase = |secs| active_phase(&schedule, Duration::from_secs(secs));
+
+    assert_eq!( (0,Duration::from_secs(10)), phase(0) );
+    assert_eq!( (0,Duration::from_secs(1)), phase(9) );
+    assert_eq!( (1,Duration::from_secs(5)), phase(10) );
+    assert_eq!( (1,Duration::from_secs(1)), phase(14) );
+    assert_eq!( (0,Duration::from_secs(10)), phase(15) );
+    assert_eq!( (1,Duration::from_secs(5)), phase(25) );
 }
 
-#[derive(Serialize,Deserialize,Debug)]
-pub struct Record {
-    pub samples : Vec<Sample>,
-    pub name : String,
-    pub last_state : State,
-    pub reward : f32,
+// 報酬が有限の値でない場合は設定に従って補正します。書き込んでよい場合はtrueを返します。
+// ロジックのバグで起きるはずなので、どのモデルで起きたか分かるようにログを出します
+fn check_reward( record:&mut Record, non_finite_reward:&NonFiniteReward, count:&mut usize ) -> bool {
+    if record.reward.is_finite() {
+        return true;
+    }
+
+    *count += 1;
+    warn!(model = %record.name, count = *count, "warning: non-finite reward {} (model:{} count:{}) {:?}", record.reward, record.name, count, record.last_state);
+
+    match non_finite_reward {
+        NonFiniteReward::Reject => false,
+        NonFiniteReward::Replace(x) => { record.reward = *x; true },
+    }
 }
 
-struct ThreadContext {
-    episode_param : EpisodeParameter,
-    batch_size : usize,
-    selfplay_receiver : Receiver<(String,Arc<(NetworkType,tch::nn::VarStore)>)>,
-    writer_sender : Sender<Record>,
+// 報酬がmin_reward以上のものだけ書き込みます。品質を重視した学習で、失敗したレコードでデータベースが埋まるのを防ぎます
+fn meets_min_reward( record:&Record, min_reward:Option<f32> ) -> bool {
+    min_reward.map(|x| record.reward >= x).unwrap_or(true)
 }
 
-struct CoroutineContext {
-    episode_param : EpisodeParameter,
-    writer_sender : Sender<Record>,
-    predict_queue : PredictQueue,
-    graph_info : RefCell<(String,Arc<(NetworkType,tch::nn::VarStore)>)>, // CellはCopy traitを要求します。StringもArcもCloneが無いのでRefCellが必要であるようです
+// 報酬と最終状態はそのままで、サンプルだけ間引きます。
+// 途中を捨てるとTDターゲットが正しく計算できなくなるので、間引く前に全てのサンプルでバリューの教師を計算して残します
+fn retain_samples( record:&mut Record, retention:&SampleRetention, value_target:&ValueTarget ) {
+    match retention {
+        SampleRetention::All => {},
+        SampleRetention::HeadTail { head, tail } => {
+            let len = record.samples.len();
+            if len > head + tail {
+                let targets = compute_value_targets(record, value_target);
+                let samples = std::mem::take(&mut record.samples);
+                record.samples = samples.into_iter().zip(targets).enumerate()
+                    .filter(|(i,_)| *i < *head || *i >= len - tail)
+                    .map(|(_,(x,target))| Sample { value_target:Some(target), ..x })
+                    .collect();
+            }
+        },
+    }
 }
 
-async fn selfplay_craftone( param:&EpisodeParameter, graph_filename:&String, predict_queue:&PredictQueue ) -> Record {
+#[test]
+fn test_retain_samples()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    let new_record = |n:u32| -> Record {
+        let samples = (0..n).map(|turn| Sample { action:Action::BasicSynthesis, state:State { turn, ..State::new(&mod_param) }, mcts_policy:[0.0;ACTION_NUM], visit_counts:[0.0;ACTION_NUM], root_value:0.0, value_pred:turn as f32 * 0.1, raw_prior:None, aux_targets:vec![], timing:None, value_target:None }).collect();
+        Record { samples, ..new_test_record("test", 1.0) }
+    };
+    let retained = |n:u32, retention:&SampleRetention, value_target:&ValueTarget| -> Record {
+        let mut record = new_record(n);
+        retain_samples(&mut record, retention, value_target);
+        record
+    };
+    let turns = |record:&Record| -> Vec<u32> { record.samples.iter().map(|x| x.state.turn).collect() };
+    let retention = SampleRetention::HeadTail { head:2, tail:3 };
+    let monte_carlo = ValueTarget::MonteCarlo;
 
-    let seed : u64 = From::from( SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get UNIXTIME").subsec_nanos() );
-    let seeds = [seed, seed];
-    let mut modifier = Modifier { mod_param:param.mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    assert_eq!( vec![0,1,7,8,9], turns(&retained(10, &retention, &monte_carlo)) );
+    assert_eq!( vec![0,1,2,3,4], turns(&retained(5, &retention, &monte_carlo)) );
+    assert_eq!( vec![0,1,2], turns(&retained(3, &retention, &monte_carlo)) );
+    assert_eq!( 10, retained(10, &SampleRetention::All, &monte_carlo).samples.len() );
 
-    let mut samples = vec![];
-    let mut state = State::new(&param.mod_param);
+    // TDターゲットは間引く前のエピソード全体で計算したものが残ります
+    let td = ValueTarget::TemporalDifference { n:3, gamma:0.9 };
+    let full = compute_value_targets(&new_record(10), &td);
+    let record = retained(10, &retention, &td);
+    let kept : Vec<f32> = record.samples.iter().map(|x| x.value_target.unwrap()).collect();
+    assert_eq!( vec![full[0],full[1],full[7],full[8],full[9]], kept );
+    assert!( (kept[1] - 0.9f32.powi(3) * 0.4).abs() < 1e-6 );
 
-    // コンテキストを１手ごとに初期化するかゲーム中で完全記憶するのが良いかが分かりませんが、一旦ここにしておきます。
-    // 多分こっちのほうが良いんだけどメモリは使います
-    let mut mcts_context = MCTSContext::new(1.0, param.alpha, param.eps, predict_queue.clone(), graph_filename.clone());
+    // 間引かない場合は書き出す時に計算するので残しません
+    assert!( retained(5, &retention, &td).samples.iter().all(|x| x.value_target.is_none()) );
+}
 
-    while !state.is_terminated() {
-        let mcts_policy = mcts_context.search(&state, &mut modifier, param.mcts_simulation_num).await;
+// preloadはセルフプレイの結果より先に書き込まれます。
+// 再起動直後に空のバッファから始めないようにするためのものです。
+// deadlineを過ぎるか送信側が全て閉じるまで書き込みます。送信側が閉じた場合はfalseを返し、書き込めなかった場合はエラーを返します
+// phaseは書き込むフェーズの番号で、別のフェーズで作ったレコードは書き込まずに捨てます
+// 進捗はintervalごとにprogressへ渡します。進捗に含めるターン数はpreloadを除いてepisode_lengthsに足していきます
+fn write_records<W:WriteRecord>( mut writer:W, preload:Vec<Record>, receiver:&Receiver<Record>, (deadline,phase):(Option<Instant>,usize), (non_finite_reward,min_reward):(&NonFiniteReward,Option<f32>), (sample_retention,value_target,reward_report):(&SampleRetention,&ValueTarget,&RewardReport), (progress,interval,episode_lengths,gauges):(&dyn Fn(SelfPlayProgress),Duration,&EpisodeLengthHistogram,&SelfPlayGauges) ) -> std::result::Result<bool,CraftSimError> {
+    let mut non_finite_count = 0;
+    let mut filtered_count = 0;
+    let mut other_phase_count = 0;
 
-        let action = if state.turn < param.start_greedy_turn {
-            select_action_weighted(&mcts_policy, &mut modifier.rng)
+    if !preload.is_empty() {
+        info!(records = preload.len(), "write {} preloaded records...", preload.len());
+        for mut record in preload {
+            if !check_reward(&mut record, non_finite_reward, &mut non_finite_count) {
+                continue;
+            }
+            if !meets_min_reward(&record, min_reward) {
+                filtered_count += 1;
+                continue;
+            }
+            writer.write_record(record)?;
         }
-        else {
-            select_action_greedy(&mcts_policy, &mut modifier.rng)
+    }
+
+    let start = Instant::now();
+    let mut next_time = start + interval;
+    let mut record_count = 0;
+    let mut sample_count = 0;
+    let mut interval_rewards = RewardHistogram::new(&reward_report.buckets);
+    let mut total_rewards = interval_rewards.clone();
+
+    let mut connected = true;
+    loop {
+        let mut record = match deadline {
+            None => match receiver.recv() {
+                Ok(record) => record,
+                Err(_) => { connected = false; break },
+            },
+            Some(deadline) => match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(record) => record,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => { connected = false; break },
+            },
         };
+        gauges.add_received();
+
+        // フェーズの境目で作りかけだったレコードは、前のフェーズの設定で作ったものなので混ぜません
+        if record.phase != phase {
+            other_phase_count += 1;
+            continue;
+        }
+        if !check_reward(&mut record, non_finite_reward, &mut non_finite_count) {
+            continue;
+        }
+        if !meets_min_reward(&record, min_reward) {
+            filtered_count += 1;
+            continue;
+        }
 
-        samples.push( Sample { action:action.clone(), state:state.clone(), mcts_policy:mcts_policy } );
+        episode_lengths.add(record.turn_count());
+        interval_rewards.add(record.reward);
+        total_rewards.add(record.reward);
+        retain_samples(&mut record, sample_retention, value_target);
 
-        state = state.run_action(&mut modifier,&action);
+        record_count += 1;
+        let samples = record.samples.len();
+        sample_count += samples;
+
+        writer.write_record(record)?;
+        gauges.add_written(samples);
+
+        let now = Instant::now();
+        if now >= next_time {
+            warn_low_reward(&interval_rewards, reward_report.warn_below);
+            progress( new_progress(now - start, (record_count,sample_count,filtered_count), episode_lengths, &interval_rewards, false) );
+            interval_rewards.clear();
+            next_time += interval;
+        }
     }
 
-    // 最終的な報酬を計算します。
-    let reward = get_reward(&state,&modifier.mod_param);
+    writer.flush()?;
 
-    // 結果を返す
-    Record { samples:samples, name:graph_filename.clone(), last_state:state, reward:reward }
+    if other_phase_count > 0 {
+        info!(discarded = other_phase_count, "discarded {} records made in other phases", other_phase_count);
+    }
+
+    // 短い実行でも数字が分かるように、間隔に関わらず最後に全体の集計を送ります
+    progress( new_progress(start.elapsed(), (record_count,sample_count,filtered_count), episode_lengths, &total_rewards, true) );
+    Ok(connected)
 }
 
-async fn selfplay_coroutine( co_ctx:Rc<CoroutineContext> ) {
-    loop {
-        let (graph_filename,_) = co_ctx.graph_info.borrow().clone();
-        let record = selfplay_craftone(&co_ctx.episode_param, &graph_filename, &co_ctx.predict_queue);
-        co_ctx.writer_sender.send(record.await).unwrap();
+// 学習が崩れ始めたことに早めに気付けるように、間隔ごとの平均報酬がwarn_belowを下回ったら警告します
+fn warn_low_reward( rewards:&RewardHistogram, warn_below:Option<f32> ) -> bool {
+    match (rewards.mean(),warn_below) {
+        (Some(mean),Some(floor)) if mean < floor as f64 => {
+            warn!(mean_reward = mean, records = rewards.count(), "warning: mean reward {:.3} of {} records is below {}", mean, rewards.count(), floor);
+            true
+        },
+        _ => false,
     }
 }
 
-fn selfplay_thread( ctx:ThreadContext ) {
+#[test]
+fn test_warn_low_reward()
+{
+    let mut rewards = RewardHistogram::new(&DEFAULT_REWARD_BUCKETS);
+    assert!( !warn_low_reward(&rewards, Some(0.5)) );
+
+    rewards.add(0.2);
+    rewards.add(0.4);
+    assert!( warn_low_reward(&rewards, Some(0.5)) );
+    assert!( !warn_low_reward(&rewards, Some(0.25)) );
+    assert!( !warn_low_reward(&rewards, None) );
+}
+
+fn new_progress( elapsed:Duration, (record_count,sample_count,filtered_count):(usize,usize,usize), episode_lengths:&EpisodeLengthHistogram, rewards:&RewardHistogram, finished:bool ) -> SelfPlayProgress {
+    // 書き込んですぐ閉じた場合に0で割らないようにします
+    let secs = (elapsed.as_millis() as f64 / 1000.0).max(0.001);
+    SelfPlayProgress { elapsed, record_count, sample_count, records_per_sec:record_count as f64 / secs, samples_per_sec:sample_count as f64 / secs, episode_lengths:episode_lengths.counts(), filtered_count, rewards:rewards.counts(), mean_reward:rewards.mean(), finished }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockWriter {
+    records : Rc<RefCell<Vec<Record>>>,
+    flushed : Rc<Cell<bool>>,
+}
 
-    // 最初の１つだけ初期化のために同期待ちします
-    let graph_info = match ctx.selfplay_receiver.recv() {
-        Ok(x) => x,
-        Err(_) => return,
+#[cfg(test)]
+impl WriteRecord for MockWriter {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
+        self.records.borrow_mut().push(record);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+        self.flushed.set(true);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn new_test_record( name:&str, reward:f32 ) -> Record {
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    Record { samples:vec![], name:name.to_string(), last_state:State::new(&mod_param), reward, thread_id:0, coroutine_id:0, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[0,0], setting:None, phase:0 }
+}
+
+#[test]
+fn test_write_records_final_progress()
+{
+    let reports = RefCell::new(vec![]);
+    let (sender,receiver) = channel();
+    for _ in 0..3 {
+        sender.send(new_test_record("selfplay", 0.5)).unwrap();
+    }
+    drop(sender);
+
+    // 間隔に届かない短い実行でも、最後に全体の集計だけは報告します
+    let progress = |x:SelfPlayProgress| reports.borrow_mut().push((x.record_count,x.finished,x.records_per_sec.is_finite()));
+    write_records( MockWriter::default(), vec![], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&progress,Duration::from_secs(3600),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+    assert_eq!( vec![(3,true,true)], *reports.borrow() );
+}
+
+#[test]
+fn test_write_records_preload()
+{
+    let records = Rc::new(RefCell::new(vec![]));
+    let (sender,receiver) = channel();
+    sender.send(new_test_record("selfplay", 0.5)).unwrap();
+    drop(sender);
+
+    write_records( MockWriter { records:records.clone(), ..Default::default() }, vec![new_test_record("preload", 0.5)], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&print_progress,Duration::from_secs(5),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+
+    let names : Vec<String> = records.borrow().iter().map(|x| x.name.clone()).collect();
+    assert_eq!( vec!["preload","selfplay"], names );
+}
+
+#[test]
+fn test_write_records_non_finite_reward()
+{
+    let run = |non_finite_reward:NonFiniteReward| {
+        let records = Rc::new(RefCell::new(vec![]));
+        let (sender,receiver) = channel();
+        sender.send(new_test_record("nan", f32::NAN)).unwrap();
+        sender.send(new_test_record("inf", f32::INFINITY)).unwrap();
+        sender.send(new_test_record("finite", 0.5)).unwrap();
+        drop(sender);
+
+        write_records( MockWriter { records:records.clone(), ..Default::default() }, vec![], &receiver, (None,0), (&non_finite_reward,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&print_progress,Duration::from_secs(5),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+        records.replace(vec![]).into_iter().map(|x| (x.name,x.reward)).collect::<Vec<(String,f32)>>()
     };
 
-    let mut predictor = Predictor::new();
-    predictor.load_network( graph_info.0.clone(), &*graph_info.1 );
+    assert_eq!( vec![("finite".to_string(),0.5)], run(NonFiniteReward::Reject) );
+    assert_eq!( vec![("nan".to_string(),0.0),("inf".to_string(),0.0),("finite".to_string(),0.5)], run(NonFiniteReward::Replace(0.0)) );
+}
 
-    // コルーチン間の共有コンテキスト
-    let co_ctx = Rc::new(CoroutineContext {
-        episode_param:ctx.episode_param,
-        writer_sender:ctx.writer_sender,
-        predict_queue:predictor.get_queue(),
-        graph_info:RefCell::new(graph_info),
+#[test]
+fn test_write_records_min_reward()
+{
+    let records = Rc::new(RefCell::new(vec![]));
+    let reports = RefCell::new(vec![]);
+    let (sender,receiver) = channel();
+    sender.send(new_test_record("low", 0.2)).unwrap();
+    sender.send(new_test_record("high", 0.8)).unwrap();
+    sender.send(new_test_record("threshold", 0.5)).unwrap();
+    drop(sender);
+
+    // 閾値を下回ったものは書き込みませんが、捨てた数として報告します
+    let progress = |x:SelfPlayProgress| reports.borrow_mut().push((x.record_count,x.filtered_count));
+    write_records( MockWriter { records:records.clone(), ..Default::default() }, vec![new_test_record("preload", 0.1)], &receiver, (None,0), (&NonFiniteReward::Reject,Some(0.5)), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&progress,Duration::from_secs(3600),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+
+    let names : Vec<String> = records.borrow().iter().map(|x| x.name.clone()).collect();
+    assert_eq!( vec!["high","threshold"], names );
+    assert_eq!( vec![(2,2)], *reports.borrow() );
+}
+
+#[test]
+fn test_write_records_phase()
+{
+    let records = Rc::new(RefCell::new(vec![]));
+    let (sender,receiver) = channel();
+    sender.send(Record { phase:0, ..new_test_record("previous", 0.5) }).unwrap();
+    sender.send(Record { phase:1, ..new_test_record("current", 0.5) }).unwrap();
+    drop(sender);
+
+    // 前のフェーズで作ったレコードは書き込み先が違うので書き込みません
+    write_records( MockWriter { records:records.clone(), ..Default::default() }, vec![], &receiver, (None,1), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&|_| {},Duration::from_secs(3600),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+    let names : Vec<String> = records.borrow().iter().map(|x| x.name.clone()).collect();
+    assert_eq!( vec!["current"], names );
+}
+
+#[test]
+fn test_write_records_written_count()
+{
+    let (sender,receiver) = channel();
+    sender.send(Record { phase:1, ..new_test_record("previous", 0.5) }).unwrap();
+    sender.send(new_test_record("low", 0.1)).unwrap();
+    sender.send(new_test_record("selfplay", 0.5)).unwrap();
+    drop(sender);
+
+    // max_recordsで使う数には、捨てたレコードと起動時に読み込んだレコードを含めません
+    let gauges = SelfPlayGauges::new();
+    write_records( MockWriter::default(), vec![new_test_record("preload", 0.5)], &receiver, (None,0), (&NonFiniteReward::Reject,Some(0.3)), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&|_| {},Duration::from_secs(3600),&EpisodeLengthHistogram::new(),&gauges) ).unwrap();
+    assert_eq!( 1, gauges.written_records() );
+    assert_eq!( 0, gauges.queue_depth() );
+}
+
+#[test]
+fn test_write_records_rewards()
+{
+    let (sender,receiver) = channel();
+    for reward in [0.1, 0.5, 0.9] {
+        sender.send(new_test_record("selfplay", reward)).unwrap();
+    }
+    drop(sender);
+
+    let reported = RefCell::new(vec![]);
+    let progress = |x:SelfPlayProgress| reported.borrow_mut().push((x.rewards,x.mean_reward));
+    let reward_report = RewardReport { buckets:vec![0.5], warn_below:Some(0.8) };
+    write_records( MockWriter::default(), vec![], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&reward_report), (&progress,Duration::from_secs(3600),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+
+    let (rewards,mean_reward) = reported.into_inner().pop().unwrap();
+    assert_eq!( vec![(Some(0.5),2),(None,1)], rewards );
+    assert!( (mean_reward.unwrap() - 0.5).abs() < 1e-6 );
+}
+
+#[test]
+fn test_write_records_progress()
+{
+    let (sender,receiver) = channel();
+    let handle = std::thread::spawn( move || {
+        for _ in 0..10 {
+            sender.send(new_test_record("selfplay", 0.5)).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
     });
 
-    // 非同期Executor
-    let mut executor = Executor::new();
-    for _ in 0..ctx.batch_size {
-        executor.spawn( selfplay_coroutine( co_ctx.clone() ) );
+    let reported = RefCell::new(vec![]);
+    let progress = |x:SelfPlayProgress| reported.borrow_mut().push((x.record_count,x.finished));
+    write_records( MockWriter::default(), vec![], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&progress,Duration::from_millis(1),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+    handle.join().unwrap();
+
+    // 最後の1回は全体の集計です
+    let mut reported = reported.into_inner();
+    assert_eq!( Some((10,true)), reported.pop() );
+    assert!( reported.iter().all(|(_,finished)| !finished) );
+    let reported : Vec<usize> = reported.into_iter().map(|(x,_)| x).collect();
+    assert!( reported.len() >= 2 );
+    assert!( reported.windows(2).all(|x| x[0] < x[1]) );
+    assert!( *reported.last().unwrap() <= 10 );
+}
+
+#[test]
+fn test_write_records_episode_lengths()
+{
+    let (sender,receiver) = channel();
+    let new_record = |turn:u32, completed:bool| {
+        let record = new_test_record("selfplay", 0.5);
+        Record { last_state:State { turn, completed, ..record.last_state }, ..record }
+    };
+    // 5ターン目で中断したものは4ターンとして数えます。preloadは数えません
+    for record in [new_record(3,true), new_record(5,true), new_record(12,true), new_record(5,false), new_record(70,true)] {
+        sender.send(record).unwrap();
     }
+    drop(sender);
+
+    let episode_lengths = EpisodeLengthHistogram::new();
+    write_records( MockWriter::default(), vec![new_record(3,true)], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&print_progress,Duration::from_secs(5),&episode_lengths,&SelfPlayGauges::new()) ).unwrap();
+
+    let counts = episode_lengths.counts();
+    assert_eq!( (Some(5),3), counts[0] );
+    assert_eq!( (Some(10),0), counts[1] );
+    assert_eq!( (Some(15),1), counts[2] );
+    assert_eq!( (None,1), *counts.last().unwrap() );
+}
+
+// スケジュールに従って書き込み先を切り替えます。
+// startはrun_simulationと共有していて、モデルの選択と同じタイミングで切り替わります
+// preloadは読み込みの失敗を起動時に返せるように、呼び出し側で読み込んでおきます。
+// 書き込めなかった場合はそのエラーで終わり、run_simulationが気付いて全体を終了させます
+fn write_thread( mysql_pool:Arc<Mutex<Pool>>, param:SelfPlayParameter, start:Instant, receiver:Receiver<Record>, mut preload:Vec<Record>, (episode_lengths,gauges):(Arc<EpisodeLengthHistogram>,Arc<SelfPlayGauges>) ) -> std::result::Result<(),CraftSimError> {
+    let progress : ProgressCallback = param.progress_callback.clone().unwrap_or_else(|| Arc::new(print_progress));
 
-    // 以下制作ループ
     loop {
-        // キューにあるだけ取得して最新状態を更新します
-        loop {
-            match ctx.selfplay_receiver.try_recv() {
-                Ok(graph_info) => {
-                    predictor.load_network( graph_info.0.clone(), &*graph_info.1 );
-                    *co_ctx.graph_info.borrow_mut() = graph_info;
+        let (index,remaining) = active_phase(&param.writer_schedule, start.elapsed());
+
+        // フェーズが1つしか無ければ切り替える必要はありません
+        let deadline = if param.writer_schedule.len() > 1 { Some(Instant::now() + remaining) } else { None };
+        let preload = std::mem::take(&mut preload);
+
+        let connected = match &param.writer_schedule[index].1 {
+            WriterParameter::Evaluation => write_records( EvaluationWriter::new( mysql_pool.clone(), param.plays_per_write, param.max_db_failures ), preload, &receiver, (deadline,index), (&param.non_finite_reward,param.min_reward), (&param.sample_retention,&param.value_target,&param.reward_report), (progress.as_ref(),param.progress_interval,&episode_lengths,&gauges) )?,
+            WriterParameter::Generation => write_records( GenerationWriter::new( mysql_pool.clone(), param.plays_per_write, param.max_db_failures, TsvFormatter { mod_param:param.episode_param.mod_param.clone(), value_target:param.value_target.clone(), priority_column:param.priority_column, settings:param.episode_param.mixed_settings.clone() } ), preload, &receiver, (deadline,index), (&param.non_finite_reward,param.min_reward), (&param.sample_retention,&param.value_target,&param.reward_report), (progress.as_ref(),param.progress_interval,&episode_lengths,&gauges) )?,
+            WriterParameter::JsonFile(path) => match JsonlWriter::open(path) {
+                Ok(writer) => write_records( writer, preload, &receiver, (deadline,index), (&param.non_finite_reward,param.min_reward), (&param.sample_retention,&param.value_target,&param.reward_report), (progress.as_ref(),param.progress_interval,&episode_lengths,&gauges) )?,
+                Err(e) => {
+                    error!("failed to open {:?} {:?}", path, e);
+                    return Err(e.into());
+                },
+            },
+            WriterParameter::SqliteEvaluation(path) => match SqliteEvaluationWriter::open(path, param.plays_per_write) {
+                Ok(writer) => write_records( writer, preload, &receiver, (deadline,index), (&param.non_finite_reward,param.min_reward), (&param.sample_retention,&param.value_target,&param.reward_report), (progress.as_ref(),param.progress_interval,&episode_lengths,&gauges) )?,
+                Err(e) => {
+                    error!("failed to open {:?} {:?}", path, e);
+                    return Err(e.into());
                 },
-                Err(TryRecvError::Disconnected) => { return },
-                Err(TryRecvError::Empty) => { break },
-            };
+            },
+            WriterParameter::SqliteGeneration(path) => match SqliteGenerationWriter::open(path, param.plays_per_write, TsvFormatter { mod_param:param.episode_param.mod_param.clone(), value_target:param.value_target.clone(), priority_column:param.priority_column, settings:param.episode_param.mixed_settings.clone() }) {
+                Ok(writer) => write_records( writer, preload, &receiver, (deadline,index), (&param.non_finite_reward,param.min_reward), (&param.sample_retention,&param.value_target,&param.reward_report), (progress.as_ref(),param.progress_interval,&episode_lengths,&gauges) )?,
+                Err(e) => {
+                    error!("failed to open {:?} {:?}", path, e);
+                    return Err(e.into());
+                },
+            },
         };
 
-        for _ in 0..5 {
-            executor.poll_all();
-            predictor.predict_batch( &co_ctx.episode_param.mod_param );
+        if !connected {
+            return Ok(());
         }
     }
 }
 
-// 戻り値の型は利用者側の都合でVecのタプルで返したほうが良いと思います
-fn spawn_selfplay_threads( episode_param:&EpisodeParameter, writer_sender:&Sender<Record>, thread_num:u32, batch_size:usize ) -> (Vec<JoinHandle<()>>,Vec<Sender<(String,Arc<(NetworkType,tch::nn::VarStore)>)>>) {
-    let mut handles = vec![];
-    let mut senders = vec![];
-    for thread_id in 0..thread_num {
-        let (sender,receiver) = channel();
-        let ctx = ThreadContext {
-            episode_param:episode_param.clone(),
-            batch_size:batch_size,
-            selfplay_receiver:receiver,
-            writer_sender:writer_sender.clone(),
-        };
-        let handle = std::thread::Builder::new().name(format!("selfplay{}",thread_id)).spawn( move ||{selfplay_thread(ctx);} ).unwrap();
-        handles.push(handle);
-        senders.push(sender);
+// 選ばれたモデルが変わらなくても、この間隔で配信し直します。
+// 読み込みに失敗したスレッドは、次に配信された時に読み込み直します
+const MODEL_REBROADCAST_INTERVAL : Duration = Duration::from_secs(60);
+
+// 選ばれたモデルを各スレッドに配信するかどうかです。lastは最後に配信したモデルと時刻です
+fn should_broadcast( last:&Option<(String,Instant)>, graph_filename:&str, now:Instant ) -> bool {
+    match last {
+        Some((name,sent)) => name != graph_filename || now.duration_since(*sent) >= MODEL_REBROADCAST_INTERVAL,
+        None => true,
     }
-    (handles,senders)
 }
 
-// ここは借用ではなくmoveである必要があるようです。詳しくはこちら
-// https://users.rust-lang.org/t/how-to-join-handles-of-threads/52494
-fn wait_threads(handles:Vec<JoinHandle<()>>) {
-    for handle in handles {
-        handle.join().unwrap();
-    }
+#[test]
+fn test_should_broadcast()
+{
+    let now = Instant::now();
+    assert!( should_broadcast(&None, "a", now) );
+
+    let last = Some(("a".to_string(),now));
+    assert!( !should_broadcast(&last, "a", now + Duration::from_secs(2)) );
+    assert!( should_broadcast(&last, "b", now + Duration::from_secs(2)) );
+    assert!( should_broadcast(&last, "a", now + MODEL_REBROADCAST_INTERVAL) );
 }
 
-fn write_records<W:WriteRecord>( mut writer:W, receiver:Receiver<Record> ) {
+// データベースのエラーが続いた時に、次に問い合わせるまで追加で待つ時間です。
+// 失敗するたびに倍にしますが、再起動を待つには十分な長さで止めます
+const DB_RETRY_BASE_DELAY : Duration = Duration::from_secs(1);
+const DB_RETRY_MAX_DELAY : Duration = Duration::from_secs(60);
 
-    let start = Instant::now();
-    let interval = Duration::new(5,0);
-    let mut next_time = start + interval;
-    let mut record_count = 0;
-    let mut sample_count = 0;
+fn db_retry_delay( failures:u32 ) -> Duration {
+    DB_RETRY_BASE_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(DB_RETRY_MAX_DELAY)
+}
 
-    while let Ok(record) = receiver.recv() {
-        record_count += 1;
-        sample_count += record.samples.len();
+#[test]
+fn test_db_retry_delay()
+{
+    assert_eq!( Duration::from_secs(1), db_retry_delay(1) );
+    assert_eq!( Duration::from_secs(2), db_retry_delay(2) );
+    assert_eq!( Duration::from_secs(32), db_retry_delay(6) );
+    assert_eq!( DB_RETRY_MAX_DELAY, db_retry_delay(7) );
+    assert_eq!( DB_RETRY_MAX_DELAY, db_retry_delay(u32::MAX) );
+}
 
-        writer.write_record(record).unwrap();
+// シグナルを受けてからメインループが気付くまでの最大の遅れです
+const SHUTDOWN_POLL_INTERVAL : Duration = Duration::from_millis(100);
 
+// 次のループまで待ちます。max_runtimeを過ぎた場合はfalseを返します。
+// 終了が遅れないように、max_runtimeまでしか待たず、shutdownが立ったらすぐに戻ります
+fn wait_next_tick( start:Instant, interval:Duration, max_runtime:Option<Duration>, shutdown:&AtomicBool ) -> bool {
+    let wait = match max_runtime {
+        None => interval,
+        Some(max_runtime) => max_runtime.saturating_sub(start.elapsed()).min(interval),
+    };
+    let deadline = Instant::now() + wait;
+    while !shutdown.load(Ordering::SeqCst) {
         let now = Instant::now();
-        if now >= next_time {
-            let duration = now - start;
-            let secs = duration.as_millis() as f64 / 1000.0;
-            eprintln!("{:.3}[secs] {}[records] {}[samples] {:.3}[records/secs] {:.3}[samples/sec]",
-                secs, record_count, sample_count, record_count as f64 / secs, sample_count as f64 / secs );
-            next_time += interval;
+        if now >= deadline {
+            break;
         }
+        std::thread::sleep((deadline - now).min(SHUTDOWN_POLL_INTERVAL));
     }
+    max_runtime.map(|x| start.elapsed() < x).unwrap_or(true)
+}
+
+#[test]
+fn test_wait_next_tick_shutdown()
+{
+    // 終了の合図があれば間隔を待たずに戻ります
+    let shutdown = AtomicBool::new(true);
+    let start = Instant::now();
+    assert!( wait_next_tick(start, Duration::from_secs(60), None, &shutdown) );
+    assert!( start.elapsed() < Duration::from_secs(1) );
 
-    writer.flush().unwrap();
+    let shutdown = AtomicBool::new(false);
+    assert!( wait_next_tick(start, Duration::from_millis(10), None, &shutdown) );
+    assert!( start.elapsed() >= Duration::from_millis(10) );
 }
 
-fn write_thread( mysql_pool:Arc<Mutex<Pool>>, param:SelfPlayParameter, receiver:Receiver<Record> ) {
-    match &param.writer_param {
-        WriterParameter::Evaluation => write_records( EvaluationWriter::new( mysql_pool, param.plays_per_write ), receiver ),
-        WriterParameter::Generation => write_records( GenerationWriter::new( mysql_pool, param.plays_per_write, param.episode_param.mod_param.clone() ), receiver ),
-    };
+// SIGINTとSIGTERMを受けたら立てるフラグです。メインループが見て、いつもの順番で終了します。
+// 終了処理の途中でもう一度受けた場合は、書き込みを待たずに終了コード1ですぐに終わります。
+// 捨てる時に登録を外すので、run_simulationが戻った後にシグナルを受けても強制終了はしません
+struct ShutdownSignal {
+    flag : Arc<AtomicBool>,
+    ids : Vec<signal_hook::SigId>,
+}
+
+impl ShutdownSignal {
+    fn install() -> std::io::Result<ShutdownSignal> {
+        use signal_hook::consts::{SIGINT,SIGTERM};
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut ids = vec![];
+        for signal in [SIGINT,SIGTERM] {
+            // 先に登録したものが先に呼ばれるので、1回目はまだフラグが立っておらず終了しません
+            ids.push(signal_hook::flag::register_conditional_shutdown(signal, 1, flag.clone())?);
+            ids.push(signal_hook::flag::register(signal, flag.clone())?);
+        }
+        Ok(ShutdownSignal { flag, ids })
+    }
+
+    fn received(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_shutdown_signal()
+{
+    // 1回目のシグナルではフラグが立つだけで、プロセスは終了しません
+    let signal = ShutdownSignal::install().unwrap();
+    assert!( !signal.received() );
+    signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+    assert!( signal.received() );
+}
+
+impl Drop for ShutdownSignal {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
 }
 
-fn run_simulation(param:&SelfPlayParameter ) {
+#[test]
+fn test_writer_queue_backpressure()
+{
+    // 1件ごとに少し待つ遅い書き込み先です
+    struct SlowWriter {
+        count : usize,
+    }
+
+    impl WriteRecord for SlowWriter {
+        fn write_record(&mut self, _record:Record) -> std::result::Result<(),CraftSimError> {
+            std::thread::sleep(Duration::from_millis(1));
+            self.count += 1;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+            Ok(())
+        }
+    }
 
-    let mysql_password = match std::env::var("MYSQL_PASSWORD") {
-        Ok(val) => format!(":{}", val ),
-        Err(_) => String::new(),
+    let (sender,receiver) = sync_channel(2);
+    let sent = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let sent = sent.clone();
+        std::thread::spawn( move || {
+            for _ in 0..6 {
+                sender.send(new_test_record("selfplay", 0.5)).unwrap();
+                sent.fetch_add(1, Ordering::SeqCst);
+            }
+        })
     };
 
-    let url = format!("mysql://{}{}@localhost:3306/craft", param.mysql_user, mysql_password );
-    eprintln!("Connect to mysql...");
-    let mysql_pool_base = Pool::new_manual(2,2,Opts::from_url(&url).unwrap()).unwrap();
-    let mysql_pool = Arc::new(Mutex::new(mysql_pool_base));
+    // 書き込み側が受け取らない間は、キューの容量を超えて送れません
+    let start = Instant::now();
+    while sent.load(Ordering::SeqCst) < 2 && start.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!( 2, sent.load(Ordering::SeqCst) );
 
-    let (writer_sender,writer_receiver) = channel();
+    // 書き込みが進めば待っていた分も送られて、全て書き込まれます
+    let connected = write_records( SlowWriter { count:0 }, vec![], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&|_| (),Duration::from_secs(5),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+    handle.join().unwrap();
+    assert!( !connected );
+    assert_eq!( 6, sent.load(Ordering::SeqCst) );
+}
 
-    // 並列処理でセルフプレイします
-    let (selfplay_handles,selfplay_senders) = spawn_selfplay_threads( &param.episode_param, &writer_sender, param.thread_num, param.batch_size );
+#[test]
+fn test_max_runtime()
+{
+    let (sender,receiver) = channel();
+    let records = Rc::new(RefCell::new(vec![]));
+    let writer = MockWriter { records:records.clone(), ..Default::default() };
+    let flushed = writer.flushed.clone();
 
-    // 書き込みスレッド作成
+    // 時間が過ぎるまでセルフプレイの代わりにレコードを送ります
+    let start = Instant::now();
+    let handle = std::thread::spawn( move || {
+        let mut count = 0;
+        loop {
+            sender.send(new_test_record("selfplay", 0.5)).unwrap();
+            count += 1;
+            if !wait_next_tick(start, Duration::from_secs(2), Some(Duration::from_millis(20)), &AtomicBool::new(false)) {
+                break count;
+            }
+        }
+    });
+
+    // 送信側が終了すれば、書き込みを終えてflushしてから戻ります
+    let connected = write_records( writer, vec![], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&print_progress,Duration::from_secs(5),&EpisodeLengthHistogram::new(),&SelfPlayGauges::new()) ).unwrap();
+    let sent = handle.join().unwrap();
+
+    assert!( start.elapsed() < Duration::from_secs(2) );
+    assert!( !connected );
+    assert!( flushed.get() );
+    assert_eq!( sent, records.borrow().len() );
+}
+
+// textfileとHTTPで公開するメトリクスをまとめて出力します
+fn render_metrics( action_counters:&ActionCounters, episode_lengths:&EpisodeLengthHistogram, gauges:&SelfPlayGauges ) -> String {
+    action_counters.render() + &episode_lengths.render() + &gauges.render()
+}
+
+#[cfg(feature="metrics-server")]
+fn start_metrics_server( addr:&str, (action_counters,episode_lengths,gauges):(Arc<ActionCounters>,Arc<EpisodeLengthHistogram>,Arc<SelfPlayGauges>) ) -> std::result::Result<(),CraftSimError> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    info!("serve metrics on http://{}/metrics", listener.local_addr()?);
+    super::metrics::spawn_metrics_server(listener, super::metrics::METRICS_CONNECTION_TIMEOUT, move || render_metrics(&action_counters, &episode_lengths, &gauges))?;
+    Ok(())
+}
+
+// HTTPの依存を持ち込まないように、機能を有効にしていない場合は設定だけで失敗させます
+#[cfg(not(feature="metrics-server"))]
+fn start_metrics_server( _addr:&str, _:(Arc<ActionCounters>,Arc<EpisodeLengthHistogram>,Arc<SelfPlayGauges>) ) -> std::result::Result<(),CraftSimError> {
+    Err(CraftSimError::Config("metrics address requires the metrics-server feature".to_string()))
+}
+
+// 前回からモデルごとに推論した状態の数の割合です。世代の切り替え中にどのモデルがどれだけ推論しているかを見るためのものです。
+// 推論したモデルが1つ以下の場合は出力しません
+fn format_prediction_shares( last:&HashMap<String,u64>, now:&HashMap<String,u64> ) -> Option<String> {
+    let mut deltas : Vec<(&String,u64)> = now.iter()
+        .map(|(name,count)| (name, count.saturating_sub(*last.get(name).unwrap_or(&0))))
+        .filter(|(_,delta)| *delta > 0)
+        .collect();
+    if deltas.len() <= 1 {
+        return None;
+    }
+    deltas.sort();
+    let total : u64 = deltas.iter().map(|(_,delta)| delta).sum();
+    Some(deltas.iter().map(|(name,delta)| format!("{} {:.1}%", name, *delta as f64 * 100.0 / total as f64)).collect::<Vec<_>>().join(", "))
+}
+
+#[test]
+fn test_format_prediction_shares()
+{
+    let counts = |x:&[(&str,u64)]| -> HashMap<String,u64> { x.iter().map(|(name,count)| (name.to_string(),*count)).collect() };
+    let last = counts(&[("a",100),("b",10)]);
+    assert_eq!( None, format_prediction_shares(&last, &counts(&[("a",200),("b",10)])) );
+    assert_eq!( Some("a 75.0%, b 25.0%".to_string()), format_prediction_shares(&last, &counts(&[("a",130),("b",20)])) );
+}
+
+fn run_simulation(param:&SelfPlayParameter ) -> std::result::Result<(),CraftSimError> {
+    let _span = info_span!("run_simulation", thread_num = param.thread_num, batch_size = param.batch_size).entered();
+
+    // 上限がbatch_sizeより小さいと推論するバッチが埋まらなくなるので、設定の誤りとして扱います
+    if let Some(max_queued_tasks) = param.max_queued_tasks {
+        if max_queued_tasks < param.batch_size {
+            return Err(CraftSimError::Config(format!("max queued tasks {} must not be less than batch size {}", max_queued_tasks, param.batch_size)));
+        }
+    }
+
+    // スレッドを作る前に登録しておきます。登録に失敗してもスレッドを残したまま返すことはありません
+    let shutdown_signal = ShutdownSignal::install()?;
+
+    // 現在のロジックで過去のレコードが再現できない場合は、データが混ざらないように起動を中止します
+    if let Some(source) = &param.startup_verification {
+        info!("Verify records...");
+        verify_startup_records(source, &param.episode_param.mod_param)?;
+    }
+
+    info!("Connect to mysql...");
+    let mysql_pool = db::connect(&db::mysql_url(&param.mysql_user, &param.mysql_host, param.mysql_port, &param.mysql_db), 2)?;
+
+    let preload = match &param.preload_records {
+        Some(source) => load_records(source)?,
+        None => vec![],
+    };
+
+    // 書き込みスレッドはスケジュールに従って書き込み先を切り替えます
     let send_param : SelfPlayParameter = param.clone();
     let send_mysql_pool = mysql_pool.clone();
-    let writer_handle = std::thread::Builder::new().name("writer".to_string()).spawn( move || { write_thread( send_mysql_pool, send_param, writer_receiver ) } ).unwrap();
+    run_simulation_with_writer( param, &shutdown_signal, mysql_pool, move |start,receiver,metrics| write_thread( send_mysql_pool, send_param, start, receiver, preload, metrics ) )
+}
+
+// run_simulationの接続などの準備を終えた後の部分です。テストでは書き込みスレッドを差し替えます
+fn run_simulation_with_writer<F>( param:&SelfPlayParameter, shutdown_signal:&ShutdownSignal, mysql_pool:Arc<Mutex<Pool>>, write:F ) -> std::result::Result<(),CraftSimError>
+    where F : FnOnce(Instant,Receiver<Record>,(Arc<EpisodeLengthHistogram>,Arc<SelfPlayGauges>)) -> std::result::Result<(),CraftSimError> + Send + 'static
+{
+    // 書き込みが詰まった時にレコードが際限なく溜まらないように、キューが一杯ならセルフプレイ側を待たせます
+    let (writer_sender,writer_receiver) = sync_channel(param.writer_queue_capacity);
+
+    // 推論結果のキャッシュは全スレッドで共有します
+    let prediction_cache = match &param.prediction_cache {
+        Some(path) => {
+            info!("Open prediction cache {}...", path);
+            Some(Arc::new(Mutex::new(PredictionCache::open(path, param.prediction_cache_size)?)))
+        },
+        None => None,
+    };
+
+    let shared = SharedContext {
+        load_limiter:Arc::new(LoadLimiter::new(param.max_concurrent_loads)),
+        prediction_cache:prediction_cache.clone(),
+        action_counters:Arc::new(ActionCounters::new()),
+        gauges:Arc::new(SelfPlayGauges::new()),
+        capacities:Arc::new((0..param.thread_num).map(|_| AtomicUsize::new(param.batch_size)).collect()),
+    };
+
+    // 並列処理でセルフプレイします。最初は最初のフェーズの設定で始めます
+    let mut episode_param = param.episode_param.clone();
+    let mut phase = 0;
+    let (selfplay_handles,selfplay_senders) = spawn_selfplay_threads( &phase_episode_param(&episode_param, &param.writer_schedule[phase].1), &writer_sender, param.thread_num, (param.coroutine_num,param.batch_size,param.max_queued_tasks,param.shutdown_mode), &param.gpu_devices, &shared );
+
+    // 書き込みスレッド作成
+    let episode_lengths = Arc::new(EpisodeLengthHistogram::new());
+    let send_episode_lengths = episode_lengths.clone();
+    let send_gauges = shared.gauges.clone();
+    let start = Instant::now();
+    let span = info_span!("writer");
+    let writer_handle = std::thread::Builder::new().name("writer".to_string()).spawn( move || { let _span = span.entered(); write( start, writer_receiver, (send_episode_lengths,send_gauges) ) } )?;
+
+    if let Some(addr) = &param.metrics_addr {
+        start_metrics_server(addr, (shared.action_counters.clone(),episode_lengths.clone(),shared.gauges.clone()))?;
+    }
 
     // 以下、終了条件を満たすまで無限ループします
-    let mut graph_cache = WeightsCache::new();
+    let mut graph_cache = WeightsCache::new(WEIGHTS_CACHE_CAPACITY);
     let mut ucb1_context = UCB1Context::new( mysql_pool.clone() );
+    let mut last_capacities : Vec<usize> = vec![param.batch_size; param.thread_num as usize];
+    let mut db_failures = 0;
+    let mut last_broadcast = None;
+    let mut last_prediction_counts = HashMap::new();
 
-    loop {
-        let model = ucb1_context.get_model(&param.selector);
+    // エラーでループを抜けた場合も、スレッドを終了させてから返します
+    let result = loop {
+        let (index,_) = active_phase(&param.writer_schedule, start.elapsed());
+        let model = ucb1_context.get_model(&param.writer_schedule[index].2);
+        if let Ok((name,_)) = &model {
+            shared.gauges.set_selected_model(name);
+        }
 
         match model {
             Err(super::selector::Error::Empty) => {
-                eprintln!("wait for ucb1 model...");
+                db_failures = 0;
+                info!("wait for ucb1 model...");
+            },
+            // データベースの再起動などはしばらく待てば直るので、セルフプレイを続けたまま問い合わせ直します。
+            // 待っている間もシグナルやmax_runtimeで終了が遅れないように、次のループまでの待ちと同じように待ちます
+            Err(x) if x.is_transient() && db_failures < param.max_db_failures => {
+                db_failures += 1;
+                let delay = db_retry_delay(db_failures);
+                error!(failures = db_failures, "failed to select model ({}/{}). retry after {:?}: {:?}", db_failures, param.max_db_failures, delay, x);
+                wait_next_tick(start, delay, param.max_runtime, &shutdown_signal.flag);
             },
-            Ok((graph_filename,network_type)) => {
-                let graph = graph_cache.load_weights(&graph_filename, network_type).unwrap();
+            Ok((graph_filename,network_type)) if should_broadcast(&last_broadcast, &graph_filename, Instant::now()) => {
+                db_failures = 0;
+                let graph = match graph_cache.load_weights(&graph_filename, network_type) {
+                    Ok(graph) => graph,
+                    Err(e) => break Err(e),
+                };
                 for sender in &selfplay_senders {
-                    sender.send((graph_filename.clone(), graph.clone())).unwrap()
+                    sender.send(ThreadMessage::Network((graph_filename.clone(), graph.clone()))).unwrap()
                 }
+                last_broadcast = Some((graph_filename,Instant::now()));
+            },
+            Ok(_) => {
+                db_failures = 0;
             },
             Err(x) => {
-                eprintln!("error on mysql {:?}", x);
-                break;
+                break Err(x.into());
             },
         }
-        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        // 設定が更新されるかフェーズが変わったら、そのフェーズの設定を配信します
+        let updated = param.control.as_ref().and_then(|x| x.take_episode_param());
+        if updated.is_some() || index != phase {
+            if let Some(x) = updated {
+                info!("update episode parameter.");
+                episode_param = x;
+            }
+            phase = index;
+            let phase_param = phase_episode_param(&episode_param, &param.writer_schedule[phase].1);
+            for sender in &selfplay_senders {
+                sender.send(ThreadMessage::EpisodeParameter(phase, Box::new(phase_param.clone()))).unwrap()
+            }
+        }
+
+        // 読み込みに失敗して能力が下がったスレッドを報告します
+        for (thread_id,capacity) in shared.capacities.iter().enumerate() {
+            let capacity = capacity.load(Ordering::SeqCst);
+            if capacity != last_capacities[thread_id] {
+                warn!(thread_id, batch_size = capacity, "selfplay{} is running with batch size {}/{}", thread_id, capacity, param.batch_size);
+                last_capacities[thread_id] = capacity;
+            }
+        }
+
+        let prediction_counts = shared.gauges.prediction_counts();
+        if let Some(shares) = format_prediction_shares(&last_prediction_counts, &prediction_counts) {
+            info!("predictions by model: {}", shares);
+        }
+        last_prediction_counts = prediction_counts;
+
+        if let Some(path) = &param.metrics_file {
+            if let Err(e) = write_textfile(path, &render_metrics(&shared.action_counters, &episode_lengths, &shared.gauges)) {
+                error!("failed to write metrics {:?}", e);
+            }
+        }
+        if !wait_next_tick(start, param.model_poll_interval, param.max_runtime, &shutdown_signal.flag) {
+            info!("reached max runtime. shutting down...");
+            break Ok(());
+        }
+        if shutdown_signal.received() {
+            info!("received signal. shutting down... (send again to exit immediately)");
+            break Ok(());
+        }
+
+        // 書き込みスレッドは送信側を閉じるまで終わらないので、先に終わっていたら書き込みに失敗しています
+        if writer_handle.is_finished() {
+            error!("writer thread stopped. shutting down...");
+            break Ok(());
+        }
+
+        // 受け取っても捨てたレコードは数えません
+        if param.max_records.map(|x| shared.gauges.written_records() >= x).unwrap_or(false) {
+            info!("reached max records. shutting down...");
+            break Ok(());
+        }
+    };
+
+    // 終了の順番です。
+    // 1. セルフプレイのスレッドへの送信側を閉じて、新しいエピソードを始めないようにします
+    // 2. 各スレッドは実行中のエピソードを送り終えてから終わるので、全て待ちます
+    // 3. 全てのレコードが送られた後で書き込み側への送信側を閉じます。書き込みスレッドは残りを書き込んでflushしてから終わります
+    // 書き込みに失敗した場合だけは書き込みスレッドが先に終わります。その後に送ったレコードは各スレッドで捨てて、書き込みのエラーを返します
+    drop(selfplay_senders);
+    let selfplay_result = wait_threads(selfplay_handles);
+    drop(writer_sender);
+    let writer_result = writer_handle.join().map_err(|_| CraftSimError::LogicViolation("writer thread panicked".to_string()))?;
+    writer_result?;
+    selfplay_result?;
+
+    if let Some(cache) = prediction_cache {
+        cache.lock().unwrap().save()?;
     }
 
-    for sender in &selfplay_senders {
-        drop(sender)
+    result
+}
+
+// 書き込めなくなったら、セルフプレイを止めて書き込みのエラーを返します
+#[test]
+fn test_run_simulation_writer_error()
+{
+    // 常に失敗する書き込み先です
+    struct FailingWriter;
+
+    impl WriteRecord for FailingWriter {
+        fn write_record(&mut self, _record:Record) -> std::result::Result<(),CraftSimError> {
+            Err(std::io::Error::other("disk full").into())
+        }
+
+        fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+            Ok(())
+        }
     }
-    wait_threads(selfplay_handles);
-    drop(writer_sender);
-    writer_handle.join().unwrap();
+
+    let param = SelfPlayParameter {
+        episode_param : new_test_episode_param(),
+        plays_per_write : 1,
+        mysql_user : "root".to_string(),
+        mysql_host : "127.0.0.1".to_string(),
+        mysql_port : 1,
+        mysql_db : "craft".to_string(),
+        thread_num : 1,
+        tch_thread_num : 1,
+        tch_interop_thread_num : 1,
+        coroutine_num : 1,
+        batch_size : 1,
+        max_queued_tasks : None,
+        gpu_devices : vec![],
+        max_concurrent_loads : 0,
+        writer_schedule : vec![(Duration::MAX, WriterParameter::Evaluation, Selector::UCB1(1.0))],
+        preload_records : None,
+        prediction_cache : None,
+        prediction_cache_size : 0,
+        value_target : ValueTarget::MonteCarlo,
+        priority_column : false,
+        non_finite_reward : NonFiniteReward::Reject,
+        shutdown_mode : ShutdownMode::Discard,
+        min_reward : None,
+        startup_verification : None,
+        metrics_file : None,
+        metrics_addr : None,
+        sample_retention : SampleRetention::All,
+        reward_report : RewardReport::default(),
+        progress_callback : None,
+        progress_interval : Duration::from_secs(10),
+        model_poll_interval : Duration::from_millis(10),
+        writer_queue_capacity : 4,
+        max_runtime : Some(Duration::from_secs(60)),
+        max_records : None,
+        control : None,
+        max_db_failures : 5,
+    };
+
+    // 起動時には接続しないようにして、モデルの選択は接続できずに待ち続けます
+    let opts = Opts::from_url(&db::mysql_url(&param.mysql_user, &param.mysql_host, param.mysql_port, &param.mysql_db)).unwrap();
+    let mysql_pool = Arc::new(Mutex::new(Pool::new_manual(0, 1, opts).unwrap()));
+    let shutdown_signal = ShutdownSignal::install().unwrap();
+
+    let start = Instant::now();
+    let result = run_simulation_with_writer( &param, &shutdown_signal, mysql_pool, |_,receiver,(episode_lengths,gauges)| {
+        write_records( FailingWriter, vec![new_test_record("preload", 0.5)], &receiver, (None,0), (&NonFiniteReward::Reject,None), (&SampleRetention::All,&ValueTarget::MonteCarlo,&RewardReport::default()), (&|_| {},Duration::from_secs(3600),&episode_lengths,&gauges) ).map(|_| ())
+    });
+    assert!( matches!( result, Err(CraftSimError::Io(_)) ) );
+    assert!( start.elapsed() < Duration::from_secs(30) );
 }
 
-pub fn run(param:&SelfPlayParameter) {
+pub fn run(param:&SelfPlayParameter) -> std::result::Result<(),CraftSimError> {
 
     // 強制シングルスレッドの設定にします。
     // 現状調査では1が最も高速らしいです。
@@ -298,5 +2896,5 @@ pub fn run(param:&SelfPlayParameter) {
     tch::set_num_threads( param.tch_thread_num as i32 );
     tch::set_num_interop_threads( param.tch_interop_thread_num as i32 );
 
-    run_simulation(param);
+    run_simulation(param)
 }
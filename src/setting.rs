@@ -1,6 +1,11 @@
 use std::sync::Arc;
 use std::collections::HashMap;
 
+use serde::{Serialize,Serializer,Deserialize,Deserializer};
+
+// 作業と品質の上がり方の計算式です。
+// パッチによって計算式が違うので、ModifierParameterごとにどの計算式を使うかを選びます。
+// run_actionはaction側の効率だけ決めて、実際の値はここに任せています
 pub trait AdvanceTable
 {
     fn working_advance(&self, efficiency:u32, high_progress:bool, veneration:bool, muscle_memory:bool) -> u32;
@@ -143,6 +148,83 @@ impl ModifierParameter {
     }
 }
 
+type PresetConstructor = fn() -> ModifierParameter;
+
+// 設定ファイルで使う名前と、定義済みのModifierParameterです
+const PRESETS : [(&str, PresetConstructor);2] = [
+    ("ishgard_reconstruction_4th", ModifierParameter::new_ishgard_reconstruction_4th),
+    ("fountain_of_usouso", ModifierParameter::new_fountain_of_usouso),
+];
+
+impl ModifierParameter {
+    pub fn from_preset_name(name:&str) -> Option<ModifierParameter> {
+        PRESETS.iter().find(|(x,_)| *x == name).map(|(_,f)| f())
+    }
+
+    // 定義済みのものと同じであればその名前を返します。advance_tableは比べられないので数値だけで見分けます
+    pub fn preset_name(&self) -> Option<&'static str> {
+        self.preset_index().map(|i| PRESETS[i].0)
+    }
+
+    // 定義済みのものの中での番号です。学習データに設定を数値で書く時に使います
+    pub fn preset_index(&self) -> Option<usize> {
+        PRESETS.iter().position(|(_,f)| f() == *self)
+    }
+
+    fn numeric_fields(&self) -> (u32,u32,u32,u32,f32,f32,u32) {
+        (self.max_working, self.max_quality, self.max_durability, self.max_cp, self.bonus_time_t, self.bonus_threshold_t, self.bonus_threshold)
+    }
+}
+
+// advance_tableは比べられないので数値だけで比べます
+impl PartialEq for ModifierParameter {
+    fn eq(&self, other:&ModifierParameter) -> bool {
+        self.numeric_fields() == other.numeric_fields()
+    }
+}
+
+impl std::fmt::Debug for ModifierParameter {
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModifierParameter")
+            .field("max_working", &self.max_working)
+            .field("max_quality", &self.max_quality)
+            .field("max_durability", &self.max_durability)
+            .field("max_cp", &self.max_cp)
+            .field("bonus_time_t", &self.bonus_time_t)
+            .field("bonus_threshold_t", &self.bonus_threshold_t)
+            .field("bonus_threshold", &self.bonus_threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+// 設定ファイルには定義済みのものの名前で書きます。advance_tableは計算式そのものなので値として書けないためです
+impl Serialize for ModifierParameter {
+    fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok,S::Error> {
+        let name = self.preset_name().ok_or_else(|| serde::ser::Error::custom("only preset modifier parameters can be serialized"))?;
+        serializer.serialize_str(name)
+    }
+}
+
+impl<'de> Deserialize<'de> for ModifierParameter {
+    fn deserialize<D:Deserializer<'de>>(deserializer:D) -> Result<ModifierParameter,D::Error> {
+        let name = String::deserialize(deserializer)?;
+        ModifierParameter::from_preset_name(&name).ok_or_else(|| serde::de::Error::custom(format!("unknown modifier parameter {}", name)))
+    }
+}
+
+#[test]
+fn test_modifier_parameter_serde()
+{
+    let json = serde_json::to_string(&ModifierParameter::new_fountain_of_usouso()).unwrap();
+    assert_eq!( "\"fountain_of_usouso\"", json );
+    let mod_param : ModifierParameter = serde_json::from_str(&json).unwrap();
+    assert_eq!( Some("fountain_of_usouso"), mod_param.preset_name() );
+    assert!( serde_json::from_str::<ModifierParameter>("\"unknown\"").is_err() );
+
+    let custom = ModifierParameter { max_cp:1, ..ModifierParameter::new_fountain_of_usouso() };
+    assert!( serde_json::to_string(&custom).is_err() );
+}
+
 impl AdvanceTable for ApproximationTable {
     fn working_advance(&self, efficiency:u32, high_progress:bool, veneration:bool, muscle_memory:bool) -> u32 {
         let cond_rate = if high_progress { 1.5 } else { 1.0 };
@@ -187,3 +269,35 @@ impl AdvanceTable for SimpleTable {
         return ( q3 * cond_rate * buff_rate ) as u32;
     }
 }
+
+// 初期状態で作業と加工を1回ずつ実行した時の値を固定しておきます。
+// 計算式を変更した場合に、意図せず別のパッチの値が変わってしまわないようにするためのものです
+#[cfg(test)]
+fn advance_for_test( mod_param:&ModifierParameter ) -> (u32,u32) {
+    use xorshift::SeedableRng;
+    use super::logic::{State,Action,Modifier};
+
+    let seeds = [1, 2];
+    let mut modifier = Modifier { mod_param:mod_param.clone(), rng:SeedableRng::from_seed(&seeds[..]) };
+    let s = State::new(mod_param);
+    let working = s.run_action(&mut modifier, &Action::BasicSynthesis).working;
+    let quality = s.run_action(&mut modifier, &Action::BasicTouch).quality;
+    (working,quality)
+}
+
+#[test]
+fn test_approximation_table()
+{
+    let mod_param = ModifierParameter::new_ishgard_reconstruction_4th();
+    assert_eq!( (566,650), advance_for_test(&mod_param) );
+    assert_eq!( 976, mod_param.advance_table.quality_advance(100, true, false, false, 0) );
+}
+
+#[test]
+fn test_simple_table()
+{
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    assert_eq!( (250,247), advance_for_test(&mod_param) );
+    assert_eq!( 370, mod_param.advance_table.quality_advance(100, false, true, false, 0) );
+    assert_eq!( 1482, mod_param.advance_table.quality_advance(300, false, false, false, 10) );
+}
@@ -0,0 +1,74 @@
+// 特定のモジュールに属さない小さな関数です
+
+use std::time::Duration;
+
+use tracing::warn;
+
+// splitmix64の1ステップです。u64の全単射なので、入力が違えば出力も必ず違います。
+// xorshiftは全て0のシードでは0しか返さないので、利用者が指定したシードはこれで混ぜてから使います
+pub fn splitmix64( x:u64 ) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[test]
+fn test_splitmix64()
+{
+    use xorshift::{Rng,SeedableRng,Xorshift128};
+
+    // 0を混ぜても0にならないので、xorshiftの系列が止まりません
+    let seeds = [splitmix64(0), splitmix64(splitmix64(0))];
+    assert!( seeds.iter().all(|x| *x != 0) );
+    let mut rng : Xorshift128 = SeedableRng::from_seed(&seeds[..]);
+    assert!( (0..4).all(|_| rng.next_u64() != 0) );
+    assert_ne!( splitmix64(1), splitmix64(2) );
+}
+
+// 失敗したら待ち時間を倍にしながらattempts回まで試します
+pub fn retry_with_backoff<T,E:std::fmt::Debug,F:FnMut() -> Result<T,E>>( attempts:u32, initial_delay:Duration, f:F ) -> Result<T,E> {
+    retry_with_backoff_if( attempts, initial_delay, |_| true, f )
+}
+
+// retryableがtrueを返すエラーだけやり直します。それ以外のエラーはすぐに返します
+pub fn retry_with_backoff_if<T,E:std::fmt::Debug,R:Fn(&E) -> bool,F:FnMut() -> Result<T,E>>( attempts:u32, initial_delay:Duration, retryable:R, mut f:F ) -> Result<T,E> {
+    let mut delay = initial_delay;
+    let mut i = 1;
+    loop {
+        match f() {
+            Ok(x) => return Ok(x),
+            Err(e) if i >= attempts || !retryable(&e) => return Err(e),
+            Err(e) => {
+                warn!(attempt = i, attempts, "retry after {:?} ({}/{}) {:?}", delay, i, attempts, e);
+                std::thread::sleep(delay);
+                delay *= 2;
+                i += 1;
+            },
+        }
+    }
+}
+
+#[test]
+fn test_retry_with_backoff()
+{
+    // 1回失敗した後に成功します
+    let mut calls = 0;
+    let ret = retry_with_backoff( 3, Duration::from_millis(1), || {
+        calls += 1;
+        if calls == 1 { Err("out of memory") } else { Ok(calls) }
+    });
+    assert_eq!( Ok(2), ret );
+
+    // 失敗し続けた場合は規定回数で諦めます
+    let mut calls = 0;
+    let ret : Result<(),&str> = retry_with_backoff( 3, Duration::from_millis(1), || { calls += 1; Err("out of memory") });
+    assert_eq!( Err("out of memory"), ret );
+    assert_eq!( 3, calls );
+
+    // やり直しても直らないエラーは1回で諦めます
+    let mut calls = 0;
+    let ret : Result<(),&str> = retry_with_backoff_if( 3, Duration::from_millis(1), |e| *e != "not found", || { calls += 1; Err("not found") });
+    assert_eq!( Err("not found"), ret );
+    assert_eq!( 1, calls );
+}
@@ -1,42 +1,55 @@
 use std::sync::{Arc,Mutex};
-use std::io::{Write,BufWriter,Result};
+use std::io::{Write,BufWriter,Result,Error};
+use std::fs::{File,OpenOptions};
+use std::path::Path;
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 use ulid::*;
 use bzip2::Compression;
 use bzip2::write::BzEncoder;
 use mysql::*;
 use mysql::prelude::*;
+use tracing::{info,error};
 use super::gcs::*;
 
 use super::formatter::*;
 use super::selfplay::*;
-use super::setting::ModifierParameter;
+use super::replay::serialize_records;
+use super::error::CraftSimError;
+use super::util::retry_with_backoff_if;
+use super::db;
 
 ////////////////////////////////////////////////////////////////////////////////
 // Trait
 ////////////////////////////////////////////////////////////////////////////////
 
 pub trait WriteRecord {
-    fn write_record(&mut self, record:Record) -> Result<()>;
-    fn flush(&mut self) -> Result<()>;
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError>;
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError>;
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 // Evaluator
 ////////////////////////////////////////////////////////////////////////////////
 
+// MySQLへの書き込みが一時的なエラーで失敗した場合は、この間隔から倍にしながら最大max_db_failures回まで試します。
+// 諦めた場合もバッファは消さずに残します
+const DB_RETRY_INITIAL_DELAY : Duration = Duration::from_secs(1);
+
 pub struct EvaluationWriter {
     mysql_pool : Arc<Mutex<Pool>>,
     plays_per_write : usize,
+    max_db_failures : u32,
     buffer : Vec<Record>,
 }
 
 impl EvaluationWriter {
-    pub fn new( mysql_pool:Arc<Mutex<Pool>>, plays_per_write:usize ) -> EvaluationWriter {
+    pub fn new( mysql_pool:Arc<Mutex<Pool>>, plays_per_write:usize, max_db_failures:u32 ) -> EvaluationWriter {
         EvaluationWriter {
             mysql_pool : mysql_pool,
             plays_per_write : plays_per_write,
+            max_db_failures,
             buffer : vec!{},
         }
     }
@@ -66,68 +79,71 @@ fn aggregate_records( records:&Vec<Record> ) -> BTreeMap<String,(f64,usize)> {
     return ret;
 }
 
-fn write_record_flush_buffer( mysql_pool:&Arc<Mutex<Pool>>, buf:&Vec<Record> ) {
+fn write_record_flush_buffer( mysql_pool:&Arc<Mutex<Pool>>, max_db_failures:u32, buf:&Vec<Record> ) -> std::result::Result<(),CraftSimError> {
     // リプレイデータの打ち上げ
     {
-        let encoded: Vec<u8> = bincode::serialize(&buf).unwrap();
+        let encoded: Vec<u8> = serialize_records(buf)?;
 
         {
-            let file = std::fs::File::create("record.bincode.bz2").unwrap();
+            let file = std::fs::File::create("record.bincode.bz2")?;
             let mut writer = BzEncoder::new(BufWriter::new(file), Compression::best());
-            writer.write_all(&encoded).unwrap();
+            writer.write_all(&encoded)?;
+            writer.finish()?.flush()?;
         }
 
         // アップロードするファイル名を決定します
         let ulid = Ulid::new().to_string();
 
         // ファイルの打ち上げ
-        eprintln!("{} Uploading...", ulid);
+        info!(ulid = %ulid, "{} Uploading...", ulid);
         let destination_path = format!("record/{}.bz2", ulid);
         match upload("record.bincode.bz2",&destination_path,"application/x-bzip2") {
-            Ok(()) => eprintln!("{} Done.", ulid),
-            Err(x) => eprintln!("{} {}", ulid, x),
+            Ok(()) => info!(ulid = %ulid, "{} Done.", ulid),
+            Err(x) => error!(ulid = %ulid, "{} {}", ulid, x),
         }
     }
 
-    // mysqlに評価の書き込み
-    {
-        let mut conn = mysql_pool.lock().unwrap().get_conn().unwrap();
-        let mut tx = conn.start_transaction(TxOpts::default()).unwrap();
+    // mysqlに評価の書き込み。アップロードはやり直さないように、ここだけやり直します
+    let sum = aggregate_records(&buf);
+    info!(models = sum.len(), "Update evaluations... {:?}", sum);
+    retry_with_backoff_if( max_db_failures, DB_RETRY_INITIAL_DELAY, db::is_transient, || insert_evaluations(mysql_pool, &sum, buf) )?;
 
-        let sum = aggregate_records(&buf);
+    Ok(())
+}
 
-        eprintln!("Update evaluations... {:?}", sum);
+fn insert_evaluations( mysql_pool:&Arc<Mutex<Pool>>, sum:&BTreeMap<String,(f64,usize)>, buf:&[Record] ) -> mysql::Result<()> {
+    let mut conn = mysql_pool.lock().unwrap().get_conn()?;
+    let mut tx = conn.start_transaction(TxOpts::default())?;
 
-        tx.exec_batch(
-            "INSERT INTO evaluation (name, total_reward, total_count) VALUES (:name, :reward, :count) \
-            ON DUPLICATE KEY UPDATE total_reward=total_reward+VALUES(total_reward), total_count=total_count+VALUES(total_count)",
-            sum.iter().map(|(k,(reward,count))| params! {"name" => k.clone(), "reward" => reward, "count" => count})
-        ).unwrap();
+    tx.exec_batch(
+        "INSERT INTO evaluation (name, total_reward, total_count) VALUES (:name, :reward, :count) \
+        ON DUPLICATE KEY UPDATE total_reward=total_reward+VALUES(total_reward), total_count=total_count+VALUES(total_count)",
+        sum.iter().map(|(k,(reward,count))| params! {"name" => k.clone(), "reward" => reward, "count" => count})
+    )?;
 
-        tx.exec_batch(
-            "INSERT INTO episode (name, reward, quality, turn) VALUES (:name, :reward, :quality, :turn)",
-            buf.iter().map(|x| params! {"name" => x.name.clone(), "reward" => x.reward, "quality" => x.last_state.quality, "turn" => x.last_state.turn - 1 })
-        ).unwrap();
+    tx.exec_batch(
+        "INSERT INTO episode (name, reward, quality, turn) VALUES (:name, :reward, :quality, :turn)",
+        buf.iter().map(|x| params! {"name" => x.name.clone(), "reward" => x.reward, "quality" => x.last_state.quality, "turn" => x.last_state.turn - 1 })
+    )?;
 
-        tx.commit().unwrap();
-    }
+    tx.commit()
 }
 
 impl WriteRecord for EvaluationWriter {
-    fn write_record(&mut self, record:Record) -> Result<()> {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
         self.buffer.push(record);
 
         if self.buffer.len() >= self.plays_per_write {
-            write_record_flush_buffer( &self.mysql_pool, &self.buffer );
+            write_record_flush_buffer( &self.mysql_pool, self.max_db_failures, &self.buffer )?;
             self.buffer.clear();
         }
 
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
         if self.buffer.len() > 0 {
-            write_record_flush_buffer( &self.mysql_pool, &self.buffer );
+            write_record_flush_buffer( &self.mysql_pool, self.max_db_failures, &self.buffer )?;
             self.buffer.clear();
         }
 
@@ -141,17 +157,19 @@ impl WriteRecord for EvaluationWriter {
 
 pub struct GenerationWriter {
     mysql_pool : Arc<Mutex<Pool>>,
-    mod_param : ModifierParameter,
+    formatter : TsvFormatter,
     plays_per_write : usize,
+    max_db_failures : u32,
     buffer : Vec<Record>,
 }
 
 impl GenerationWriter {
-    pub fn new( mysql_pool:Arc<Mutex<Pool>>, plays_per_write:usize, mod_param:ModifierParameter ) -> GenerationWriter {
+    pub fn new( mysql_pool:Arc<Mutex<Pool>>, plays_per_write:usize, max_db_failures:u32, formatter:TsvFormatter ) -> GenerationWriter {
         GenerationWriter {
             mysql_pool : mysql_pool,
-            mod_param : mod_param,
+            formatter,
             plays_per_write : plays_per_write,
+            max_db_failures,
             buffer : vec!{},
         }
     }
@@ -166,62 +184,299 @@ fn write_samples<W:Write,F:Formatter>( writer:&mut W, formatter:&F, record:&Reco
     Ok(())
 }
 
-fn write_samples_flush_buffer( mysql_pool:&Arc<Mutex<Pool>>, mod_param:&ModifierParameter, buf:&Vec<Record> ) {
+fn write_samples_flush_buffer( mysql_pool:&Arc<Mutex<Pool>>, max_db_failures:u32, formatter:&TsvFormatter, buf:&Vec<Record> ) -> std::result::Result<(),CraftSimError> {
 
     // アップロードするファイル名を決定します
     let ulid = Ulid::new().to_string();
 
     // ファイルに全部書き込み
-    eprintln!("{} Output records...", ulid);
+    info!(ulid = %ulid, records = buf.len(), "{} Output records...", ulid);
 
     {
-        let file = std::fs::File::create("sample.txt.bz2").unwrap();
+        let file = std::fs::File::create("sample.txt.bz2")?;
         let mut writer = BzEncoder::new(BufWriter::new(file), Compression::best());
-        let formatter = TsvFormatter { mod_param:mod_param.clone()};
-
         for x in buf {
-            write_samples( &mut writer, &formatter, x ).unwrap()
+            write_samples( &mut writer, formatter, x )?;
         }
 
-        writer.flush().unwrap()
+        writer.finish()?.flush()?;
     }
 
     // ファイルの打ち上げ
-    eprintln!("{} Uploading...", ulid);
+    info!(ulid = %ulid, "{} Uploading...", ulid);
     let destination_path = format!("sample/{}.bz2", ulid);
     match upload("sample.txt.bz2",&destination_path,"application/x-bzip2") {
-        Ok(()) => eprintln!("{} Done.", ulid),
-        Err(x) => eprintln!("{} {}", ulid, x),
+        Ok(()) => info!(ulid = %ulid, "{} Done.", ulid),
+        Err(x) => error!(ulid = %ulid, "{} {}", ulid, x),
     }
 
-    // mysqlに書き込んだサンプル名を登録
-    {
-        let mut conn = mysql_pool.lock().unwrap().get_conn().unwrap();
-        let mut tx = conn.start_transaction(TxOpts::default()).unwrap();
+    // mysqlに書き込んだサンプル名を登録。アップロードはやり直さないように、ここだけやり直します
+    retry_with_backoff_if( max_db_failures, DB_RETRY_INITIAL_DELAY, db::is_transient, || insert_sample(mysql_pool, &ulid) )?;
 
-        tx.exec_drop( "INSERT INTO sample (name) VALUES (:name)", params!{"name" => ulid.to_string()} ).unwrap();
-        tx.commit().unwrap();
-    }
+    Ok(())
+}
+
+fn insert_sample( mysql_pool:&Arc<Mutex<Pool>>, ulid:&str ) -> mysql::Result<()> {
+    let mut conn = mysql_pool.lock().unwrap().get_conn()?;
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+
+    tx.exec_drop( "INSERT INTO sample (name) VALUES (:name)", params!{"name" => ulid.to_string()} )?;
+    tx.commit()
 }
 
 impl WriteRecord for GenerationWriter {
-    fn write_record(&mut self, record:Record) -> Result<()> {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
         self.buffer.push(record);
 
         if self.buffer.len() >= self.plays_per_write {
-            write_samples_flush_buffer( &self.mysql_pool, &self.mod_param, &self.buffer );
+            write_samples_flush_buffer( &self.mysql_pool, self.max_db_failures, &self.formatter, &self.buffer )?;
             self.buffer.clear();
         }
 
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
         if self.buffer.len() > 0 {
-            write_samples_flush_buffer( &self.mysql_pool, &self.mod_param, &self.buffer );
+            write_samples_flush_buffer( &self.mysql_pool, self.max_db_failures, &self.formatter, &self.buffer )?;
             self.buffer.clear();
         }
 
         Ok(())
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// SQLite
+////////////////////////////////////////////////////////////////////////////////
+
+// MySQLを用意せずに手元で少し回すための書き込み先です。
+// 表はMySQLと同じ名前と列にしますが、アップロードはしないので、サンプルは本体もsample表に入れます
+const SQLITE_SCHEMA : &str = "
+    CREATE TABLE IF NOT EXISTS evaluation (name TEXT PRIMARY KEY, total_reward REAL NOT NULL, total_count INTEGER NOT NULL);
+    CREATE TABLE IF NOT EXISTS episode (name TEXT NOT NULL, reward REAL NOT NULL, quality INTEGER NOT NULL, turn INTEGER NOT NULL);
+    CREATE TABLE IF NOT EXISTS sample (name TEXT PRIMARY KEY, body TEXT NOT NULL);
+";
+
+fn sqlite_error( e:rusqlite::Error ) -> Error {
+    Error::other(e)
+}
+
+// 表が無ければ作ります。既にあるファイルを開いた場合はそのまま追記します
+fn open_sqlite<P:AsRef<Path>>( path:P ) -> Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+    conn.execute_batch(SQLITE_SCHEMA).map_err(sqlite_error)?;
+    Ok(conn)
+}
+
+pub struct SqliteEvaluationWriter {
+    conn : rusqlite::Connection,
+    plays_per_write : usize,
+    buffer : Vec<Record>,
+}
+
+impl SqliteEvaluationWriter {
+    pub fn open<P:AsRef<Path>>( path:P, plays_per_write:usize ) -> Result<SqliteEvaluationWriter> {
+        Ok(SqliteEvaluationWriter { conn : open_sqlite(path)?, plays_per_write, buffer : vec!{} })
+    }
+
+    fn flush_buffer(&mut self) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        for (name,(reward,count)) in aggregate_records(&self.buffer) {
+            tx.execute(
+                "INSERT INTO evaluation (name, total_reward, total_count) VALUES (?1, ?2, ?3) \
+                ON CONFLICT(name) DO UPDATE SET total_reward=total_reward+excluded.total_reward, total_count=total_count+excluded.total_count",
+                rusqlite::params![name, reward, count as i64])?;
+        }
+        for x in &self.buffer {
+            tx.execute("INSERT INTO episode (name, reward, quality, turn) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![x.name, x.reward as f64, x.last_state.quality, x.turn_count()])?;
+        }
+        tx.commit()?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl WriteRecord for SqliteEvaluationWriter {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
+        self.buffer.push(record);
+
+        if self.buffer.len() >= self.plays_per_write {
+            self.flush_buffer().map_err(sqlite_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+        if !self.buffer.is_empty() {
+            self.flush_buffer().map_err(sqlite_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct SqliteGenerationWriter {
+    conn : rusqlite::Connection,
+    formatter : TsvFormatter,
+    plays_per_write : usize,
+    buffer : Vec<Record>,
+}
+
+impl SqliteGenerationWriter {
+    pub fn open<P:AsRef<Path>>( path:P, plays_per_write:usize, formatter:TsvFormatter ) -> Result<SqliteGenerationWriter> {
+        Ok(SqliteGenerationWriter { conn : open_sqlite(path)?, formatter, plays_per_write, buffer : vec!{} })
+    }
+
+    // GenerationWriterがアップロードするファイルと同じ内容を1行にまとめて入れます
+    fn flush_buffer(&mut self) -> Result<()> {
+        let mut body = vec![];
+        for x in &self.buffer {
+            write_samples( &mut body, &self.formatter, x )?;
+        }
+        let body = String::from_utf8(body).map_err(Error::other)?;
+
+        let tx = self.conn.transaction().map_err(sqlite_error)?;
+        tx.execute("INSERT INTO sample (name, body) VALUES (?1, ?2)", rusqlite::params![Ulid::new().to_string(), body]).map_err(sqlite_error)?;
+        tx.commit().map_err(sqlite_error)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl WriteRecord for SqliteGenerationWriter {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
+        self.buffer.push(record);
+
+        if self.buffer.len() >= self.plays_per_write {
+            self.flush_buffer()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+        if !self.buffer.is_empty() {
+            self.flush_buffer()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn new_test_record( name:&str, reward:f32 ) -> Record {
+    use super::logic::State;
+    use super::setting::ModifierParameter;
+
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+    Record { samples:vec![], name:name.to_string(), last_state:State::new(&mod_param), reward, thread_id:1, coroutine_id:2, adversarial:false, resign:ResignOutcome::Played, truncated:false, seeds:[0,0], setting:None, phase:0 }
+}
+
+#[test]
+fn test_sqlite_evaluation_writer()
+{
+    let path = std::env::temp_dir().join(format!("records_{}.sqlite", std::process::id()));
+    let evaluation = |path:&Path| -> Vec<(String,f64,i64)> {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let mut stmt = conn.prepare("SELECT name, total_reward, total_count FROM evaluation ORDER BY name").unwrap();
+        let rows = stmt.query_map([], |x| Ok((x.get(0)?, x.get(1)?, x.get(2)?))).unwrap();
+        rows.map(|x| x.unwrap()).collect()
+    };
+
+    // plays_per_writeに達するまでは書き込みません
+    let mut writer = SqliteEvaluationWriter::open(&path, 2).unwrap();
+    writer.write_record(new_test_record("a", 0.5)).unwrap();
+    assert!( evaluation(&path).is_empty() );
+    writer.write_record(new_test_record("b", 0.25)).unwrap();
+    writer.write_record(new_test_record("a", 0.25)).unwrap();
+    assert_eq!( vec![("a".to_string(),0.5,1), ("b".to_string(),0.25,1)], evaluation(&path) );
+    writer.flush().unwrap();
+    drop(writer);
+
+    // 開き直しても表は作り直さずに追記します
+    let mut writer = SqliteEvaluationWriter::open(&path, 2).unwrap();
+    writer.write_record(new_test_record("a", 0.5)).unwrap();
+    writer.flush().unwrap();
+    let episodes : i64 = rusqlite::Connection::open(&path).unwrap().query_row("SELECT COUNT(*) FROM episode", [], |x| x.get(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!( 4, episodes );
+}
+
+#[test]
+fn test_sqlite_generation_writer()
+{
+    use super::formatter::ValueTarget;
+    use super::setting::ModifierParameter;
+
+    let path = std::env::temp_dir().join(format!("samples_{}.sqlite", std::process::id()));
+    let formatter = TsvFormatter { mod_param:ModifierParameter::new_fountain_of_usouso(), value_target:ValueTarget::MonteCarlo, priority_column:false, settings:vec![] };
+    let mut writer = SqliteGenerationWriter::open(&path, 2, formatter).unwrap();
+    for _ in 0..3 {
+        writer.write_record(new_test_record("a", 0.5)).unwrap();
+    }
+    writer.flush().unwrap();
+    drop(writer);
+
+    let samples : i64 = rusqlite::Connection::open(&path).unwrap().query_row("SELECT COUNT(*) FROM sample", [], |x| x.get(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!( 2, samples );
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// JSON Lines
+////////////////////////////////////////////////////////////////////////////////
+
+// レコードを1行に1つずつJSONでファイルに追記します。
+// MySQLもアップロードも使わないので、手元での実験やテストのためのものです
+pub struct JsonlWriter {
+    writer : BufWriter<File>,
+}
+
+impl JsonlWriter {
+    pub fn open<P:AsRef<Path>>( path:P ) -> Result<JsonlWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlWriter { writer : BufWriter::new(file) })
+    }
+}
+
+impl WriteRecord for JsonlWriter {
+    fn write_record(&mut self, record:Record) -> std::result::Result<(),CraftSimError> {
+        serde_json::to_writer(&mut self.writer, &record).map_err(Error::from)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(),CraftSimError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_jsonl_writer()
+{
+    use std::io::{BufRead,BufReader};
+    use super::logic::State;
+    use super::setting::ModifierParameter;
+
+    let path = std::env::temp_dir().join(format!("records_{}.jsonl", std::process::id()));
+    let mod_param = ModifierParameter::new_fountain_of_usouso();
+
+    // 開き直しても前の内容の後ろに追記します
+    for (name,reward) in [("a",0.25), ("b",0.5)] {
+        let mut writer = JsonlWriter::open(&path).unwrap();
+        writer.write_record(new_test_record(name, reward)).unwrap();
+        writer.flush().unwrap();
+    }
+
+    let records : Vec<Record> = BufReader::new(File::open(&path).unwrap()).lines().map(|x| serde_json::from_str(&x.unwrap()).unwrap()).collect();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!( 2, records.len() );
+    assert_eq!( ("a",0.25), (records[0].name.as_str(), records[0].reward) );
+    assert_eq!( ("b",0.5), (records[1].name.as_str(), records[1].reward) );
+    assert_eq!( State::new(&mod_param), records[1].last_state );
+    assert_eq!( (1,2), (records[1].thread_id, records[1].coroutine_id) );
+}